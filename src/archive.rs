@@ -6,11 +6,41 @@
 
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use flate2::write::GzEncoder;
-use std::convert::TryInto;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::{File, OpenOptions};
-use tar::Builder;
+use std::io::{Read, Seek, Write};
+use tar::{Builder, HeaderMode};
+
+/// The name of the entry which lists the digests of all files within an
+/// archive, if [ArchiveBuilder::with_checksum_manifest] was requested.
+///
+/// The format matches the output of `sha256sum`, so the unpacked contents
+/// can be checked with `sha256sum -c`.
+pub const CHECKSUM_MANIFEST: &str = "contents.sha256";
+
+// The largest file size which can be stored in a ustar header (8 GiB - 1).
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+// The size of each header, and the granularity of data, within a tarball.
+const TAR_BLOCK_SIZE: u64 = 512;
+
+// The granularity at which holes are found in sparse files. This must be a
+// multiple of TAR_BLOCK_SIZE.
+const SPARSE_BLOCK_SIZE: u64 = 4096;
+
+// PAX records which describe a sparse file, in version 1.0 of the format
+// used by GNU tar.
+const PAX_SPARSE_MAJOR: &str = "GNU.sparse.major";
+const PAX_SPARSE_MINOR: &str = "GNU.sparse.minor";
+const PAX_SPARSE_NAME: &str = "GNU.sparse.name";
+const PAX_SPARSE_REALSIZE: &str = "GNU.sparse.realsize";
+
+// PAX extended records, as pairs of keys and values.
+type PaxRecords = Vec<(&'static str, Vec<u8>)>;
 
 /// These interfaces are similar to some methods in [tar::Builder].
 ///
@@ -83,18 +113,1046 @@ pub fn open_tarfile<P: AsRef<Utf8Path> + std::fmt::Debug>(tarfile: P) -> Result<
 pub trait Encoder: std::io::Write + Send {}
 impl<T> Encoder for T where T: std::io::Write + Send {}
 
+/// Describes how a zone image is compressed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Compressed with gzip, producing a `.tar.gz` file.
+    #[default]
+    Gzip,
+
+    /// Compressed with xz (LZMA2), producing a `.tar.xz` file.
+    Xz,
+}
+
+impl Compression {
+    /// Returns the file extension used for archives with this compression.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "tar.gz",
+            Compression::Xz => "tar.xz",
+        }
+    }
+}
+
+/// A [std::io::Write] implementation which compresses data using the
+/// algorithm selected by [Compression].
+pub enum Compressor<W: std::io::Write> {
+    Gzip(GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+}
+
+impl<W: std::io::Write> Compressor<W> {
+    pub fn new(writer: W, compression: Compression) -> Self {
+        match compression {
+            Compression::Gzip => Self::Gzip(GzEncoder::new(writer, flate2::Compression::fast())),
+            Compression::Xz => Self::Xz(xz2::write::XzEncoder::new(writer, 6)),
+        }
+    }
+
+    /// Flushes all compressed data, returning the underlying writer.
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            Self::Gzip(w) => w.finish(),
+            Self::Xz(w) => w.finish(),
+        }
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Gzip(w) => w.write(buf),
+            Self::Xz(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Gzip(w) => w.flush(),
+            Self::Xz(w) => w.flush(),
+        }
+    }
+}
+
+// Magic numbers identifying compressed streams.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+// Uncompressed tar files are identified by "ustar" at this offset, which is
+// present in both POSIX and GNU headers.
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// Opens an archive for reading, detecting from the contents of the file
+/// whether it is compressed with gzip, xz, or zstd, or is a plain tarball.
+pub fn open_tarfile_any(path: &Utf8Path) -> Result<Box<dyn Read>> {
+    let mut file = open_tarfile(path)?;
+    let mut magic = [0u8; TAR_MAGIC_OFFSET + TAR_MAGIC.len()];
+    let mut len = 0;
+    while len < magic.len() {
+        let count = file.read(&mut magic[len..])?;
+        if count == 0 {
+            break;
+        }
+        len += count;
+    }
+    file.rewind()?;
+
+    let magic = &magic[..len];
+    if magic.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(flate2::read::GzDecoder::new(file)))
+    } else if magic.starts_with(XZ_MAGIC) {
+        Ok(Box::new(xz2::read::XzDecoder::new(file)))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Ok(Box::new(zstd::stream::read::Decoder::new(file)?))
+    } else if magic.get(TAR_MAGIC_OFFSET..) == Some(TAR_MAGIC) {
+        Ok(Box::new(file))
+    } else {
+        bail!("Unrecognized archive format for {path}");
+    }
+}
+
+/// Describes the format of headers written for each archive entry.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// POSIX ustar headers.
+    ///
+    /// Paths are limited to 255 bytes, and files are limited to 8 GiB.
+    /// Entries which cannot be represented cause an error.
+    Ustar,
+
+    /// GNU headers, using GNU extensions for long paths.
+    ///
+    /// This is what the [tar] crate emits by default.
+    #[default]
+    Gnu,
+
+    /// POSIX.1-2001 headers, using PAX extended records for long paths,
+    /// large files, and sub-second modification times.
+    ///
+    /// Sparse files are stored as GNU tar does in PAX archives, using
+    /// `GNU.sparse.*` records (format 1.0). Their entries are named after a
+    /// placeholder, and their data begins with a map of their holes: use
+    /// [entry_path] and [entry_contents] to read them.
+    Pax,
+}
+
 pub struct ArchiveBuilder<E: Encoder> {
     pub builder: tar::Builder<E>,
+    format: ArchiveFormat,
+    mode: HeaderMode,
+    // If present, the hex-encoded digests of all files appended from the
+    // host, keyed by their path within the archive.
+    checksums: Option<Vec<(String, String)>>,
 }
 
 impl<E: Encoder> ArchiveBuilder<E> {
-    pub fn new(builder: tar::Builder<E>) -> Self {
-        Self { builder }
+    /// Wraps a [tar::Builder], configuring it to emit deterministic headers
+    /// and to preserve holes in sparse files.
+    pub fn new(mut builder: tar::Builder<E>) -> Self {
+        let mode = HeaderMode::Deterministic;
+        builder.mode(mode);
+        builder.sparse(true);
+        Self {
+            builder,
+            format: ArchiveFormat::default(),
+            mode,
+            checksums: None,
+        }
+    }
+
+    /// Requests that a [CHECKSUM_MANIFEST] entry is added when the archive
+    /// is finalized.
+    ///
+    /// Only files appended from the host are listed; metadata added from
+    /// memory (such as version files) is not.
+    pub fn with_checksum_manifest(mut self) -> Self {
+        self.checksums = Some(vec![]);
+        self
+    }
+
+    /// Selects the header format used for subsequently appended entries.
+    pub fn with_format(mut self, format: ArchiveFormat) -> Self {
+        self.format = format;
+        self
     }
 
-    pub fn into_inner(self) -> Result<E> {
+    /// Selects how metadata is copied from the host into entry headers.
+    ///
+    /// Archives are deterministic by default; sub-second modification times
+    /// are only recorded with [HeaderMode::Complete] and [ArchiveFormat::Pax].
+    pub fn with_header_mode(mut self, mode: HeaderMode) -> Self {
+        self.builder.mode(mode);
+        self.mode = mode;
+        self
+    }
+
+    /// Returns the header format used by this archive.
+    pub fn format(&self) -> ArchiveFormat {
+        self.format
+    }
+
+    /// Finalizes the archive, writing the [CHECKSUM_MANIFEST] if requested.
+    pub fn into_inner(mut self) -> Result<E> {
+        if let Some(checksums) = self.checksums.take() {
+            let mut contents = String::new();
+            for (name, digest) in &checksums {
+                contents.push_str(&format!("{digest}  {name}\n"));
+            }
+            self.append_entry(&InMemoryEntry::file(CHECKSUM_MANIFEST, contents))
+                .context("Adding checksum manifest")?;
+        }
         self.builder.into_inner().context("Finalizing archive")
     }
+
+    /// Similar to [tar::Builder::append_path_with_name], but respects the
+    /// format of the archive.
+    pub fn append_path_with_name(&mut self, path: &Utf8Path, name: &Utf8Path) -> Result<()> {
+        self.append_path_with_metadata(path, name, &EntryMetadata::default())
+    }
+
+    /// Identical to [Self::append_path_with_name], but overrides the
+    /// metadata read from the host with any fields set in `metadata`.
+    pub fn append_path_with_metadata(
+        &mut self,
+        path: &Utf8Path,
+        name: &Utf8Path,
+        metadata: &EntryMetadata,
+    ) -> Result<()> {
+        if self.checksums.is_some() && path.is_file() {
+            self.record_checksum(name, &mut File::open(path)?)?;
+        }
+        if self.format == ArchiveFormat::Gnu && metadata.is_empty() {
+            return Ok(self.builder.append_path_with_name(path, name)?);
+        }
+        let meta = std::fs::metadata(path)?;
+        if meta.is_file() {
+            let file = File::open(path)?;
+            if self.format != ArchiveFormat::Ustar && is_sparse(&meta) {
+                let (header, extensions) = self.formatted_header(&meta, metadata)?;
+                let (map, data) = scan_sparse(file)?;
+                return self.append_sparse(header, name, extensions, &map, data);
+            }
+            self.append_formatted(&meta, name, file, metadata)
+        } else if meta.is_dir() {
+            self.append_formatted(&meta, name, std::io::empty(), metadata)
+        } else {
+            bail!("Cannot add {path} to archive: not a file or directory");
+        }
+    }
+
+    /// Similar to [tar::Builder::append_file], but respects the format of
+    /// the archive.
+    pub fn append_file(&mut self, name: &Utf8Path, file: &mut File) -> Result<()> {
+        self.record_checksum(name, file)?;
+        if self.format == ArchiveFormat::Gnu {
+            return Ok(self.builder.append_file(name, file)?);
+        }
+        let meta = file.metadata()?;
+        self.append_formatted(&meta, name, file, &EntryMetadata::default())
+    }
+
+    // Adds the digest of the remainder of `file` to the checksum manifest,
+    // if one was requested, leaving the file where it was found.
+    fn record_checksum(&mut self, name: &Utf8Path, file: &mut File) -> Result<()> {
+        if let Some(checksums) = &mut self.checksums {
+            let start = file.stream_position()?;
+            let mut hasher = Sha256::new();
+            std::io::copy(file, &mut hasher)?;
+            file.seek(std::io::SeekFrom::Start(start))?;
+            checksums.push((name.to_string(), hex::encode(hasher.finalize())));
+        }
+        Ok(())
+    }
+
+    /// Similar to [tar::Builder::append_dir], but respects the format of
+    /// the archive.
+    pub fn append_dir(&mut self, name: &Utf8Path, src_path: &Utf8Path) -> Result<()> {
+        self.append_dir_with_metadata(name, src_path, &EntryMetadata::default())
+    }
+
+    /// Identical to [Self::append_dir], but overrides the metadata read from
+    /// `src_path` with any fields set in `metadata`.
+    pub fn append_dir_with_metadata(
+        &mut self,
+        name: &Utf8Path,
+        src_path: &Utf8Path,
+        metadata: &EntryMetadata,
+    ) -> Result<()> {
+        if self.format == ArchiveFormat::Gnu && metadata.is_empty() {
+            return Ok(self.builder.append_dir(name, src_path)?);
+        }
+        let meta = std::fs::metadata(src_path)?;
+        self.append_formatted(&meta, name, std::io::empty(), metadata)
+    }
+
+    /// Identical to [Self::append_path_with_name], but uses
+    /// [tokio::task::block_in_place] to avoid blocking other async tasks.
+    pub async fn append_path_with_name_async(
+        &mut self,
+        path: &Utf8Path,
+        name: &Utf8Path,
+    ) -> Result<()> {
+        tokio::task::block_in_place(move || self.append_path_with_name(path, name))
+    }
+
+    /// Identical to [Self::append_path_with_metadata], but uses
+    /// [tokio::task::block_in_place] to avoid blocking other async tasks.
+    pub async fn append_path_with_metadata_async(
+        &mut self,
+        path: &Utf8Path,
+        name: &Utf8Path,
+        metadata: &EntryMetadata,
+    ) -> Result<()> {
+        tokio::task::block_in_place(move || self.append_path_with_metadata(path, name, metadata))
+    }
+
+    /// Identical to [Self::append_file], but uses
+    /// [tokio::task::block_in_place] to avoid blocking other async tasks.
+    pub async fn append_file_async(&mut self, name: &Utf8Path, file: &mut File) -> Result<()> {
+        tokio::task::block_in_place(move || self.append_file(name, file))
+    }
+
+    /// Appends an entry constructed in memory, with full control over its
+    /// header.
+    pub fn append_entry(&mut self, entry: &InMemoryEntry) -> Result<()> {
+        let mut header = match self.format {
+            ArchiveFormat::Gnu => tar::Header::new_gnu(),
+            ArchiveFormat::Ustar | ArchiveFormat::Pax => tar::Header::new_ustar(),
+        };
+        header.set_entry_type(entry.entry_type);
+        header.set_mode(entry.mode);
+        header.set_mtime(entry.mtime);
+        header.set_uid(entry.uid);
+        header.set_gid(entry.gid);
+        header.set_size(entry.contents.len() as u64);
+
+        if self.format == ArchiveFormat::Gnu {
+            // The tar crate handles long names for GNU headers itself.
+            return match &entry.link_name {
+                Some(link_name) => {
+                    Ok(self
+                        .builder
+                        .append_link(&mut header, &entry.path, link_name)?)
+                }
+                None => Ok(self.builder.append_data(
+                    &mut header,
+                    &entry.path,
+                    entry.contents.as_slice(),
+                )?),
+            };
+        }
+        self.append_header(
+            header,
+            &entry.path,
+            entry.link_name.as_deref(),
+            vec![],
+            entry.contents.as_slice(),
+        )
+    }
+
+    /// Copies an entry read from another archive, without unpacking it to
+    /// disk.
+    ///
+    /// The entry's path, link name, and metadata are preserved. Sparse
+    /// entries remain sparse, unless this is an [ArchiveFormat::Ustar]
+    /// archive.
+    pub fn append_archive_entry<R: Read>(&mut self, mut entry: tar::Entry<'_, R>) -> Result<()> {
+        let name = entry_path(&mut entry)?;
+        self.append_archive_entry_with_name(entry, &name)
+    }
+
+    /// Identical to [Self::append_archive_entry], but stores the entry at
+    /// `name`.
+    pub fn append_archive_entry_with_name<'a, R: Read>(
+        &mut self,
+        mut entry: tar::Entry<'a, R>,
+        name: &Utf8Path,
+    ) -> Result<()> {
+        let link_name = entry
+            .link_name()?
+            .map(|link_name| Utf8PathBuf::try_from(link_name.into_owned()))
+            .transpose()?;
+        let pax_sparse = pax_sparse_file(&mut entry)?;
+
+        let src = entry.header();
+        let sparse = pax_sparse.is_some() || src.entry_type().is_gnu_sparse();
+        let mut header = match self.format {
+            ArchiveFormat::Gnu => tar::Header::new_gnu(),
+            ArchiveFormat::Ustar | ArchiveFormat::Pax => tar::Header::new_ustar(),
+        };
+        header.set_entry_type(match src.entry_type() {
+            tar::EntryType::GNUSparse => tar::EntryType::Regular,
+            entry_type => entry_type,
+        });
+        header.set_mode(src.mode()?);
+        header.set_mtime(src.mtime()?);
+        header.set_uid(src.uid()?);
+        header.set_gid(src.gid()?);
+        header.set_size(entry.size());
+
+        let is_file = header.entry_type().is_file();
+        let digest = match pax_sparse {
+            Some((_, size)) if self.format != ArchiveFormat::Ustar => {
+                // The runs of data are copied as they are, rather than
+                // filling in the holes only to find them again.
+                let map = SparseMap::read_pax(&mut entry, size)?;
+                let mut data = SparseReader::runs(entry, &map, self.checksums.is_some());
+                self.append_sparse(header, name, vec![], &map, &mut data)?;
+                data.hasher.map(|hasher| hasher.finalize())
+            }
+            pax_sparse => {
+                // The tar crate fills in the holes of GNU sparse entries
+                // itself.
+                let contents: Box<dyn Read + 'a> = match pax_sparse {
+                    Some((_, size)) => {
+                        header.set_size(size);
+                        let map = SparseMap::read_pax(&mut entry, size)?;
+                        Box::new(SparseReader::new(entry, &map))
+                    }
+                    None => Box::new(entry),
+                };
+                let mut data = HashingReader {
+                    inner: contents,
+                    hasher: Sha256::new(),
+                };
+                if sparse && self.format != ArchiveFormat::Ustar {
+                    let (map, spool) = scan_sparse(&mut data)?;
+                    self.append_sparse(header, name, vec![], &map, spool)?;
+                } else if self.format == ArchiveFormat::Gnu {
+                    match &link_name {
+                        Some(link_name) => {
+                            self.builder.append_link(&mut header, name, link_name)?
+                        }
+                        None => self.builder.append_data(&mut header, name, &mut data)?,
+                    }
+                } else {
+                    self.append_header(header, name, link_name.as_deref(), vec![], &mut data)?;
+                }
+                Some(data.hasher.finalize())
+            }
+        };
+
+        if let (Some(checksums), Some(digest)) = (&mut self.checksums, digest) {
+            if is_file {
+                checksums.push((name.to_string(), hex::encode(digest)));
+            }
+        }
+        Ok(())
+    }
+
+    // Appends a sparse file, given the map of its holes and its remaining
+    // data.
+    fn append_sparse<D: Read>(
+        &mut self,
+        mut header: tar::Header,
+        name: &Utf8Path,
+        mut extensions: PaxRecords,
+        map: &SparseMap,
+        data: D,
+    ) -> Result<()> {
+        match self.format {
+            ArchiveFormat::Gnu => {
+                header.set_entry_type(tar::EntryType::GNUSparse);
+                header.set_size(map.data_len());
+                let gnu = header
+                    .as_gnu_mut()
+                    .context("Sparse entries require a GNU header")?;
+                gnu.set_real_size(map.size);
+                let regions = &map.regions;
+                let (first, rest) = regions.split_at(regions.len().min(gnu.sparse.len()));
+                for (sparse, (offset, len)) in gnu.sparse.iter_mut().zip(first) {
+                    sparse.set_offset(*offset);
+                    sparse.set_length(*len);
+                }
+                gnu.set_is_extended(!rest.is_empty());
+
+                // Regions which don't fit in the header follow it in
+                // extension headers, ahead of the data.
+                let mut extensions = vec![];
+                let per_extension = tar::GnuExtSparseHeader::new().sparse.len();
+                let mut chunks = rest.chunks(per_extension).peekable();
+                while let Some(chunk) = chunks.next() {
+                    let mut extension = tar::GnuExtSparseHeader::new();
+                    for (sparse, (offset, len)) in extension.sparse.iter_mut().zip(chunk) {
+                        sparse.set_offset(*offset);
+                        sparse.set_length(*len);
+                    }
+                    extension.set_is_extended(chunks.peek().is_some());
+                    extensions.extend_from_slice(extension.as_bytes());
+                }
+                self.builder
+                    .append_data(&mut header, name, extensions.as_slice().chain(data))?;
+                Ok(())
+            }
+            ArchiveFormat::Pax => {
+                // The entry is named after a placeholder, so that readers
+                // which don't understand these records don't mistake the
+                // map for the contents of the file.
+                let pax_map = map.to_pax();
+                header.set_size(pax_map.len() as u64 + map.data_len());
+                extensions.extend([
+                    (PAX_SPARSE_MAJOR, b"1".to_vec()),
+                    (PAX_SPARSE_MINOR, b"0".to_vec()),
+                    (PAX_SPARSE_NAME, name.as_str().as_bytes().to_vec()),
+                    (PAX_SPARSE_REALSIZE, map.size.to_string().into_bytes()),
+                ]);
+                let placeholder = name
+                    .parent()
+                    .unwrap_or(Utf8Path::new(""))
+                    .join("GNUSparseFile.0")
+                    .join(name.file_name().unwrap_or_default());
+                self.append_header(
+                    header,
+                    &placeholder,
+                    None,
+                    extensions,
+                    pax_map.as_slice().chain(data),
+                )
+            }
+            ArchiveFormat::Ustar => {
+                header.set_size(map.size);
+                self.append_header(header, name, None, extensions, SparseReader::new(data, map))
+            }
+        }
+    }
+
+    // Appends an entry with a header derived from host metadata, with any
+    // fields of `metadata` taking precedence.
+    fn append_formatted<R: Read>(
+        &mut self,
+        meta: &std::fs::Metadata,
+        name: &Utf8Path,
+        data: R,
+        metadata: &EntryMetadata,
+    ) -> Result<()> {
+        let (mut header, extensions) = self.formatted_header(meta, metadata)?;
+        if self.format == ArchiveFormat::Gnu {
+            return Ok(self.builder.append_data(&mut header, name, data)?);
+        }
+        self.append_header(header, name, None, extensions, data)
+    }
+
+    // Returns the header used by append_formatted, and any PAX records
+    // which accompany it.
+    fn formatted_header(
+        &self,
+        meta: &std::fs::Metadata,
+        metadata: &EntryMetadata,
+    ) -> Result<(tar::Header, PaxRecords)> {
+        let mut header = match self.format {
+            ArchiveFormat::Gnu => tar::Header::new_gnu(),
+            ArchiveFormat::Ustar | ArchiveFormat::Pax => tar::Header::new_ustar(),
+        };
+        header.set_metadata_in_mode(meta, self.mode);
+        metadata.apply(&mut header);
+
+        let mut extensions = vec![];
+        if self.format == ArchiveFormat::Pax && matches!(self.mode, HeaderMode::Complete) {
+            let mtime = meta
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            if mtime.subsec_nanos() != 0 {
+                let mtime = format!("{}.{:09}", mtime.as_secs(), mtime.subsec_nanos());
+                extensions.push(("mtime", mtime.into_bytes()));
+            }
+        }
+        Ok((header, extensions))
+    }
+
+    // Appends an entry with a ustar header, adding PAX extended records
+    // for anything which does not fit (if the format allows it).
+    fn append_header<R: Read>(
+        &mut self,
+        mut header: tar::Header,
+        name: &Utf8Path,
+        link_name: Option<&Utf8Path>,
+        mut extensions: PaxRecords,
+        data: R,
+    ) -> Result<()> {
+        let pax = self.format == ArchiveFormat::Pax;
+
+        if let Err(err) = header.set_path(name) {
+            if !pax {
+                return Err(err).with_context(|| format!("Cannot store path {name} in header"));
+            }
+            // The header still needs a name, but readers which understand
+            // PAX records will ignore it.
+            extensions.push(("path", name.as_str().as_bytes().to_vec()));
+            header.set_path(truncated_file_name(name))?;
+        }
+
+        if let Some(link_name) = link_name {
+            if let Err(err) = header.set_link_name(link_name) {
+                if !pax {
+                    return Err(err)
+                        .with_context(|| format!("Cannot store link {link_name} in header"));
+                }
+                extensions.push(("linkpath", link_name.as_str().as_bytes().to_vec()));
+                header.set_link_name(truncated_file_name(link_name))?;
+            }
+        }
+
+        let size = header.size()?;
+        if size > USTAR_MAX_SIZE {
+            if !pax {
+                bail!("Cannot store {name} in header: {size} bytes exceeds the ustar limit");
+            }
+            extensions.push(("size", size.to_string().into_bytes()));
+        }
+
+        self.builder
+            .append_pax_extensions(extensions.iter().map(|(k, v)| (*k, v.as_slice())))?;
+        header.set_cksum();
+        self.builder.append(&header, data)?;
+        Ok(())
+    }
+}
+
+// Computes the digest of data as it is read.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+// The runs of data within a sparse file, as the offset and length of each,
+// and the size of the file, holes included.
+#[derive(Debug)]
+struct SparseMap {
+    regions: Vec<(u64, u64)>,
+    size: u64,
+}
+
+impl SparseMap {
+    // Returns the number of bytes of data, excluding holes.
+    fn data_len(&self) -> u64 {
+        self.regions.iter().map(|(_, len)| len).sum()
+    }
+
+    // Reads the map which begins the data of a PAX sparse entry: the number
+    // of runs, then the offset and length of each, as decimal lines padded
+    // to a whole block.
+    fn read_pax<R: Read>(reader: &mut R, size: u64) -> Result<Self> {
+        let mut consumed = 0;
+        let count = read_map_line(reader, &mut consumed)?;
+        let mut regions = vec![];
+        let mut end = 0;
+        for _ in 0..count {
+            let offset = read_map_line(reader, &mut consumed)?;
+            let len = read_map_line(reader, &mut consumed)?;
+            if offset < end || offset.checked_add(len).map_or(true, |end| end > size) {
+                bail!("Sparse map has out of order or overlapping runs");
+            }
+            end = offset + len;
+            regions.push((offset, len));
+        }
+        let padding = consumed.next_multiple_of(TAR_BLOCK_SIZE) - consumed;
+        std::io::copy(&mut reader.take(padding), &mut std::io::sink())?;
+        Ok(Self { regions, size })
+    }
+
+    // Formats the map as it begins the data of a PAX sparse entry.
+    fn to_pax(&self) -> Vec<u8> {
+        let mut map = format!("{}\n", self.regions.len());
+        for (offset, len) in &self.regions {
+            map.push_str(&format!("{offset}\n{len}\n"));
+        }
+        let mut map = map.into_bytes();
+        map.resize(map.len().next_multiple_of(TAR_BLOCK_SIZE as usize), 0);
+        map
+    }
+}
+
+// Reads one number of a PAX sparse map, counting the bytes consumed.
+fn read_map_line<R: Read>(reader: &mut R, consumed: &mut u64) -> Result<u64> {
+    let mut digits = String::new();
+    loop {
+        let mut byte = [0];
+        reader.read_exact(&mut byte).context("Reading sparse map")?;
+        *consumed += 1;
+        match byte[0] {
+            b'\n' => break,
+            digit @ b'0'..=b'9' if digits.len() < 20 => digits.push(digit.into()),
+            _ => bail!("Invalid sparse map"),
+        }
+    }
+    digits.parse().context("Invalid sparse map")
+}
+
+// Finds the holes in the contents of a file, treating any block holding only
+// zeroes as one, and spools the remaining data to a temporary file.
+//
+// Holes are found by contents, rather than asked of the filesystem, since
+// the tar crate fills them in when reading entries; the data is spooled
+// because the map of holes has to be written ahead of it.
+fn scan_sparse<R: Read>(mut contents: R) -> Result<(SparseMap, File)> {
+    let mut spool = camino_tempfile::tempfile()?;
+    let mut regions: Vec<(u64, u64)> = vec![];
+    let mut offset = 0;
+    let mut block = vec![];
+    loop {
+        block.clear();
+        let len = contents
+            .by_ref()
+            .take(SPARSE_BLOCK_SIZE)
+            .read_to_end(&mut block)? as u64;
+        if len == 0 {
+            break;
+        }
+        if block.iter().any(|b| *b != 0) {
+            spool.write_all(&block)?;
+            match regions.last_mut() {
+                Some((start, run)) if *start + *run == offset => *run += len,
+                _ => regions.push((offset, len)),
+            }
+        }
+        offset += len;
+    }
+    // As GNU tar does, end the map with an empty run, which is needed if the
+    // file ends with a hole.
+    regions.push((offset, 0));
+    spool.rewind()?;
+    Ok((
+        SparseMap {
+            regions,
+            size: offset,
+        },
+        spool,
+    ))
+}
+
+// Reads a sparse file from its runs of data.
+struct SparseReader<R> {
+    inner: R,
+    regions: VecDeque<(u64, u64)>,
+    size: u64,
+    pos: u64,
+    // Whether holes are read as zeroes, or skipped.
+    fill_holes: bool,
+    // If present, the digest of the contents of the file, holes included.
+    hasher: Option<Sha256>,
+}
+
+impl<R> SparseReader<R> {
+    // Reads the contents of the file, filling in holes with zeroes.
+    fn new(inner: R, map: &SparseMap) -> Self {
+        Self {
+            inner,
+            regions: map.regions.iter().copied().collect(),
+            size: map.size,
+            pos: 0,
+            fill_holes: true,
+            hasher: None,
+        }
+    }
+
+    // Reads only the runs of data, optionally computing the digest of the
+    // contents of the file as they're read.
+    fn runs(inner: R, map: &SparseMap, hash: bool) -> Self {
+        Self {
+            fill_holes: false,
+            hasher: hash.then(Sha256::new),
+            ..Self::new(inner, map)
+        }
+    }
+}
+
+impl<R: Read> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let (start, end) = match self.regions.front() {
+                Some((offset, len)) => (*offset, offset + len),
+                None => (self.size, self.size),
+            };
+            if self.pos < start {
+                let mut len = start - self.pos;
+                if self.fill_holes {
+                    len = len.min(buf.len() as u64);
+                }
+                if let Some(hasher) = &mut self.hasher {
+                    hash_zeroes(hasher, len);
+                }
+                self.pos += len;
+                if !self.fill_holes {
+                    continue;
+                }
+                buf[..len as usize].fill(0);
+                return Ok(len as usize);
+            }
+            if self.pos < end {
+                let len = (end - self.pos).min(buf.len() as u64) as usize;
+                let n = self.inner.read(&mut buf[..len])?;
+                if n == 0 && len > 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                if let Some(hasher) = &mut self.hasher {
+                    hasher.update(&buf[..n]);
+                }
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            if self.regions.pop_front().is_none() {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+// Feeds `len` zeroes to `hasher`.
+fn hash_zeroes(hasher: &mut Sha256, mut len: u64) {
+    let zeroes = [0; SPARSE_BLOCK_SIZE as usize];
+    while len > 0 {
+        let n = len.min(SPARSE_BLOCK_SIZE);
+        hasher.update(&zeroes[..n as usize]);
+        len -= n;
+    }
+}
+
+// Returns the path and size of the file held by a PAX sparse entry, or None
+// for other entries.
+fn pax_sparse_file<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Option<(Utf8PathBuf, u64)>> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(None);
+    };
+    let (mut major, mut minor, mut name, mut size) = (None, None, None, None);
+    for extension in extensions {
+        let extension = extension?;
+        match extension.key() {
+            Ok(PAX_SPARSE_MAJOR) => major = Some(extension.value()?),
+            Ok(PAX_SPARSE_MINOR) => minor = Some(extension.value()?),
+            Ok(PAX_SPARSE_NAME) => name = Some(Utf8PathBuf::from(extension.value()?)),
+            Ok(PAX_SPARSE_REALSIZE) => size = Some(extension.value()?.parse::<u64>()?),
+            _ => (),
+        }
+    }
+    match (major, minor, name, size) {
+        (None, ..) => Ok(None),
+        (Some("1"), Some("0"), Some(name), Some(size)) => Ok(Some((name, size))),
+        (Some(major), minor, ..) => bail!(
+            "Unsupported sparse file format {major}.{}",
+            minor.unwrap_or("?")
+        ),
+    }
+}
+
+/// Returns the path of an entry within its archive.
+///
+/// Unlike [tar::Entry::path], this names sparse files within
+/// [ArchiveFormat::Pax] archives by their own path, rather than that of a
+/// placeholder.
+pub fn entry_path<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Utf8PathBuf> {
+    match pax_sparse_file(entry)? {
+        Some((name, _)) => Ok(name),
+        None => Ok(Utf8PathBuf::try_from(entry.path()?.into_owned())?),
+    }
+}
+
+/// Returns a reader of the contents of an entry.
+///
+/// Unlike reading the [tar::Entry] itself, this fills in the holes of sparse
+/// files within [ArchiveFormat::Pax] archives.
+pub fn entry_contents<'a, R: Read>(mut entry: tar::Entry<'a, R>) -> Result<Box<dyn Read + 'a>> {
+    match pax_sparse_file(&mut entry)? {
+        Some((_, size)) => {
+            let map = SparseMap::read_pax(&mut entry, size)?;
+            Ok(Box::new(SparseReader::new(entry, &map)))
+        }
+        None => Ok(Box::new(entry)),
+    }
+}
+
+// Unpacks an entry to `dst`. Unlike [tar::Entry::unpack], this leaves holes
+// in sparse files within PAX archives, rather than writing out their maps.
+fn unpack_entry<R: Read>(mut entry: tar::Entry<'_, R>, dst: &Utf8Path) -> Result<()> {
+    let Some((_, size)) = pax_sparse_file(&mut entry)? else {
+        entry.unpack(dst)?;
+        return Ok(());
+    };
+    let map = SparseMap::read_pax(&mut entry, size)?;
+    let mut file = File::create(dst)?;
+    for (offset, len) in &map.regions {
+        file.seek(std::io::SeekFrom::Start(*offset))?;
+        if std::io::copy(&mut (&mut entry).take(*len), &mut file)? != *len {
+            bail!("Sparse file {dst} is truncated");
+        }
+    }
+    file.set_len(size)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(entry.header().mode()?))?;
+    }
+    let mtime = filetime::FileTime::from_unix_time(entry.header().mtime()? as i64, 0);
+    filetime::set_file_handle_times(&file, None, Some(mtime))?;
+    Ok(())
+}
+
+/// Overrides for the permissions and ownership recorded in an entry's
+/// header.
+///
+/// Fields which are unset are taken from the host (or, for entries
+/// constructed in memory, from their defaults).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct EntryMetadata {
+    /// Permission bits of the entry.
+    pub mode: Option<u32>,
+    /// The user which owns the entry.
+    pub uid: Option<u64>,
+    /// The group which owns the entry.
+    pub gid: Option<u64>,
+}
+
+impl EntryMetadata {
+    /// Returns true if no fields are overridden.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn apply(&self, header: &mut tar::Header) {
+        if let Some(mode) = self.mode {
+            header.set_mode(mode);
+        }
+        if let Some(uid) = self.uid {
+            header.set_uid(uid);
+        }
+        if let Some(gid) = self.gid {
+            header.set_gid(gid);
+        }
+    }
+}
+
+/// The modification time used for entries which don't specify one.
+///
+/// This matches the timestamp used by [HeaderMode::Deterministic].
+pub const DETERMINISTIC_MTIME: u64 = 1153704088;
+
+/// Describes an entry which is constructed in memory, rather than read from
+/// the host.
+///
+/// See [ArchiveBuilder::append_entry].
+#[derive(Clone, Debug)]
+pub struct InMemoryEntry {
+    /// Path of the entry within the archive.
+    pub path: Utf8PathBuf,
+    /// The contents of the entry. Should be empty for non-files.
+    pub contents: Vec<u8>,
+    /// Permission bits of the entry.
+    pub mode: u32,
+    /// Modification time, in seconds since the Unix epoch.
+    pub mtime: u64,
+    pub uid: u64,
+    pub gid: u64,
+    /// The kind of entry (file, directory, symlink, ...).
+    pub entry_type: tar::EntryType,
+    /// The target of the entry, for links.
+    pub link_name: Option<Utf8PathBuf>,
+}
+
+impl InMemoryEntry {
+    /// Creates a regular file, using the same defaults as other
+    /// deterministic entries.
+    pub fn file<P: Into<Utf8PathBuf>, C: Into<Vec<u8>>>(path: P, contents: C) -> Self {
+        Self {
+            path: path.into(),
+            contents: contents.into(),
+            mode: 0o644,
+            mtime: DETERMINISTIC_MTIME,
+            uid: 0,
+            gid: 0,
+            entry_type: tar::EntryType::Regular,
+            link_name: None,
+        }
+    }
+
+    /// Creates a directory.
+    pub fn directory<P: Into<Utf8PathBuf>>(path: P) -> Self {
+        Self {
+            mode: 0o755,
+            entry_type: tar::EntryType::Directory,
+            ..Self::file(path, vec![])
+        }
+    }
+
+    /// Creates a symbolic link at `path`, pointing to `target`.
+    pub fn symlink<P: Into<Utf8PathBuf>, T: Into<Utf8PathBuf>>(path: P, target: T) -> Self {
+        Self {
+            mode: 0o777,
+            entry_type: tar::EntryType::Symlink,
+            link_name: Some(target.into()),
+            ..Self::file(path, vec![])
+        }
+    }
+
+    /// Creates a named pipe (FIFO).
+    pub fn fifo<P: Into<Utf8PathBuf>>(path: P) -> Self {
+        Self {
+            entry_type: tar::EntryType::Fifo,
+            ..Self::file(path, vec![])
+        }
+    }
+
+    /// Sets the permission bits of the entry.
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the owner and group of the entry.
+    pub fn with_owner(mut self, uid: u64, gid: u64) -> Self {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    /// Overrides the permissions and ownership of the entry with any
+    /// fields set in `metadata`.
+    pub fn with_metadata(mut self, metadata: &EntryMetadata) -> Self {
+        self.mode = metadata.mode.unwrap_or(self.mode);
+        self.uid = metadata.uid.unwrap_or(self.uid);
+        self.gid = metadata.gid.unwrap_or(self.gid);
+        self
+    }
+
+    /// Sets the modification time of the entry.
+    pub fn with_mtime(mut self, mtime: u64) -> Self {
+        self.mtime = mtime;
+        self
+    }
+}
+
+// Returns true if the file occupies fewer blocks on disk than its length
+// implies, indicating that it contains holes.
+#[cfg(unix)]
+fn is_sparse(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.blocks() * 512 < meta.len()
+}
+
+#[cfg(not(unix))]
+fn is_sparse(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+// Returns the file name of a path, truncated to fit within a ustar header.
+fn truncated_file_name(path: &Utf8Path) -> &str {
+    let name = path.file_name().unwrap_or_default();
+    let mut end = name.len().min(100);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
 }
 
 /// Adds a package at `package_path` to a new zone image
@@ -103,54 +1161,766 @@ pub async fn add_package_to_zone_archive<E: Encoder>(
     archive: &mut ArchiveBuilder<E>,
     package_path: &Utf8Path,
 ) -> Result<()> {
-    let tmp = camino_tempfile::tempdir()?;
-    let gzr = flate2::read::GzDecoder::new(open_tarfile(package_path)?);
-    if gzr.header().is_none() {
-        bail!(
-            "Missing gzip header from {} - cannot add it to zone image",
-            package_path,
-        );
+    let options = ComponentOptions {
+        allow_override: true,
+        ..Default::default()
+    };
+    add_component_to_zone_archive(
+        archive,
+        package_path,
+        &mut ComponentFiles::default(),
+        &options,
+    )
+    .await
+}
+
+/// Tracks which component of a composite package supplies each file.
+///
+/// See [add_component_to_zone_archive].
+#[derive(Debug, Default)]
+pub struct ComponentFiles {
+    // The component which first supplied each file.
+    added: BTreeMap<Utf8PathBuf, Utf8PathBuf>,
+    // The last component permitted to replace each file.
+    overrides: BTreeMap<Utf8PathBuf, Utf8PathBuf>,
+}
+
+impl ComponentFiles {
+    /// Records the files of a component whose files may replace those of
+    /// earlier components, so that the replaced files are omitted.
+    ///
+    /// This must be called for each such component, in the order in which
+    /// components are added, before any are added.
+    pub fn add_overrides(
+        &mut self,
+        package_path: &Utf8Path,
+        options: &ComponentOptions,
+    ) -> Result<()> {
+        let reader = open_tarfile_any(package_path)
+            .with_context(|| format!("Cannot read files from {package_path}"))?;
+        let mut reader = tar::Archive::new(reader);
+        for entry in reader.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type() == tar::EntryType::Directory {
+                continue;
+            }
+            let entry_path = entry_path(&mut entry)?;
+            if let Some((_, archive_path)) = component_entry_path(&entry_path, options)? {
+                self.overrides
+                    .insert(archive_path, package_path.to_path_buf());
+            }
+        }
+        Ok(())
     }
-    let mut component_reader = tar::Archive::new(gzr);
+}
+
+// Returns the path within the zone of an entry of a component, and the path
+// at which it's added to the archive, unless it's omitted.
+fn component_entry_path(
+    entry_path: &Utf8Path,
+    options: &ComponentOptions,
+) -> Result<Option<(Utf8PathBuf, Utf8PathBuf)>> {
+    // Ignore the JSON header files, and any checksums: the zone image
+    // records its own.
+    if entry_path == "oxide.json" || entry_path == CHECKSUM_MANIFEST {
+        return Ok(None);
+    }
+
+    let relative_path = entry_path.strip_prefix("root/")?;
+    let zone_path = Utf8Path::new("/").join(relative_path);
+    if options.is_excluded(&zone_path) {
+        return Ok(None);
+    }
+    let archive_path = match options.rename.get(&zone_path) {
+        Some(renamed) => Utf8Path::new("root").join(renamed.strip_prefix("/")?),
+        None => entry_path.to_path_buf(),
+    };
+    Ok(Some((zone_path, archive_path)))
+}
+
+/// Controls how the files of a component are added to a zone image.
+///
+/// Paths are absolute paths within the zone, such as
+/// "/opt/oxide/my-service/config.toml".
+#[derive(Debug, Default)]
+pub struct ComponentOptions {
+    /// If "true", files may replace files with the same path from earlier
+    /// components.
+    pub allow_override: bool,
+
+    /// Files and directories matching any of these patterns are omitted.
+    ///
+    /// As with shell globs, `*` does not match `/`, but `**` matches any
+    /// number of directories.
+    pub exclude: Vec<glob::Pattern>,
+
+    /// Files which are added under a different path, keyed by their
+    /// original path.
+    pub rename: BTreeMap<Utf8PathBuf, Utf8PathBuf>,
+}
+
+impl ComponentOptions {
+    fn is_excluded(&self, path: &Utf8Path) -> bool {
+        self.exclude
+            .iter()
+            .any(|pattern| glob_matches(pattern, path))
+    }
+}
+
+// Matches a path against a pattern, where `*` does not match `/`.
+fn glob_matches(pattern: &glob::Pattern, path: &Utf8Path) -> bool {
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    pattern.matches_with(path.as_str(), options)
+}
+
+/// Identical to [add_package_to_zone_archive], but records the files added
+/// in `files`, and applies the filters in `options`.
+///
+/// Unless `options.allow_override` is set, it is an error for the package to
+/// contain a file which was already added by another component. Directories
+/// may be shared between components. Files which a later component replaces,
+/// as recorded by [ComponentFiles::add_overrides], are omitted.
+pub async fn add_component_to_zone_archive<E: Encoder>(
+    archive: &mut ArchiveBuilder<E>,
+    package_path: &Utf8Path,
+    files: &mut ComponentFiles,
+    options: &ComponentOptions,
+) -> Result<()> {
+    let tmp = camino_tempfile::tempdir()?;
+    let reader = open_tarfile_any(package_path)
+        .with_context(|| format!("Cannot add {package_path} to zone image"))?;
+    let mut component_reader = tar::Archive::new(reader);
     let entries = component_reader.entries()?;
 
     // First, unpack the existing entries
     for entry in entries {
         let mut entry = entry?;
 
-        // Ignore the JSON header files
-        let entry_path = entry.path()?;
-        if entry_path == Utf8Path::new("oxide.json") {
+        let entry_path = entry_path(&mut entry)?;
+        let Some((zone_path, archive_path)) = component_entry_path(&entry_path, options)? else {
             continue;
-        }
+        };
+        let relative_path = zone_path.strip_prefix("/")?;
 
-        let entry_path: &Utf8Path = entry_path.strip_prefix("root/")?.try_into()?;
-        let entry_unpack_path = tmp.path().join(entry_path);
-        entry.unpack(&entry_unpack_path)?;
+        if entry.header().entry_type() != tar::EntryType::Directory {
+            if let Some(previous) = files.added.get(&archive_path) {
+                if !options.allow_override {
+                    bail!(
+                        "{archive_path} from {package_path} would override the copy from \
+                         {previous}; set 'allow_override' on the component to permit this"
+                    );
+                }
+            } else {
+                files
+                    .added
+                    .insert(archive_path.clone(), package_path.to_path_buf());
+            }
+            if files
+                .overrides
+                .get(&archive_path)
+                .is_some_and(|replacement| replacement != package_path)
+            {
+                continue;
+            }
+        }
 
-        let entry_path = entry.path()?.into_owned();
-        let entry_path: &Utf8Path = entry_path.as_path().try_into()?;
+        let entry_unpack_path = tmp.path().join(relative_path);
+        unpack_entry(entry, &entry_unpack_path)?;
         assert!(entry_unpack_path.exists());
 
         archive
-            .builder
-            .append_path_with_name_async(entry_unpack_path, entry_path)
+            .append_path_with_name_async(&entry_unpack_path, &archive_path)
             .await?;
     }
     Ok(())
 }
 
+/// Copies the contents of a zone image into `archive`, without unpacking it
+/// to disk.
+///
+/// As with [add_package_to_zone_archive], the "oxide.json" header and any
+/// [CHECKSUM_MANIFEST] are omitted. Sparse files are copied without filling
+/// in their holes.
+pub fn copy_zone_archive<E: Encoder>(
+    archive: &mut ArchiveBuilder<E>,
+    package_path: &Utf8Path,
+) -> Result<()> {
+    let reader = open_tarfile_any(package_path)
+        .with_context(|| format!("Cannot copy {package_path} to zone image"))?;
+    let mut component_reader = tar::Archive::new(reader);
+    for entry in component_reader.entries()? {
+        let entry = entry?;
+        let entry_path = entry.path()?;
+        if entry_path == Utf8Path::new("oxide.json")
+            || entry_path == Utf8Path::new(CHECKSUM_MANIFEST)
+        {
+            continue;
+        }
+        archive.append_archive_entry(entry)?;
+    }
+    Ok(())
+}
+
+/// Copies the contents of a tarball package into `archive`, without
+/// unpacking it to disk.
+///
+/// Each entry is copied with its header, so modes, owners, and entry types
+/// (such as symlinks and FIFOs) are preserved. Entries at any of `omitted`,
+/// and any [CHECKSUM_MANIFEST], are skipped.
+pub fn copy_tarball_archive<E: Encoder>(
+    archive: &mut ArchiveBuilder<E>,
+    package_path: &Utf8Path,
+    omitted: &[&str],
+) -> Result<()> {
+    let reader =
+        open_tarfile_any(package_path).with_context(|| format!("Cannot read {package_path}"))?;
+    let mut reader = tar::Archive::new(reader);
+    for entry in reader.entries()? {
+        let entry = entry?;
+        let entry_path = entry.path()?;
+        if omitted
+            .iter()
+            .any(|omitted| entry_path == Utf8Path::new(omitted))
+            || entry_path == Utf8Path::new(CHECKSUM_MANIFEST)
+        {
+            continue;
+        }
+        archive.append_archive_entry(entry)?;
+    }
+    Ok(())
+}
+
+/// Calls `f` with each entry of the package at `package_path` whose path
+/// matches any of `patterns`.
+///
+/// Paths are absolute: for zone images, they are the path of the entry
+/// within the zone, and for other packages, they are relative to the root of
+/// the archive. It is an error for a pattern to match nothing.
+pub fn visit_package_entries(
+    package_path: &Utf8Path,
+    patterns: &[glob::Pattern],
+    mut f: impl FnMut(&Utf8Path, tar::Entry<'_, Box<dyn Read>>) -> Result<()>,
+) -> Result<()> {
+    let reader = open_tarfile_any(package_path)
+        .with_context(|| format!("Cannot read files from {package_path}"))?;
+    let mut reader = tar::Archive::new(reader);
+    let mut matched = vec![false; patterns.len()];
+    let mut zoned = false;
+    for (i, entry) in reader.entries()?.enumerate() {
+        let mut entry = entry?;
+        let entry_path = entry_path(&mut entry)?;
+
+        // Zone images always begin with their "oxide.json" header.
+        if i == 0 && entry_path == "oxide.json" {
+            zoned = true;
+            continue;
+        }
+        if entry_path == CHECKSUM_MANIFEST {
+            continue;
+        }
+        let relative_path = if zoned {
+            match entry_path.strip_prefix("root") {
+                Ok(path) => path,
+                Err(_) => continue,
+            }
+        } else {
+            &entry_path
+        };
+        let path = Utf8Path::new("/").join(relative_path);
+
+        let mut found = false;
+        for (pattern, matched) in patterns.iter().zip(&mut matched) {
+            if glob_matches(pattern, &path) {
+                *matched = true;
+                found = true;
+            }
+        }
+        if found {
+            f(&path, entry).with_context(|| format!("Cannot copy {path} from {package_path}"))?;
+        }
+    }
+
+    if let Some((pattern, _)) = patterns
+        .iter()
+        .zip(&matched)
+        .find(|(_, matched)| !**matched)
+    {
+        bail!("No files in {package_path} match '{pattern}'");
+    }
+    Ok(())
+}
+
 pub async fn new_compressed_archive_builder(
     path: &Utf8Path,
-) -> Result<ArchiveBuilder<GzEncoder<File>>> {
+    compression: Compression,
+) -> Result<ArchiveBuilder<Compressor<File>>> {
     let file = create_tarfile(path)?;
+    Ok(new_compressed_archive_writer(file, compression))
+}
+
+/// Creates a builder for a compressed archive which is written to `writer`.
+pub fn new_compressed_archive_writer<W: Encoder>(
+    writer: W,
+    compression: Compression,
+) -> ArchiveBuilder<Compressor<W>> {
     // TODO: Consider using async compression, async tar.
     // It's not the *worst* thing in the world for a packaging tool to block
     // here, but it would help the other async threads remain responsive if
     // we avoided blocking.
-    let gzw = GzEncoder::new(file, flate2::Compression::fast());
-    let mut archive = Builder::new(gzw);
-    archive.mode(tar::HeaderMode::Deterministic);
+    let archive = Builder::new(Compressor::new(writer, compression));
+
+    // Zone images may contain deeply-nested paths and large files, which
+    // PAX headers represent portably.
+    ArchiveBuilder::new(archive).with_format(ArchiveFormat::Pax)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn long_path() -> Utf8PathBuf {
+        let mut path = Utf8PathBuf::from("root");
+        for _ in 0..20 {
+            path.push("a-deeply-nested-directory");
+        }
+        path.push("file.txt");
+        path
+    }
+
+    const SPARSE_LEN: u64 = 4 << 20;
+
+    // Creates a file which is all hole, but for a few bytes at its end.
+    // Returns false if the filesystem doesn't support holes.
+    fn create_sparse_file(path: &Utf8Path) -> bool {
+        let mut file = File::create(path).unwrap();
+        file.set_len(SPARSE_LEN).unwrap();
+        file.seek(std::io::SeekFrom::End(-4)).unwrap();
+        file.write_all(b"data").unwrap();
+        is_sparse(&path.metadata().unwrap())
+    }
+
+    fn assert_sparse_contents(contents: &[u8]) {
+        assert_eq!(contents.len() as u64, SPARSE_LEN);
+        assert!(contents[..contents.len() - 4].iter().all(|b| *b == 0));
+        assert_eq!(&contents[contents.len() - 4..], b"data");
+    }
+
+    // Returns an archive holding a sparse file at "root/disk.img", or None
+    // if the filesystem doesn't support holes.
+    fn sparse_archive(format: ArchiveFormat) -> Option<Vec<u8>> {
+        let tmp = camino_tempfile::tempdir().unwrap();
+        let src = tmp.path().join("disk.img");
+        if !create_sparse_file(&src) {
+            return None;
+        }
+        let mut archive = ArchiveBuilder::new(Builder::new(vec![])).with_format(format);
+        archive
+            .append_path_with_name(&src, Utf8Path::new("root/disk.img"))
+            .unwrap();
+        let bytes = archive.into_inner().unwrap();
+        assert!(bytes.len() < (1 << 20), "holes were materialized");
+        Some(bytes)
+    }
+
+    // Returns the path and contents of the first entry of an archive.
+    fn first_entry(bytes: &[u8]) -> (Utf8PathBuf, Vec<u8>) {
+        let mut reader = tar::Archive::new(bytes);
+        let mut entry = reader.entries().unwrap().next().unwrap().unwrap();
+        let path = entry_path(&mut entry).unwrap();
+        let mut contents = vec![];
+        entry_contents(entry)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        (path, contents)
+    }
+
+    #[test]
+    fn pax_long_paths_round_trip() {
+        let tmp = camino_tempfile::tempdir().unwrap();
+        let src = tmp.path().join("file.txt");
+        std::fs::write(&src, "contents").unwrap();
+
+        let mut archive = ArchiveBuilder::new(Builder::new(vec![])).with_format(ArchiveFormat::Pax);
+        archive.append_path_with_name(&src, &long_path()).unwrap();
+        let bytes = archive.into_inner().unwrap();
+
+        let mut reader = tar::Archive::new(bytes.as_slice());
+        let mut entries = reader.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), long_path().as_std_path());
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "contents");
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn checksum_manifest_lists_files() {
+        let tmp = camino_tempfile::tempdir().unwrap();
+        let src = tmp.path().join("file.txt");
+        std::fs::write(&src, "contents").unwrap();
+
+        let mut archive = ArchiveBuilder::new(Builder::new(vec![])).with_checksum_manifest();
+        archive
+            .append_dir(Utf8Path::new("dir"), tmp.path())
+            .unwrap();
+        archive
+            .append_path_with_name(&src, Utf8Path::new("dir/file.txt"))
+            .unwrap();
+        archive
+            .append_file(
+                Utf8Path::new("dir/copy.txt"),
+                &mut File::open(&src).unwrap(),
+            )
+            .unwrap();
+        let bytes = archive.into_inner().unwrap();
+
+        let mut reader = tar::Archive::new(bytes.as_slice());
+        let mut entries = reader.entries().unwrap();
+        assert_eq!(
+            entries.next().unwrap().unwrap().path().unwrap().to_str(),
+            Some("dir")
+        );
+        assert_eq!(
+            entries.next().unwrap().unwrap().path().unwrap().to_str(),
+            Some("dir/file.txt")
+        );
+        let mut copy = entries.next().unwrap().unwrap();
+        assert_eq!(copy.path().unwrap().to_str(), Some("dir/copy.txt"));
+        let mut contents = String::new();
+        copy.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "contents");
+        let mut manifest = entries.next().unwrap().unwrap();
+        assert_eq!(manifest.path().unwrap().to_str(), Some(CHECKSUM_MANIFEST));
+        let mut contents = String::new();
+        manifest.read_to_string(&mut contents).unwrap();
+        assert_eq!(
+            contents,
+            "d1b2a59fbea7e20077af9f91b27e95e865061b270be03ff539ab3b73587882e8  dir/file.txt\n\
+             d1b2a59fbea7e20077af9f91b27e95e865061b270be03ff539ab3b73587882e8  dir/copy.txt\n"
+        );
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn pax_sparse_files_preserve_holes() {
+        let Some(bytes) = sparse_archive(ArchiveFormat::Pax) else {
+            // The filesystem doesn't support holes; nothing to check.
+            return;
+        };
+
+        // No GNU extensions should appear in a PAX archive: the file is
+        // described by PAX records, and named after a placeholder.
+        let mut reader = tar::Archive::new(bytes.as_slice());
+        let mut entries = reader.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.header().entry_type(), tar::EntryType::Regular);
+        assert!(entry.header().as_ustar().is_some());
+        assert_eq!(
+            entry.path().unwrap().to_str(),
+            Some("root/GNUSparseFile.0/disk.img")
+        );
+        assert_eq!(entry_path(&mut entry).unwrap(), "root/disk.img");
+        let mut contents = vec![];
+        entry_contents(entry)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_sparse_contents(&contents);
+        assert!(entries.next().is_none());
+
+        // GNU tar, if it's available, should restore the original file.
+        let is_gnu_tar = std::process::Command::new("tar")
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.stdout.starts_with(b"tar (GNU tar)"));
+        if is_gnu_tar {
+            let tmp = camino_tempfile::tempdir().unwrap();
+            let archive = tmp.path().join("archive.tar");
+            std::fs::write(&archive, &bytes).unwrap();
+            let status = std::process::Command::new("tar")
+                .arg("-xf")
+                .arg(&archive)
+                .arg("-C")
+                .arg(tmp.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+            let contents = std::fs::read(tmp.path().join("root/disk.img")).unwrap();
+            assert_sparse_contents(&contents);
+        }
+    }
+
+    #[test]
+    fn sparse_entries_copy_with_holes() {
+        use ArchiveFormat::{Gnu, Pax, Ustar};
+        for (from, to) in [(Gnu, Gnu), (Gnu, Pax), (Pax, Gnu), (Pax, Pax), (Pax, Ustar)] {
+            let Some(bytes) = sparse_archive(from) else {
+                // The filesystem doesn't support holes; nothing to check.
+                return;
+            };
+
+            let mut copy = ArchiveBuilder::new(Builder::new(vec![]))
+                .with_format(to)
+                .with_checksum_manifest();
+            let mut reader = tar::Archive::new(bytes.as_slice());
+            for entry in reader.entries().unwrap() {
+                copy.append_archive_entry(entry.unwrap()).unwrap();
+            }
+            let copied = copy.into_inner().unwrap();
+            if to == Ustar {
+                // Holes can only be filled in.
+                assert!(copied.len() as u64 > SPARSE_LEN);
+            } else {
+                assert!(copied.len() < (1 << 20), "{from:?} to {to:?} filled holes");
+            }
+
+            let (path, contents) = first_entry(&copied);
+            assert_eq!(path, "root/disk.img");
+            assert_sparse_contents(&contents);
+
+            // The checksum covers the contents, holes included.
+            let mut reader = tar::Archive::new(copied.as_slice());
+            let mut manifest = reader.entries().unwrap().nth(1).unwrap().unwrap();
+            assert_eq!(manifest.path().unwrap().to_str(), Some(CHECKSUM_MANIFEST));
+            let mut listed = String::new();
+            manifest.read_to_string(&mut listed).unwrap();
+            assert_eq!(
+                listed,
+                format!(
+                    "{}  root/disk.img\n",
+                    hex::encode(Sha256::digest(&contents))
+                )
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn composite_components_keep_holes() {
+        for format in [ArchiveFormat::Gnu, ArchiveFormat::Pax] {
+            let Some(bytes) = sparse_archive(format) else {
+                // The filesystem doesn't support holes; nothing to check.
+                return;
+            };
+            let tmp = camino_tempfile::tempdir().unwrap();
+            let component = tmp.path().join("component.tar");
+            std::fs::write(&component, bytes).unwrap();
+
+            let mut archive = ArchiveBuilder::new(Builder::new(vec![])).with_format(format);
+            add_package_to_zone_archive(&mut archive, &component)
+                .await
+                .unwrap();
+            let composite = archive.into_inner().unwrap();
+            assert!(composite.len() < (1 << 20), "{format:?} filled holes");
+
+            let (path, contents) = first_entry(&composite);
+            assert_eq!(path, "root/disk.img");
+            assert_sparse_contents(&contents);
+        }
+    }
+
+    #[test]
+    fn in_memory_entries_round_trip() {
+        for format in [ArchiveFormat::Ustar, ArchiveFormat::Gnu, ArchiveFormat::Pax] {
+            let mut archive = ArchiveBuilder::new(Builder::new(vec![])).with_format(format);
+            archive
+                .append_entry(&InMemoryEntry::directory("bin"))
+                .unwrap();
+            archive
+                .append_entry(
+                    &InMemoryEntry::file("bin/tool", b"\x7fELF".to_vec())
+                        .with_mode(0o755)
+                        .with_mtime(1234),
+                )
+                .unwrap();
+            archive
+                .append_entry(&InMemoryEntry::symlink("tool", "bin/tool"))
+                .unwrap();
+            archive
+                .append_entry(&InMemoryEntry::fifo("bin/pipe").with_mode(0o600))
+                .unwrap();
+            let bytes = archive.into_inner().unwrap();
+
+            let mut reader = tar::Archive::new(bytes.as_slice());
+            let mut entries = reader.entries().unwrap();
+
+            let dir = entries.next().unwrap().unwrap();
+            assert_eq!(dir.header().entry_type(), tar::EntryType::Directory);
+            assert_eq!(dir.header().mode().unwrap(), 0o755);
+
+            let mut file = entries.next().unwrap().unwrap();
+            assert_eq!(file.path().unwrap().to_str(), Some("bin/tool"));
+            assert_eq!(file.header().mode().unwrap(), 0o755);
+            assert_eq!(file.header().mtime().unwrap(), 1234);
+            let mut contents = vec![];
+            file.read_to_end(&mut contents).unwrap();
+            assert_eq!(contents, b"\x7fELF");
+
+            let link = entries.next().unwrap().unwrap();
+            assert_eq!(link.header().entry_type(), tar::EntryType::Symlink);
+            assert_eq!(
+                link.link_name().unwrap().unwrap().to_str(),
+                Some("bin/tool")
+            );
+
+            let fifo = entries.next().unwrap().unwrap();
+            assert_eq!(fifo.header().entry_type(), tar::EntryType::Fifo);
+            assert_eq!(fifo.header().mode().unwrap(), 0o600);
+            assert_eq!(fifo.header().size().unwrap(), 0);
+            assert!(entries.next().is_none());
+        }
+    }
+
+    #[test]
+    fn archive_entries_copy_between_formats() {
+        let long_path = format!("root/{}/file", "d".repeat(200));
+        let mut source = ArchiveBuilder::new(Builder::new(vec![])).with_format(ArchiveFormat::Gnu);
+        source
+            .append_entry(&InMemoryEntry::file("oxide.json", b"{}".to_vec()))
+            .unwrap();
+        source
+            .append_entry(
+                &InMemoryEntry::file(long_path.as_str(), b"data".to_vec()).with_mode(0o600),
+            )
+            .unwrap();
+        source
+            .append_entry(&InMemoryEntry::symlink("root/link", long_path.as_str()))
+            .unwrap();
+        let bytes = source.into_inner().unwrap();
+
+        let mut copy = ArchiveBuilder::new(Builder::new(vec![]))
+            .with_format(ArchiveFormat::Pax)
+            .with_checksum_manifest();
+        let mut reader = tar::Archive::new(bytes.as_slice());
+        for entry in reader.entries().unwrap().skip(1) {
+            copy.append_archive_entry(entry.unwrap()).unwrap();
+        }
+        let bytes = copy.into_inner().unwrap();
+
+        let mut reader = tar::Archive::new(bytes.as_slice());
+        let mut entries = reader.entries().unwrap();
+        let mut file = entries.next().unwrap().unwrap();
+        assert_eq!(file.path().unwrap().to_str(), Some(long_path.as_str()));
+        assert_eq!(file.header().mode().unwrap(), 0o600);
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "data");
+
+        let link = entries.next().unwrap().unwrap();
+        assert_eq!(
+            link.link_name().unwrap().unwrap().to_str(),
+            Some(long_path.as_str())
+        );
+
+        let mut manifest = entries.next().unwrap().unwrap();
+        let mut contents = String::new();
+        manifest.read_to_string(&mut contents).unwrap();
+        assert_eq!(
+            contents,
+            format!(
+                "3a6eb0790f39ac87c94f3856b2dd2c5d110e6811602261a9a923d3bb23adc8b7  {long_path}\n"
+            )
+        );
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn open_tarfile_any_detects_compression() {
+        let tmp = camino_tempfile::tempdir().unwrap();
+
+        let tarball = {
+            let mut archive = ArchiveBuilder::new(Builder::new(vec![]));
+            archive
+                .append_entry(&InMemoryEntry::file("file.txt", "contents"))
+                .unwrap();
+            archive.into_inner().unwrap()
+        };
+        let gzip = {
+            let mut w = GzEncoder::new(vec![], flate2::Compression::fast());
+            w.write_all(&tarball).unwrap();
+            w.finish().unwrap()
+        };
+        let xz = {
+            let mut w = xz2::write::XzEncoder::new(vec![], 6);
+            w.write_all(&tarball).unwrap();
+            w.finish().unwrap()
+        };
+        let zstd = zstd::stream::encode_all(tarball.as_slice(), 0).unwrap();
+
+        for (name, bytes) in [
+            ("raw.tar", &tarball),
+            ("pkg.tar.gz", &gzip),
+            ("pkg.tar.xz", &xz),
+            ("pkg.tar.zst", &zstd),
+        ] {
+            let path = tmp.path().join(name);
+            std::fs::write(&path, bytes).unwrap();
+
+            let mut reader = tar::Archive::new(open_tarfile_any(&path).unwrap());
+            let mut entries = reader.entries().unwrap();
+            let mut entry = entries.next().unwrap().unwrap();
+            assert_eq!(entry.path().unwrap().to_str(), Some("file.txt"), "{name}");
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, "contents", "{name}");
+        }
 
-    Ok(ArchiveBuilder::new(archive))
+        let path = tmp.path().join("garbage");
+        std::fs::write(&path, "not an archive").unwrap();
+        assert!(
+            open_tarfile_any(&path).is_err(),
+            "Should not recognize garbage"
+        );
+    }
+
+    #[test]
+    fn ustar_rejects_long_paths() {
+        let tmp = camino_tempfile::tempdir().unwrap();
+        let src = tmp.path().join("file.txt");
+        std::fs::write(&src, "contents").unwrap();
+
+        let mut archive =
+            ArchiveBuilder::new(Builder::new(vec![])).with_format(ArchiveFormat::Ustar);
+        archive
+            .append_path_with_name(&src, &long_path())
+            .expect_err("ustar headers cannot store long paths");
+    }
+
+    #[test]
+    fn entry_metadata_overrides_host() {
+        let tmp = camino_tempfile::tempdir().unwrap();
+        let src = tmp.path().join("file.txt");
+        std::fs::write(&src, "contents").unwrap();
+        let metadata = EntryMetadata {
+            mode: Some(0o4755),
+            uid: Some(12),
+            gid: None,
+        };
+
+        for format in [ArchiveFormat::Ustar, ArchiveFormat::Gnu, ArchiveFormat::Pax] {
+            let mut archive = ArchiveBuilder::new(Builder::new(vec![])).with_format(format);
+            archive
+                .append_dir_with_metadata(Utf8Path::new("dir"), tmp.path(), &metadata)
+                .unwrap();
+            archive
+                .append_path_with_metadata(&src, Utf8Path::new("dir/file.txt"), &metadata)
+                .unwrap();
+            let bytes = archive.into_inner().unwrap();
+
+            let mut reader = tar::Archive::new(bytes.as_slice());
+            for entry in reader.entries().unwrap() {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                assert_eq!(header.mode().unwrap(), 0o4755, "{format:?}");
+                assert_eq!(header.uid().unwrap(), 12, "{format:?}");
+                // Deterministic headers don't record the owner on the host.
+                assert_eq!(header.gid().unwrap(), 0, "{format:?}");
+            }
+        }
+    }
 }
@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A command-line tool for building the packages described by a manifest,
+//! for consumers without a wrapper of their own.
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use clap::{Parser, Subcommand};
+use omicron_zone_package::config::{self, Config, PresetName};
+use omicron_zone_package::package::BuildConfig;
+use omicron_zone_package::progress::{EventReporter, ProgressEvent};
+use omicron_zone_package::report::CacheOutcome;
+use omicron_zone_package::sbom::Sbom;
+use omicron_zone_package::target::TargetMap;
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(
+    name = "omicron-package",
+    about = "Builds the packages described by a manifest"
+)]
+struct Args {
+    /// The package manifest.
+    #[arg(short, long, default_value = "package-manifest.toml")]
+    manifest: Utf8PathBuf,
+
+    /// The directory within which packages are built.
+    #[arg(short, long, default_value = "out")]
+    out: Utf8PathBuf,
+
+    /// A target preset defined by the manifest.
+    #[arg(short, long)]
+    preset: Option<PresetName>,
+
+    /// Target key-value pairs, such as "machine=gimlet".
+    ///
+    /// These take precedence over the values of the preset.
+    #[arg(short, long, value_name = "KEY=VALUE")]
+    target: Vec<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the inputs of each package, without building anything.
+    Plan {
+        /// Prints every input, as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Prints what each package contains, and where each part came from,
+    /// as JSON.
+    Sbom,
+    /// Builds every package for the target.
+    Build {
+        /// The number of packages to build at once.
+        #[arg(short, long, default_value_t = 4)]
+        jobs: usize,
+
+        /// Rebuilds every package, ignoring the cache.
+        #[arg(long)]
+        no_cache: bool,
+    },
+    /// Stamps the packages deployed to the target with a version.
+    Stamp { version: semver::Version },
+    /// Checks that every package has been built from its current inputs.
+    Verify,
+    /// Prints the dependencies between packages, as a Graphviz graph.
+    Graph,
+}
+
+impl Args {
+    // Determines the target from the preset and key-value pairs.
+    fn target(&self, config: &Config) -> Result<TargetMap> {
+        let mut overrides = TargetMap::default();
+        for pairs in &self.target {
+            overrides.0.extend(pairs.parse::<TargetMap>()?.0);
+        }
+        match &self.preset {
+            Some(preset) => Ok(TargetMap::from_preset(config, preset, &overrides)?),
+            None => {
+                if let Some(keys) = &config.target.keys {
+                    keys.validate(&overrides)?;
+                }
+                Ok(overrides)
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    let args = Args::parse();
+    let config =
+        config::parse(&args.manifest).with_context(|| format!("Parsing {}", args.manifest))?;
+    let target = args.target(&config)?;
+    let out = &args.out;
+
+    match &args.command {
+        Command::Plan { json } => {
+            let build_config = BuildConfig {
+                target: &target,
+                ..Default::default()
+            };
+            let plans = config.plan_all(out, &build_config)?;
+            if *json {
+                let inputs = plans
+                    .iter()
+                    .map(|(name, inputs)| (name, inputs.iter().collect::<Vec<_>>()))
+                    .collect::<std::collections::BTreeMap<_, _>>();
+                println!("{}", serde_json::to_string_pretty(&inputs)?);
+            } else {
+                for (name, inputs) in &plans {
+                    println!(
+                        "{name}: {} inputs, {} bytes",
+                        inputs.len(),
+                        inputs.byte_len()
+                    );
+                }
+            }
+        }
+        Command::Sbom => {
+            let build_config = BuildConfig {
+                target: &target,
+                ..Default::default()
+            };
+            let sboms = config
+                .plan_all(out, &build_config)?
+                .iter()
+                .map(|(name, inputs)| (name.clone(), Sbom::from_inputs(inputs)))
+                .collect::<std::collections::BTreeMap<_, _>>();
+            println!("{}", serde_json::to_string_pretty(&sboms)?);
+        }
+        Command::Build { jobs, no_cache } => {
+            let log = slog::Logger::root(slog::Discard, slog::o!());
+            let (progress, mut events) = EventReporter::new(log);
+            let printer = tokio::spawn(async move {
+                while let Some(reported) = events.recv().await {
+                    // Errors are printed alongside the outcome of each build.
+                    if let ProgressEvent::Warning { message } = reported.event {
+                        match reported.package {
+                            Some(package) => eprintln!("{package}: warning: {message}"),
+                            None => eprintln!("warning: {message}"),
+                        }
+                    }
+                }
+            });
+
+            let build_config = BuildConfig {
+                target: &target,
+                progress: &progress,
+                cache_disabled: *no_cache,
+                ..Default::default()
+            };
+            let results = config.build_all(&build_config, out, *jobs).await;
+            drop(progress);
+            printer.await?;
+
+            let mut failed = false;
+            for (name, result) in results {
+                match result {
+                    Ok(report) => {
+                        let outcome = match report.cache {
+                            CacheOutcome::Hit => "cached".to_string(),
+                            CacheOutcome::Miss { reason } => format!("built ({reason})"),
+                        };
+                        println!("{name}: {outcome}: {}", report.output_path);
+                    }
+                    Err(err) => {
+                        failed = true;
+                        println!("{name}: failed: {err:#}");
+                    }
+                }
+            }
+            if failed {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Stamp { version } => {
+            for path in config.stamp_all(&target, out, version).await? {
+                println!("{path}");
+            }
+        }
+        Command::Verify => {
+            let build_config = BuildConfig {
+                target: &target,
+                ..Default::default()
+            };
+            let cache = build_config.open_cache(out).await?;
+            let mut stale = false;
+            for (name, inputs) in config.plan_all(out, &build_config)? {
+                let output_path =
+                    config.packages[&name].get_target_output_path(&name, out, &target)?;
+                let differences = cache.explain(&inputs, &output_path).await?;
+                if differences.is_empty() {
+                    println!("{name}: up-to-date");
+                    continue;
+                }
+                stale = true;
+                println!("{name}: out-of-date");
+                for difference in differences {
+                    println!("    {difference}");
+                }
+            }
+            if stale {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Graph => {
+            println!("digraph packages {{");
+            for (name, deps) in config.packages_to_build(&target).dependencies() {
+                println!("    \"{name}\";");
+                for dep in deps {
+                    println!("    \"{name}\" -> \"{dep}\";");
+                }
+            }
+            println!("}}");
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
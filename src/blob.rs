@@ -6,20 +6,26 @@
 
 use anyhow::{anyhow, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
+use camino_tempfile::NamedUtf8TempFile;
 use chrono::{DateTime, FixedOffset, Utc};
 use futures_util::StreamExt;
 use reqwest::header::{CONTENT_LENGTH, LAST_MODIFIED};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 
-use crate::progress::{NoProgress, Progress};
+use crate::cache::CACHE_SUBDIRECTORY;
+use crate::progress::{NoProgress, Progress, ProgressEvent};
 
 // Path to the blob S3 Bucket.
 const S3_BUCKET: &str = "https://oxide-omicron-build.s3.amazonaws.com";
 // Name for the directory component where downloaded blobs are stored.
 pub(crate) const BLOB: &str = "blob";
+// Name for the directory, within the cache directory, which remembers
+// whether blobs were current.
+const BLOB_FRESHNESS: &str = "blob-freshness";
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Source {
@@ -33,8 +39,13 @@ impl Source {
             Self::S3(s) => format!("{}/{}", S3_BUCKET, s),
             Self::Buildomat(spec) => {
                 format!(
-                    "https://buildomat.eng.oxide.computer/public/file/oxidecomputer/{}/{}/{}/{}",
-                    spec.repo, spec.series, spec.commit, spec.artifact
+                    "{}/public/file/{}/{}/{}/{}/{}",
+                    crate::package::DEFAULT_BUILDOMAT_URL,
+                    crate::package::DEFAULT_BUILDOMAT_ORG,
+                    spec.repo,
+                    spec.series,
+                    spec.commit,
+                    spec.artifact
                 )
             }
         }
@@ -90,11 +101,95 @@ impl Source {
     }
 }
 
+/// Remembers when downloaded blobs were last confirmed to be current, so
+/// that builds within `ttl` of that check need not contact the server.
+///
+/// Records are kept in the cache directory within the output directory.
+pub struct BlobFreshness {
+    directory: Utf8PathBuf,
+    ttl: Duration,
+}
+
+// The outcome of confirming that the blob at `url` was current.
+#[derive(Serialize, Deserialize)]
+struct FreshnessRecord {
+    url: String,
+    len: u64,
+    modified: SystemTime,
+    checked: SystemTime,
+}
+
+impl BlobFreshness {
+    pub fn new(output_directory: &Utf8Path, ttl: Duration) -> Self {
+        Self {
+            directory: output_directory
+                .join(CACHE_SUBDIRECTORY)
+                .join(BLOB_FRESHNESS),
+            ttl,
+        }
+    }
+
+    fn record_path(&self, url: &str) -> Utf8PathBuf {
+        let digest = Sha256::digest(url.as_bytes());
+        self.directory.join(format!("{}.json", hex::encode(digest)))
+    }
+
+    // Returns true if `destination` was found to be current within the TTL,
+    // and has not changed since.
+    async fn is_fresh(&self, url: &str, destination: &Utf8Path) -> bool {
+        let Ok(contents) = tokio::fs::read(self.record_path(url)).await else {
+            return false;
+        };
+        let Ok(record) = serde_json::from_slice::<FreshnessRecord>(&contents) else {
+            return false;
+        };
+        let Ok(metadata) = tokio::fs::metadata(destination).await else {
+            return false;
+        };
+        let expired = record
+            .checked
+            .elapsed()
+            .map_or(true, |elapsed| elapsed >= self.ttl);
+        record.url == url
+            && !expired
+            && metadata.len() == record.len
+            && metadata.modified().ok() == Some(record.modified)
+    }
+
+    // Records that `destination` is current.
+    async fn record(&self, url: &str, destination: &Utf8Path) -> Result<()> {
+        let metadata = tokio::fs::metadata(destination).await?;
+        let record = FreshnessRecord {
+            url: url.to_string(),
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            checked: SystemTime::now(),
+        };
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let path = self.record_path(url);
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(&record)?).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+}
+
 // Downloads "source" from S3_BUCKET to "destination".
 pub async fn download(
     progress: &dyn Progress,
     source: &Source,
     destination: &Utf8Path,
+) -> Result<()> {
+    download_with_freshness(progress, source, destination, None).await
+}
+
+/// Like [download], but skips asking the server whether blobs from S3 are
+/// current if `freshness` remembers a recent check.
+pub async fn download_with_freshness(
+    progress: &dyn Progress,
+    source: &Source,
+    destination: &Utf8Path,
+    freshness: Option<&BlobFreshness>,
 ) -> Result<()> {
     let blob = destination
         .file_name()
@@ -103,11 +198,65 @@ pub async fn download(
         .to_string();
 
     let url = source.get_url();
+    let freshness = freshness.filter(|_| matches!(source, Source::S3(_)));
+    if let Some(freshness) = freshness {
+        if freshness.is_fresh(&url, destination).await {
+            return Ok(());
+        }
+    }
+
     let client = reqwest::Client::new();
     if !source.download_required(&url, &client, destination).await? {
+        if let Some(freshness) = freshness {
+            freshness.record(&url, destination).await?;
+        }
         return Ok(());
     }
 
+    // Download alongside the destination, so that a failed download never
+    // truncates a blob which was previously downloaded.
+    let dir = match destination.parent() {
+        Some(dir) if !dir.as_str().is_empty() => dir,
+        _ => Utf8Path::new("."),
+    };
+    let tmp = NamedUtf8TempFile::new_in(dir)
+        .with_context(|| format!("Cannot create temporary file for {destination}"))?;
+    let file = tokio::fs::File::from_std(tmp.as_file().try_clone()?);
+    let last_modified = fetch(progress, &client, &url, &blob, file).await?;
+    // Temporary files are only accessible by their owner, unlike the blobs
+    // we've historically downloaded.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tmp.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o644))?;
+    }
+    tmp.persist(destination)
+        .with_context(|| format!("Cannot create {destination}"))?;
+
+    // Set destination file's modified time based on HTTPS response
+    if let Some(last_modified) = last_modified {
+        filetime::set_file_mtime(
+            destination,
+            filetime::FileTime::from_system_time(last_modified.into()),
+        )?;
+    }
+    if let Some(freshness) = freshness {
+        freshness.record(&url, destination).await?;
+    }
+
+    Ok(())
+}
+
+// Writes the contents of "url" to "file", returning the last-modified time
+// advertised by the server, if any.
+pub(crate) async fn fetch(
+    progress: &dyn Progress,
+    client: &reqwest::Client,
+    url: &str,
+    name: &str,
+    mut file: tokio::fs::File,
+) -> Result<Option<DateTime<FixedOffset>>> {
     let response = client.get(url).send().await?.error_for_status()?;
     let response_headers = response.headers();
 
@@ -130,22 +279,31 @@ pub async fn download(
         None
     };
 
-    // Write file bytes to destination
-    let mut file = tokio::fs::File::create(destination).await?;
-
     // Create a sub-progress for the blob download
     let blob_progress = if let Some(length) = content_length {
-        progress.sub_progress(length)
+        progress.named_sub_progress(name.to_string().into(), length)
     } else {
         Box::new(NoProgress::new())
     };
-    blob_progress.set_message(blob.into());
+    blob_progress.set_message(name.to_string().into());
+    progress.increment_total_bytes(content_length.unwrap_or(0));
 
     let mut stream = response.bytes_stream();
+    let mut downloaded = 0;
     while let Some(chunk) = stream.next().await {
         let chunk = chunk?;
         file.write_all(&chunk).await?;
         blob_progress.increment_completed(chunk.len() as u64);
+        if content_length.is_none() {
+            progress.increment_total_bytes(chunk.len() as u64);
+        }
+        progress.increment_completed_bytes(chunk.len() as u64);
+        downloaded += chunk.len() as u64;
+        progress.report(ProgressEvent::BlobDownloadProgress {
+            name: name.to_string(),
+            downloaded,
+            total: content_length,
+        });
     }
     drop(blob_progress);
 
@@ -160,20 +318,10 @@ pub async fn download(
     // All this to say we need to explicitly sync here before returning
     // and trying to add the blob to the archive.
     file.sync_all().await?;
-    drop(file);
-
-    // Set destination file's modified time based on HTTPS response
-    if let Some(last_modified) = last_modified {
-        filetime::set_file_mtime(
-            destination,
-            filetime::FileTime::from_system_time(last_modified.into()),
-        )?;
-    }
-
-    Ok(())
+    Ok(last_modified)
 }
 
-async fn get_sha256_digest(path: &Utf8Path) -> Result<[u8; 32]> {
+pub(crate) async fn get_sha256_digest(path: &Utf8Path) -> Result<[u8; 32]> {
     let mut reader = BufReader::new(
         tokio::fs::File::open(path)
             .await
@@ -207,3 +355,23 @@ fn test_converts() {
     let _last_modified: DateTime<FixedOffset> =
         chrono::DateTime::parse_from_rfc2822(last_modified).unwrap();
 }
+
+#[tokio::test]
+async fn test_blob_freshness() {
+    let dir = camino_tempfile::tempdir().unwrap();
+    let blob = dir.path().join("blob");
+    let url = "https://example.com/blob";
+    tokio::fs::write(&blob, "contents").await.unwrap();
+
+    let freshness = BlobFreshness::new(dir.path(), Duration::from_secs(3600));
+    assert!(!freshness.is_fresh(url, &blob).await);
+    freshness.record(url, &blob).await.unwrap();
+    assert!(freshness.is_fresh(url, &blob).await);
+    assert!(!freshness.is_fresh("https://example.com/other", &blob).await);
+
+    // Checks expire, and are forgotten if the blob changes.
+    let expired = BlobFreshness::new(dir.path(), Duration::ZERO);
+    assert!(!expired.is_fresh(url, &blob).await);
+    tokio::fs::write(&blob, "other contents").await.unwrap();
+    assert!(!freshness.is_fresh(url, &blob).await);
+}
@@ -14,22 +14,47 @@
 //! to build a package are the same, the output should be the same, so
 //! we can use the cached output to avoid an unnecessary package construction
 //! step.
+//!
+//! Manifests and artifacts may also be shared between machines through a
+//! [CacheBackend]; see [Cache::set_remote].
 
-use crate::digest::{DefaultDigest, Digest, FileDigester};
-use crate::input::{BuildInput, BuildInputs};
+use crate::archive::{create_tarfile, open_tarfile_any, ArchiveBuilder, Compression, Compressor};
+use crate::digest::Digest;
+pub use crate::digest::DigestAlgorithm;
+use crate::input::{BuildInput, BuildInputs, InputOrigin};
+use crate::json_file::update_locked_json;
 
 use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
 use camino::{Utf8Path, Utf8PathBuf};
+use camino_tempfile::NamedUtf8TempFile;
+use fs2::FileExt;
+use futures::{StreamExt, TryStreamExt};
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use std::marker::PhantomData;
+use sha2::Digest as _;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 pub const CACHE_SUBDIRECTORY: &str = "manifest-cache";
 
+// The file within the cache directory recording when artifacts and blobs
+// were last used, for [Cache::evict].
+const USAGE_FILE: &str = "usage.json";
+
 pub type Inputs = Vec<BuildInput>;
 
+/// The number of files hashed concurrently by default.
+///
+/// See [Cache::set_hashing_parallelism].
+pub const DEFAULT_HASHING_PARALLELISM: usize = 32;
+
 // It's not actually a map, because serde doesn't like enum keys.
 //
 // This has the side-effect that changing the order of input files
@@ -43,22 +68,98 @@ struct InputEntry {
     value: Option<Digest>,
 }
 
+// The version of the format in which manifests are written.
+//
+// Increment this when changing the format of [ArtifactManifest], and add a
+// migration to [MANIFEST_MIGRATIONS] if older manifests can be upgraded.
+const MANIFEST_VERSION: u32 = 3;
+
+// Upgrades manifests written in older formats, indexed by the version they
+// upgrade from.
+//
+// Manifests written before versions were recorded are version 0.
+const MANIFEST_MIGRATIONS: &[fn(&mut serde_json::Value)] = &[
+    // 0 -> 1: Adds the version, and drops a field which was always null.
+    |manifest| {
+        if let Some(manifest) = manifest.as_object_mut() {
+            manifest.remove("phantom");
+        }
+    },
+    // 1 -> 2: Adds the salt, which older manifests were written without.
+    |manifest| {
+        if let Some(manifest) = manifest.as_object_mut() {
+            manifest.insert("salt".to_string(), serde_json::Value::Null);
+        }
+    },
+    // 2 -> 3: Adds metadata overrides to files and directories, and folds
+    // "AddDirectoryWithMetadata" into "AddDirectory".
+    |manifest| {
+        let Some(inputs) = manifest["inputs"].as_array_mut() else {
+            return;
+        };
+        for input in inputs {
+            let Some(key) = input["key"].as_object_mut() else {
+                continue;
+            };
+            if let Some(dir) = key.remove("AddDirectory") {
+                key.insert(
+                    "AddDirectory".to_string(),
+                    serde_json::json!({ "dir": dir, "metadata": {} }),
+                );
+            } else if let Some(mut dir) = key.remove("AddDirectoryWithMetadata") {
+                let metadata = serde_json::json!({
+                    "mode": dir["mode"].take(),
+                    "uid": dir["uid"].take(),
+                    "gid": dir["gid"].take(),
+                });
+                key.insert(
+                    "AddDirectory".to_string(),
+                    serde_json::json!({ "dir": dir["dir"].take(), "metadata": metadata }),
+                );
+            }
+            for name in ["AddFile", "AddInMemoryFile"] {
+                if let Some(file) = key.get_mut(name).and_then(|file| file.as_object_mut()) {
+                    file.insert("metadata".to_string(), serde_json::json!({}));
+                }
+            }
+        }
+    },
+];
+
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct ArtifactManifest<D = DefaultDigest> {
+pub struct ArtifactManifest {
+    // The version of the format of this manifest.
+    #[serde(default)]
+    version: u32,
+
     // All inputs, which create this artifact
     inputs: InputMap,
 
     // Output, created by this artifact
     output_path: Utf8PathBuf,
 
-    // Which digest is being used?
-    phantom: PhantomData<D>,
+    // The digest of the output, if recorded by this version of the crate.
+    #[serde(default)]
+    output_digest: Option<Digest>,
+
+    // The salt supplied by the caller when the artifact was cached.
+    salt: Option<String>,
 }
 
-impl<D: FileDigester> ArtifactManifest<D> {
+impl ArtifactManifest {
     /// Reads all inputs and outputs, collecting their digests.
-    async fn new(inputs: &BuildInputs, output_path: Utf8PathBuf) -> anyhow::Result<Self> {
-        let result = Self::new_internal(inputs, output_path, None).await?;
+    async fn new(
+        inputs: &BuildInputs,
+        output_path: Utf8PathBuf,
+        hashing: Hashing<'_>,
+    ) -> anyhow::Result<Self> {
+        let mut result = Self::new_internal(inputs, output_path, None, hashing).await?;
+        result.output_digest = Some(
+            hashing
+                .for_outputs()
+                .get_digest(&result.output_path)
+                .await?,
+        );
         Ok(result)
     }
 
@@ -67,59 +168,111 @@ impl<D: FileDigester> ArtifactManifest<D> {
     // equal to the digests found in "compare_with". This helps improve
     // the "cache miss" case, by allowing us to stop calculating hashes
     // as soon as we find any divergence.
+    //
+    // At most "hashing.parallelism" inputs are hashed at once.
     async fn new_internal(
         inputs: &BuildInputs,
         output_path: Utf8PathBuf,
         compare_with: Option<&Self>,
+        hashing: Hashing<'_>,
     ) -> Result<Self, CacheError> {
-        let input_entry_tasks = inputs.0.iter().cloned().enumerate().map(|(i, input)| {
-            let expected_input = compare_with.map(|manifest| &manifest.inputs.0[i]);
-            async move {
-                let digest = if let Some(input_path) = input.input_path() {
-                    Some(D::get_digest(input_path).await?)
-                } else {
-                    None
-                };
-                let input = InputEntry {
-                    key: input.clone(),
-                    value: digest,
-                };
-
-                if let Some(expected_input) = expected_input {
-                    if *expected_input != input {
-                        CacheError::miss(format!(
-                            "Differing build inputs.\nSaw {:#?}\nExpected {:#?})",
-                            input, expected_input
-                        ));
+        // Origins describe inputs, but don't affect the artifact, so they
+        // aren't recorded.
+        let input_entry_tasks =
+            inputs
+                .iter_with_origins()
+                .enumerate()
+                .map(|(i, (origin, input))| {
+                    let input = input.clone();
+                    let expected_input = compare_with.map(|manifest| &manifest.inputs.0[i]);
+                    async move {
+                        let digest = if let Some(input_path) = input.input_path() {
+                            // Inputs may have been removed, as by [Cache::evict];
+                            // that's a miss, rather than a failure.
+                            let digest = hashing.get_digest(input_path).await.map_err(|err| {
+                                if is_not_found(&err) && expected_input.is_some() {
+                                    CacheError::from(MissReason::InputMissing {
+                                        path: input_path.to_path_buf(),
+                                        origin: origin.cloned(),
+                                    })
+                                } else {
+                                    CacheError::from(err)
+                                }
+                            })?;
+                            Some(digest)
+                        } else if let BuildInput::AddSymlink { target, .. } = &input {
+                            Some(
+                                hashing
+                                    .algorithm
+                                    .get_data_digest(target.as_str().as_bytes()),
+                            )
+                        } else {
+                            None
+                        };
+                        let input = InputEntry {
+                            key: input.clone(),
+                            value: digest,
+                        };
+
+                        if let Some(expected_input) = expected_input {
+                            if *expected_input != input {
+                                return Err(match input.key.input_path() {
+                                    Some(path) => MissReason::InputDigestChanged {
+                                        path: path.to_path_buf(),
+                                        origin: origin.cloned(),
+                                    },
+                                    None => MissReason::InputSetChanged,
+                                }
+                                .into());
+                            }
+                        };
+
+                        Ok::<_, CacheError>(input)
                     }
-                };
-
-                Ok::<_, CacheError>(input)
-            }
-        });
+                });
 
-        let inputs = InputMap(futures::future::try_join_all(input_entry_tasks).await?);
+        // Bound the number of files open at once, which packages with many
+        // inputs could otherwise exhaust.
+        let inputs = InputMap(
+            futures::stream::iter(input_entry_tasks)
+                .buffered(hashing.parallelism.max(1))
+                .try_collect()
+                .await?,
+        );
 
         Ok(Self {
+            version: MANIFEST_VERSION,
             inputs,
             output_path,
-            phantom: PhantomData,
+            output_digest: compare_with.and_then(|manifest| manifest.output_digest.clone()),
+            salt: compare_with.and_then(|manifest| manifest.salt.clone()),
         })
     }
 
-    // Writes a manifest file to a particular location.
-    async fn write_to(&self, path: &Utf8PathBuf) -> anyhow::Result<()> {
-        let Some(extension) = path.extension() else {
-            bail!("Missing extension?");
-        };
-        if extension != "json" {
-            bail!("JSON encoding is all we know. Write to a '.json' file?");
+    // Serializes the manifest with `encoding`.
+    fn to_bytes(&self, encoding: ManifestEncoding) -> anyhow::Result<Vec<u8>> {
+        match encoding {
+            ManifestEncoding::Json => {
+                serde_json::to_vec(&self).context("Failed to serialize ArtifactManifest to JSON")
+            }
+            ManifestEncoding::Postcard => postcard::to_stdvec(&self)
+                .context("Failed to serialize ArtifactManifest to postcard"),
         }
-        let serialized =
-            serde_json::to_string(&self).context("Failed to serialize ArtifactManifest to JSON")?;
+    }
 
-        let mut f = File::create(path).await?;
-        f.write_all(serialized.as_bytes()).await?;
+    // Writes a manifest file to a particular location, encoded as
+    // indicated by its extension.
+    async fn write_to(&self, path: &Utf8PathBuf) -> anyhow::Result<()> {
+        let encoding = ManifestEncoding::from_path(path)?;
+        let serialized = self.to_bytes(encoding)?;
+
+        // Write to a temporary file first, so an interrupted write cannot
+        // leave a truncated manifest behind.
+        let tmp_path = path.with_extension(format!("{}.tmp", encoding.extension()));
+        let mut f = File::create(&tmp_path).await?;
+        f.write_all(&serialized).await?;
+        f.sync_all().await?;
+        tokio::fs::rename(&tmp_path, path).await?;
         Ok(())
     }
 
@@ -127,37 +280,129 @@ impl<D: FileDigester> ArtifactManifest<D> {
     //
     // Does not validate whether or not any corresponding artifacts exist.
     async fn read_from(path: &Utf8PathBuf) -> Result<Self, CacheError> {
-        let Some(extension) = path.extension() else {
-            return Err(anyhow!("Missing extension?").into());
-        };
-        if extension != "json" {
-            return Err(anyhow!("JSON encoding is all we know. Read from a '.json' file?").into());
-        }
-
+        ManifestEncoding::from_path(path)?;
         let mut f = match File::open(path).await {
             Ok(f) => f,
             Err(e) => {
                 if matches!(e.kind(), std::io::ErrorKind::NotFound) {
-                    return Err(CacheError::miss(format!("File {} not found", path)));
+                    return Err(MissReason::ManifestMissing { path: path.clone() }.into());
                 } else {
                     return Err(anyhow!(e).into());
                 }
             }
         };
-        let mut buffer = String::new();
-        f.read_to_string(&mut buffer)
-            .await
-            .map_err(|e| anyhow!(e))?;
+        let mut buffer = vec![];
+        f.read_to_end(&mut buffer).await.map_err(|e| anyhow!(e))?;
+        Self::parse(path, &buffer)
+    }
 
+    // Parses the contents of a manifest file, read from `path`, migrating it
+    // from older versions.
+    fn parse(path: &Utf8Path, buffer: &[u8]) -> Result<Self, CacheError> {
         // In the case that we cannot read the manifest, treat it as "missing".
         // This will force a rebuild anyway.
-        let Ok(manifest) = serde_json::from_str(&buffer) else {
-            return Err(CacheError::miss(format!(
-                "Cannot parse manifest at {}",
-                path
-            )));
+        let cannot_parse = || {
+            CacheError::from(MissReason::ManifestUnreadable {
+                path: path.to_path_buf(),
+            })
         };
-        Ok(manifest)
+        let incompatible = |version| {
+            CacheError::from(MissReason::IncompatibleManifest {
+                path: path.to_path_buf(),
+                version,
+            })
+        };
+
+        match ManifestEncoding::from_path(path)? {
+            ManifestEncoding::Json => {
+                // Manifests in the current format are deserialized directly,
+                // which is much faster than going through a [serde_json::Value]
+                // for manifests with many inputs.
+                #[derive(Deserialize)]
+                struct Version {
+                    #[serde(default)]
+                    version: u32,
+                }
+                let Version { version } =
+                    serde_json::from_slice(buffer).map_err(|_| cannot_parse())?;
+                if version > MANIFEST_VERSION {
+                    return Err(incompatible(version));
+                }
+                if version == MANIFEST_VERSION {
+                    return serde_json::from_slice(buffer).map_err(|_| cannot_parse());
+                }
+
+                let mut value: serde_json::Value =
+                    serde_json::from_slice(buffer).map_err(|_| cannot_parse())?;
+                for migration in &MANIFEST_MIGRATIONS[version as usize..] {
+                    migration(&mut value);
+                }
+                let mut manifest: Self =
+                    serde_json::from_value(value).map_err(|_| cannot_parse())?;
+                manifest.version = MANIFEST_VERSION;
+                Ok(manifest)
+            }
+            ManifestEncoding::Postcard => {
+                // The encoding is not self-describing, so manifests written
+                // in other formats cannot be migrated.
+                let (version, _) =
+                    postcard::take_from_bytes::<u32>(buffer).map_err(|_| cannot_parse())?;
+                if version != MANIFEST_VERSION {
+                    return Err(incompatible(version));
+                }
+                postcard::from_bytes(buffer).map_err(|_| cannot_parse())
+            }
+        }
+    }
+}
+
+/// Describes how manifests are encoded within the cache.
+///
+/// Manifests are stored in files named for their encoding, as in
+/// `my-service.tar.gz.json`, so switching encodings causes lookups to miss.
+///
+/// Postcard manifests are smaller, and faster to parse for packages with
+/// many inputs. JSON is the default, since JSON manifests can be inspected
+/// by hand.
+///
+/// As postcard isn't self-describing, every field of the types reachable
+/// from [ArtifactManifest] must always be serialized; fields may not use
+/// `skip_serializing_if`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ManifestEncoding {
+    /// Encoded as JSON, in files ending with ".json".
+    #[default]
+    Json,
+
+    /// Encoded with [postcard], in files ending with ".postcard".
+    ///
+    /// Manifests written by other versions of this crate cannot be read,
+    /// and cause lookups to miss.
+    Postcard,
+}
+
+impl ManifestEncoding {
+    /// Returns the file extension used for manifests with this encoding.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ManifestEncoding::Json => "json",
+            ManifestEncoding::Postcard => "postcard",
+        }
+    }
+
+    fn from_extension(extension: &str) -> Option<Self> {
+        [ManifestEncoding::Json, ManifestEncoding::Postcard]
+            .into_iter()
+            .find(|encoding| encoding.extension() == extension)
+    }
+
+    // Returns the encoding of the manifest at `path`.
+    fn from_path(path: &Utf8Path) -> anyhow::Result<Self> {
+        let extension = path
+            .extension()
+            .ok_or_else(|| anyhow!("Missing extension?"))?;
+        Self::from_extension(extension)
+            .ok_or_else(|| anyhow!("Unknown manifest encoding for '.{extension}' file {path}"))
     }
 }
 
@@ -168,7 +413,12 @@ pub enum CacheError {
     /// but that we should probably try to continue with package building
     /// anyway.
     #[error("Cache Miss: {reason}")]
-    CacheMiss { reason: String },
+    CacheMiss { reason: MissReason },
+
+    /// Another build sharing the output directory holds the lock on an
+    /// artifact; see [Cache::lock].
+    #[error("{path} is locked by another build")]
+    Locked { path: Utf8PathBuf },
 
     /// Other errors, which could indicate a more fundamental problem.
     ///
@@ -178,328 +428,2828 @@ pub enum CacheError {
     Other(#[from] anyhow::Error),
 }
 
-impl CacheError {
-    // Convenience wrapper
-    fn miss<T: Into<String>>(t: T) -> Self {
-        CacheError::CacheMiss { reason: t.into() }
+impl From<MissReason> for CacheError {
+    fn from(reason: MissReason) -> Self {
+        CacheError::CacheMiss { reason }
     }
 }
 
-/// Provides access to a set of manifests describing packages.
-///
-/// Provides two primary operations:
-/// - [Self::lookup]: Support for finding previously-built packages
-/// - [Self::update]: Support for updating a package's latest manifest
-pub struct Cache {
-    disabled: bool,
-    cache_directory: Utf8PathBuf,
+/// Describes why a [CacheError::CacheMiss] occurred.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MissReason {
+    /// The cache has been disabled.
+    Disabled,
+    /// No manifest has been recorded at `path`.
+    ManifestMissing { path: Utf8PathBuf },
+    /// The manifest at `path` could not be parsed.
+    ManifestUnreadable { path: Utf8PathBuf },
+    /// The manifest at `path` was written in a format, `version`, which this
+    /// version of the crate cannot read or migrate.
+    IncompatibleManifest { path: Utf8PathBuf, version: u32 },
+    /// Inputs have been added, removed, or reordered.
+    InputSetChanged,
+    /// The contents of the input at `path`, which came from `origin`,
+    /// differ from those recorded.
+    InputDigestChanged {
+        path: Utf8PathBuf,
+        origin: Option<InputOrigin>,
+    },
+    /// The input at `path`, which came from `origin`, no longer exists.
+    InputMissing {
+        path: Utf8PathBuf,
+        origin: Option<InputOrigin>,
+    },
+    /// The recorded manifest differs from the inputs in some other way.
+    ManifestChanged,
+    /// The artifact was cached with a different salt; see
+    /// [Cache::set_salt].
+    SaltChanged {
+        previous: Option<String>,
+        current: Option<String>,
+    },
+    /// The artifact was recorded at `previous`, rather than `current`.
+    OutputChanged {
+        previous: Utf8PathBuf,
+        current: Utf8PathBuf,
+    },
+    /// The artifact does not exist.
+    OutputMissing,
+    /// The contents of the artifact differ from those recorded, as when a
+    /// build was interrupted while writing it.
+    OutputDigestChanged,
+    /// The remote cache could not be reached.
+    RemoteUnavailable { message: String },
+    /// The lookup missed in the output directory, for the `local` reason,
+    /// and then in the remote cache, for the `remote` reason.
+    Remote {
+        local: Box<MissReason>,
+        remote: Box<MissReason>,
+    },
 }
 
-impl Cache {
-    /// Ensures the cache directory exists within the output directory
-    pub async fn new(output_directory: &Utf8Path) -> anyhow::Result<Self> {
-        let cache_directory = output_directory.join(CACHE_SUBDIRECTORY);
-        tokio::fs::create_dir_all(&cache_directory).await?;
-        Ok(Self {
-            disabled: false,
-            cache_directory,
-        })
+impl MissReason {
+    /// Returns the category of this reason, as counted by [CacheCounters].
+    pub fn kind(&self) -> CacheMissKind {
+        match self {
+            MissReason::Disabled => CacheMissKind::Disabled,
+            MissReason::ManifestMissing { .. } | MissReason::ManifestUnreadable { .. } => {
+                CacheMissKind::ManifestMissing
+            }
+            MissReason::IncompatibleManifest { .. } => CacheMissKind::IncompatibleManifest,
+            MissReason::InputSetChanged
+            | MissReason::InputDigestChanged { .. }
+            | MissReason::InputMissing { .. }
+            | MissReason::ManifestChanged => CacheMissKind::InputsChanged,
+            MissReason::SaltChanged { .. } => CacheMissKind::SaltChanged,
+            MissReason::OutputChanged { .. } | MissReason::OutputDigestChanged => {
+                CacheMissKind::OutputChanged
+            }
+            MissReason::OutputMissing => CacheMissKind::OutputMissing,
+            MissReason::RemoteUnavailable { .. } => CacheMissKind::RemoteUnavailable,
+            MissReason::Remote { remote, .. } => remote.kind(),
+        }
     }
+}
 
-    /// If "disable" is true, causes cache operations to be no-ops.
-    /// Otherwise, causes the cache to act normally.
-    pub fn set_disable(&mut self, disable: bool) {
-        self.disabled = disable;
+// Returns true if `err` was caused by a file which does not exist.
+fn is_not_found(err: &anyhow::Error) -> bool {
+    err.root_cause()
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound)
+}
+
+// Returns true if the artifact at `output_path` exists.
+//
+// An artifact which cannot be located, as when its directory is unreadable,
+// is treated as missing: that's a miss, which rebuilds it, rather than a
+// failure.
+async fn output_exists(output_path: &Utf8Path) -> bool {
+    tokio::fs::try_exists(output_path).await.unwrap_or(false)
+}
+
+// Formats as " (from {origin})", or nothing if the origin is unknown.
+struct FromOrigin<'a>(&'a Option<InputOrigin>);
+
+impl std::fmt::Display for FromOrigin<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(origin) => write!(f, " (from {origin})"),
+            None => Ok(()),
+        }
     }
+}
 
-    /// Looks up an entry from the cache.
-    ///
-    /// Confirms that the artifact exists.
-    pub async fn lookup(
-        &self,
-        inputs: &BuildInputs,
-        output_path: &Utf8Path,
-    ) -> Result<ArtifactManifest, CacheError> {
-        if self.disabled {
-            return Err(CacheError::miss("Cache disabled"));
+impl std::fmt::Display for MissReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MissReason::Disabled => write!(f, "Cache disabled"),
+            MissReason::ManifestMissing { path } => write!(f, "File {path} not found"),
+            MissReason::ManifestUnreadable { path } => {
+                write!(f, "Cannot parse manifest at {path}")
+            }
+            MissReason::IncompatibleManifest { path, version } => write!(
+                f,
+                "Manifest at {path} has version {version}, but only version \
+                 {MANIFEST_VERSION} is supported"
+            ),
+            MissReason::InputSetChanged => write!(f, "Set of inputs has changed"),
+            MissReason::InputDigestChanged { path, origin } => {
+                write!(f, "Input {path}{} has changed", FromOrigin(origin))
+            }
+            MissReason::InputMissing { path, origin } => {
+                write!(f, "Input {path}{} does not exist", FromOrigin(origin))
+            }
+            MissReason::ManifestChanged => write!(f, "Manifests appear different"),
+            MissReason::SaltChanged { previous, current } => write!(
+                f,
+                "Salt changed from {} -> {}",
+                previous.as_deref().unwrap_or("none"),
+                current.as_deref().unwrap_or("none")
+            ),
+            MissReason::OutputChanged { previous, current } => {
+                write!(f, "Output path changed from {previous} -> {current}")
+            }
+            MissReason::OutputMissing => write!(f, "Output does not exist"),
+            MissReason::OutputDigestChanged => {
+                write!(f, "Output has changed since it was cached")
+            }
+            MissReason::RemoteUnavailable { message } => write!(f, "Unavailable: {message}"),
+            MissReason::Remote { local, remote } => write!(f, "{local}; remote: {remote}"),
         }
+    }
+}
 
-        let artifact_filename = output_path
-            .file_name()
-            .ok_or_else(|| CacheError::Other(anyhow!("Output has no file name")))?;
-        let mut manifest_filename = String::from(artifact_filename);
-        manifest_filename.push_str(".json");
+/// Categorizes the reasons for a [CacheError::CacheMiss].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CacheMissKind {
+    /// The cache has been disabled.
+    Disabled,
+    /// No usable manifest has been recorded for the artifact.
+    ManifestMissing,
+    /// The manifest was written in a format which this version of the
+    /// crate cannot read.
+    IncompatibleManifest,
+    /// The inputs, or their contents, differ from those recorded.
+    InputsChanged,
+    /// The artifact was cached with a different salt.
+    SaltChanged,
+    /// The artifact was recorded under a different path or name.
+    OutputChanged,
+    /// The artifact does not exist.
+    OutputMissing,
+    /// The remote cache could not be reached.
+    RemoteUnavailable,
+}
 
-        let manifest_path = self.cache_directory.join(manifest_filename);
+impl std::fmt::Display for CacheMissKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            CacheMissKind::Disabled => "cache disabled",
+            CacheMissKind::ManifestMissing => "manifest missing",
+            CacheMissKind::IncompatibleManifest => "incompatible manifest",
+            CacheMissKind::InputsChanged => "inputs changed",
+            CacheMissKind::SaltChanged => "salt changed",
+            CacheMissKind::OutputChanged => "output changed",
+            CacheMissKind::OutputMissing => "output missing",
+            CacheMissKind::RemoteUnavailable => "remote unavailable",
+        };
+        write!(f, "{s}")
+    }
+}
 
-        // Look up the manifest file in the cache
-        let manifest = ArtifactManifest::read_from(&manifest_path).await?;
+/// Accumulates statistics about cache usage, such as across all of the
+/// packages built by a CI job.
+///
+/// See [crate::package::BuildConfig::cache_counters].
+#[derive(Debug, Default)]
+pub struct CacheCounters {
+    hits: AtomicU64,
+    misses: Mutex<BTreeMap<CacheMissKind, u64>>,
+    bytes_hashed: AtomicU64,
+    hashing_nanos: AtomicU64,
+}
 
-        // Do a quick check if the input files are different.
-        //
-        // We'll actually validate the digests later, but this lets us bail
-        // early if any files were added or removed.
-        if inputs
-            .0
-            .iter()
-            .ne(manifest.inputs.0.iter().map(|entry| &entry.key))
-        {
-            return Err(CacheError::miss("Set of inputs has changed"));
-        }
-        if output_path != manifest.output_path {
-            return Err(CacheError::miss(format!(
-                "Output path changed from {} -> {}",
-                manifest.output_path, output_path,
-            )));
-        }
+impl CacheCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        // Confirm the output file exists
-        if !tokio::fs::try_exists(&output_path)
-            .await
-            .map_err(|e| CacheError::miss(format!("Cannot locate output artifact: {e}")))?
-        {
-            return Err(CacheError::miss("Output does not exist"));
+    /// Returns the statistics accumulated so far.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.lock().unwrap().clone(),
+            bytes_hashed: self.bytes_hashed.load(Ordering::Relaxed),
+            hashing_time: Duration::from_nanos(self.hashing_nanos.load(Ordering::Relaxed)),
         }
+    }
 
-        // Confirm the output matches.
-        let Some(observed_filename) = manifest.output_path.file_name() else {
-            return Err(CacheError::miss(format!(
-                "Missing output file name from manifest {}",
-                manifest.output_path
-            )));
-        };
-        if observed_filename != artifact_filename {
-            return Err(CacheError::miss(format!(
-                "Wrong output name in manifest (saw {}, expected {})",
-                observed_filename, artifact_filename
-            )));
+    fn record_lookup<T>(&self, result: &Result<T, CacheError>) {
+        match result {
+            Ok(_) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(CacheError::CacheMiss { reason }) => {
+                *self
+                    .misses
+                    .lock()
+                    .unwrap()
+                    .entry(reason.kind())
+                    .or_default() += 1;
+            }
+            Err(_) => (),
         }
+    }
+}
 
-        // Finally, compare the manifests, including their digests.
-        //
-        // This calculation bails out early if any inputs don't match.
-        let calculated_manifest =
-            ArtifactManifest::new_internal(inputs, output_path.to_path_buf(), Some(&manifest))
-                .await?;
+/// Statistics about cache usage, returned by [CacheCounters::stats].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of lookups which found a usable artifact.
+    pub hits: u64,
+    /// The number of lookups which missed, by their reason.
+    pub misses: BTreeMap<CacheMissKind, u64>,
+    /// The total size of the files hashed to compare inputs.
+    pub bytes_hashed: u64,
+    /// The time spent hashing files.
+    ///
+    /// Files are hashed concurrently, so this may exceed the time taken by
+    /// builds.
+    pub hashing_time: Duration,
+}
 
-        // This is a hard stop-gap against any other differences in the
-        // manifests. The error message here is worse (we don't know "why"),
-        // but it's a quick check that's protective.
-        if calculated_manifest != manifest {
-            return Err(CacheError::miss("Manifests appear different"));
-        }
+impl CacheStats {
+    /// The number of lookups which missed, for any reason.
+    pub fn total_misses(&self) -> u64 {
+        self.misses.values().sum()
+    }
 
-        Ok(manifest)
+    /// The fraction of lookups which hit, or None if there were none.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.total_misses();
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
     }
+}
 
-    /// Updates an artifact's entry within the cache
-    pub async fn update(
-        &self,
-        inputs: &BuildInputs,
-        output_path: &Utf8Path,
-    ) -> Result<(), CacheError> {
-        if self.disabled {
-            // Return immediately, regardless of the input. We have nothing to
-            // calculate, and nothing to save.
-            return Ok(());
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} hits, {} misses", self.hits, self.total_misses())?;
+        if let Some(hit_rate) = self.hit_rate() {
+            write!(f, " ({:.1}% hit rate)", hit_rate * 100.0)?;
+        }
+        if !self.misses.is_empty() {
+            let reasons = self
+                .misses
+                .iter()
+                .map(|(kind, count)| format!("{kind}: {count}"))
+                .collect::<Vec<_>>();
+            write!(f, " [{}]", reasons.join(", "))?;
         }
+        write!(
+            f,
+            "; hashed {} bytes in {:.2?}",
+            self.bytes_hashed, self.hashing_time
+        )
+    }
+}
 
-        // This call actually acquires the digests for all inputs
-        let manifest =
-            ArtifactManifest::<DefaultDigest>::new(inputs, output_path.to_path_buf()).await?;
+/// Remembers the digests of files, so that files shared by many packages
+/// built in one session are only hashed once.
+///
+/// Files are identified by their path, size, and modification time; files
+/// modified without changing these are not hashed again.
+///
+/// See [crate::package::BuildConfig::digest_memo].
+#[derive(Debug, Default)]
+pub struct DigestMemo {
+    digests: Mutex<HashMap<DigestMemoKey, Digest>>,
+}
 
-        let Some(artifact_filename) = manifest.output_path.file_name() else {
-            return Err(anyhow!("Bad manifest: Missing output name").into());
-        };
+impl DigestMemo {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        let mut manifest_filename = String::from(artifact_filename);
-        manifest_filename.push_str(".json");
-        let manifest_path = self.cache_directory.join(manifest_filename);
-        manifest.write_to(&manifest_path).await?;
+    /// Returns the number of digests remembered.
+    pub fn len(&self) -> usize {
+        self.digests.lock().unwrap().len()
+    }
 
-        Ok(())
+    /// Returns true if no digests have been remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::input::MappedPath;
-    use camino::Utf8PathBuf;
-    use camino_tempfile::{tempdir, Utf8TempDir};
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct DigestMemoKey {
+    path: Utf8PathBuf,
+    len: u64,
+    modified: std::time::SystemTime,
+    algorithm: DigestAlgorithm,
+}
 
-    struct CacheTest {
-        _input_dir: Utf8TempDir,
-        output_dir: Utf8TempDir,
+// Describes how a [Cache] hashes its inputs.
+#[derive(Clone, Copy)]
+struct Hashing<'a> {
+    counters: Option<&'a CacheCounters>,
+    memo: Option<&'a DigestMemo>,
+    parallelism: usize,
+    algorithm: DigestAlgorithm,
+}
 
-        input_path: Utf8PathBuf,
-        output_path: Utf8PathBuf,
+impl Hashing<'_> {
+    // Returns the configuration used to take digests of outputs.
+    //
+    // Outputs must be read to detect corruption, so they're hashed with
+    // [DigestAlgorithm::Blake3] when [DigestAlgorithm::Metadata] is used for
+    // inputs. Neither `counters` nor `memo` are used, since they describe
+    // inputs.
+    fn for_outputs(self) -> Self {
+        Self {
+            counters: None,
+            memo: None,
+            algorithm: if self.algorithm.reads_contents() {
+                self.algorithm
+            } else {
+                DigestAlgorithm::Blake3
+            },
+            ..self
+        }
     }
 
-    impl CacheTest {
-        fn new() -> Self {
-            let input_dir = tempdir().unwrap();
-            let output_dir = tempdir().unwrap();
-            let input_path = input_dir.path().join("binary.exe");
-            let output_path = output_dir.path().join("output.tar.gz");
-            Self {
-                _input_dir: input_dir,
-                output_dir,
-                input_path,
-                output_path,
+    // Computes the digest of `path`, recording the work done in `counters`,
+    // unless it's remembered by `memo`.
+    async fn get_digest(&self, path: &Utf8Path) -> anyhow::Result<Digest> {
+        let key = match self.memo {
+            Some(memo) => {
+                let metadata = tokio::fs::metadata(path).await?;
+                let key = DigestMemoKey {
+                    path: path.to_path_buf(),
+                    len: metadata.len(),
+                    modified: metadata.modified()?,
+                    algorithm: self.algorithm,
+                };
+                if let Some(digest) = memo.digests.lock().unwrap().get(&key) {
+                    return Ok(digest.clone());
+                }
+                Some((memo, key))
             }
-        }
+            None => None,
+        };
 
-        async fn create_input(&self, contents: &str) {
-            tokio::fs::write(&self.input_path, contents).await.unwrap()
+        let start = Instant::now();
+        let digest = self.algorithm.get_digest(path).await?;
+        if let Some(counters) = self.counters.filter(|_| self.algorithm.reads_contents()) {
+            let elapsed = start.elapsed().as_nanos().try_into().unwrap_or(u64::MAX);
+            counters.hashing_nanos.fetch_add(elapsed, Ordering::Relaxed);
+            let size = tokio::fs::metadata(path).await.map_or(0, |m| m.len());
+            counters.bytes_hashed.fetch_add(size, Ordering::Relaxed);
         }
-
-        async fn create_output(&self, contents: &str) {
-            tokio::fs::write(&self.output_path, contents).await.unwrap()
+        if let Some((memo, key)) = key {
+            memo.digests.lock().unwrap().insert(key, digest.clone());
         }
+        Ok(digest)
+    }
+}
 
-        async fn remove_output(&self) {
-            tokio::fs::remove_file(&self.output_path).await.unwrap()
+/// Describes why a cached artifact cannot be used.
+///
+/// Differences among the inputs are reported individually; anything else is
+/// reported as the [MissReason] which a lookup would return. See
+/// [Cache::explain].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheDifference {
+    /// The artifact cannot be used, for a reason unrelated to its inputs.
+    Miss(MissReason),
+
+    /// An input was not used to build the cached artifact.
+    InputAdded {
+        input: BuildInput,
+        origin: Option<InputOrigin>,
+    },
+
+    /// An input used to build the cached artifact is no longer present.
+    InputRemoved(BuildInput),
+
+    /// The same inputs are present, but in a different order.
+    InputsReordered,
+
+    /// The contents of an input on the host have changed.
+    InputChanged {
+        input: BuildInput,
+        path: Utf8PathBuf,
+        origin: Option<InputOrigin>,
+    },
+}
+
+impl CacheDifference {
+    /// Returns the reason which [Cache::lookup] would give for this
+    /// difference, were it the first one found.
+    pub fn reason(&self) -> MissReason {
+        match self {
+            CacheDifference::Miss(reason) => reason.clone(),
+            CacheDifference::InputAdded { .. }
+            | CacheDifference::InputRemoved(_)
+            | CacheDifference::InputsReordered => MissReason::InputSetChanged,
+            CacheDifference::InputChanged { path, origin, .. } => MissReason::InputDigestChanged {
+                path: path.clone(),
+                origin: origin.clone(),
+            },
         }
     }
 
-    fn expect_missing_manifest(err: &CacheError, file: &str) {
-        match &err {
-            CacheError::CacheMiss { reason } => {
-                let expected = format!("{file}.json not found");
-                assert!(reason.contains(&expected), "{}", reason);
-            }
-            _ => panic!("Unexpected error: {}", err),
-        }
+    /// Returns the category of this difference; see [MissReason::kind].
+    pub fn kind(&self) -> CacheMissKind {
+        self.reason().kind()
     }
+}
 
-    fn expect_cache_disabled(err: &CacheError) {
-        match &err {
-            CacheError::CacheMiss { reason } => {
-                assert!(reason.contains("Cache disabled"), "{}", reason);
+impl From<MissReason> for CacheDifference {
+    fn from(reason: MissReason) -> Self {
+        CacheDifference::Miss(reason)
+    }
+}
+
+impl std::fmt::Display for CacheDifference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheDifference::Miss(reason) => write!(f, "{reason}"),
+            CacheDifference::InputAdded { input, origin } => {
+                write!(f, "Input added: {input:?}{}", FromOrigin(origin))
+            }
+            CacheDifference::InputRemoved(input) => write!(f, "Input removed: {input:?}"),
+            CacheDifference::InputsReordered => write!(f, "Inputs have been reordered"),
+            CacheDifference::InputChanged { path, origin, .. } => {
+                write!(f, "Input changed: {path}{}", FromOrigin(origin))
             }
-            _ => panic!("Unexpected error: {}", err),
         }
     }
+}
 
-    fn expect_changed_manifests(err: &CacheError) {
-        match &err {
-            CacheError::CacheMiss { reason } => {
-                assert!(reason.contains("Manifests appear different"), "{}", reason);
-            }
-            _ => panic!("Unexpected error: {}", err),
+/// Stores manifests and artifacts outside of the output directory, so that
+/// they may be shared between machines.
+///
+/// Objects are named by keys such as "manifests/my-service.tar.gz.json".
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    /// Copies the object named `key` to `destination`, returning false if no
+    /// such object exists.
+    async fn get(&self, key: &str, destination: &Utf8Path) -> anyhow::Result<bool>;
+
+    /// Copies `source` to the object named `key`, replacing any existing
+    /// object.
+    async fn put(&self, key: &str, source: &Utf8Path) -> anyhow::Result<()>;
+}
+
+/// A [CacheBackend] which stores objects within a directory, such as one
+/// shared between machines over NFS.
+pub struct DirectoryBackend {
+    directory: Utf8PathBuf,
+}
+
+impl DirectoryBackend {
+    pub fn new<P: Into<Utf8PathBuf>>(directory: P) -> Self {
+        Self {
+            directory: directory.into(),
         }
     }
+}
 
-    fn expect_missing_output(err: &CacheError) {
-        match &err {
-            CacheError::CacheMiss { reason } => {
-                assert!(reason.contains("Output does not exist"), "{}", reason);
-            }
-            _ => panic!("Unexpected error: {}", err),
+#[async_trait]
+impl CacheBackend for DirectoryBackend {
+    async fn get(&self, key: &str, destination: &Utf8Path) -> anyhow::Result<bool> {
+        let path = self.directory.join(key);
+        match tokio::fs::copy(&path, destination).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Copying {path} to {destination}")),
         }
     }
 
+    async fn put(&self, key: &str, source: &Utf8Path) -> anyhow::Result<()> {
+        let path = self.directory.join(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Copy to a temporary file first, so readers never observe a
+        // partially-written object. Each writer has its own, so that
+        // concurrent writers of the same object don't interfere.
+        let tmp = NamedUtf8TempFile::new_in(path.parent().unwrap_or(&self.directory))
+            .with_context(|| format!("Cannot create temporary file for {path}"))?;
+        tokio::fs::copy(source, tmp.path())
+            .await
+            .with_context(|| format!("Copying {source} to {}", tmp.path()))?;
+        tmp.persist(&path)
+            .with_context(|| format!("Cannot persist {path}"))?;
+        Ok(())
+    }
+}
+
+/// A [CacheBackend] which stores objects at "{base_url}/{key}", reading
+/// them with GET requests and writing them with PUT requests.
+///
+/// This suits S3 buckets, and similar stores, which permit anonymous
+/// access or accept credentials supplied as default headers of the client
+/// passed to [Self::with_client].
+pub struct HttpBackend {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl HttpBackend {
+    pub fn new<S: Into<String>>(base_url: S) -> Self {
+        Self::with_client(base_url, reqwest::Client::new())
+    }
+
+    pub fn with_client<S: Into<String>>(base_url: S, client: reqwest::Client) -> Self {
+        let mut base_url = base_url.into();
+        while base_url.ends_with('/') {
+            base_url.pop();
+        }
+        Self { base_url, client }
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{key}", self.base_url)
+    }
+}
+
+#[async_trait]
+impl CacheBackend for HttpBackend {
+    async fn get(&self, key: &str, destination: &Utf8Path) -> anyhow::Result<bool> {
+        let url = self.url(key);
+        let response = self.client.get(&url).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        let response = response
+            .error_for_status()
+            .with_context(|| format!("GET failed for {url}"))?;
+        let mut file = File::create(destination).await?;
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+        Ok(true)
+    }
+
+    async fn put(&self, key: &str, source: &Utf8Path) -> anyhow::Result<()> {
+        let url = self.url(key);
+        let file = File::open(source).await?;
+        // Stores such as S3 reject uploads without a length.
+        let length = file.metadata().await?.len();
+        self.client
+            .put(&url)
+            .header(CONTENT_LENGTH, length)
+            .body(reqwest::Body::wrap_stream(
+                tokio_util::io::ReaderStream::new(file),
+            ))
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("PUT failed for {url}"))?;
+        Ok(())
+    }
+}
+
+// Names the manifest describing `artifact_filename` within a [CacheBackend].
+fn manifest_key(artifact_filename: &str, encoding: ManifestEncoding) -> String {
+    format!("manifests/{artifact_filename}.{}", encoding.extension())
+}
+
+// Returns true if `name` is a single, normal component of a path, and so
+// cannot name a file outside of the directory it's joined onto.
+fn is_file_name(name: &str) -> bool {
+    let mut components = Utf8Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(camino::Utf8Component::Normal(normal)), None) if normal == name
+    )
+}
+
+// Names the artifact described by `manifest` (as serialized) within a
+// [CacheBackend].
+//
+// Artifacts are named for their manifest, so that machines updating the
+// cache concurrently cannot pair one's manifest with another's artifact.
+fn artifact_key(manifest: &[u8], artifact_filename: &str) -> String {
+    let digest = sha2::Sha256::digest(manifest);
+    format!("artifacts/{}/{artifact_filename}", hex::encode(digest))
+}
+
+// Locks the file at `lock_path`, creating it if necessary, either
+// exclusively or `shared` with other holders.
+//
+// If the lock is held elsewhere, this waits for it to be released if `wait`
+// is true, and otherwise returns `None`.
+async fn lock_file(
+    lock_path: &Utf8Path,
+    shared: bool,
+    wait: bool,
+) -> anyhow::Result<Option<std::fs::File>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path)
+        .with_context(|| format!("Opening {lock_path}"))?;
+    let attempt = if shared {
+        FileExt::try_lock_shared(&file)
+    } else {
+        FileExt::try_lock_exclusive(&file)
+    };
+    match attempt {
+        Ok(()) => return Ok(Some(file)),
+        Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => (),
+        Err(e) => return Err(anyhow!(e).context(format!("Locking {lock_path}"))),
+    }
+    if !wait {
+        return Ok(None);
+    }
+    let file = tokio::task::spawn_blocking(move || {
+        let result = if shared {
+            FileExt::lock_shared(&file)
+        } else {
+            FileExt::lock_exclusive(&file)
+        };
+        result.map(|()| file)
+    })
+    .await?
+    .with_context(|| format!("Locking {lock_path}"))?;
+    Ok(Some(file))
+}
+
+/// Bounds the files kept by a [Cache]; see [Cache::evict].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EvictionPolicy {
+    /// The largest total size, in bytes, of the files kept.
+    pub max_bytes: Option<u64>,
+
+    /// The longest time for which an unused file is kept.
+    pub max_age: Option<Duration>,
+}
+
+/// Prevents other builds from writing an artifact, and the cache from
+/// evicting the blobs used to build it, while held.
+///
+/// See [Cache::lock].
+pub struct ArtifactLock {
+    // The locks are released when these are closed.
+    _file: std::fs::File,
+    _blobs: Vec<std::fs::File>,
+}
+
+/// Provides access to a set of manifests describing packages.
+///
+/// Provides two primary operations:
+/// - [Self::lookup]: Support for finding previously-built packages
+/// - [Self::update]: Support for updating a package's latest manifest
+///
+/// [Self::explain] describes why a lookup would miss.
+pub struct Cache<'a> {
+    disabled: bool,
+    cache_directory: Utf8PathBuf,
+    remote: Option<&'a dyn CacheBackend>,
+    hashing: Hashing<'a>,
+    verify_outputs: bool,
+    manifest_encoding: ManifestEncoding,
+    salt: Option<&'a str>,
+    warnings: Mutex<Vec<String>>,
+}
+
+impl<'a> Cache<'a> {
+    /// Ensures the cache directory exists within the output directory
+    pub async fn new(output_directory: &Utf8Path) -> anyhow::Result<Self> {
+        let cache_directory = output_directory.join(CACHE_SUBDIRECTORY);
+        tokio::fs::create_dir_all(&cache_directory).await?;
+        Ok(Self {
+            disabled: false,
+            cache_directory,
+            remote: None,
+            hashing: Hashing {
+                counters: None,
+                memo: None,
+                parallelism: DEFAULT_HASHING_PARALLELISM,
+                algorithm: DigestAlgorithm::default(),
+            },
+            verify_outputs: false,
+            manifest_encoding: ManifestEncoding::default(),
+            salt: None,
+            warnings: Mutex::new(vec![]),
+        })
+    }
+
+    /// If "disable" is true, causes cache operations to be no-ops.
+    /// Otherwise, causes the cache to act normally.
+    pub fn set_disable(&mut self, disable: bool) {
+        self.disabled = disable;
+    }
+
+    /// Shares manifests and artifacts through `remote`, in addition to the
+    /// output directory.
+    ///
+    /// Lookups which miss locally consult the remote, copying any artifact
+    /// found there into the output directory, and updates are copied to the
+    /// remote. If the remote is unavailable, lookups miss, and updates only
+    /// affect the output directory.
+    ///
+    /// Manifests record the paths of inputs and outputs, so artifacts are
+    /// only shared between machines which build from the same paths.
+    pub fn set_remote(&mut self, remote: Option<&'a dyn CacheBackend>) {
+        self.remote = remote;
+    }
+
+    /// Records lookups, and the files hashed to perform them, in `counters`.
+    pub fn set_counters(&mut self, counters: Option<&'a CacheCounters>) {
+        self.hashing.counters = counters;
+    }
+
+    /// Sets the number of files hashed concurrently when comparing inputs
+    /// with the cache, which defaults to [DEFAULT_HASHING_PARALLELISM].
+    pub fn set_hashing_parallelism(&mut self, parallelism: usize) {
+        self.hashing.parallelism = parallelism;
+    }
+
+    /// Reuses the digests of files remembered by `memo`, and remembers those
+    /// which are computed.
+    pub fn set_digest_memo(&mut self, memo: Option<&'a DigestMemo>) {
+        self.hashing.memo = memo;
+    }
+
+    /// Selects the algorithm used to take digests of inputs.
+    ///
+    /// Artifacts cached using one algorithm miss when looked up with
+    /// another.
+    pub fn set_digest_algorithm(&mut self, algorithm: DigestAlgorithm) {
+        self.hashing.algorithm = algorithm;
+    }
+
+    /// If "verify" is true, lookups confirm that the contents of artifacts
+    /// match those recorded when they were cached, rather than only that
+    /// they exist.
+    ///
+    /// This requires reading each artifact on every lookup. Artifacts cached
+    /// by older versions of this crate are not verified.
+    pub fn set_verify_outputs(&mut self, verify: bool) {
+        self.verify_outputs = verify;
+    }
+
+    /// Selects the encoding of manifests written to, and read from, the
+    /// cache.
+    pub fn set_manifest_encoding(&mut self, encoding: ManifestEncoding) {
+        self.manifest_encoding = encoding;
+    }
+
+    /// Records `salt` alongside cached artifacts, and ignores artifacts
+    /// cached with a different salt.
+    ///
+    /// Changing the salt causes everything to be rebuilt, and distinct salts
+    /// keep unrelated projects which share an output directory from using
+    /// each other's artifacts.
+    pub fn set_salt(&mut self, salt: Option<&'a str>) {
+        self.salt = salt;
+    }
+
+    /// Returns, and forgets, problems which haven't prevented the cache from
+    /// being used, such as a remote which could not be updated.
+    pub fn take_warnings(&self) -> Vec<String> {
+        std::mem::take(&mut self.warnings.lock().unwrap())
+    }
+
+    // Records a problem which doesn't prevent the cache from being used; see
+    // [Self::take_warnings].
+    fn warn(&self, message: String) {
+        self.warnings.lock().unwrap().push(message);
+    }
+
+    // Returns the path of the manifest describing `output_path`.
+    fn manifest_path(&self, output_path: &Utf8Path) -> anyhow::Result<Utf8PathBuf> {
+        let artifact_filename = output_path
+            .file_name()
+            .ok_or_else(|| anyhow!("Output has no file name"))?;
+        Ok(self.cache_directory.join(format!(
+            "{artifact_filename}.{}",
+            self.manifest_encoding.extension()
+        )))
+    }
+
+    /// Locks the artifact at `output_path` against other builds sharing the
+    /// output directory, until the returned guard is dropped.
+    ///
+    /// Builders should hold this from before [Self::lookup] until after
+    /// [Self::update], so that concurrent builds of the same artifact do not
+    /// interleave their writes to it or its manifest.
+    ///
+    /// The blobs among `inputs` are also held, though not exclusively:
+    /// builds may share them, but [Self::evict] skips them.
+    ///
+    /// If `wait` is true, this waits for other builds to release the lock;
+    /// otherwise, it fails with [CacheError::Locked].
+    pub async fn lock(
+        &self,
+        inputs: &BuildInputs,
+        output_path: &Utf8Path,
+        wait: bool,
+    ) -> Result<ArtifactLock, CacheError> {
+        let lock_path = self.artifact_lock_path(output_path)?;
+        let Some(file) = lock_file(&lock_path, false, wait).await? else {
+            return Err(CacheError::Locked {
+                path: output_path.to_path_buf(),
+            });
+        };
+
+        // Eviction only holds blobs briefly, so this always waits for it.
+        let mut blobs = vec![];
+        for input in inputs.iter() {
+            if let BuildInput::AddBlob { path, .. } = input {
+                let lock_path = self.blob_lock_path(&path.from)?;
+                blobs.extend(lock_file(&lock_path, true, true).await?);
+            }
+        }
+        Ok(ArtifactLock {
+            _file: file,
+            _blobs: blobs,
+        })
+    }
+
+    // Returns the path of the file locked by builds of `output_path`.
+    fn artifact_lock_path(&self, output_path: &Utf8Path) -> anyhow::Result<Utf8PathBuf> {
+        let artifact_filename = output_path
+            .file_name()
+            .ok_or_else(|| anyhow!("Output has no file name"))?;
+        Ok(self
+            .cache_directory
+            .join(format!("{artifact_filename}.lock")))
+    }
+
+    // Returns the path of the file locked by builds using the blob at `path`.
+    //
+    // Blobs with the same file name share a lock, which at worst keeps one
+    // of them from being evicted.
+    fn blob_lock_path(&self, path: &Utf8Path) -> anyhow::Result<Utf8PathBuf> {
+        let blob_filename = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Blob has no file name"))?;
+        Ok(self
+            .cache_directory
+            .join(format!("{blob_filename}.blob.lock")))
+    }
+
+    /// Describes every difference between `inputs` and the manifest stored
+    /// for `output_path`.
+    ///
+    /// Unlike [Self::lookup], which stops at the first difference, this
+    /// examines all inputs. An empty result indicates that a lookup would
+    /// hit.
+    pub async fn explain(
+        &self,
+        inputs: &BuildInputs,
+        output_path: &Utf8Path,
+    ) -> anyhow::Result<Vec<CacheDifference>> {
+        if self.disabled {
+            return Ok(vec![MissReason::Disabled.into()]);
+        }
+
+        let mut differences = vec![];
+        if !output_exists(output_path).await {
+            differences.push(MissReason::OutputMissing.into());
+        }
+
+        let manifest_path = self.manifest_path(output_path)?;
+        let manifest = match ArtifactManifest::read_from(&manifest_path).await {
+            Ok(manifest) => manifest,
+            Err(CacheError::CacheMiss { reason }) => {
+                differences.push(reason.into());
+                return Ok(differences);
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if manifest.salt.as_deref() != self.salt {
+            differences.push(
+                MissReason::SaltChanged {
+                    previous: manifest.salt.clone(),
+                    current: self.salt.map(str::to_string),
+                }
+                .into(),
+            );
+        }
+        if output_path != manifest.output_path {
+            differences.push(
+                MissReason::OutputChanged {
+                    previous: manifest.output_path.clone(),
+                    current: output_path.to_path_buf(),
+                }
+                .into(),
+            );
+        }
+
+        // Inputs are compared by their serialized form, since they may be
+        // numerous, and are neither hashable nor ordered.
+        let key = |input: &BuildInput| serde_json::to_string(input).map_err(|e| anyhow!(e));
+        let mut recorded = HashMap::new();
+        for entry in &manifest.inputs.0 {
+            recorded.insert(key(&entry.key)?, entry);
+        }
+        let mut current = HashMap::new();
+        for (_, input) in inputs.iter_with_origins() {
+            current.insert(key(input)?, input);
+        }
+
+        for (origin, input) in inputs.iter_with_origins() {
+            let Some(entry) = recorded.get(&key(input)?) else {
+                differences.push(CacheDifference::InputAdded {
+                    input: input.clone(),
+                    origin: origin.cloned(),
+                });
+                continue;
+            };
+            if let Some(path) = input.input_path() {
+                let digest = match self.hashing.get_digest(path).await {
+                    Ok(digest) => digest,
+                    Err(err) if is_not_found(&err) => {
+                        differences.push(
+                            MissReason::InputMissing {
+                                path: path.to_path_buf(),
+                                origin: origin.cloned(),
+                            }
+                            .into(),
+                        );
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                if entry.value.as_ref() != Some(&digest) {
+                    differences.push(CacheDifference::InputChanged {
+                        input: input.clone(),
+                        path: path.to_path_buf(),
+                        origin: origin.cloned(),
+                    });
+                }
+            }
+        }
+        for entry in &manifest.inputs.0 {
+            if !current.contains_key(&key(&entry.key)?) {
+                differences.push(CacheDifference::InputRemoved(entry.key.clone()));
+            }
+        }
+
+        let reordered = inputs
+            .iter_with_origins()
+            .map(|(_, input)| input)
+            .ne(manifest.inputs.0.iter().map(|entry| &entry.key));
+        if reordered
+            && !differences.iter().any(|d| {
+                matches!(
+                    d,
+                    CacheDifference::InputAdded { .. } | CacheDifference::InputRemoved(_)
+                )
+            })
+        {
+            differences.push(CacheDifference::InputsReordered);
+        }
+
+        Ok(differences)
+    }
+
+    /// Looks up an entry from the cache.
+    ///
+    /// Confirms that the artifact exists.
+    pub async fn lookup(
+        &self,
+        inputs: &BuildInputs,
+        output_path: &Utf8Path,
+    ) -> Result<ArtifactManifest, CacheError> {
+        let result = if self.disabled {
+            Err(MissReason::Disabled.into())
+        } else {
+            match (self.lookup_local(inputs, output_path).await, self.remote) {
+                (Err(CacheError::CacheMiss { reason }), Some(remote)) => self
+                    .lookup_remote(remote, inputs, output_path)
+                    .await
+                    .map_err(|err| match err {
+                        CacheError::CacheMiss {
+                            reason: remote_reason,
+                        } => MissReason::Remote {
+                            local: Box::new(reason),
+                            remote: Box::new(remote_reason),
+                        }
+                        .into(),
+                        err => err,
+                    }),
+                (result, _) => result,
+            }
+        };
+        if let Some(counters) = self.hashing.counters {
+            counters.record_lookup(&result);
+        }
+        if result.is_ok() {
+            self.record_use(inputs, output_path).await;
+        }
+        result
+    }
+
+    // Looks up an entry within the output directory.
+    async fn lookup_local(
+        &self,
+        inputs: &BuildInputs,
+        output_path: &Utf8Path,
+    ) -> Result<ArtifactManifest, CacheError> {
+        let manifest_path = self.manifest_path(output_path)?;
+
+        // Look up the manifest file in the cache
+        let manifest = ArtifactManifest::read_from(&manifest_path).await?;
+
+        // Confirm the output file exists
+        if !output_exists(output_path).await {
+            return Err(MissReason::OutputMissing.into());
+        }
+
+        self.verify(inputs, output_path, &manifest).await?;
+        self.verify_output(output_path, &manifest).await?;
+        Ok(manifest)
+    }
+
+    // Looks up an entry within `remote`, copying it into the output
+    // directory if it matches `inputs`.
+    async fn lookup_remote(
+        &self,
+        remote: &dyn CacheBackend,
+        inputs: &BuildInputs,
+        output_path: &Utf8Path,
+    ) -> Result<ArtifactManifest, CacheError> {
+        let artifact_filename = output_path
+            .file_name()
+            .ok_or_else(|| CacheError::Other(anyhow!("Output has no file name")))?;
+        let manifest_path = self.manifest_path(output_path)?;
+
+        // Download alongside their final locations, so that a partial
+        // download is never mistaken for a cached artifact.
+        let remote_manifest_path = self.cache_directory.join(format!(
+            "{artifact_filename}.remote.{}",
+            self.manifest_encoding.extension()
+        ));
+        let remote_output_path = Utf8PathBuf::from(format!("{output_path}.remote"));
+
+        let result = async {
+            let unavailable = |e: anyhow::Error| {
+                CacheError::from(MissReason::RemoteUnavailable {
+                    message: format!("{e:#}"),
+                })
+            };
+            let key = manifest_key(artifact_filename, self.manifest_encoding);
+            if !remote
+                .get(&key, &remote_manifest_path)
+                .await
+                .map_err(unavailable)?
+            {
+                return Err(MissReason::ManifestMissing { path: key.into() }.into());
+            }
+            let manifest = ArtifactManifest::read_from(&remote_manifest_path).await?;
+            self.verify(inputs, output_path, &manifest).await?;
+
+            let contents = tokio::fs::read(&remote_manifest_path)
+                .await
+                .map_err(|e| anyhow!(e))?;
+            if !remote
+                .get(
+                    &artifact_key(&contents, artifact_filename),
+                    &remote_output_path,
+                )
+                .await
+                .map_err(unavailable)?
+            {
+                return Err(MissReason::OutputMissing.into());
+            }
+            self.verify_output(&remote_output_path, &manifest).await?;
+            tokio::fs::rename(&remote_output_path, output_path)
+                .await
+                .map_err(|e| anyhow!(e))?;
+            tokio::fs::rename(&remote_manifest_path, &manifest_path)
+                .await
+                .map_err(|e| anyhow!(e))?;
+            Ok(manifest)
+        }
+        .await;
+
+        // Both files have been moved into place if the lookup succeeded.
+        let _ = tokio::fs::remove_file(&remote_manifest_path).await;
+        let _ = tokio::fs::remove_file(&remote_output_path).await;
+        result
+    }
+
+    // Confirms that `manifest` describes building `output_path` from
+    // `inputs`.
+    async fn verify(
+        &self,
+        inputs: &BuildInputs,
+        output_path: &Utf8Path,
+        manifest: &ArtifactManifest,
+    ) -> Result<(), CacheError> {
+        let artifact_filename = output_path
+            .file_name()
+            .ok_or_else(|| CacheError::Other(anyhow!("Output has no file name")))?;
+
+        // Do a quick check if the input files are different.
+        //
+        // We'll actually validate the digests later, but this lets us bail
+        // early if any files were added or removed.
+        if inputs
+            .iter_with_origins()
+            .map(|(_, input)| input)
+            .ne(manifest.inputs.0.iter().map(|entry| &entry.key))
+        {
+            return Err(MissReason::InputSetChanged.into());
+        }
+        if manifest.salt.as_deref() != self.salt {
+            return Err(MissReason::SaltChanged {
+                previous: manifest.salt.clone(),
+                current: self.salt.map(str::to_string),
+            }
+            .into());
+        }
+
+        // Confirm the output matches, including its name.
+        if output_path != manifest.output_path
+            || manifest.output_path.file_name() != Some(artifact_filename)
+        {
+            return Err(MissReason::OutputChanged {
+                previous: manifest.output_path.clone(),
+                current: output_path.to_path_buf(),
+            }
+            .into());
+        }
+
+        // Finally, compare the manifests, including their digests.
+        //
+        // This calculation bails out early if any inputs don't match.
+        let calculated_manifest = ArtifactManifest::new_internal(
+            inputs,
+            output_path.to_path_buf(),
+            Some(manifest),
+            self.hashing,
+        )
+        .await?;
+
+        // This is a hard stop-gap against any other differences in the
+        // manifests. The error message here is worse (we don't know "why"),
+        // but it's a quick check that's protective.
+        if calculated_manifest != *manifest {
+            return Err(MissReason::ManifestChanged.into());
+        }
+
+        Ok(())
+    }
+
+    // Confirms that the artifact at `path` matches the digest recorded in
+    // `manifest`, if requested by [Self::set_verify_outputs].
+    async fn verify_output(
+        &self,
+        path: &Utf8Path,
+        manifest: &ArtifactManifest,
+    ) -> Result<(), CacheError> {
+        let Some(expected) = manifest
+            .output_digest
+            .as_ref()
+            .filter(|_| self.verify_outputs)
+        else {
+            return Ok(());
+        };
+        let digest = self.hashing.for_outputs().get_digest(path).await?;
+        if digest != *expected {
+            return Err(MissReason::OutputDigestChanged.into());
+        }
+        Ok(())
+    }
+
+    /// Updates an artifact's entry within the cache
+    pub async fn update(
+        &self,
+        inputs: &BuildInputs,
+        output_path: &Utf8Path,
+    ) -> Result<(), CacheError> {
+        if self.disabled {
+            // Return immediately, regardless of the input. We have nothing to
+            // calculate, and nothing to save.
+            return Ok(());
+        }
+
+        // This call actually acquires the digests for all inputs
+        let mut manifest =
+            ArtifactManifest::new(inputs, output_path.to_path_buf(), self.hashing).await?;
+        manifest.salt = self.salt.map(str::to_string);
+
+        if manifest.output_path.file_name().is_none() {
+            return Err(anyhow!("Bad manifest: Missing output name").into());
+        }
+
+        let manifest_path = self.manifest_path(&manifest.output_path)?;
+        manifest.write_to(&manifest_path).await?;
+
+        if let Some(remote) = self.remote {
+            // The artifact remains cached locally if this fails.
+            if let Err(err) = Self::update_remote(remote, &manifest_path, output_path).await {
+                self.warn(format!("Failed to update remote cache: {err:#}"));
+            }
+        }
+
+        self.record_use(inputs, output_path).await;
+        Ok(())
+    }
+
+    // Records that the artifact at `output_path`, and the blobs among its
+    // `inputs`, have just been used.
+    //
+    // This only informs eviction, so a failure is reported as a warning
+    // rather than failing the build.
+    async fn record_use(&self, inputs: &BuildInputs, output_path: &Utf8Path) {
+        let mut paths = vec![output_path.to_path_buf()];
+        paths.extend(inputs.iter().filter_map(|input| match input {
+            BuildInput::AddBlob { path, .. } => Some(path.from.clone()),
+            _ => None,
+        }));
+        let now = SystemTime::now();
+        let result = self
+            .update_usage(move |usage| {
+                for path in paths {
+                    usage.insert(path, now);
+                }
+            })
+            .await;
+        if let Err(err) = result {
+            self.warn(format!("Failed to record cache usage: {err:#}"));
+        }
+    }
+
+    // Applies `f` to the record of when each file was last used.
+    //
+    // The record is locked while it's updated, since it's shared by every
+    // build using the output directory.
+    async fn update_usage<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut BTreeMap<Utf8PathBuf, SystemTime>) -> T + Send + 'static,
+    {
+        // An unreadable record is discarded; at worst, this causes files to be
+        // evicted sooner than they would be otherwise.
+        update_locked_json(self.cache_directory.join(USAGE_FILE), f).await
+    }
+
+    /// Removes the least recently used artifacts and blobs, until they
+    /// satisfy `policy`.
+    ///
+    /// Only files used by this cache are considered: artifacts which were
+    /// cached or found in the cache, and the blobs used to build them.
+    /// Artifacts locked by builds in progress, and the blobs used by those
+    /// builds, are skipped. Packages which
+    /// used an evicted blob are rebuilt, downloading it again.
+    ///
+    /// Returns the paths of the evicted files, least recently used first.
+    pub async fn evict(&self, policy: &EvictionPolicy) -> anyhow::Result<Vec<Utf8PathBuf>> {
+        let usage = self.update_usage(|usage| usage.clone()).await?;
+
+        // Forget files which have been removed by other means.
+        let mut entries = vec![];
+        for (path, last_used) in usage {
+            if let Ok(metadata) = tokio::fs::metadata(&path).await {
+                entries.push((last_used, path, metadata.len()));
+            }
+        }
+        entries.sort();
+
+        let now = SystemTime::now();
+        let mut total: u64 = entries.iter().map(|(_, _, size)| size).sum();
+        let mut evicted = vec![];
+        for (last_used, path, size) in entries {
+            let too_old = policy.max_age.is_some_and(|max_age| {
+                now.duration_since(last_used).is_ok_and(|age| age > max_age)
+            });
+            let too_big = policy.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+            if !too_old && !too_big {
+                continue;
+            }
+            // Files used by builds in progress are skipped, whether they're
+            // the artifacts being built or the blobs used to build them.
+            let Some(_artifact_lock) =
+                lock_file(&self.artifact_lock_path(&path)?, false, false).await?
+            else {
+                continue;
+            };
+            let Some(_blob_lock) = lock_file(&self.blob_lock_path(&path)?, false, false).await?
+            else {
+                continue;
+            };
+
+            // Remove the manifest first, so the artifact is never mistaken
+            // for a cached one while it's being removed.
+            if let Some(artifact_filename) = path.file_name() {
+                for encoding in [ManifestEncoding::Json, ManifestEncoding::Postcard] {
+                    let manifest_path = self
+                        .cache_directory
+                        .join(format!("{artifact_filename}.{}", encoding.extension()));
+                    match tokio::fs::remove_file(&manifest_path).await {
+                        Ok(()) => (),
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                        Err(e) => {
+                            return Err(anyhow!(e).context(format!("Removing {manifest_path}")))
+                        }
+                    }
+                }
+            }
+            tokio::fs::remove_file(&path)
+                .await
+                .with_context(|| format!("Removing {path}"))?;
+            total -= size;
+            evicted.push(path);
+        }
+
+        let removed = evicted.clone();
+        self.update_usage(move |usage| {
+            for path in &removed {
+                usage.remove(path);
+            }
+        })
+        .await?;
+        Ok(evicted)
+    }
+
+    /// Bundles the cached artifacts at `output_paths`, and the manifests
+    /// describing them, into a gzipped tarball at `destination`.
+    ///
+    /// The bundle may be restored with [Self::import], for example to share
+    /// a cache between the stages of a CI pipeline. As with
+    /// [Self::set_remote], artifacts are only reused when built from the
+    /// same inputs.
+    pub async fn export(
+        &self,
+        output_paths: &[Utf8PathBuf],
+        destination: &Utf8Path,
+    ) -> anyhow::Result<()> {
+        let mut entries = vec![];
+        for output_path in output_paths {
+            let artifact_filename = output_path
+                .file_name()
+                .ok_or_else(|| anyhow!("Output has no file name"))?;
+            let manifest_path = self.manifest_path(output_path)?;
+            let contents = tokio::fs::read(&manifest_path)
+                .await
+                .with_context(|| format!("No cached manifest for {output_path}"))?;
+            if !tokio::fs::try_exists(output_path).await? {
+                bail!("Cached artifact {output_path} does not exist");
+            }
+            entries.push((
+                manifest_path,
+                manifest_key(artifact_filename, self.manifest_encoding),
+            ));
+            entries.push((
+                output_path.clone(),
+                artifact_key(&contents, artifact_filename),
+            ));
+        }
+
+        let destination = destination.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let file = create_tarfile(&destination)?;
+            let mut archive =
+                ArchiveBuilder::new(tar::Builder::new(Compressor::new(file, Compression::Gzip)));
+            for (path, name) in entries {
+                archive.append_path_with_name(&path, Utf8Path::new(&name))?;
+            }
+            archive.into_inner()?.finish()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Restores the artifacts, and the manifests describing them, from a
+    /// bundle created by [Self::export].
+    ///
+    /// Each artifact is placed within this cache's output directory, under
+    /// the file name with which it was exported, regardless of where it was
+    /// exported from. Returns those paths.
+    ///
+    /// The bundle isn't trusted: entries which would be placed anywhere
+    /// else are rejected.
+    pub async fn import(&self, source: &Utf8Path) -> anyhow::Result<Vec<Utf8PathBuf>> {
+        let source = source.to_path_buf();
+        let cache_directory = self.cache_directory.clone();
+        let output_directory = self
+            .cache_directory
+            .parent()
+            .expect("cache directory is within the output directory")
+            .to_path_buf();
+        let staged = tokio::task::spawn_blocking(move || {
+            let mut archive = tar::Archive::new(open_tarfile_any(&source)?);
+
+            // Manifests precede the artifacts they describe.
+            let mut manifests = HashMap::new();
+            let mut staged = vec![];
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let name = Utf8PathBuf::try_from(entry.path()?.into_owned())?;
+                if !entry.header().entry_type().is_file() {
+                    bail!("Entry {name} in {source} is not a file");
+                }
+                if let Some(manifest_filename) = name.as_str().strip_prefix("manifests/") {
+                    let (artifact_filename, extension) = manifest_filename
+                        .rsplit_once('.')
+                        .filter(|(artifact_filename, extension)| {
+                            is_file_name(artifact_filename)
+                                && ManifestEncoding::from_extension(extension).is_some()
+                        })
+                        .ok_or_else(|| anyhow!("Unexpected entry {name} in {source}"))?;
+                    let mut contents = vec![];
+                    std::io::Read::read_to_end(&mut entry, &mut contents)?;
+                    manifests.insert(
+                        artifact_filename.to_string(),
+                        (extension.to_string(), contents),
+                    );
+                    continue;
+                }
+                let Some(artifact_filename) = name.file_name().map(str::to_string) else {
+                    bail!("Unexpected entry {name} in {source}");
+                };
+
+                let (extension, contents) = manifests
+                    .get(&artifact_filename)
+                    .ok_or_else(|| anyhow!("No manifest for {name} in {source}"))?;
+                if name.as_str() != artifact_key(contents, &artifact_filename) {
+                    bail!("Entry {name} in {source} does not match its manifest");
+                }
+                let manifest_path =
+                    cache_directory.join(format!("{artifact_filename}.{extension}"));
+                let mut manifest = ArtifactManifest::parse(&manifest_path, contents)
+                    .map_err(|e| anyhow!("Invalid manifest for {name} in {source}: {e}"))?;
+                if manifest.output_path.file_name() != Some(artifact_filename.as_str()) {
+                    bail!(
+                        "Manifest for {name} in {source} describes {}",
+                        manifest.output_path
+                    );
+                }
+
+                // The artifact may have been exported from an output
+                // directory elsewhere, such as by a runner with a different
+                // workspace path.
+                let output_path = output_directory.join(&artifact_filename);
+                manifest.output_path = output_path.clone();
+                let contents = manifest.to_bytes(ManifestEncoding::from_path(&manifest_path)?)?;
+
+                // Unpack alongside the final locations, so that an
+                // interrupted import is never mistaken for a cached artifact.
+                let import_output_path = Utf8PathBuf::from(format!("{output_path}.import"));
+                let import_manifest_path =
+                    manifest_path.with_extension(format!("import.{extension}"));
+                entry.unpack(&import_output_path)?;
+                std::fs::write(&import_manifest_path, contents)?;
+                staged.push([
+                    (import_output_path, output_path),
+                    (import_manifest_path, manifest_path),
+                ]);
+            }
+            anyhow::Ok(staged)
+        })
+        .await??;
+
+        // Builds hold the lock of an artifact from looking it up until it's
+        // updated, so it's only replaced between builds.
+        let mut imported = vec![];
+        for [(import_output_path, output_path), (import_manifest_path, manifest_path)] in staged {
+            let _lock = lock_file(&self.artifact_lock_path(&output_path)?, false, true).await?;
+            tokio::fs::rename(&import_output_path, &output_path).await?;
+            tokio::fs::rename(&import_manifest_path, &manifest_path).await?;
+            imported.push(output_path);
+        }
+        Ok(imported)
+    }
+
+    // Copies an artifact, and then the manifest describing it, to `remote`.
+    async fn update_remote(
+        remote: &dyn CacheBackend,
+        manifest_path: &Utf8Path,
+        output_path: &Utf8Path,
+    ) -> anyhow::Result<()> {
+        let artifact_filename = output_path
+            .file_name()
+            .ok_or_else(|| anyhow!("Output has no file name"))?;
+        let encoding = ManifestEncoding::from_path(manifest_path)?;
+        let contents = tokio::fs::read(manifest_path).await?;
+        remote
+            .put(&artifact_key(&contents, artifact_filename), output_path)
+            .await?;
+        remote
+            .put(&manifest_key(artifact_filename, encoding), manifest_path)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::archive::EntryMetadata;
+    use crate::input::{MappedPath, TargetDirectory};
+    use camino::Utf8PathBuf;
+    use camino_tempfile::{tempdir, Utf8TempDir};
+
+    struct CacheTest {
+        _input_dir: Utf8TempDir,
+        output_dir: Utf8TempDir,
+
+        input_path: Utf8PathBuf,
+        output_path: Utf8PathBuf,
+    }
+
+    impl CacheTest {
+        fn new() -> Self {
+            let input_dir = tempdir().unwrap();
+            let output_dir = tempdir().unwrap();
+            let input_path = input_dir.path().join("binary.exe");
+            let output_path = output_dir.path().join("output.tar.gz");
+            Self {
+                _input_dir: input_dir,
+                output_dir,
+                input_path,
+                output_path,
+            }
+        }
+
+        async fn create_input(&self, contents: &str) {
+            tokio::fs::write(&self.input_path, contents).await.unwrap()
+        }
+
+        async fn create_output(&self, contents: &str) {
+            tokio::fs::write(&self.output_path, contents).await.unwrap()
+        }
+
+        async fn remove_output(&self) {
+            tokio::fs::remove_file(&self.output_path).await.unwrap()
+        }
+    }
+
+    fn expect_missing_manifest(err: &CacheError, file: &str) {
+        match &err {
+            CacheError::CacheMiss { reason } => {
+                let reason = reason.to_string();
+                let expected = format!("{file}.json not found");
+                assert!(reason.contains(&expected), "{}", reason);
+            }
+            _ => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    fn expect_cache_disabled(err: &CacheError) {
+        match &err {
+            CacheError::CacheMiss { reason } => {
+                let reason = reason.to_string();
+                assert!(reason.contains("Cache disabled"), "{}", reason);
+            }
+            _ => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    fn expect_changed_input(err: &CacheError, path: &Utf8Path) {
+        match &err {
+            CacheError::CacheMiss {
+                reason: MissReason::InputDigestChanged { path: changed, .. },
+            } => assert_eq!(changed, path),
+            _ => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    fn expect_miss(err: &CacheError, expected: &str) {
+        match &err {
+            CacheError::CacheMiss { reason } => {
+                let reason = reason.to_string();
+                assert!(reason.contains(expected), "{}", reason);
+            }
+            _ => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    fn expect_missing_output(err: &CacheError) {
+        match &err {
+            CacheError::CacheMiss { reason } => {
+                let reason = reason.to_string();
+                assert!(reason.contains("Output does not exist"), "{}", reason);
+            }
+            _ => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_lookup_misses_before_update() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
+
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+
+        // Look for the package in the cache. It shouldn't exist.
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_missing_manifest(&err, "output.tar.gz");
+
+        // Create the output we're expecting
+        test.create_output("Hi I'm the output file").await;
+
+        // Still expect a failure; we haven't called "cache.update".
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_missing_manifest(&err, "output.tar.gz");
+    }
+
+    #[tokio::test]
+    async fn test_cache_lookup_hits_after_update() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
+
+        // Create the output we're expecting
+        test.create_output("Hi I'm the output file").await;
+
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+
+        // If we update the cache, we expect a hit.
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+
+        // If we update the input again, we expect a miss.
+        test.create_input("hi i'M tHe InPuT fIlE").await;
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_changed_input(&err, &test.input_path);
+    }
+
+    #[tokio::test]
+    async fn test_cache_lookup_misses_after_removing_output() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
+
+        // Create the output we're expecting
+        test.create_output("Hi I'm the output file").await;
+
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+
+        // If we update the cache, we expect a hit.
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+
+        // If we remove the output file, we expect a miss.
+        // This is somewhat of a "special case", as all the inputs are the same.
+        test.remove_output().await;
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_missing_output(&err);
+    }
+
+    #[tokio::test]
+    async fn test_cache_disabled_always_misses() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
+
+        // Create the output we're expecting
+        test.create_output("Hi I'm the output file").await;
+
+        let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.set_disable(true);
+
+        // Updating the cache should still succeed, though it'll do nothing.
+        cache.update(&inputs, &test.output_path).await.unwrap();
+
+        // The lookup will miss, as the cache has been disabled.
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_cache_disabled(&err);
+    }
+
+    #[tokio::test]
+    async fn test_cache_explain_lists_differences() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let file = BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap();
+        let fingerprint = |value: &str| BuildInput::Fingerprint {
+            name: "features".to_string(),
+            value: value.to_string(),
+        };
+        let inputs = BuildInputs::from(vec![file.clone(), fingerprint("a")]);
+
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        let differences = cache.explain(&inputs, &test.output_path).await.unwrap();
+        assert_eq!(differences[0], MissReason::OutputMissing.into());
+        assert!(matches!(
+            differences[1],
+            CacheDifference::Miss(MissReason::ManifestMissing { .. })
+        ));
+        assert_eq!(differences[1].kind(), CacheMissKind::ManifestMissing);
+
+        // Once the cache is updated, there are no differences.
+        test.create_output("Hi I'm the output file").await;
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        assert!(cache
+            .explain(&inputs, &test.output_path)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Every difference is reported, not just the first.
+        test.create_input("hi i'M tHe InPuT fIlE").await;
+        let inputs = BuildInputs::from(vec![file.clone(), fingerprint("b")]);
+        let differences = cache.explain(&inputs, &test.output_path).await.unwrap();
+        assert_eq!(
+            differences,
+            [
+                CacheDifference::InputChanged {
+                    input: file.clone(),
+                    path: test.input_path.clone(),
+                    origin: None,
+                },
+                CacheDifference::InputAdded {
+                    input: fingerprint("b"),
+                    origin: None,
+                },
+                CacheDifference::InputRemoved(fingerprint("a")),
+            ]
+        );
+        assert_eq!(
+            differences[0].to_string(),
+            format!("Input changed: {}", test.input_path)
+        );
+        assert_eq!(
+            differences[0].reason(),
+            MissReason::InputDigestChanged {
+                path: test.input_path.clone(),
+                origin: None,
+            }
+        );
+        assert_eq!(differences[1].kind(), CacheMissKind::InputsChanged);
+
+        // Removed inputs are reported, rather than failing the explanation.
+        tokio::fs::remove_file(&test.input_path).await.unwrap();
+        let differences = cache.explain(&inputs, &test.output_path).await.unwrap();
+        assert_eq!(
+            differences[0],
+            MissReason::InputMissing {
+                path: test.input_path.clone(),
+                origin: None,
+            }
+            .into()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_lookup_misses_after_changing_fingerprint() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = |features: &str| {
+            BuildInputs::from(vec![
+                BuildInput::add_file(MappedPath {
+                    from: test.input_path.to_path_buf(),
+                    to: Utf8PathBuf::from("/very/important/file"),
+                })
+                .unwrap(),
+                BuildInput::Fingerprint {
+                    name: "features".to_string(),
+                    value: features.to_string(),
+                },
+            ])
+        };
+
+        // Create the output we're expecting
+        test.create_output("Hi I'm the output file").await;
+
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.update(&inputs("a"), &test.output_path).await.unwrap();
+        cache.lookup(&inputs("a"), &test.output_path).await.unwrap();
+
+        // The files are unchanged, but the fingerprint differs.
+        let err = cache
+            .lookup(&inputs("a,b"), &test.output_path)
+            .await
+            .unwrap_err();
+        match &err {
+            CacheError::CacheMiss { reason } => {
+                let reason = reason.to_string();
+                assert!(reason.contains("Set of inputs has changed"), "{}", reason);
+            }
+            _ => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_lookup_hits_remote() {
+        let test = CacheTest::new();
+        let remote_dir = tempdir().unwrap();
+        let remote = DirectoryBackend::new(remote_dir.path());
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
+        test.create_output("Hi I'm the output file").await;
+
+        let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.set_remote(Some(&remote));
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        assert!(cache.take_warnings().is_empty());
+
+        // Only the artifact and its manifest are left in the remote, and no
+        // temporary files.
+        let objects = walkdir::WalkDir::new(remote_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .count();
+        assert_eq!(objects, 2);
+
+        // Emulate another machine, which has built nothing.
+        test.remove_output().await;
+        tokio::fs::remove_dir_all(test.output_dir.path().join(CACHE_SUBDIRECTORY))
+            .await
+            .unwrap();
+        let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_missing_manifest(&err, "output.tar.gz");
+
+        // With the remote, the artifact is copied into the output directory,
+        // and later found locally.
+        cache.set_remote(Some(&remote));
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(&test.output_path).await.unwrap(),
+            "Hi I'm the output file"
+        );
+        cache.set_remote(None);
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+
+        // Artifacts built from other inputs are not copied.
+        test.remove_output().await;
+        test.create_input("hi i'M tHe InPuT fIlE").await;
+        cache.set_remote(Some(&remote));
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_miss(
+            &err,
+            &format!("remote: Input {} has changed", test.input_path),
+        );
+        assert!(!test.output_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_cache_remote_unavailable() {
+        let test = CacheTest::new();
+        // Nothing listens on this port.
+        let remote = HttpBackend::new("http://127.0.0.1:1/cache/");
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
+
+        let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.set_remote(Some(&remote));
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_miss(&err, "remote: Unavailable");
+
+        // Updates still succeed locally, though the failure to update the
+        // remote is reported.
+        test.create_output("Hi I'm the output file").await;
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        let warnings = cache.take_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0].starts_with("Failed to update remote cache"),
+            "{warnings:?}"
+        );
+        assert!(cache.take_warnings().is_empty());
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cache_counters() {
+        let test = CacheTest::new();
+        let counters = CacheCounters::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
+
+        let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.set_counters(Some(&counters));
+        assert_eq!(counters.stats(), CacheStats::default());
+        assert_eq!(counters.stats().hit_rate(), None);
+
+        cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        test.create_output("Hi I'm the output file").await;
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+        cache.set_disable(true);
+        cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+
+        let stats = counters.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(
+            stats.misses,
+            BTreeMap::from([
+                (CacheMissKind::Disabled, 1),
+                (CacheMissKind::ManifestMissing, 1)
+            ])
+        );
+        assert_eq!(stats.hit_rate(), Some(0.5));
+        // The input is hashed by the update, and by both hits.
+        assert_eq!(stats.bytes_hashed, 3 * "Hi I'm the input file".len() as u64);
+        assert!(stats.to_string().starts_with(
+            "2 hits, 2 misses (50.0% hit rate) [cache disabled: 1, manifest missing: 1]"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cache_many_inputs() {
+        let test = CacheTest::new();
+        let input_dir = tempdir().unwrap();
+        let mut inputs = BuildInputs::new();
+        for i in 0..500 {
+            let path = input_dir.path().join(format!("file-{i}"));
+            tokio::fs::write(&path, format!("file {i}")).await.unwrap();
+            inputs.push(
+                BuildInput::add_file(MappedPath {
+                    from: path,
+                    to: Utf8PathBuf::from(format!("/opt/file-{i}")),
+                })
+                .unwrap(),
+            );
+        }
+        test.create_output("Hi I'm the output file").await;
+
+        let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.set_hashing_parallelism(4);
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        let manifest = cache.lookup(&inputs, &test.output_path).await.unwrap();
+
+        // Digests are recorded in the order of the inputs, regardless of
+        // the order in which they're computed.
+        assert!(manifest
+            .inputs
+            .0
+            .iter()
+            .map(|entry| &entry.key)
+            .eq(inputs.iter()));
+
+        tokio::fs::write(input_dir.path().join("file-250"), "changed")
+            .await
+            .unwrap();
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_changed_input(&err, &input_dir.path().join("file-250"));
+    }
+
     #[tokio::test]
-    async fn test_cache_lookup_misses_before_update() {
+    async fn test_cache_digest_memo() {
         let test = CacheTest::new();
+        let counters = CacheCounters::new();
+        let memo = DigestMemo::new();
 
         test.create_input("Hi I'm the input file").await;
-        let inputs = BuildInputs(vec![BuildInput::add_file(MappedPath {
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
             from: test.input_path.to_path_buf(),
             to: Utf8PathBuf::from("/very/important/file"),
         })
         .unwrap()]);
+        test.create_output("Hi I'm the output file").await;
 
-        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.set_counters(Some(&counters));
+        cache.set_digest_memo(Some(&memo));
 
-        // Look for the package in the cache. It shouldn't exist.
+        // The input is hashed once, and then remembered.
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+        let size = "Hi I'm the input file".len() as u64;
+        assert_eq!(counters.stats().bytes_hashed, size);
+        assert_eq!(memo.len(), 1);
+
+        // Modifying the input causes it to be hashed again.
+        test.create_input("Hi I'm the modified input file").await;
         let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
-        expect_missing_manifest(&err, "output.tar.gz");
+        expect_changed_input(&err, &test.input_path);
+        assert_eq!(
+            counters.stats().bytes_hashed,
+            size + "Hi I'm the modified input file".len() as u64
+        );
+    }
 
-        // Create the output we're expecting
+    #[tokio::test]
+    async fn test_cache_digest_algorithm() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
         test.create_output("Hi I'm the output file").await;
 
-        // Still expect a failure; we haven't called "cache.update".
+        let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.set_digest_algorithm(DigestAlgorithm::Xxh3);
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        let manifest = cache.lookup(&inputs, &test.output_path).await.unwrap();
+        assert!(matches!(manifest.inputs.0[0].value, Some(Digest::Xxh3(_))));
+
+        // Switching algorithms invalidates the cached artifact.
+        cache.set_digest_algorithm(DigestAlgorithm::Blake3);
         let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
-        expect_missing_manifest(&err, "output.tar.gz");
+        expect_changed_input(&err, &test.input_path);
     }
 
     #[tokio::test]
-    async fn test_cache_lookup_hits_after_update() {
+    async fn test_cache_metadata_digests() {
         let test = CacheTest::new();
+        let counters = CacheCounters::new();
 
         test.create_input("Hi I'm the input file").await;
-        let inputs = BuildInputs(vec![BuildInput::add_file(MappedPath {
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
             from: test.input_path.to_path_buf(),
             to: Utf8PathBuf::from("/very/important/file"),
         })
         .unwrap()]);
+        test.create_output("Hi I'm the output file").await;
 
-        // Create the output we're expecting
+        let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.set_digest_algorithm(DigestAlgorithm::Metadata);
+        cache.set_counters(Some(&counters));
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+
+        // Nothing is hashed, but changes to the size are noticed.
+        assert_eq!(counters.stats().bytes_hashed, 0);
+        test.create_input("Hi I'm the modified input file").await;
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_changed_input(&err, &test.input_path);
+    }
+
+    #[tokio::test]
+    async fn test_manifest_versions() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
         test.create_output("Hi I'm the output file").await;
 
         let cache = Cache::new(test.output_dir.path()).await.unwrap();
-
-        // If we update the cache, we expect a hit.
         cache.update(&inputs, &test.output_path).await.unwrap();
+        let manifest_path = cache.manifest_path(&test.output_path).unwrap();
+        let write_manifest = |manifest: &serde_json::Value| {
+            std::fs::write(&manifest_path, manifest.to_string()).unwrap()
+        };
+        let mut manifest: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        assert_eq!(manifest["version"], MANIFEST_VERSION);
+
+        // Manifests written before versions were recorded are migrated.
+        manifest.as_object_mut().unwrap().remove("version");
+        manifest["phantom"] = serde_json::Value::Null;
+        write_manifest(&manifest);
         cache.lookup(&inputs, &test.output_path).await.unwrap();
 
-        // If we update the input again, we expect a miss.
-        test.create_input("hi i'M tHe InPuT fIlE").await;
+        // Manifests from the future miss, saying why.
+        manifest["version"] = (MANIFEST_VERSION + 1).into();
+        write_manifest(&manifest);
         let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
-        expect_changed_manifests(&err);
+        let CacheError::CacheMiss { reason } = &err else {
+            panic!("Unexpected error: {err}");
+        };
+        assert_eq!(reason.kind(), CacheMissKind::IncompatibleManifest);
+        let expected = format!("has version {}", MANIFEST_VERSION + 1);
+        assert!(reason.to_string().contains(&expected), "{reason}");
     }
 
     #[tokio::test]
-    async fn test_cache_lookup_misses_after_removing_output() {
+    async fn test_manifest_migrates_entry_metadata() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![
+            BuildInput::add_directory(TargetDirectory("/very".into())),
+            BuildInput::AddDirectory {
+                dir: TargetDirectory("/very/important".into()),
+                metadata: EntryMetadata {
+                    mode: Some(0o700),
+                    uid: Some(12),
+                    gid: Some(34),
+                },
+            },
+            BuildInput::add_file(MappedPath {
+                from: test.input_path.to_path_buf(),
+                to: Utf8PathBuf::from("/very/important/file"),
+            })
+            .unwrap(),
+        ]);
+        test.create_output("Hi I'm the output file").await;
+
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.update(&inputs, &test.output_path).await.unwrap();
+
+        // Rewrite the manifest as version 2 would have written it.
+        let manifest_path = cache.manifest_path(&test.output_path).unwrap();
+        let mut manifest: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        manifest["version"] = 2.into();
+        let keys = &mut manifest["inputs"];
+        keys[0]["key"] = serde_json::json!({ "AddDirectory": "/very" });
+        keys[1]["key"] = serde_json::json!({
+            "AddDirectoryWithMetadata": {
+                "dir": "/very/important",
+                "mode": 0o700,
+                "uid": 12,
+                "gid": 34,
+            }
+        });
+        keys[2]["key"]["AddFile"]
+            .as_object_mut()
+            .unwrap()
+            .remove("metadata");
+        std::fs::write(&manifest_path, manifest.to_string()).unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+
+        // Changing the metadata of an entry invalidates the artifact.
+        let mut inputs: Vec<_> = inputs.into_iter().collect();
+        let BuildInput::AddFile { metadata, .. } = &mut inputs[2] else {
+            panic!("Unexpected input: {:?}", inputs[2]);
+        };
+        metadata.mode = Some(0o755);
+        let inputs = BuildInputs::from(inputs);
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_miss(&err, "Set of inputs has changed");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cache_lock() {
+        let test = CacheTest::new();
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+
+        let inputs = BuildInputs::new();
+        let lock = cache.lock(&inputs, &test.output_path, false).await.unwrap();
+        let err = cache
+            .lock(&inputs, &test.output_path, false)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("{} is locked by another build", test.output_path)
+        );
+
+        // Other artifacts may be locked independently.
+        let other = test.output_dir.path().join("other.tar.gz");
+        cache.lock(&inputs, &other, false).await.unwrap();
+
+        // Waiting succeeds, once the lock is released.
+        let waiter = {
+            let output_path = test.output_path.clone();
+            let output_dir = test.output_dir.path().to_path_buf();
+            tokio::spawn(async move {
+                let cache = Cache::new(&output_dir).await.unwrap();
+                cache
+                    .lock(&BuildInputs::new(), &output_path, true)
+                    .await
+                    .map(|_| ())
+            })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+        drop(lock);
+        waiter.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cache_export_import() {
         let test = CacheTest::new();
 
         test.create_input("Hi I'm the input file").await;
-        let inputs = BuildInputs(vec![BuildInput::add_file(MappedPath {
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
             from: test.input_path.to_path_buf(),
             to: Utf8PathBuf::from("/very/important/file"),
         })
         .unwrap()]);
-
-        // Create the output we're expecting
         test.create_output("Hi I'm the output file").await;
 
         let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.update(&inputs, &test.output_path).await.unwrap();
 
-        // If we update the cache, we expect a hit.
+        let bundle_dir = tempdir().unwrap();
+        let bundle = bundle_dir.path().join("cache.tar.gz");
+        cache
+            .export(std::slice::from_ref(&test.output_path), &bundle)
+            .await
+            .unwrap();
+
+        // Uncached artifacts cannot be exported.
+        let uncached = test.output_dir.path().join("uncached.tar.gz");
+        let err = cache
+            .export(&[uncached], &bundle_dir.path().join("x.tar.gz"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("No cached manifest"), "{err}");
+
+        // Start from an empty output directory, as on another runner.
+        tokio::fs::remove_dir_all(test.output_dir.path())
+            .await
+            .unwrap();
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_missing_manifest(&err, "output.tar.gz");
+
+        let imported = cache.import(&bundle).await.unwrap();
+        assert_eq!(imported, vec![test.output_path.clone()]);
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(&test.output_path).await.unwrap(),
+            "Hi I'm the output file"
+        );
+
+        // Artifacts may be imported into a different output directory, as on
+        // a runner with a different workspace path.
+        let other_dir = tempdir().unwrap();
+        let cache = Cache::new(other_dir.path()).await.unwrap();
+        let other_output_path = other_dir.path().join("output.tar.gz");
+        let imported = cache.import(&bundle).await.unwrap();
+        assert_eq!(imported, vec![other_output_path.clone()]);
+        cache.lookup(&inputs, &other_output_path).await.unwrap();
+
+        // Imports wait for builds holding the lock of an artifact.
+        let lock = cache
+            .lock(&inputs, &other_output_path, false)
+            .await
+            .unwrap();
+        let importer = {
+            let output_dir = other_dir.path().to_path_buf();
+            tokio::spawn(async move {
+                let cache = Cache::new(&output_dir).await.unwrap();
+                cache.import(&bundle).await.map(|_| ())
+            })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!importer.is_finished());
+        drop(lock);
+        importer.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cache_import_rejects_malicious_bundles() {
+        let test = CacheTest::new();
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
+        test.create_output("Hi I'm the output file").await;
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        let manifest_path = cache.manifest_path(&test.output_path).unwrap();
+
+        // A manifest claiming to describe a file elsewhere.
+        let victim_dir = tempdir().unwrap();
+        let victim_path = victim_dir.path().join("output.tar.gz");
+        let mut manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&manifest_path).unwrap()).unwrap();
+        manifest["output_path"] = serde_json::json!(victim_path);
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+
+        // Writes a bundle of `entries`, without the validation performed by
+        // [tar::Header::set_path].
+        let bundle_dir = tempdir().unwrap();
+        let write_bundle = |entries: &[(&str, tar::EntryType, &[u8])]| {
+            let bundle = bundle_dir.path().join("bundle.tar");
+            let mut builder = tar::Builder::new(std::fs::File::create(&bundle).unwrap());
+            for (name, entry_type, contents) in entries {
+                let mut header = tar::Header::new_gnu();
+                header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+                header.set_entry_type(*entry_type);
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder.append(&header, *contents).unwrap();
+            }
+            builder.finish().unwrap();
+            bundle
+        };
+        let new_cache = || async {
+            let output_dir = tempdir().unwrap();
+            let cache = Cache::new(output_dir.path()).await.unwrap();
+            (output_dir, cache)
+        };
+        let artifact = artifact_key(&manifest, "output.tar.gz");
+
+        // The artifact is placed within the output directory, not at the
+        // path named by the manifest.
+        let bundle = write_bundle(&[
+            (
+                "manifests/output.tar.gz.json",
+                tar::EntryType::Regular,
+                &manifest,
+            ),
+            (&artifact, tar::EntryType::Regular, b"contents"),
+        ]);
+        let (output_dir, cache) = new_cache().await;
+        let imported = cache.import(&bundle).await.unwrap();
+        let output_path = output_dir.path().join("output.tar.gz");
+        assert_eq!(imported, vec![output_path.clone()]);
+        assert!(!victim_path.exists());
+        let manifest = ArtifactManifest::read_from(&cache.manifest_path(&output_path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(manifest.output_path, output_path);
+
+        let escape = "manifests/../../output.tar.gz.json";
+        let bundle = write_bundle(&[(escape, tar::EntryType::Regular, b"{}")]);
+        let (output_dir, cache) = new_cache().await;
+        let err = cache.import(&bundle).await.unwrap_err();
+        assert!(err.to_string().contains("Unexpected entry"), "{err}");
+        assert!(!output_dir
+            .path()
+            .parent()
+            .unwrap()
+            .join("output.tar.gz.json")
+            .exists());
+
+        let bundle =
+            write_bundle(&[("manifests/output.tar.gz.json", tar::EntryType::Symlink, b"")]);
+        let (_output_dir, cache) = new_cache().await;
+        let err = cache.import(&bundle).await.unwrap_err();
+        assert!(err.to_string().contains("is not a file"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_cache_verify_outputs() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
+        test.create_output("Hi I'm the output file").await;
+
+        let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
         cache.update(&inputs, &test.output_path).await.unwrap();
+        let manifest = cache.lookup(&inputs, &test.output_path).await.unwrap();
+        assert!(manifest.output_digest.is_some());
+
+        // A truncated output is only noticed when verifying outputs.
+        test.create_output("Hi I'm the").await;
         cache.lookup(&inputs, &test.output_path).await.unwrap();
+        cache.set_verify_outputs(true);
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_miss(&err, "Output has changed since it was cached");
 
-        // If we remove the output file, we expect a miss.
-        // This is somewhat of a "special case", as all the inputs are the same.
-        test.remove_output().await;
+        // Outputs are read, even if inputs are not.
+        cache.set_digest_algorithm(DigestAlgorithm::Metadata);
+        test.create_output("Hi I'm the output file").await;
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        let manifest = cache.lookup(&inputs, &test.output_path).await.unwrap();
+        assert!(matches!(manifest.output_digest, Some(Digest::Blake3(_))));
+        test.create_output("Hi I'm the outpuT file").await;
         let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
-        expect_missing_output(&err);
+        expect_miss(&err, "Output has changed since it was cached");
     }
 
     #[tokio::test]
-    async fn test_cache_disabled_always_misses() {
+    async fn test_cache_manifest_encoding() {
         let test = CacheTest::new();
 
         test.create_input("Hi I'm the input file").await;
-        let inputs = BuildInputs(vec![BuildInput::add_file(MappedPath {
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
             from: test.input_path.to_path_buf(),
             to: Utf8PathBuf::from("/very/important/file"),
         })
         .unwrap()]);
+        test.create_output("Hi I'm the output file").await;
 
-        // Create the output we're expecting
+        let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.set_manifest_encoding(ManifestEncoding::Postcard);
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        let manifest_path = cache.manifest_path(&test.output_path).unwrap();
+        assert_eq!(manifest_path.extension(), Some("postcard"));
+        assert!(manifest_path.exists());
+        let manifest = cache.lookup(&inputs, &test.output_path).await.unwrap();
+        assert_eq!(manifest.inputs.0.len(), 1);
+
+        // Manifests in other encodings are not consulted.
+        cache.set_manifest_encoding(ManifestEncoding::Json);
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_missing_manifest(&err, "output.tar.gz");
+
+        // Binary manifests from other versions cannot be migrated.
+        let mut contents = std::fs::read(&manifest_path).unwrap();
+        contents[0] = 0;
+        let err = ArtifactManifest::parse(&manifest_path, &contents).unwrap_err();
+        expect_miss(&err, "has version 0");
+    }
+
+    #[test]
+    fn test_manifest_round_trips_every_input() {
+        let buildomat = |to: Option<&str>| {
+            crate::blob::Source::Buildomat(crate::package::PrebuiltBlob {
+                repo: "repo".to_string(),
+                series: "series".to_string(),
+                commit: "commit".to_string(),
+                artifact: "artifact".to_string(),
+                sha256: "sha256".to_string(),
+                to: to.map(|to| serde_json::from_value(serde_json::json!(to)).unwrap()),
+            })
+        };
+        let mapped = || MappedPath {
+            from: Utf8PathBuf::from("/from"),
+            to: Utf8PathBuf::from("/to"),
+        };
+        let metadata = EntryMetadata {
+            mode: Some(0o755),
+            uid: None,
+            gid: Some(1),
+        };
+        let inputs = vec![
+            BuildInput::AddInMemoryFile {
+                dst_path: "/file".into(),
+                contents: "contents".to_string(),
+                metadata,
+            },
+            BuildInput::AddEmptyFile {
+                path: "/empty".into(),
+                metadata: EntryMetadata::default(),
+            },
+            BuildInput::AddFifo {
+                path: "/fifo".into(),
+                metadata,
+            },
+            BuildInput::AddDirectory {
+                dir: TargetDirectory("/dir".into()),
+                metadata,
+            },
+            BuildInput::AddFile {
+                mapped_path: mapped(),
+                len: 7,
+                metadata,
+            },
+            BuildInput::AddBlob {
+                path: mapped(),
+                blob: crate::blob::Source::S3("blob.tar.gz".into()),
+            },
+            BuildInput::AddBlob {
+                path: mapped(),
+                blob: buildomat(None),
+            },
+            BuildInput::AddBlob {
+                path: mapped(),
+                blob: buildomat(Some("/opt/{{image}}/blob")),
+            },
+            BuildInput::AddSymlink {
+                link: "/link".into(),
+                target: "target".into(),
+            },
+            BuildInput::AddPackage(crate::input::TargetPackage("a.tar.gz".into())),
+            BuildInput::AddPackageFiles {
+                package: crate::input::TargetPackage("b.tar.gz".into()),
+                paths: vec!["/opt/*".to_string()],
+            },
+            BuildInput::Fingerprint {
+                name: "name".to_string(),
+                value: "value".to_string(),
+            },
+            BuildInput::Dependency("/dependency".into()),
+        ];
+        // Ensures that new variants are added to this test.
+        for input in &inputs {
+            match input {
+                BuildInput::AddInMemoryFile { .. }
+                | BuildInput::AddEmptyFile { .. }
+                | BuildInput::AddFifo { .. }
+                | BuildInput::AddDirectory { .. }
+                | BuildInput::AddFile { .. }
+                | BuildInput::AddBlob { .. }
+                | BuildInput::AddSymlink { .. }
+                | BuildInput::AddPackage(_)
+                | BuildInput::AddPackageFiles { .. }
+                | BuildInput::Fingerprint { .. }
+                | BuildInput::Dependency(_) => (),
+            }
+        }
+
+        let manifest = ArtifactManifest {
+            version: MANIFEST_VERSION,
+            inputs: InputMap(
+                inputs
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, key)| InputEntry {
+                        key,
+                        value: (i % 2 == 0).then(|| Digest::Blake3("abc".to_string())),
+                    })
+                    .collect(),
+            ),
+            output_path: "out/output.tar.gz".into(),
+            output_digest: None,
+            salt: None,
+        };
+        let json = serde_json::to_vec(&manifest).unwrap();
+        let parsed = ArtifactManifest::parse(Utf8Path::new("a.tar.gz.json"), &json).unwrap();
+        assert_eq!(parsed, manifest);
+        let postcard = postcard::to_stdvec(&manifest).unwrap();
+        let parsed =
+            ArtifactManifest::parse(Utf8Path::new("a.tar.gz.postcard"), &postcard).unwrap();
+        assert_eq!(parsed, manifest);
+    }
+
+    #[tokio::test]
+    async fn test_cache_salt() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
         test.create_output("Hi I'm the output file").await;
 
         let mut cache = Cache::new(test.output_dir.path()).await.unwrap();
-        cache.set_disable(true);
+        cache.set_salt(Some("epoch-1"));
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+
+        // Bumping the salt invalidates everything, as does removing it.
+        cache.set_salt(Some("epoch-2"));
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_miss(&err, "Salt changed from epoch-1 -> epoch-2");
+        assert_eq!(
+            cache.explain(&inputs, &test.output_path).await.unwrap(),
+            vec![CacheDifference::Miss(MissReason::SaltChanged {
+                previous: Some("epoch-1".to_string()),
+                current: Some("epoch-2".to_string()),
+            })]
+        );
+        cache.set_salt(None);
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_miss(&err, "Salt changed from epoch-1 -> none");
 
-        // Updating the cache should still succeed, though it'll do nothing.
         cache.update(&inputs, &test.output_path).await.unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+    }
 
-        // The lookup will miss, as the cache has been disabled.
+    #[tokio::test]
+    async fn test_cache_symlink() {
+        let test = CacheTest::new();
+        let symlink = |target: &str| BuildInput::AddSymlink {
+            link: Utf8PathBuf::from("/opt/svc/current"),
+            target: Utf8PathBuf::from(target),
+        };
+
+        let inputs = BuildInputs::from(vec![symlink("v1")]);
+        test.create_output("Hi I'm the output file").await;
+
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+
+        // Pointing the link elsewhere invalidates the artifact.
+        let inputs = BuildInputs::from(vec![symlink("v2")]);
         let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
-        expect_cache_disabled(&err);
+        expect_miss(&err, "Set of inputs has changed");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cache_evict() {
+        let test = CacheTest::new();
+        let blob_path = test.output_dir.path().join("blob").join("firmware.bin");
+        std::fs::create_dir_all(blob_path.parent().unwrap()).unwrap();
+        std::fs::write(&blob_path, "firmware").unwrap();
+
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![
+            BuildInput::add_file(MappedPath {
+                from: test.input_path.to_path_buf(),
+                to: Utf8PathBuf::from("/very/important/file"),
+            })
+            .unwrap(),
+            BuildInput::AddBlob {
+                path: MappedPath {
+                    from: blob_path.clone(),
+                    to: Utf8PathBuf::from("/firmware.bin"),
+                },
+                blob: crate::blob::Source::S3("firmware.bin".into()),
+            },
+        ]);
+        test.create_output("Hi I'm the output file").await;
+
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.update(&inputs, &test.output_path).await.unwrap();
+
+        // Nothing is evicted while within the policy.
+        let policy = EvictionPolicy {
+            max_bytes: Some(1000),
+            max_age: Some(Duration::from_secs(3600)),
+        };
+        assert!(cache.evict(&policy).await.unwrap().is_empty());
+
+        // Another, more recently used artifact.
+        let other_path = test.output_dir.path().join("other.tar.gz");
+        std::fs::write(&other_path, "other").unwrap();
+        cache
+            .update(&BuildInputs::from(vec![]), &other_path)
+            .await
+            .unwrap();
+
+        // Exceeding the size evicts the least recently used files, and the
+        // manifests describing them.
+        let policy = EvictionPolicy {
+            max_bytes: Some(5),
+            max_age: None,
+        };
+        let evicted = cache.evict(&policy).await.unwrap();
+        assert_eq!(evicted.len(), 2);
+        assert!(evicted.contains(&test.output_path));
+        assert!(evicted.contains(&blob_path));
+        assert!(!test.output_path.exists());
+        assert!(!blob_path.exists());
+        assert!(!cache.manifest_path(&test.output_path).unwrap().exists());
+        assert!(other_path.exists());
+        cache
+            .lookup(&BuildInputs::from(vec![]), &other_path)
+            .await
+            .unwrap();
+
+        // Evicted blobs cause a miss, rather than a failure.
+        test.create_output("Hi I'm the output file").await;
+        std::fs::write(&blob_path, "firmware").unwrap();
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        std::fs::remove_file(&blob_path).unwrap();
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_miss(&err, &format!("Input {blob_path} does not exist"));
+
+        // Files unused for too long are evicted, regardless of size.
+        let policy = EvictionPolicy {
+            max_bytes: None,
+            max_age: Some(Duration::ZERO),
+        };
+        let evicted = cache.evict(&policy).await.unwrap();
+        assert_eq!(evicted.len(), 2);
+        assert!(!other_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_cache_evict_skips_locked() {
+        let test = CacheTest::new();
+        let blob_path = test.output_dir.path().join("blob").join("firmware.bin");
+        std::fs::create_dir_all(blob_path.parent().unwrap()).unwrap();
+        std::fs::write(&blob_path, "firmware").unwrap();
+
+        let blob = BuildInput::AddBlob {
+            path: MappedPath {
+                from: blob_path.clone(),
+                to: Utf8PathBuf::from("/firmware.bin"),
+            },
+            blob: crate::blob::Source::S3("firmware.bin".into()),
+        };
+        let inputs = BuildInputs::from(vec![blob.clone()]);
+        test.create_output("Hi I'm the output file").await;
+
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache.update(&inputs, &test.output_path).await.unwrap();
+
+        // Another package, using the same blob, is being built.
+        let other_path = test.output_dir.path().join("other.tar.gz");
+        let lock = cache
+            .lock(&BuildInputs::from(vec![blob]), &other_path, false)
+            .await
+            .unwrap();
+
+        // The artifact which isn't being built is evicted, but not the blob.
+        let policy = EvictionPolicy {
+            max_bytes: None,
+            max_age: Some(Duration::ZERO),
+        };
+        let evicted = cache.evict(&policy).await.unwrap();
+        assert_eq!(evicted, vec![test.output_path.clone()]);
+        assert!(blob_path.exists());
+
+        // Nor is an artifact being built.
+        test.create_output("Hi I'm the output file").await;
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        let output_lock = cache
+            .lock(&BuildInputs::new(), &test.output_path, false)
+            .await
+            .unwrap();
+        drop(lock);
+        let evicted = cache.evict(&policy).await.unwrap();
+        assert_eq!(evicted, vec![blob_path.clone()]);
+        assert!(test.output_path.exists());
+
+        drop(output_lock);
+        let evicted = cache.evict(&policy).await.unwrap();
+        assert_eq!(evicted, vec![test.output_path.clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_cache_usage_unrecorded() {
+        let test = CacheTest::new();
+        test.create_input("Hi I'm the input file").await;
+        let inputs = BuildInputs::from(vec![BuildInput::add_file(MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        })
+        .unwrap()]);
+        test.create_output("Hi I'm the output file").await;
+
+        // The record of usage can't be read.
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        std::fs::create_dir(cache.cache_directory.join(USAGE_FILE)).unwrap();
+
+        // The cache is still usable, but the failure is reported.
+        cache.update(&inputs, &test.output_path).await.unwrap();
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+        let warnings = cache.take_warnings();
+        assert_eq!(warnings.len(), 2);
+        for warning in warnings {
+            assert!(
+                warning.starts_with("Failed to record cache usage"),
+                "{warning}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_ignores_origins() {
+        let test = CacheTest::new();
+
+        test.create_input("Hi I'm the input file").await;
+        let path = MappedPath {
+            from: test.input_path.to_path_buf(),
+            to: Utf8PathBuf::from("/very/important/file"),
+        };
+        let file = BuildInput::add_file(path.clone()).unwrap();
+        test.create_output("Hi I'm the output file").await;
+
+        let cache = Cache::new(test.output_dir.path()).await.unwrap();
+        cache
+            .update(&BuildInputs::from(vec![file.clone()]), &test.output_path)
+            .await
+            .unwrap();
+
+        // Recording where an input came from doesn't change the artifact.
+        let mut inputs = BuildInputs::new();
+        let origin = InputOrigin::Path {
+            from: path.from.to_string(),
+            to: path.to.to_string(),
+        };
+        inputs.extend_with_origin(origin.clone(), [file]);
+        cache.lookup(&inputs, &test.output_path).await.unwrap();
+
+        // But it's reported when the input changes.
+        test.create_input("hi i'M tHe InPuT fIlE").await;
+        let err = cache.lookup(&inputs, &test.output_path).await.unwrap_err();
+        expect_changed_input(&err, &test.input_path);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Cache Miss: Input {} (from {origin}) has changed",
+                test.input_path
+            )
+        );
     }
 }
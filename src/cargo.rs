@@ -0,0 +1,140 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tools for inspecting the cargo workspace which produces Rust binaries.
+
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+
+/// Describes the layout of a cargo workspace, as reported by
+/// `cargo metadata`.
+///
+/// This is intended to be loaded once per build session and shared between
+/// packages via [crate::package::BuildConfig].
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct CargoMetadata {
+    /// The directory in which cargo places build output.
+    pub target_directory: Utf8PathBuf,
+
+    /// The root directory of the workspace.
+    pub workspace_root: Utf8PathBuf,
+
+    /// The names of all binary targets within the workspace.
+    #[serde(rename = "packages", deserialize_with = "deserialize_binaries")]
+    pub binaries: BTreeSet<String>,
+}
+
+#[derive(Deserialize)]
+struct MetadataPackage {
+    targets: Vec<MetadataTarget>,
+}
+
+#[derive(Deserialize)]
+struct MetadataTarget {
+    name: String,
+    kind: Vec<String>,
+}
+
+fn deserialize_binaries<'de, D>(deserializer: D) -> Result<BTreeSet<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let packages = Vec::<MetadataPackage>::deserialize(deserializer)?;
+    Ok(packages
+        .into_iter()
+        .flat_map(|package| package.targets)
+        .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+        .map(|target| target.name)
+        .collect())
+}
+
+impl CargoMetadata {
+    /// Runs `cargo metadata` for the workspace containing `directory`.
+    pub async fn load(directory: &Utf8Path) -> Result<Self> {
+        let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let output = tokio::process::Command::new(cargo)
+            .args(["metadata", "--format-version", "1", "--no-deps"])
+            .current_dir(directory)
+            .output()
+            .await
+            .context("Failed to spawn cargo")?;
+        if !output.status.success() {
+            bail!(
+                "cargo metadata failed: {}\n{}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Self::parse(&output.stdout)
+    }
+
+    /// Parses the output of `cargo metadata --format-version 1`.
+    pub fn parse(json: &[u8]) -> Result<Self> {
+        serde_json::from_slice(json).context("Failed to parse cargo metadata")
+    }
+
+    /// Returns an error if any of `binary_names` are not binary targets
+    /// within the workspace.
+    pub fn validate_binaries<'a>(
+        &self,
+        binary_names: impl IntoIterator<Item = &'a String>,
+    ) -> Result<()> {
+        let missing: Vec<&str> = binary_names
+            .into_iter()
+            .filter(|name| !self.binaries.contains(*name))
+            .map(|name| name.as_str())
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "Binaries not found in workspace '{}': {}",
+                self.workspace_root,
+                missing.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const METADATA: &str = r#"{
+        "packages": [
+            {
+                "name": "pkg",
+                "targets": [
+                    { "name": "pkg", "kind": ["lib"] },
+                    { "name": "svc", "kind": ["bin"] }
+                ]
+            }
+        ],
+        "target_directory": "/ws/out",
+        "workspace_root": "/ws",
+        "version": 1
+    }"#;
+
+    #[test]
+    fn parse_metadata() {
+        let metadata = CargoMetadata::parse(METADATA.as_bytes()).unwrap();
+        assert_eq!(metadata.target_directory, "/ws/out");
+        assert_eq!(metadata.workspace_root, "/ws");
+        assert_eq!(metadata.binaries, BTreeSet::from(["svc".to_string()]));
+    }
+
+    #[test]
+    fn validate_missing_binaries() {
+        let metadata = CargoMetadata::parse(METADATA.as_bytes()).unwrap();
+        metadata.validate_binaries(&["svc".to_string()]).unwrap();
+        let err = metadata
+            .validate_binaries(&["svc".to_string(), "pkg".to_string()])
+            .expect_err("'pkg' is not a binary");
+        assert_eq!(
+            err.to_string(),
+            "Binaries not found in workspace '/ws': pkg"
+        );
+    }
+}
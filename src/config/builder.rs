@@ -0,0 +1,340 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Constructing configurations in code, rather than parsing manifests.
+
+use crate::hook::BuildHook;
+use crate::package::{InterpolatedString, Package, PackageOutput, PackageSource, ResourceHints};
+use crate::smf::SmfConfig;
+use crate::target::{TargetExpr, TargetFilter, TargetMap, TargetSchema};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use super::{
+    Config, InvalidConfigIdent, PackageName, PresetName, ServiceName, TargetConfig,
+    TargetSchemaError,
+};
+
+/// Errors which may be returned by [PackageBuilder::build] and
+/// [ConfigBuilder::build].
+#[derive(Error, Debug)]
+pub enum BuilderError {
+    #[error("Invalid identifier '{ident}': {err}")]
+    InvalidIdent {
+        ident: String,
+        err: InvalidConfigIdent,
+    },
+    #[error("Composite packages must be zone images")]
+    CompositeRequiresZone,
+    #[error("SMF manifests can only be generated for zone packages")]
+    SmfRequiresZone,
+    #[error("Package '{0}' was already defined")]
+    DuplicatePackage(PackageName),
+    #[error("Preset '{0}' was already defined")]
+    DuplicatePreset(PresetName),
+    #[error(transparent)]
+    InvalidTarget(#[from] TargetSchemaError),
+}
+
+fn ident<T: std::str::FromStr<Err = InvalidConfigIdent>>(ident: String) -> Result<T, BuilderError> {
+    ident
+        .parse()
+        .map_err(|err| BuilderError::InvalidIdent { ident, err })
+}
+
+/// Constructs a [Package], with the defaults used by manifests.
+///
+/// ```
+/// use omicron_zone_package::config::PackageBuilder;
+/// use omicron_zone_package::package::{PackageOutput, PackageSource};
+///
+/// let package = PackageBuilder::new("my-service", PackageSource::Manual, PackageOutput::Tarball)
+///     .setup_hint("Run ./build.sh first")
+///     .build()
+///     .unwrap();
+/// assert_eq!(package.service_name.as_str(), "my-service");
+/// ```
+pub struct PackageBuilder {
+    service_name: String,
+    package: Package,
+}
+
+impl PackageBuilder {
+    /// Begins a package for the service `service_name`.
+    pub fn new<S: Into<String>>(
+        service_name: S,
+        source: PackageSource,
+        output: PackageOutput,
+    ) -> Self {
+        Self {
+            service_name: service_name.into(),
+            package: Package {
+                // Replaced when the package is built.
+                service_name: ServiceName::new_const("placeholder"),
+                source,
+                output,
+                only_for_targets: None,
+                not_for_targets: None,
+                tags: vec![],
+                only_if: None,
+                setup_hint: None,
+                checksum_manifest: false,
+                preserve_symlinks: false,
+                version_in_filename: false,
+                output_template: None,
+                smf: None,
+                metadata: BTreeMap::new(),
+                pre_build: vec![],
+                post_build: vec![],
+                resources: ResourceHints::default(),
+            },
+        }
+    }
+
+    /// See [Package::only_for_targets].
+    pub fn only_for_targets(mut self, targets: TargetFilter) -> Self {
+        self.package.only_for_targets = Some(targets);
+        self
+    }
+
+    /// See [Package::not_for_targets].
+    pub fn not_for_targets(mut self, targets: TargetFilter) -> Self {
+        self.package.not_for_targets = Some(targets);
+        self
+    }
+
+    /// See [Package::only_if].
+    pub fn only_if(mut self, expr: TargetExpr) -> Self {
+        self.package.only_if = Some(expr);
+        self
+    }
+
+    /// Adds a label to [Package::tags].
+    pub fn tag<S: Into<String>>(mut self, tag: S) -> Self {
+        self.package.tags.push(tag.into());
+        self
+    }
+
+    /// See [Package::setup_hint].
+    pub fn setup_hint<S: Into<String>>(mut self, hint: S) -> Self {
+        self.package.setup_hint = Some(hint.into());
+        self
+    }
+
+    /// See [Package::checksum_manifest].
+    pub fn checksum_manifest(mut self, enabled: bool) -> Self {
+        self.package.checksum_manifest = enabled;
+        self
+    }
+
+    /// See [Package::preserve_symlinks].
+    pub fn preserve_symlinks(mut self, enabled: bool) -> Self {
+        self.package.preserve_symlinks = enabled;
+        self
+    }
+
+    /// See [Package::version_in_filename].
+    pub fn version_in_filename(mut self, enabled: bool) -> Self {
+        self.package.version_in_filename = enabled;
+        self
+    }
+
+    /// See [Package::output_template].
+    pub fn output_template(mut self, template: &str) -> Self {
+        self.package.output_template = Some(InterpolatedString::from(template));
+        self
+    }
+
+    /// See [Package::smf].
+    pub fn smf(mut self, smf: SmfConfig) -> Self {
+        self.package.smf = Some(smf);
+        self
+    }
+
+    /// Adds a key-value pair to [Package::metadata].
+    pub fn metadata<K: Into<String>>(mut self, key: K, value: &str) -> Self {
+        self.package
+            .metadata
+            .insert(key.into(), InterpolatedString::from(value));
+        self
+    }
+
+    /// Adds a command to [Package::pre_build].
+    pub fn pre_build(mut self, hook: BuildHook) -> Self {
+        self.package.pre_build.push(hook);
+        self
+    }
+
+    /// Adds a command to [Package::post_build].
+    pub fn post_build(mut self, hook: BuildHook) -> Self {
+        self.package.post_build.push(hook);
+        self
+    }
+
+    /// See [Package::resources].
+    pub fn resources(mut self, resources: ResourceHints) -> Self {
+        self.package.resources = resources;
+        self
+    }
+
+    /// Validates and returns the package.
+    pub fn build(self) -> Result<Package, BuilderError> {
+        let mut package = self.package;
+        package.service_name = ident(self.service_name)?;
+        let zoned = matches!(package.output, PackageOutput::Zone { .. });
+        if !zoned && matches!(package.source, PackageSource::Composite { .. }) {
+            return Err(BuilderError::CompositeRequiresZone);
+        }
+        if !zoned && package.smf.is_some() {
+            return Err(BuilderError::SmfRequiresZone);
+        }
+        Ok(package)
+    }
+}
+
+/// Constructs a [Config].
+///
+/// Unlike manifests, packages and presets may not be replaced once added.
+#[derive(Default)]
+pub struct ConfigBuilder {
+    packages: Vec<(String, Package)>,
+    presets: Vec<(String, TargetMap)>,
+    keys: Option<TargetSchema>,
+}
+
+impl ConfigBuilder {
+    /// Begins an empty configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a package named `name`, usually created by [PackageBuilder].
+    pub fn package<S: Into<String>>(mut self, name: S, package: Package) -> Self {
+        self.packages.push((name.into(), package));
+        self
+    }
+
+    /// Adds a target preset named `name`.
+    pub fn preset<S: Into<String>>(mut self, name: S, target: TargetMap) -> Self {
+        self.presets.push((name.into(), target));
+        self
+    }
+
+    /// Declares the keys which targets may define; see [TargetConfig::keys].
+    pub fn target_keys(mut self, keys: TargetSchema) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    /// Validates and returns the configuration.
+    pub fn build(self) -> Result<Config, BuilderError> {
+        let mut packages = BTreeMap::new();
+        for (name, package) in self.packages {
+            let name: PackageName = ident(name)?;
+            if packages.contains_key(&name) {
+                return Err(BuilderError::DuplicatePackage(name));
+            }
+            packages.insert(name, package);
+        }
+        let mut presets = BTreeMap::new();
+        for (name, target) in self.presets {
+            let name: PresetName = ident(name)?;
+            if presets.contains_key(&name) {
+                return Err(BuilderError::DuplicatePreset(name));
+            }
+            presets.insert(name, target);
+        }
+        let config = Config {
+            packages,
+            target: TargetConfig {
+                presets,
+                keys: self.keys,
+            },
+            include: vec![],
+        };
+        config.validate_targets()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builders() {
+        let a = PackageBuilder::new(
+            "a",
+            PackageSource::Manual,
+            PackageOutput::Zone {
+                intermediate_only: true,
+                compression: Default::default(),
+            },
+        )
+        .build()
+        .unwrap();
+        let b = PackageBuilder::new(
+            "b",
+            PackageSource::Composite {
+                packages: vec!["a.tar.gz".into()],
+            },
+            PackageOutput::Zone {
+                intermediate_only: false,
+                compression: Default::default(),
+            },
+        )
+        .metadata("commit", "{{commit}}")
+        .build()
+        .unwrap();
+        let cfg = ConfigBuilder::new()
+            .package("a", a)
+            .package("b", b.clone())
+            .preset("dev", "image=standard".parse().unwrap())
+            .build()
+            .unwrap();
+
+        let order = cfg
+            .packages_to_build(&TargetMap::default())
+            .build_order()
+            .map(|batch| {
+                batch
+                    .into_iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(order, [["a"], ["b"]]);
+        let deps = cfg
+            .packages_to_build(&TargetMap::default())
+            .dependencies()
+            .into_iter()
+            .map(|(name, deps)| {
+                let deps = deps.iter().map(|dep| dep.as_str()).collect::<Vec<_>>();
+                (name.as_str(), deps)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(deps, [("a", vec![]), ("b", vec!["a"])]);
+
+        let err = PackageBuilder::new("1st", PackageSource::Manual, PackageOutput::Tarball)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, BuilderError::InvalidIdent { .. }), "{err}");
+
+        let err = PackageBuilder::new(
+            "c",
+            PackageSource::Composite { packages: vec![] },
+            PackageOutput::Tarball,
+        )
+        .build()
+        .unwrap_err();
+        assert!(matches!(err, BuilderError::CompositeRequiresZone), "{err}");
+
+        let err = ConfigBuilder::new()
+            .package("b", b.clone())
+            .package("b", b)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, BuilderError::DuplicatePackage(_)), "{err}");
+    }
+}
@@ -0,0 +1,329 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Locating errors within manifests.
+
+use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Describes an error in a manifest, and where it was found.
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// The manifest containing the error.
+    pub path: PathBuf,
+    /// The line and column (both 1-based) at which the error was found, if
+    /// known.
+    pub location: Option<(usize, usize)>,
+    /// The key containing the error, as in `package.my-service.source`, if
+    /// known.
+    pub key: Option<String>,
+    /// A description of the error.
+    pub message: String,
+    /// A suggestion for fixing the error, if any.
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    // Describes a manifest which is not valid TOML.
+    pub(super) fn syntax(path: &Path, contents: &str, err: &toml::de::Error) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            location: err.span().map(|span| line_and_column(contents, span.start)),
+            key: None,
+            message: err.message().to_string(),
+            hint: None,
+        }
+    }
+
+    // Describes a manifest which could not be deserialized into a
+    // configuration.
+    //
+    // `contents` holds the manifest as written, if it is TOML; it's used to
+    // find the key which caused the error. That's `keys`, if provided, or
+    // otherwise the path at which `err` occurred.
+    pub(super) fn invalid(
+        path: &Path,
+        contents: Option<&str>,
+        err: serde_path_to_error::Error<toml::de::Error>,
+        keys: Option<Vec<String>>,
+    ) -> Self {
+        let keys = keys.unwrap_or_else(|| error_keys(&err));
+        let message = err.into_inner().message().to_string();
+        let key = (!keys.is_empty()).then(|| keys.join("."));
+        let hint = hint(&message, key.as_deref());
+        Self::at(path, contents, keys, message, hint)
+    }
+
+    // Describes an error in the value at `keys`, such as one which
+    // deserialized successfully but is not valid.
+    //
+    // As with [Self::invalid], `contents` is used to find the key, if it's
+    // provided.
+    pub(super) fn at(
+        path: &Path,
+        contents: Option<&str>,
+        keys: Vec<String>,
+        message: String,
+        hint: Option<String>,
+    ) -> Self {
+        let location = contents.and_then(|contents| {
+            let spans: KeySpans = toml::from_str(contents).ok()?;
+            let span = spans.find(&keys)?;
+            Some(line_and_column(contents, span.start))
+        });
+        Self {
+            path: path.to_path_buf(),
+            location,
+            key: (!keys.is_empty()).then(|| keys.join(".")),
+            message,
+            hint,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some((line, column)) = self.location {
+            write!(f, ":{line}:{column}")?;
+        }
+        write!(f, ": ")?;
+        if let Some(key) = &self.key {
+            write!(f, "in '{key}': ")?;
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(hint) = &self.hint {
+            write!(f, " (hint: {hint})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+// Returns the keys along the path at which `err` occurred, with array
+// elements keyed by their index.
+pub(super) fn error_keys(err: &serde_path_to_error::Error<toml::de::Error>) -> Vec<String> {
+    err.path()
+        .iter()
+        .filter_map(|segment| match segment {
+            serde_path_to_error::Segment::Map { key } => Some(key.clone()),
+            serde_path_to_error::Segment::Seq { index } => Some(index.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+// Converts a byte offset within `contents` to a 1-based line and column.
+fn line_and_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+// Suggests a fix for common mistakes, based on the message reported by serde.
+fn hint(message: &str, key: Option<&str>) -> Option<String> {
+    if let Some(field) = quoted_after(message, "missing field ") {
+        return Some(match key {
+            Some(key) => format!("add '{field}' to '{key}', or to the template it uses"),
+            None => format!("add '{field}' to the manifest"),
+        });
+    }
+    let unknown = unknown_field(message).or_else(|| quoted_after(message, "unknown variant "))?;
+    let (_, expected) = message.split_once("expected")?;
+    let closest = expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|candidate| (edit_distance(unknown, candidate), candidate))
+        .min()?;
+    (closest.0 <= (unknown.len() / 3).max(1)).then(|| format!("did you mean '{}'?", closest.1))
+}
+
+// Returns the field named by serde's "unknown field" error message.
+pub(super) fn unknown_field(message: &str) -> Option<&str> {
+    quoted_after(message, "unknown field ")
+}
+
+// Returns the backtick-quoted word which follows `prefix` in `message`.
+fn quoted_after<'a>(message: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = message.split_once(prefix)?.1.strip_prefix('`')?;
+    Some(rest.split_once('`')?.0)
+}
+
+// Counts the single-character edits needed to turn `a` into `b`, where
+// swapping two adjacent characters (as in "mdoe") counts as one edit.
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    // The distances from the first i - 2, i - 1, and i characters of `a` to
+    // each prefix of `b`.
+    let mut before = vec![0; b.len() + 1];
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    let mut row = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        row[0] = i;
+        for j in 1..=b.len() {
+            let substitution = previous[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            row[j] = substitution.min(previous[j] + 1).min(row[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(before[j - 2] + 1);
+            }
+        }
+        std::mem::swap(&mut before, &mut previous);
+        std::mem::swap(&mut previous, &mut row);
+    }
+    previous[b.len()]
+}
+
+// The span of each key within a TOML document, nested as in the document.
+//
+// Array elements are keyed by their index, and spanned by their value.
+#[derive(Default)]
+struct KeySpans(BTreeMap<String, (Range<usize>, KeySpans)>);
+
+impl KeySpans {
+    // Returns the span of the most deeply nested key along `keys` which
+    // exists in the document.
+    fn find(&self, keys: &[String]) -> Option<Range<usize>> {
+        let (first, rest) = keys.split_first()?;
+        let (span, children) = self.0.get(first)?;
+        children.find(rest).or_else(|| Some(span.clone()))
+    }
+}
+
+impl<'de> Deserialize<'de> for KeySpans {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(KeySpansVisitor)
+    }
+}
+
+struct KeySpansVisitor;
+
+impl<'de> Visitor<'de> for KeySpansVisitor {
+    type Value = KeySpans;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a TOML value")
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<KeySpans, E> {
+        Ok(KeySpans::default())
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<KeySpans, E> {
+        Ok(KeySpans::default())
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<KeySpans, E> {
+        Ok(KeySpans::default())
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<KeySpans, E> {
+        Ok(KeySpans::default())
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<KeySpans, E> {
+        Ok(KeySpans::default())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<KeySpans, A::Error> {
+        let mut spans = KeySpans::default();
+        while let Some(element) = seq.next_element::<toml::Spanned<KeySpans>>()? {
+            let span = element.span();
+            spans
+                .0
+                .insert(spans.0.len().to_string(), (span, element.into_inner()));
+        }
+        Ok(spans)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<KeySpans, A::Error> {
+        let mut spans = KeySpans::default();
+        while let Some(key) = map.next_key::<toml::Spanned<String>>()? {
+            let span = key.span();
+            let children = map.next_value::<KeySpans>()?;
+            spans.0.insert(key.into_inner(), (span, children));
+        }
+        Ok(spans)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_spans() {
+        let contents = r#"
+[package.foo]
+service_name = "foo"
+source.type = "local"
+
+[[package.foo.source.rust]]
+binary_names = ["foo"]
+"#;
+        let spans: KeySpans = toml::from_str(contents).unwrap();
+        let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+        let location = |keys: Vec<String>| {
+            let span = spans.find(&keys).unwrap();
+            line_and_column(contents, span.start)
+        };
+
+        assert_eq!(location(keys(&["package", "foo", "service_name"])), (3, 1));
+        assert_eq!(
+            location(keys(&["package", "foo", "source", "type"])),
+            (4, 8)
+        );
+        assert_eq!(
+            location(keys(&[
+                "package",
+                "foo",
+                "source",
+                "rust",
+                "0",
+                "binary_names"
+            ])),
+            (7, 1)
+        );
+        // Keys which are absent fall back to their closest parent.
+        assert_eq!(location(keys(&["package", "foo", "output"])), (2, 10));
+        assert!(spans.find(&keys(&["target"])).is_none());
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("mode", "mode"), 0);
+        assert_eq!(edit_distance("mdoe", "mode"), 1);
+        assert_eq!(edit_distance("instal_as", "install_as"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_hints() {
+        assert_eq!(
+            hint("missing field `service_name`", Some("package.foo")).as_deref(),
+            Some("add 'service_name' to 'package.foo', or to the template it uses")
+        );
+        assert_eq!(
+            hint(
+                "unknown variant `tarbal`, expected `zone` or `tarball`",
+                Some("package.foo.output.type")
+            )
+            .as_deref(),
+            Some("did you mean 'tarball'?")
+        );
+        assert_eq!(
+            hint("unknown field `xyz`, expected `package` or `paths`", None),
+            None
+        );
+        assert_eq!(hint("invalid type: integer `1`", None), None);
+    }
+}
@@ -4,15 +4,22 @@
 
 //! Configuration for a package.
 
-use crate::package::{Package, PackageOutput, PackageSource};
-use crate::target::TargetMap;
+use crate::input::BuildInputs;
+use crate::package::{BuildConfig, Package, PackageOutput, PackageSource};
+use crate::progress::ProgressEvent;
+use crate::report::BuildReport;
+use crate::target::{TargetMap, TargetParseError, TargetSchema};
+use anyhow::Context;
+use camino::{Utf8Path, Utf8PathBuf};
+use futures::StreamExt;
 use serde_derive::Deserialize;
-use std::collections::BTreeMap;
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 use topological_sort::TopologicalSort;
 
-use super::{PackageName, PresetName};
+use super::diagnostic::{error_keys, unknown_field};
+use super::{Diagnostic, PackageName, PresetName};
 
 /// Describes a set of packages to act upon.
 ///
@@ -25,37 +32,28 @@ struct OutputFile(String);
 
 impl<'a> PackageMap<'a> {
     pub fn build_order(&self) -> PackageDependencyIter<'a> {
-        let lookup_by_output = self
-            .0
-            .iter()
-            .map(|(name, package)| (OutputFile(package.get_output_file(name)), (*name, *package)))
-            .collect::<BTreeMap<_, _>>();
+        let lookup_by_output = self.lookup_by_output();
 
         // Collect all packages, and sort them in dependency order,
         // so we know which ones to build first.
         let mut outputs = TopologicalSort::<OutputFile>::new();
         for (package_output, (_, package)) in &lookup_by_output {
-            match &package.source {
-                PackageSource::Local { .. }
-                | PackageSource::Prebuilt { .. }
-                | PackageSource::Manual => {
-                    // Skip intermediate leaf packages; if necessary they'll be
-                    // added to the dependency graph by whatever composite package
-                    // actually depends on them.
-                    if !matches!(
-                        package.output,
-                        PackageOutput::Zone {
-                            intermediate_only: true
-                        }
-                    ) {
-                        outputs.insert(package_output.clone());
-                    }
-                }
-                PackageSource::Composite { packages: deps } => {
-                    for dep in deps {
-                        outputs.add_dependency(OutputFile(dep.clone()), package_output.clone());
+            for dep in dependencies(package) {
+                outputs.add_dependency(OutputFile(dep.to_string()), package_output.clone());
+            }
+            // Skip intermediate leaf packages; if necessary they'll be added
+            // to the dependency graph by whatever composite package actually
+            // depends on them.
+            if !matches!(package.source, PackageSource::Composite { .. })
+                && !matches!(
+                    package.output,
+                    PackageOutput::Zone {
+                        intermediate_only: true,
+                        ..
                     }
-                }
+                )
+            {
+                outputs.insert(package_output.clone());
             }
         }
 
@@ -64,6 +62,32 @@ impl<'a> PackageMap<'a> {
             outputs,
         }
     }
+
+    /// Returns the packages within this map which each package directly
+    /// depends upon, as ordered by [Self::build_order].
+    pub fn dependencies(&self) -> BTreeMap<&'a PackageName, Vec<&'a PackageName>> {
+        let lookup_by_output = self.lookup_by_output();
+        self.0
+            .iter()
+            .map(|(name, package)| {
+                let deps = dependencies(package)
+                    .into_iter()
+                    .filter_map(|output| lookup_by_output.get(&OutputFile(output.to_string())))
+                    .map(|(dep, _)| *dep)
+                    .collect();
+                (*name, deps)
+            })
+            .collect()
+    }
+
+    // Returns each package, by the name of the file which building it
+    // creates.
+    fn lookup_by_output(&self) -> BTreeMap<OutputFile, (&'a PackageName, &'a Package)> {
+        self.0
+            .iter()
+            .map(|(name, package)| (OutputFile(package.get_output_file(name)), (*name, *package)))
+            .collect()
+    }
 }
 
 /// Returns all packages in the order in which they should be built.
@@ -100,8 +124,30 @@ impl<'a> Iterator for PackageDependencyIter<'a> {
     }
 }
 
+// Returns the output files of the other packages from which the package is
+// built.
+pub(super) fn dependencies(package: &Package) -> Vec<&str> {
+    match &package.source {
+        PackageSource::Composite { packages } => {
+            packages.iter().map(|dep| dep.package.as_str()).collect()
+        }
+        PackageSource::Local { package_files, .. } => package_files
+            .iter()
+            .map(|files| files.package.as_str())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// Returns true if the package is produced by [Package::create], rather than
+// being supplied by the user.
+fn is_assembled(package: &Package) -> bool {
+    !matches!(package.source, PackageSource::Manual)
+}
+
 /// Describes the configuration for a set of packages.
 #[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     /// Packages to be built and installed.
     #[serde(default, rename = "package")]
@@ -110,9 +156,198 @@ pub struct Config {
     /// Target configuration.
     #[serde(default)]
     pub target: TargetConfig,
+
+    /// Glob patterns matching other manifests whose packages and presets
+    /// are merged into this one, such as "services/*.toml".
+    ///
+    /// Patterns are relative to the directory containing the manifest. A
+    /// manifest included more than once, such as by two manifests which
+    /// are themselves included, is only merged once.
+    #[serde(default)]
+    pub include: Vec<String>,
 }
 
 impl Config {
+    /// Merges `overlay` into this configuration, such that
+    /// environment-specific manifests may add packages and presets to a
+    /// base manifest, or override parts of them (for example, to build a
+    /// package locally which is usually prebuilt).
+    ///
+    /// Packages defined by both are merged field by field: the overlay's
+    /// [Package::source] and [Package::output] replace the base's, and
+    /// everything else, such as the service name and targets, is inherited
+    /// from the base. Presets defined by both are replaced as a whole.
+    /// Whether the overlay's definitions are used is determined by
+    /// `policy`, and each is returned as a conflict. If `policy` is
+    /// [MergePolicy::Reject] and any conflicts exist, nothing is merged.
+    ///
+    /// Fields which a merged package's overlay sets, other than its source
+    /// and output, are dropped. Under [MergePolicy::PreferOverlay], those
+    /// which differ from the base's are returned as
+    /// [MergeConflict::DroppedField].
+    ///
+    /// The result is validated as by [Self::validate_targets], since the
+    /// overlay may declare target keys, or use keys, which the other does
+    /// not. If it is invalid, nothing is replaced.
+    pub fn merge(
+        &mut self,
+        overlay: Config,
+        policy: MergePolicy,
+    ) -> Result<Vec<MergeConflict>, MergeError> {
+        let mut conflicts = vec![];
+        for (name, package) in &overlay.packages {
+            let Some(base) = self.packages.get(name) else {
+                continue;
+            };
+            conflicts.push(MergeConflict::Package(name.clone()));
+            if policy == MergePolicy::PreferOverlay {
+                conflicts.extend(dropped_fields(base, package).into_iter().map(|field| {
+                    MergeConflict::DroppedField {
+                        package: name.clone(),
+                        field,
+                    }
+                }));
+            }
+        }
+        conflicts.extend(
+            overlay
+                .target
+                .presets
+                .keys()
+                .filter(|name| self.target.presets.contains_key(*name))
+                .cloned()
+                .map(MergeConflict::Preset),
+        );
+
+        let mut merged = self.clone();
+        match policy {
+            MergePolicy::Reject if !conflicts.is_empty() => {
+                return Err(MergeError::Conflicts { conflicts });
+            }
+            MergePolicy::Reject | MergePolicy::PreferOverlay => {
+                for (name, package) in overlay.packages {
+                    match merged.packages.get_mut(&name) {
+                        Some(base) => {
+                            base.source = package.source;
+                            base.output = package.output;
+                        }
+                        None => {
+                            merged.packages.insert(name, package);
+                        }
+                    }
+                }
+                merged.target.presets.extend(overlay.target.presets);
+            }
+            MergePolicy::PreferBase => {
+                for (name, package) in overlay.packages {
+                    merged.packages.entry(name).or_insert(package);
+                }
+                for (name, preset) in overlay.target.presets {
+                    merged.target.presets.entry(name).or_insert(preset);
+                }
+            }
+        }
+        merged.target.extend_keys(overlay.target.keys);
+        merged.validate_targets()?;
+        *self = merged;
+        Ok(conflicts)
+    }
+
+    /// Ensures that presets, and the targets named by packages, only use
+    /// the keys and values declared by [TargetConfig::keys].
+    ///
+    /// This is checked when manifests are parsed. If no keys are declared,
+    /// any are permitted.
+    pub fn validate_targets(&self) -> Result<(), TargetSchemaError> {
+        let Some(schema) = &self.target.keys else {
+            return Ok(());
+        };
+        let context = |context: String| move |err| TargetSchemaError { context, err };
+        for (name, preset) in &self.target.presets {
+            schema
+                .validate(preset)
+                .map_err(context(format!("Preset '{name}'")))?;
+        }
+        for (name, package) in &self.packages {
+            if let Some(filter) = &package.only_for_targets {
+                schema
+                    .validate_filter(filter)
+                    .map_err(context(format!("Package '{name}' (only_for_targets)")))?;
+            }
+            if let Some(filter) = &package.not_for_targets {
+                schema
+                    .validate_filter(filter)
+                    .map_err(context(format!("Package '{name}' (not_for_targets)")))?;
+            }
+            if let Some(expr) = &package.only_if {
+                schema
+                    .validate_expr(expr)
+                    .map_err(context(format!("Package '{name}' (only_if)")))?;
+            }
+            if let PackageSource::Local { paths, .. } = &package.source {
+                for (i, path) in paths.iter().enumerate() {
+                    if let Some(expr) = &path.only_if {
+                        schema
+                            .validate_expr(expr)
+                            .map_err(context(format!("Package '{name}' (paths[{i}].only_if)")))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces the name of each package from which files are copied, as
+    // written in manifests, with the file it creates.
+    fn resolve_package_files(&mut self) -> Result<(), ParseError> {
+        let outputs = self
+            .packages
+            .iter()
+            .map(|(name, package)| (name.clone(), package.get_output_file(name)))
+            .collect::<BTreeMap<_, _>>();
+        for (name, package) in &mut self.packages {
+            let PackageSource::Local { package_files, .. } = &mut package.source else {
+                continue;
+            };
+            for files in package_files {
+                let Some(output) = PackageName::new(files.package.as_str())
+                    .ok()
+                    .and_then(|dependency| outputs.get(&dependency))
+                else {
+                    return Err(ParseError::UnknownDependency {
+                        package: name.clone(),
+                        dependency: files.package.clone(),
+                    });
+                };
+                files.package = output.clone();
+            }
+        }
+        Ok(())
+    }
+
+    // Ensures that no package is built from another whose output is named
+    // by [Package::output_template], since packages refer to one another by
+    // [Package::get_output_file].
+    fn validate_output_templates(&self) -> Result<(), ParseError> {
+        let templated = self
+            .packages
+            .iter()
+            .filter(|(_, package)| package.output_template.is_some() && is_assembled(package))
+            .map(|(name, package)| (package.get_output_file(name), name))
+            .collect::<BTreeMap<_, _>>();
+        for (name, package) in &self.packages {
+            for output in dependencies(package) {
+                if let Some(dependency) = templated.get(output) {
+                    return Err(ParseError::TemplatedDependency {
+                        package: name.clone(),
+                        dependency: (*dependency).clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Returns target packages to be assembled on the builder machine.
     pub fn packages_to_build(&self, target: &TargetMap) -> PackageMap<'_> {
         PackageMap(
@@ -123,6 +358,77 @@ impl Config {
         )
     }
 
+    /// Returns the packages named by `names`, along with every package they
+    /// transitively depend upon, to be assembled for `target`.
+    ///
+    /// Use [PackageMap::build_order] to build them in dependency order.
+    pub fn packages_to_build_subset(
+        &self,
+        target: &TargetMap,
+        names: &[PackageName],
+    ) -> anyhow::Result<PackageMap<'_>> {
+        let all_packages = self.packages_to_build(target).0;
+        let lookup_by_output = all_packages
+            .iter()
+            .map(|(name, package)| (package.get_output_file(name), *name))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut subset = BTreeMap::new();
+        let mut pending = names.iter().collect::<Vec<_>>();
+        while let Some(name) = pending.pop() {
+            if subset.contains_key(name) {
+                continue;
+            }
+            let (name, package) = all_packages.get_key_value(name).ok_or_else(|| {
+                if self.packages.contains_key(name) {
+                    anyhow::anyhow!("Package '{name}' is not built for this target")
+                } else {
+                    anyhow::anyhow!("Package '{name}' is not defined")
+                }
+            })?;
+            for dependency in dependencies(package) {
+                let dependency = lookup_by_output.get(dependency).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Package '{name}' depends on '{dependency}', \
+                         which no package creates"
+                    )
+                })?;
+                pending.push(dependency);
+            }
+            subset.insert(*name, *package);
+        }
+        Ok(PackageMap(subset))
+    }
+
+    /// Returns all packages labelled with `tag`, regardless of target.
+    pub fn packages_with_tag(&self, tag: &str) -> PackageMap<'_> {
+        PackageMap(
+            self.packages
+                .iter()
+                .filter(|(_, pkg)| pkg.tags.iter().any(|t| t == tag))
+                .collect(),
+        )
+    }
+
+    /// Returns target packages labelled with any of `tags`, along with every
+    /// package they transitively depend upon.
+    ///
+    /// See [Self::packages_to_build_subset].
+    pub fn packages_to_build_with_tags(
+        &self,
+        target: &TargetMap,
+        tags: &[&str],
+    ) -> anyhow::Result<PackageMap<'_>> {
+        let names = self
+            .packages_to_build(target)
+            .0
+            .into_iter()
+            .filter(|(_, pkg)| pkg.tags.iter().any(|t| tags.contains(&t.as_str())))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        self.packages_to_build_subset(target, &names)
+    }
+
     /// Returns target packages which should execute on the deployment machine.
     pub fn packages_to_deploy(&self, target: &TargetMap) -> PackageMap<'_> {
         let all_packages = self.packages_to_build(target).0;
@@ -130,20 +436,354 @@ impl Config {
             all_packages
                 .into_iter()
                 .filter(|(_, pkg)| match pkg.output {
-                    PackageOutput::Zone { intermediate_only } => !intermediate_only,
-                    PackageOutput::Tarball => true,
+                    PackageOutput::Zone {
+                        intermediate_only, ..
+                    } => !intermediate_only,
+                    PackageOutput::Tarball | PackageOutput::Ips { .. } => true,
                 })
                 .collect(),
         )
     }
+
+    /// Ensures that no two packages deployed to `target` would be installed
+    /// at the same path; see [Package::get_output_path_for_service].
+    pub fn validate_deployment(&self, target: &TargetMap) -> Result<(), DeploymentError> {
+        let mut installed = BTreeMap::<_, Vec<_>>::new();
+        for (name, package) in self.packages_to_deploy(target).0 {
+            installed
+                .entry(package.get_output_file_for_service())
+                .or_default()
+                .push(name.clone());
+        }
+        let collisions = installed
+            .into_iter()
+            .filter(|(_, packages)| packages.len() > 1)
+            .collect::<Vec<_>>();
+        if collisions.is_empty() {
+            Ok(())
+        } else {
+            Err(DeploymentError { collisions })
+        }
+    }
+
+    /// Identifies the inputs of every package built for the target of
+    /// `build_config`, without building anything.
+    ///
+    /// Manual packages, which are supplied by the user, are omitted. See
+    /// [Package::plan].
+    pub fn plan_all(
+        &self,
+        output_directory: &Utf8Path,
+        build_config: &BuildConfig<'_>,
+    ) -> anyhow::Result<BTreeMap<PackageName, BuildInputs>> {
+        self.packages_to_build(build_config.target)
+            .0
+            .into_iter()
+            .filter(|(_, package)| is_assembled(package))
+            .map(|(name, package)| {
+                let inputs = package
+                    .plan(name, output_directory, build_config)
+                    .with_context(|| format!("Failed to plan {name}"))?;
+                Ok((name.clone(), inputs))
+            })
+            .collect()
+    }
+
+    /// Builds every package for the target of `build_config`, in dependency
+    /// order, returning the outcome of each build.
+    ///
+    /// Packages within a batch of [PackageMap::build_order] are built
+    /// concurrently, with at most `parallelism` packages being built at
+    /// once, and at most one package marked
+    /// [crate::package::ResourceHints::heavy]. Each
+    /// package reports progress through a
+    /// [crate::progress::Progress::named_sub_progress] of
+    /// `build_config.progress`, named after the package.
+    ///
+    /// Manual packages, which are supplied by the user, are omitted. If any
+    /// package within a batch fails, later batches (which may depend upon
+    /// it) are not built.
+    pub async fn build_all(
+        &self,
+        build_config: &BuildConfig<'_>,
+        output_directory: &Utf8Path,
+        parallelism: usize,
+    ) -> BTreeMap<PackageName, anyhow::Result<BuildReport>> {
+        let progress = build_config.progress;
+        let packages = self.packages_to_build(build_config.target);
+        progress.increment_total(
+            packages
+                .0
+                .values()
+                .filter(|package| is_assembled(package))
+                .count() as u64,
+        );
+
+        for (name, package) in &packages.0 {
+            if is_assembled(package) {
+                progress.report(ProgressEvent::PackageQueued {
+                    package: (*name).clone(),
+                });
+            }
+        }
+
+        let heavy = tokio::sync::Semaphore::new(1);
+        let mut results = BTreeMap::new();
+        for batch in packages.build_order() {
+            // Start heavy packages first, so that they don't wait for one
+            // another after all lighter packages have finished.
+            let mut batch = batch
+                .into_iter()
+                .filter(|(_, package)| is_assembled(package))
+                .collect::<Vec<_>>();
+            batch.sort_by_key(|(_, package)| !package.resources.heavy);
+            let heavy = &heavy;
+            let builds = batch.into_iter().map(|(name, package)| async move {
+                let _permit = if package.resources.heavy {
+                    Some(heavy.acquire().await.expect("semaphore is never closed"))
+                } else {
+                    None
+                };
+                let sub_progress = progress.named_sub_progress(name.to_string().into(), 0);
+                let config = BuildConfig {
+                    progress: &*sub_progress,
+                    ..*build_config
+                };
+                let result = package
+                    .create_with_report(name, output_directory, &config)
+                    .await
+                    .with_context(|| format!("Failed to build {name}"));
+                if let Err(err) = &result {
+                    sub_progress.error(format!("{err:#}").into());
+                }
+                progress.increment_completed(1);
+                (name.clone(), result)
+            });
+            let batch_results: Vec<_> = futures::stream::iter(builds)
+                .buffer_unordered(parallelism.max(1))
+                .collect()
+                .await;
+            let failed = batch_results.iter().any(|(_, result)| result.is_err());
+            results.extend(batch_results);
+            if failed {
+                break;
+            }
+        }
+        results
+    }
+
+    /// Stamps all packages which should be deployed for `target` with
+    /// `version`, returning the paths of the stamped packages.
+    ///
+    /// Stamped packages are named for `target`; see
+    /// [Package::get_target_versioned_output_path].
+    ///
+    /// All packages must have already been built within `output_directory`.
+    /// Packages are stamped in the same dependency order in which they are
+    /// built.
+    pub async fn stamp_all(
+        &self,
+        target: &TargetMap,
+        output_directory: &Utf8Path,
+        version: &semver::Version,
+    ) -> anyhow::Result<Vec<Utf8PathBuf>> {
+        let to_deploy = self.packages_to_deploy(target);
+        let mut stamped = vec![];
+        for batch in self.packages_to_build(target).build_order() {
+            let stamps = batch
+                .into_iter()
+                .filter(|(name, _)| to_deploy.0.contains_key(name))
+                .map(|(name, package)| async move {
+                    package
+                        .stamp_for_target(name, output_directory, target, version, &BTreeMap::new())
+                        .await
+                        .with_context(|| format!("Failed to stamp {name}"))
+                });
+            stamped.extend(futures::future::try_join_all(stamps).await?);
+        }
+        Ok(stamped)
+    }
+}
+
+// Returns the fields of `overlay` which differ from those of `base`, other
+// than its source and output, and which [Config::merge] therefore drops.
+//
+// Fields which the overlay leaves at their defaults are assumed to be unset.
+fn dropped_fields(base: &Package, overlay: &Package) -> Vec<&'static str> {
+    fn differs<T: Default + PartialEq>(overlay: &T, base: &T) -> bool {
+        *overlay != T::default() && overlay != base
+    }
+
+    // Destructure the overlay, so that new fields aren't forgotten here.
+    let Package {
+        service_name,
+        source: _,
+        output: _,
+        only_for_targets,
+        not_for_targets,
+        tags,
+        only_if,
+        setup_hint,
+        checksum_manifest,
+        preserve_symlinks,
+        version_in_filename,
+        output_template,
+        smf,
+        metadata,
+        pre_build,
+        post_build,
+        resources,
+    } = overlay;
+    [
+        ("service_name", *service_name != base.service_name),
+        (
+            "only_for_targets",
+            differs(only_for_targets, &base.only_for_targets),
+        ),
+        (
+            "not_for_targets",
+            differs(not_for_targets, &base.not_for_targets),
+        ),
+        ("tags", differs(tags, &base.tags)),
+        ("only_if", differs(only_if, &base.only_if)),
+        ("setup_hint", differs(setup_hint, &base.setup_hint)),
+        (
+            "checksum_manifest",
+            differs(checksum_manifest, &base.checksum_manifest),
+        ),
+        (
+            "preserve_symlinks",
+            differs(preserve_symlinks, &base.preserve_symlinks),
+        ),
+        (
+            "version_in_filename",
+            differs(version_in_filename, &base.version_in_filename),
+        ),
+        (
+            "output_template",
+            differs(output_template, &base.output_template),
+        ),
+        ("smf", differs(smf, &base.smf)),
+        ("metadata", differs(metadata, &base.metadata)),
+        ("pre_build", differs(pre_build, &base.pre_build)),
+        ("post_build", differs(post_build, &base.post_build)),
+        ("resources", differs(resources, &base.resources)),
+    ]
+    .into_iter()
+    .filter_map(|(field, dropped)| dropped.then_some(field))
+    .collect()
+}
+
+/// Determines how [Config::merge] resolves packages and presets which are
+/// defined by both configurations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// The overlay's definition is used: packages are merged, and presets
+    /// replaced.
+    PreferOverlay,
+    /// The base's definition is kept, and the overlay's ignored.
+    PreferBase,
+    /// Definitions may not conflict.
+    Reject,
+}
+
+/// A package or preset defined by both configurations passed to
+/// [Config::merge], or a field of such a package which is dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MergeConflict {
+    Package(PackageName),
+    Preset(PresetName),
+    /// A field of a package, set by the overlay, which is dropped in favor
+    /// of the base's.
+    DroppedField {
+        package: PackageName,
+        field: &'static str,
+    },
+}
+
+impl std::fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MergeConflict::Package(name) => write!(f, "package '{name}'"),
+            MergeConflict::Preset(name) => write!(f, "preset '{name}'"),
+            MergeConflict::DroppedField { package, field } => {
+                write!(f, "field '{field}' of package '{package}'")
+            }
+        }
+    }
+}
+
+/// Errors which may be returned by [Config::merge].
+#[derive(Error, Debug)]
+pub enum MergeError {
+    /// Definitions conflict under [MergePolicy::Reject].
+    #[error(
+        "Overlay redefines {}",
+        .conflicts.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    Conflicts { conflicts: Vec<MergeConflict> },
+    /// The combined configuration names target keys or values which it
+    /// does not declare.
+    #[error(transparent)]
+    InvalidTarget(#[from] TargetSchemaError),
+}
+
+/// Returned by [Config::validate_deployment] when multiple packages would be
+/// installed at the same path.
+#[derive(Error, Debug)]
+#[error(
+    "Packages share an installed file name: {}",
+    .collisions
+        .iter()
+        .map(|(file, packages)| format!(
+            "{} ({file})",
+            packages.iter().map(|p| format!("'{p}'")).collect::<Vec<_>>().join(", ")
+        ))
+        .collect::<Vec<_>>()
+        .join("; ")
+)]
+pub struct DeploymentError {
+    /// Installed file names, and the packages which would share them.
+    pub collisions: Vec<(String, Vec<PackageName>)>,
 }
 
 /// Configuration for targets, including preset configuration.
 #[derive(Clone, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
 pub struct TargetConfig {
     /// Preset configuration for targets.
     #[serde(default, rename = "preset")]
     pub presets: BTreeMap<PresetName, TargetMap>,
+
+    /// The keys which targets may define, and their permitted values.
+    ///
+    /// If provided, presets and the targets named by packages are checked
+    /// against these when the manifest is parsed; see
+    /// [Config::validate_targets].
+    #[serde(default)]
+    pub keys: Option<TargetSchema>,
+}
+
+impl TargetConfig {
+    // Adds the keys declared by `other` to those of this configuration.
+    fn extend_keys(&mut self, other: Option<TargetSchema>) {
+        match (&mut self.keys, other) {
+            (Some(keys), Some(other)) => keys.extend(other),
+            (keys @ None, other) => *keys = other,
+            (Some(_), None) => (),
+        }
+    }
+}
+
+/// A package or preset which names a target key or value not declared by
+/// [TargetConfig::keys].
+#[derive(Error, Debug)]
+#[error("{context}: {err}")]
+pub struct TargetSchemaError {
+    /// Describes where the key or value was named, such as
+    /// "Package 'foo' (only_for_targets)".
+    pub context: String,
+    pub err: TargetParseError,
 }
 
 /// Errors which may be returned when parsing the server configuration.
@@ -151,24 +791,906 @@ pub struct TargetConfig {
 pub enum ParseError {
     #[error("Cannot parse toml: {0}")]
     Toml(#[from] toml::de::Error),
+    #[error("{0}")]
+    Invalid(Box<Diagnostic>),
+    #[cfg(feature = "json")]
+    #[error("Cannot parse json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "yaml")]
+    #[error("Cannot parse yaml: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("Parsing {} requires the '{feature}' feature", .path.display())]
+    UnsupportedFormat {
+        path: PathBuf,
+        feature: &'static str,
+    },
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Invalid include pattern '{pattern}': {err}")]
+    IncludePattern {
+        pattern: String,
+        err: glob::PatternError,
+    },
+    #[error("Include pattern '{pattern}' in {} matched no files", .path.display())]
+    IncludeMissing { pattern: String, path: PathBuf },
+    #[error("{} includes itself", .path.display())]
+    IncludeCycle { path: PathBuf },
+    #[error("Package '{name}' in {} was already defined in {}", .path.display(), .previous.display())]
+    DuplicatePackage {
+        name: PackageName,
+        path: PathBuf,
+        previous: PathBuf,
+    },
+    #[error("Preset '{name}' in {} was already defined in {}", .path.display(), .previous.display())]
+    DuplicatePreset {
+        name: PresetName,
+        path: PathBuf,
+        previous: PathBuf,
+    },
+    #[error("In {}: {err}", .path.display())]
+    Included { path: PathBuf, err: Box<ParseError> },
+    #[error("Package '{package}' uses unknown template '{template}'")]
+    UnknownTemplate { package: String, template: String },
+    #[error("Package '{package}' must name its template with a string")]
+    InvalidTemplate { package: String },
+    #[error("'schema' must be a positive integer")]
+    InvalidSchema,
+    #[error(
+        "Manifest requires schema version {version}, but this version of \
+         omicron-zone-package supports at most {SCHEMA_VERSION}; try upgrading it"
+    )]
+    UnsupportedSchema { version: i64 },
+    #[error("No package in {} is named or creates '{package}'", .path.display())]
+    ExternalPackageMissing { package: String, path: PathBuf },
+    #[error("Variable '{name}' is not defined")]
+    UnknownVariable { name: String },
+    #[error(
+        "Package '{package}' cannot be built from '{dependency}', whose output \
+         is named by a template"
+    )]
+    TemplatedDependency {
+        package: PackageName,
+        dependency: PackageName,
+    },
+    #[error("Package '{package}' copies files from '{dependency}', which is not defined")]
+    UnknownDependency {
+        package: PackageName,
+        dependency: String,
+    },
+    #[error(transparent)]
+    InvalidTarget(#[from] TargetSchemaError),
+}
+
+/// Controls how manifests treat fields which they do not recognize.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Unknown fields are ignored, and reported as warnings by
+    /// [parse_with_warnings] and [parse_manifest_with_warnings].
+    ///
+    /// The "smf", "pre_build", "post_build", and "package_files" tables
+    /// reject unknown fields regardless, as do JSON and YAML manifests.
+    #[default]
+    Lenient,
+    /// Unknown fields are rejected, with a suggestion if they resemble a
+    /// known field.
+    Strict,
+}
+
+// Tables which rejected unknown fields before [ParseMode] existed, and
+// continue to do so in lenient mode.
+const ALWAYS_STRICT: &[&str] = &["smf", "pre_build", "post_build", "package_files"];
+
+// State shared by all of the manifests parsed together.
+struct Session {
+    mode: ParseMode,
+    // Problems which did not prevent parsing.
+    warnings: Vec<Diagnostic>,
+}
+
+impl Session {
+    fn new(mode: ParseMode) -> Self {
+        Self {
+            mode,
+            warnings: vec![],
+        }
+    }
+}
+
+// Definitions which a manifest shares with the manifests it includes.
+#[derive(Clone, Default)]
+struct Scope {
+    // Package templates, keyed by name.
+    templates: BTreeMap<String, toml::Table>,
+    // Variables, keyed by name.
+    vars: BTreeMap<String, String>,
+}
+
+// The formats in which manifests may be written.
+#[derive(Clone, Copy, Debug)]
+enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    // Identifies the format of a manifest from its extension, defaulting to
+    // TOML.
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Format::Json,
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Toml,
+        }
+    }
+
+    // Parses a manifest.
+    //
+    // JSON and YAML manifests are deserialized as written, since they're
+    // typically generated by other tools; only TOML manifests may use
+    // templates, variables, and schema versions, or contain fields ignored
+    // in [ParseMode::Lenient].
+    #[cfg_attr(all(feature = "json", feature = "yaml"), allow(unused_variables))]
+    fn parse(
+        self,
+        contents: &str,
+        path: &Path,
+        scope: &mut Scope,
+        session: &mut Session,
+    ) -> Result<Config, ParseError> {
+        let cfg = match self {
+            Format::Toml => parse_toml(contents, path, scope, session),
+            #[cfg(feature = "json")]
+            Format::Json => Ok(serde_json::from_str(contents)?),
+            #[cfg(not(feature = "json"))]
+            Format::Json => Err(ParseError::UnsupportedFormat {
+                path: path.to_path_buf(),
+                feature: "json",
+            }),
+            #[cfg(feature = "yaml")]
+            Format::Yaml => Ok(serde_yaml::from_str(contents)?),
+            #[cfg(not(feature = "yaml"))]
+            Format::Yaml => Err(ParseError::UnsupportedFormat {
+                path: path.to_path_buf(),
+                feature: "yaml",
+            }),
+        }?;
+        let contents = matches!(self, Format::Toml).then_some(contents);
+        validate_tarball_destinations(&cfg, path, contents)?;
+        Ok(cfg)
+    }
+}
+
+// Rejects tarball packages which place entries at absolute paths, which tar
+// cannot represent.
+//
+// Paths are checked as written: those which only become absolute once
+// interpolated are rejected when the package is built.
+fn validate_tarball_destinations(
+    cfg: &Config,
+    path: &Path,
+    contents: Option<&str>,
+) -> Result<(), ParseError> {
+    for (name, package) in &cfg.packages {
+        let (
+            PackageOutput::Tarball,
+            PackageSource::Local {
+                blobs,
+                buildomat_blobs,
+                paths,
+                dirs,
+                placeholders,
+                ..
+            },
+        ) = (&package.output, &package.source)
+        else {
+            continue;
+        };
+        let destinations =
+            paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| (["paths", "to"], i, Some(&path.to)))
+                .chain(
+                    blobs
+                        .iter()
+                        .flatten()
+                        .enumerate()
+                        .map(|(i, blob)| (["blobs", "to"], i, blob.to.as_ref())),
+                )
+                .chain(
+                    buildomat_blobs
+                        .iter()
+                        .flatten()
+                        .enumerate()
+                        .map(|(i, blob)| (["buildomat_blobs", "to"], i, blob.to.as_ref())),
+                )
+                .chain(
+                    dirs.iter()
+                        .enumerate()
+                        .map(|(i, dir)| (["dirs", "path"], i, Some(&dir.path))),
+                )
+                .chain(placeholders.iter().enumerate().map(|(i, placeholder)| {
+                    (["placeholders", "path"], i, Some(&placeholder.path))
+                }));
+        for ([field, key], index, destination) in destinations {
+            let Some(destination) = destination.map(|d| d.as_str()) else {
+                continue;
+            };
+            if !destination.starts_with('/') {
+                continue;
+            }
+            let keys = [
+                "package",
+                name.as_str(),
+                "source",
+                field,
+                &index.to_string(),
+                key,
+            ]
+            .map(str::to_string)
+            .to_vec();
+            return Err(ParseError::Invalid(Box::new(Diagnostic::at(
+                path,
+                contents,
+                keys,
+                format!("'{destination}' must be relative, since package '{name}' is a tarball"),
+                Some("remove the leading '/'".to_string()),
+            ))));
+        }
+    }
+    Ok(())
+}
+
+// Parses the contents of a TOML manifest, expanding package templates and
+// variables.
+//
+// `scope` holds the templates and variables available to the manifest, and
+// is extended with those it defines.
+fn parse_toml(
+    contents: &str,
+    path: &Path,
+    scope: &mut Scope,
+    session: &mut Session,
+) -> Result<Config, ParseError> {
+    let mut manifest: toml::Table = toml::from_str(contents)
+        .map_err(|err| ParseError::Invalid(Box::new(Diagnostic::syntax(path, contents, &err))))?;
+    upgrade_schema(&mut manifest)?;
+    if let Some(defined) = manifest.remove("template") {
+        scope
+            .templates
+            .extend(defined.try_into::<BTreeMap<_, _>>()?);
+    }
+    if let Some(defined) = manifest.remove("vars") {
+        scope.vars.extend(defined.try_into::<BTreeMap<_, _>>()?);
+    }
+    if let Some(toml::Value::Table(packages)) = manifest.get_mut("package") {
+        for (name, package) in packages.iter_mut() {
+            // Let deserialization report malformed packages.
+            let toml::Value::Table(package) = package else {
+                continue;
+            };
+            let Some(template) = package.remove("template") else {
+                continue;
+            };
+            let toml::Value::String(template) = template else {
+                return Err(ParseError::InvalidTemplate {
+                    package: name.clone(),
+                });
+            };
+            let Some(defaults) = scope.templates.get(&template) else {
+                return Err(ParseError::UnknownTemplate {
+                    package: name.clone(),
+                    template,
+                });
+            };
+            let mut merged = defaults.clone();
+            merge_tables(&mut merged, std::mem::take(package));
+            *package = merged;
+        }
+    }
+    let mut manifest = toml::Value::Table(manifest);
+    expand_vars(&mut manifest, &scope.vars)?;
+    loop {
+        let err = match serde_path_to_error::deserialize(manifest.clone()) {
+            Ok(cfg) => return Ok(cfg),
+            Err(err) => err,
+        };
+        let contents = Some(contents);
+        if session.mode == ParseMode::Lenient {
+            if let Some(field) = remove_unknown_field(&mut manifest, &err) {
+                session
+                    .warnings
+                    .push(Diagnostic::invalid(path, contents, err, Some(field)));
+                continue;
+            }
+        }
+        // Locate unknown fields as precisely as lenient mode does, rather
+        // than at the enclosing table of an internally tagged enum.
+        let keys = remove_unknown_field(&mut manifest.clone(), &err);
+        return Err(ParseError::Invalid(Box::new(Diagnostic::invalid(
+            path, contents, err, keys,
+        ))));
+    }
+}
+
+// Removes the field which `err` reports as unknown from `manifest`, and
+// returns the path to the field which was removed.
+//
+// Returns None if `err` reports some other problem, or the field lies
+// within a table listed in [ALWAYS_STRICT].
+fn remove_unknown_field(
+    manifest: &mut toml::Value,
+    err: &serde_path_to_error::Error<toml::de::Error>,
+) -> Option<Vec<String>> {
+    let field = unknown_field(err.inner().message())?;
+    let keys = error_keys(err);
+    // The path may include the unknown field itself, or stop short of its
+    // table when deserializing an internally tagged enum. In the latter case
+    // the field may occur more than once beneath the path, in tables where
+    // it is valid: only remove the occurrence whose removal resolves `err`.
+    for len in (0..=keys.len()).rev() {
+        let Some(value) = lookup(manifest, &keys[..len]) else {
+            continue;
+        };
+        let mut occurrences = vec![];
+        find_nested(value, field, &mut keys[..len].to_vec(), &mut occurrences);
+        if occurrences.is_empty() {
+            continue;
+        }
+        for occurrence in occurrences {
+            // Skip the package name, which may coincide with one of these
+            // tables.
+            if occurrence
+                .iter()
+                .skip(2)
+                .any(|key| ALWAYS_STRICT.contains(&key.as_str()))
+            {
+                continue;
+            }
+            let mut candidate = manifest.clone();
+            let (field, parent) = occurrence.split_last().expect("occurrences are non-empty");
+            if let Some(toml::Value::Table(table)) = lookup(&mut candidate, parent) {
+                table.remove(field);
+            }
+            let resolved = match serde_path_to_error::deserialize::<_, Config>(candidate.clone()) {
+                Ok(_) => true,
+                Err(other) => {
+                    other.path().to_string() != err.path().to_string()
+                        || other.inner().message() != err.inner().message()
+                }
+            };
+            if resolved {
+                *manifest = candidate;
+                return Some(occurrence);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+// Appends the path to each occurrence of `field` within the tables in
+// `value` to `occurrences`, depth-first. `path` is the path to `value`.
+fn find_nested(
+    value: &toml::Value,
+    field: &str,
+    path: &mut Vec<String>,
+    occurrences: &mut Vec<Vec<String>>,
+) {
+    let children: Box<dyn Iterator<Item = (String, &toml::Value)>> = match value {
+        toml::Value::Table(table) => {
+            if table.contains_key(field) {
+                let mut occurrence = path.clone();
+                occurrence.push(field.to_string());
+                occurrences.push(occurrence);
+            }
+            Box::new(table.iter().map(|(key, value)| (key.clone(), value)))
+        }
+        toml::Value::Array(array) => Box::new(
+            array
+                .iter()
+                .enumerate()
+                .map(|(index, value)| (index.to_string(), value)),
+        ),
+        _ => return,
+    };
+    for (key, child) in children {
+        path.push(key);
+        find_nested(child, field, path, occurrences);
+        path.pop();
+    }
+}
+
+// Returns the value nested within `value` along `keys`, in which array
+// elements are keyed by their index.
+fn lookup<'a>(value: &'a mut toml::Value, keys: &[String]) -> Option<&'a mut toml::Value> {
+    match keys.split_first() {
+        None => Some(value),
+        Some((key, rest)) => {
+            let child = match value {
+                toml::Value::Array(array) => array.get_mut(key.parse::<usize>().ok()?)?,
+                value => value.get_mut(key.as_str())?,
+            };
+            lookup(child, rest)
+        }
+    }
+}
+
+/// The newest version of the manifest schema which this crate understands.
+///
+/// Manifests may declare the version they are written for with a top-level
+/// `schema = N`; those which do not are assumed to use version 1.
+pub const SCHEMA_VERSION: i64 = 1;
+
+// Rewrites manifests written for older schemas into the current one, indexed
+// by the version they upgrade from. The shim for version N is applied to
+// manifests declaring version N or earlier.
+const SCHEMA_SHIMS: &[fn(&mut toml::Table)] = &[];
+
+// Checks the schema declared by `manifest`, and applies any shims it needs.
+fn upgrade_schema(manifest: &mut toml::Table) -> Result<(), ParseError> {
+    let version = match manifest.remove("schema") {
+        None => 1,
+        Some(toml::Value::Integer(version)) if version >= 1 => version,
+        Some(_) => return Err(ParseError::InvalidSchema),
+    };
+    if version > SCHEMA_VERSION {
+        return Err(ParseError::UnsupportedSchema { version });
+    }
+    for shim in SCHEMA_SHIMS.iter().skip(version as usize - 1) {
+        shim(manifest);
+    }
+    Ok(())
+}
+
+// Replaces each "{{var.NAME}}" within the strings of `value` with the
+// variable NAME.
+fn expand_vars(value: &mut toml::Value, vars: &BTreeMap<String, String>) -> Result<(), ParseError> {
+    match value {
+        toml::Value::String(value) => {
+            let mut input = value.as_str();
+            let mut output = String::new();
+            while let Some((before, rest)) = input.split_once("{{var.") {
+                // Leave unterminated references for [InterpolatedString] to
+                // report.
+                let Some((name, after)) = rest.split_once("}}") else {
+                    break;
+                };
+                let var = vars.get(name).ok_or_else(|| ParseError::UnknownVariable {
+                    name: name.to_string(),
+                })?;
+                output.push_str(before);
+                output.push_str(var);
+                input = after;
+            }
+            output.push_str(input);
+            *value = output;
+        }
+        toml::Value::Array(values) => {
+            for value in values {
+                expand_vars(value, vars)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                expand_vars(value, vars)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+// Merges `overrides` into `base`, combining tables recursively.
+fn merge_tables(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => {
+                merge_tables(base, value);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
 }
 
 /// Parses a manifest into a package [`Config`].
+///
+/// Any manifests named by [Config::include] are resolved relative to the
+/// current directory.
+///
+/// Packages may inherit fields from a template by naming it, as in:
+///
+/// ```toml
+/// [template.rust-zone]
+/// output.type = "zone"
+/// source.type = "local"
+/// source.rust.release = true
+///
+/// [package.my-service]
+/// template = "rust-zone"
+/// service_name = "my-service"
+/// source.rust.binary_names = ["my-service"]
+/// ```
+///
+/// Fields set by the package take precedence, and tables are merged.
+///
+/// Strings may also refer to variables defined within the `vars` table,
+/// which are substituted while parsing:
+///
+/// ```toml
+/// [vars]
+/// firmware_version = "1.0.4"
+///
+/// [package.firmware]
+/// service_name = "firmware"
+/// source.type = "local"
+/// source.paths = [
+///   { from = "out/firmware-{{var.firmware_version}}.bin", to = "/opt/oxide/firmware.bin" },
+/// ]
+/// output.type = "tarball"
+/// ```
+///
+/// Other keys, such as `{{image}}`, are left to be interpolated for the
+/// target at build time.
+///
+/// Templates and variables are available within the manifest which defines
+/// them, and any manifests it includes.
 pub fn parse_manifest(manifest: &str) -> Result<Config, ParseError> {
-    let cfg = toml::from_str::<Config>(manifest)?;
+    parse_manifest_with_mode(manifest, ParseMode::default())
+}
+
+/// Identical to [parse_manifest], but treats unknown fields according to
+/// `mode`.
+pub fn parse_manifest_with_mode(manifest: &str, mode: ParseMode) -> Result<Config, ParseError> {
+    Ok(parse_manifest_with_warnings(manifest, mode)?.0)
+}
+
+/// Identical to [parse_manifest_with_mode], but also returns problems with
+/// the manifest which did not prevent it from being parsed, such as fields
+/// ignored in [ParseMode::Lenient].
+pub fn parse_manifest_with_warnings(
+    manifest: &str,
+    mode: ParseMode,
+) -> Result<(Config, Vec<Diagnostic>), ParseError> {
+    let mut session = Session::new(mode);
+    let cfg = parse_str(manifest, Format::Toml, &mut session)?;
+    Ok((cfg, session.warnings))
+}
+
+/// Identical to [parse_manifest], but for a manifest written in JSON.
+///
+/// The manifest is deserialized as written: it may not use templates,
+/// variables, or a schema version, and unknown fields are always rejected.
+#[cfg(feature = "json")]
+pub fn parse_json(manifest: &str) -> Result<Config, ParseError> {
+    parse_str(
+        manifest,
+        Format::Json,
+        &mut Session::new(ParseMode::default()),
+    )
+}
+
+/// Identical to [parse_manifest], but for a manifest written in YAML.
+///
+/// As with [parse_json], the manifest is deserialized as written.
+#[cfg(feature = "yaml")]
+pub fn parse_yaml(manifest: &str) -> Result<Config, ParseError> {
+    parse_str(
+        manifest,
+        Format::Yaml,
+        &mut Session::new(ParseMode::default()),
+    )
+}
+
+fn parse_str(manifest: &str, format: Format, session: &mut Session) -> Result<Config, ParseError> {
+    let path = Path::new("<manifest>");
+    let mut scope = Scope::default();
+    let cfg = format.parse(manifest, path, &mut scope, session)?;
+    let mut origins = Origins::default();
+    origins.record(&cfg, path)?;
+    let cfg = resolve_external(cfg, Path::new("."), &mut vec![], &mut origins, session)?;
+    let mut cfg = resolve_includes(
+        cfg,
+        Path::new("."),
+        path,
+        &mut vec![],
+        &mut origins,
+        &scope,
+        session,
+    )?;
+    cfg.resolve_package_files()?;
+    cfg.validate_targets()?;
+    cfg.validate_output_templates()?;
     Ok(cfg)
 }
+
 /// Parses a path in the filesystem into a package [`Config`].
+///
+/// Any manifests named by [Config::include] are parsed as well, and their
+/// packages and presets merged. Each package and preset may only be defined
+/// once across all files.
+///
+/// Manifests ending in ".json", ".yaml", or ".yml" are parsed as JSON or
+/// YAML respectively, if the corresponding feature is enabled (see
+/// [parse_json]); others are parsed as TOML.
+///
+/// See [parse_manifest] for a description of package templates and
+/// variables.
 pub fn parse<P: AsRef<Path>>(path: P) -> Result<Config, ParseError> {
-    let contents = std::fs::read_to_string(path.as_ref())?;
-    parse_manifest(&contents)
+    parse_with_mode(path, ParseMode::default())
+}
+
+/// Identical to [parse], but treats unknown fields according to `mode`.
+pub fn parse_with_mode<P: AsRef<Path>>(path: P, mode: ParseMode) -> Result<Config, ParseError> {
+    Ok(parse_with_warnings(path, mode)?.0)
+}
+
+/// Identical to [parse_with_mode], but also returns problems with the
+/// manifests which did not prevent them from being parsed, such as fields
+/// ignored in [ParseMode::Lenient].
+pub fn parse_with_warnings<P: AsRef<Path>>(
+    path: P,
+    mode: ParseMode,
+) -> Result<(Config, Vec<Diagnostic>), ParseError> {
+    let mut session = Session::new(mode);
+    let mut cfg = parse_file(
+        path.as_ref(),
+        &mut vec![],
+        &mut Origins::default(),
+        &Scope::default(),
+        &mut session,
+    )?;
+    cfg.resolve_package_files()?;
+    cfg.validate_targets()?;
+    cfg.validate_output_templates()?;
+    Ok((cfg, session.warnings))
+}
+
+// Records the file in which each package and preset was defined, to report
+// duplicates.
+#[derive(Default)]
+struct Origins {
+    packages: BTreeMap<PackageName, PathBuf>,
+    presets: BTreeMap<PresetName, PathBuf>,
+    // The canonical paths of the manifests already included.
+    included: BTreeSet<PathBuf>,
+}
+
+impl Origins {
+    fn record(&mut self, cfg: &Config, path: &Path) -> Result<(), ParseError> {
+        for name in cfg.packages.keys() {
+            if let Some(previous) = self.packages.insert(name.clone(), path.to_path_buf()) {
+                return Err(ParseError::DuplicatePackage {
+                    name: name.clone(),
+                    path: path.to_path_buf(),
+                    previous,
+                });
+            }
+        }
+        for name in cfg.target.presets.keys() {
+            if let Some(previous) = self.presets.insert(name.clone(), path.to_path_buf()) {
+                return Err(ParseError::DuplicatePreset {
+                    name: name.clone(),
+                    path: path.to_path_buf(),
+                    previous,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Parses a manifest, and those it includes.
+//
+// `stack` holds the manifests currently being parsed, to detect cycles.
+fn parse_file(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    origins: &mut Origins,
+    scope: &Scope,
+    session: &mut Session,
+) -> Result<Config, ParseError> {
+    let canonical = path.canonicalize()?;
+    if stack.contains(&canonical) {
+        return Err(ParseError::IncludeCycle {
+            path: path.to_path_buf(),
+        });
+    }
+    let contents = std::fs::read_to_string(path)?;
+    let mut scope = scope.clone();
+    let cfg = Format::from_path(path).parse(&contents, path, &mut scope, session)?;
+    origins.record(&cfg, path)?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    stack.push(canonical);
+    let cfg = resolve_external(cfg, dir, stack, origins, session)?;
+    let cfg = resolve_includes(cfg, dir, path, stack, origins, &scope, session)?;
+    stack.pop();
+    Ok(cfg)
+}
+
+// Adds the packages which composite packages within `cfg` reference from
+// other manifests, along with the packages they depend upon.
+fn resolve_external(
+    mut cfg: Config,
+    dir: &Path,
+    stack: &mut Vec<PathBuf>,
+    origins: &mut Origins,
+    session: &mut Session,
+) -> Result<Config, ParseError> {
+    // Components name the packages they import; replace each name with the
+    // file it creates, as used by other components.
+    let mut parsed = BTreeMap::new();
+    let mut pending = vec![];
+    for package in cfg.packages.values_mut() {
+        let PackageSource::Composite { packages } = &mut package.source else {
+            continue;
+        };
+        for component in packages {
+            let Some(manifest) = &component.manifest else {
+                continue;
+            };
+            let path = dir.join(manifest);
+            let external = parse_external(&mut parsed, &path, stack, session)?;
+            let Some((name, package)) = PackageName::new(component.package.as_str())
+                .ok()
+                .and_then(|name| external.packages.get_key_value(&name))
+            else {
+                return Err(ParseError::ExternalPackageMissing {
+                    package: component.package.clone(),
+                    path,
+                });
+            };
+            component.package = package.get_output_file(name);
+            pending.push((path, component.package.clone()));
+        }
+    }
+
+    while let Some((path, output)) = pending.pop() {
+        let external = parse_external(&mut parsed, &path, stack, session)?;
+        let Some((name, package)) = external
+            .packages
+            .iter()
+            .find(|(name, package)| package.get_output_file(name) == output)
+        else {
+            return Err(ParseError::ExternalPackageMissing {
+                package: output,
+                path,
+            });
+        };
+        match origins.packages.get(name) {
+            // Already imported, by another component.
+            Some(previous) if *previous == path => continue,
+            Some(previous) => {
+                return Err(ParseError::DuplicatePackage {
+                    name: name.clone(),
+                    path,
+                    previous: previous.clone(),
+                })
+            }
+            None => (),
+        }
+        origins.packages.insert(name.clone(), path.clone());
+        // Files are copied from packages named within the other manifest,
+        // whose names are only resolved once every package is imported.
+        let copied_from = match &package.source {
+            PackageSource::Local { package_files, .. } => package_files
+                .iter()
+                .filter_map(|files| PackageName::new(files.package.as_str()).ok())
+                .filter_map(|name| {
+                    let package = external.packages.get(&name)?;
+                    Some(package.get_output_file(&name))
+                })
+                .collect(),
+            _ => dependencies(package)
+                .into_iter()
+                .map(str::to_string)
+                .collect::<Vec<_>>(),
+        };
+        pending.extend(copied_from.into_iter().map(|output| (path.clone(), output)));
+        let mut package = package.clone();
+        package.rebase_paths(path.parent().unwrap_or(Path::new(".")));
+        cfg.packages.insert(name.clone(), package);
+    }
+    Ok(cfg)
+}
+
+// Parses the manifest at `path`, from which packages are imported, unless
+// it's within `parsed` already.
+fn parse_external<'a>(
+    parsed: &'a mut BTreeMap<PathBuf, Config>,
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    session: &mut Session,
+) -> Result<&'a Config, ParseError> {
+    match parsed.entry(path.to_path_buf()) {
+        std::collections::btree_map::Entry::Occupied(entry) => Ok(entry.into_mut()),
+        std::collections::btree_map::Entry::Vacant(entry) => {
+            let external = parse_file(
+                path,
+                stack,
+                &mut Origins::default(),
+                &Scope::default(),
+                session,
+            )
+            .map_err(|err| match err {
+                err @ (ParseError::Included { .. }
+                | ParseError::Invalid(_)
+                | ParseError::IncludeCycle { .. }) => err,
+                err => ParseError::Included {
+                    path: path.to_path_buf(),
+                    err: Box::new(err),
+                },
+            })?;
+            Ok(entry.insert(external))
+        }
+    }
+}
+
+// Merges the manifests included by `cfg`, which was read from `path`.
+fn resolve_includes(
+    mut cfg: Config,
+    dir: &Path,
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    origins: &mut Origins,
+    scope: &Scope,
+    session: &mut Session,
+) -> Result<Config, ParseError> {
+    for pattern in &cfg.include.clone() {
+        let full_pattern = dir.join(pattern);
+        let paths = glob::glob(&full_pattern.to_string_lossy()).map_err(|err| {
+            ParseError::IncludePattern {
+                pattern: pattern.clone(),
+                err,
+            }
+        })?;
+        let mut matched = false;
+        for included in paths {
+            let included = included.map_err(std::io::Error::from)?;
+            matched = true;
+            // Leave cycles for [parse_file] to report.
+            let canonical = included.canonicalize()?;
+            if !stack.contains(&canonical) && !origins.included.insert(canonical) {
+                continue;
+            }
+            let included_cfg =
+                parse_file(&included, stack, origins, scope, session).map_err(|err| match err {
+                    // Errors within nested files already name them.
+                    err @ (ParseError::Included { .. }
+                    | ParseError::Invalid(_)
+                    | ParseError::IncludeCycle { .. }
+                    | ParseError::DuplicatePackage { .. }
+                    | ParseError::DuplicatePreset { .. }) => err,
+                    err => ParseError::Included {
+                        path: included.clone(),
+                        err: Box::new(err),
+                    },
+                })?;
+            cfg.packages.extend(included_cfg.packages);
+            cfg.target.presets.extend(included_cfg.target.presets);
+            cfg.target.extend_keys(included_cfg.target.keys);
+        }
+        if !matched {
+            return Err(ParseError::IncludeMissing {
+                pattern: pattern.clone(),
+                path: path.to_path_buf(),
+            });
+        }
+    }
+    Ok(cfg)
 }
 
 #[cfg(test)]
 mod test {
     use crate::config::ServiceName;
+    use crate::package::{InterpolatedString, ResourceHints};
 
     use super::*;
 
@@ -180,18 +1702,42 @@ mod test {
             source: PackageSource::Manual,
             output: PackageOutput::Tarball,
             only_for_targets: None,
+            not_for_targets: None,
+            tags: vec![],
+            only_if: None,
             setup_hint: None,
+            checksum_manifest: false,
+            preserve_symlinks: false,
+            version_in_filename: false,
+            output_template: None,
+            smf: None,
+            metadata: BTreeMap::new(),
+            pre_build: vec![],
+            post_build: vec![],
+            resources: ResourceHints::default(),
         };
 
         let pkg_b_name = PackageName::new_const("pkg-b");
         let pkg_b = Package {
             service_name: ServiceName::new_const("b"),
             source: PackageSource::Composite {
-                packages: vec![pkg_a.get_output_file(&pkg_a_name)],
+                packages: vec![pkg_a.get_output_file(&pkg_a_name).into()],
             },
             output: PackageOutput::Tarball,
             only_for_targets: None,
+            not_for_targets: None,
+            tags: vec![],
+            only_if: None,
             setup_hint: None,
+            checksum_manifest: false,
+            preserve_symlinks: false,
+            version_in_filename: false,
+            output_template: None,
+            smf: None,
+            metadata: BTreeMap::new(),
+            pre_build: vec![],
+            post_build: vec![],
+            resources: ResourceHints::default(),
         };
 
         let cfg = Config {
@@ -200,6 +1746,7 @@ mod test {
                 (pkg_b_name.clone(), pkg_b.clone()),
             ]),
             target: TargetConfig::default(),
+            include: vec![],
         };
 
         let mut order = cfg.packages_to_build(&TargetMap::default()).build_order();
@@ -220,20 +1767,44 @@ mod test {
         let pkg_a = Package {
             service_name: ServiceName::new_const("a"),
             source: PackageSource::Composite {
-                packages: vec![String::from("pkg-b.tar")],
+                packages: vec!["pkg-b.tar".into()],
             },
             output: PackageOutput::Tarball,
             only_for_targets: None,
+            not_for_targets: None,
+            tags: vec![],
+            only_if: None,
             setup_hint: None,
+            checksum_manifest: false,
+            preserve_symlinks: false,
+            version_in_filename: false,
+            output_template: None,
+            smf: None,
+            metadata: BTreeMap::new(),
+            pre_build: vec![],
+            post_build: vec![],
+            resources: ResourceHints::default(),
         };
         let pkg_b = Package {
             service_name: ServiceName::new_const("b"),
             source: PackageSource::Composite {
-                packages: vec![String::from("pkg-a.tar")],
+                packages: vec!["pkg-a.tar".into()],
             },
             output: PackageOutput::Tarball,
             only_for_targets: None,
+            not_for_targets: None,
+            tags: vec![],
+            only_if: None,
             setup_hint: None,
+            checksum_manifest: false,
+            preserve_symlinks: false,
+            version_in_filename: false,
+            output_template: None,
+            smf: None,
+            metadata: BTreeMap::new(),
+            pre_build: vec![],
+            post_build: vec![],
+            resources: ResourceHints::default(),
         };
 
         let cfg = Config {
@@ -242,6 +1813,7 @@ mod test {
                 (pkg_b_name.clone(), pkg_b.clone()),
             ]),
             target: TargetConfig::default(),
+            include: vec![],
         };
 
         let mut order = cfg.packages_to_build(&TargetMap::default()).build_order();
@@ -258,19 +1830,1024 @@ mod test {
         let pkg_a = Package {
             service_name: ServiceName::new_const("a"),
             source: PackageSource::Composite {
-                packages: vec![String::from("pkg-b.tar")],
+                packages: vec!["pkg-b.tar".into()],
             },
             output: PackageOutput::Tarball,
             only_for_targets: None,
+            not_for_targets: None,
+            tags: vec![],
+            only_if: None,
             setup_hint: None,
+            checksum_manifest: false,
+            preserve_symlinks: false,
+            version_in_filename: false,
+            output_template: None,
+            smf: None,
+            metadata: BTreeMap::new(),
+            pre_build: vec![],
+            post_build: vec![],
+            resources: ResourceHints::default(),
         };
 
         let cfg = Config {
             packages: BTreeMap::from([(pkg_a_name.clone(), pkg_a.clone())]),
             target: TargetConfig::default(),
+            include: vec![],
         };
 
         let mut order = cfg.packages_to_build(&TargetMap::default()).build_order();
         order.next();
     }
+
+    #[test]
+    fn test_include() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let write = |path: &str, contents: &str| {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+        let package = |name: &str| {
+            format!(
+                r#"
+                [package.{name}]
+                service_name = "{name}"
+                source.type = "manual"
+                output.type = "zone"
+                "#
+            )
+        };
+        let root = write(
+            "root.toml",
+            &format!(
+                "include = [\"services/*.toml\"]\n{}\n[target.preset.dev]\nimage = \"dev\"\n",
+                package("a")
+            ),
+        );
+        write("services/b.toml", &package("b"));
+        write("services/c.toml", &package("c"));
+
+        let cfg = parse(&root).unwrap();
+        assert_eq!(
+            cfg.packages
+                .keys()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>(),
+            ["a", "b", "c"]
+        );
+        assert!(cfg
+            .target
+            .presets
+            .contains_key(&PresetName::new_const("dev")));
+
+        // Packages may only be defined once
+        let duplicate = write("services/d.toml", &package("b"));
+        let err = parse(&root).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Package 'b' in {duplicate} was already defined in {}",
+                dir.path().join("services/b.toml")
+            )
+        );
+        std::fs::remove_file(&duplicate).unwrap();
+
+        // Errors identify the file which caused them
+        let invalid = write("services/invalid.toml", "package = 3");
+        let err = parse(&root).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with(&format!("{invalid}:1:1: in 'package': ")),
+            "{err}"
+        );
+        std::fs::remove_file(&invalid).unwrap();
+
+        // Manifests included along several paths are merged once
+        write(
+            "services/b.toml",
+            &format!("include = [\"../common/d.toml\"]\n{}", package("b")),
+        );
+        write(
+            "services/c.toml",
+            &format!("include = [\"../common/d.toml\"]\n{}", package("c")),
+        );
+        write("common/d.toml", &package("d"));
+        let cfg = parse(&root).unwrap();
+        assert_eq!(
+            cfg.packages
+                .keys()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>(),
+            ["a", "b", "c", "d"]
+        );
+
+        // Cycles are rejected
+        write("services/cycle.toml", "include = [\"../root.toml\"]");
+        let err = parse(&root).unwrap_err();
+        assert!(matches!(err, ParseError::IncludeCycle { .. }), "{err}");
+    }
+
+    #[test]
+    fn test_templates() {
+        let cfg = parse_manifest(
+            r#"
+            [template.zone]
+            output.type = "zone"
+            output.intermediate_only = true
+            source.type = "local"
+            only_for_targets.image = "standard"
+
+            [package.a]
+            template = "zone"
+            service_name = "a"
+
+            [package.b]
+            template = "zone"
+            service_name = "b"
+            output.intermediate_only = false
+            "#,
+        )
+        .unwrap();
+        let a = &cfg.packages[&PackageName::new_const("a")];
+        let b = &cfg.packages[&PackageName::new_const("b")];
+        assert!(matches!(a.source, PackageSource::Local { .. }));
+        assert!(matches!(
+            a.output,
+            PackageOutput::Zone {
+                intermediate_only: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            b.output,
+            PackageOutput::Zone {
+                intermediate_only: false,
+                ..
+            }
+        ));
+        assert_eq!(a.only_for_targets, b.only_for_targets);
+
+        let err = parse_manifest(
+            r#"
+            [package.a]
+            template = "missing"
+            service_name = "a"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Package 'a' uses unknown template 'missing'"
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_parse_json() {
+        // Null values, which TOML cannot represent, are accepted.
+        let cfg = parse_json(
+            r#"{
+                "package": {
+                    "a": {
+                        "service_name": "a",
+                        "source": { "type": "manual" },
+                        "output": { "type": "zone" },
+                        "setup_hint": null
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let a = &cfg.packages[&PackageName::new_const("a")];
+        assert!(matches!(a.output, PackageOutput::Zone { .. }));
+        assert_eq!(a.setup_hint, None);
+
+        // Templates are only expanded within TOML manifests.
+        let err = parse_json(
+            r#"{
+                "template": { "zone": { "output": { "type": "zone" } } },
+                "package": {}
+            }"#,
+        )
+        .unwrap_err();
+        assert!(matches!(err, ParseError::Json(_)), "{err}");
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_parse_yaml() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.yml");
+        std::fs::write(
+            &path,
+            "package:\n  a:\n    service_name: a\n    source:\n      type: manual\n    output:\n      type: tarball\n",
+        )
+        .unwrap();
+        let cfg = parse(&path).unwrap();
+        let a = &cfg.packages[&PackageName::new_const("a")];
+        assert!(matches!(a.output, PackageOutput::Tarball));
+    }
+
+    #[cfg(not(feature = "yaml"))]
+    #[test]
+    fn test_parse_yaml_unsupported() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.yaml");
+        std::fs::write(&path, "package: {}\n").unwrap();
+        let err = parse(&path).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ParseError::UnsupportedFormat {
+                    feature: "yaml",
+                    ..
+                }
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let manifest = r#"
+[package.a]
+service_name = "a"
+source.type = "manual"
+output.type = "zone"
+
+[package.b]
+source.type = "manual"
+output.type = "tarbal"
+"#;
+        // Invalid values are located by their key
+        let err = parse_manifest(manifest).unwrap_err();
+        let ParseError::Invalid(diagnostic) = &err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(diagnostic.location, Some((9, 8)), "{err}");
+        assert_eq!(diagnostic.key.as_deref(), Some("package.b.output.type"));
+        assert_eq!(diagnostic.hint.as_deref(), Some("did you mean 'tarball'?"));
+        assert!(
+            err.to_string().starts_with(
+                "<manifest>:9:8: in 'package.b.output.type': unknown variant `tarbal`"
+            ),
+            "{err}"
+        );
+
+        // Missing fields are reported against their table
+        let err = parse_manifest(&manifest.replace("tarbal", "tarball")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<manifest>:7:10: in 'package.b': missing field `service_name` \
+             (hint: add 'service_name' to 'package.b', or to the template it uses)"
+        );
+
+        // Syntax errors are located too
+        let err = parse_manifest("[package.a]\nservice_name = \"a\n").unwrap_err();
+        let ParseError::Invalid(diagnostic) = &err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(diagnostic.location.map(|(line, _)| line), Some(2), "{err}");
+    }
+
+    #[test]
+    fn test_tarball_destinations() {
+        let manifest = r#"
+[package.a]
+service_name = "a"
+source.type = "local"
+source.paths = [{ from = "a.txt", to = "opt/a.txt" }]
+source.blobs = ["plain.bin", { path = "mapped.bin", to = "/opt/mapped.bin" }]
+output.type = "tarball"
+"#;
+        // Destinations within tarballs must be relative
+        let err = parse_manifest(manifest).unwrap_err();
+        let ParseError::Invalid(diagnostic) = &err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(diagnostic.location, Some((6, 53)), "{err}");
+        assert_eq!(
+            err.to_string(),
+            "<manifest>:6:53: in 'package.a.source.blobs.1.to': '/opt/mapped.bin' must be \
+             relative, since package 'a' is a tarball (hint: remove the leading '/')"
+        );
+        let err = parse_manifest(&manifest.replace("opt/a.txt", "/opt/a.txt")).unwrap_err();
+        let ParseError::Invalid(diagnostic) = &err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(
+            diagnostic.key.as_deref(),
+            Some("package.a.source.paths.0.to")
+        );
+
+        // ... but zone images place them within their root
+        parse_manifest(&manifest.replace("\"tarball\"", "\"zone\"")).unwrap();
+        parse_manifest(&manifest.replace("/opt/mapped.bin", "opt/mapped.bin")).unwrap();
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        let manifest = r#"
+            [package.a]
+            service_name = "a"
+            source.type = "local"
+            source.rust.binary_names = ["a"]
+            source.rust.relase = true
+            output.type = "zone"
+            only_for_target.image = "standard"
+        "#;
+
+        // Unknown fields are ignored by default
+        let cfg = parse_manifest(manifest).unwrap();
+        let package = &cfg.packages[&PackageName::new_const("a")];
+        assert!(package.only_for_targets.is_none());
+
+        // ... though they are reported as warnings
+        let (_, warnings) = parse_manifest_with_warnings(manifest, ParseMode::Lenient).unwrap();
+        let warnings = warnings
+            .iter()
+            .map(|warning| {
+                (
+                    warning.location,
+                    warning.key.as_deref(),
+                    warning.hint.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            vec![
+                (
+                    Some((8, 13)),
+                    Some("package.a.only_for_target"),
+                    Some("did you mean 'only_for_targets'?")
+                ),
+                (
+                    Some((6, 25)),
+                    Some("package.a.source.rust.relase"),
+                    Some("did you mean 'release'?")
+                ),
+            ]
+        );
+
+        // ... and rejected in strict mode, with a suggestion
+        let err = parse_manifest_with_mode(manifest, ParseMode::Strict).unwrap_err();
+        let ParseError::Invalid(diagnostic) = &err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(diagnostic.location.map(|(line, _)| line), Some(8), "{err}");
+        assert_eq!(
+            diagnostic.hint.as_deref(),
+            Some("did you mean 'only_for_targets'?")
+        );
+        let err = parse_manifest_with_mode(
+            &manifest.replace("only_for_target.", "only_for_targets."),
+            ParseMode::Strict,
+        )
+        .unwrap_err();
+        let ParseError::Invalid(diagnostic) = &err else {
+            panic!("unexpected error: {err}");
+        };
+        assert!(
+            diagnostic.message.starts_with("unknown field `relase`"),
+            "{err}"
+        );
+        assert_eq!(diagnostic.hint.as_deref(), Some("did you mean 'release'?"));
+
+        // Tables which have always been strict remain so
+        let err = parse_manifest(&format!("{manifest}\nsmf.strat = \"a\"\n")).unwrap_err();
+        assert!(err.to_string().contains("unknown field `strat`"), "{err}");
+    }
+
+    #[test]
+    fn test_parse_mode_inline_tables() {
+        // Each table may also be written as a string; misspelled keys within
+        // the table are found regardless.
+        let cases = [
+            (
+                r#"source.type = "local"
+                source.blobs = [{ path = "a.bin", too = "opt/a.bin" }]"#,
+                "package.a.source.blobs.0.too",
+                "did you mean 'to'?",
+            ),
+            (
+                r#"source.type = "local"
+                source.dirs = [{ path = "/x", mdoe = 448 }]"#,
+                "package.a.source.dirs.0.mdoe",
+                "did you mean 'mode'?",
+            ),
+            (
+                r#"source.type = "local"
+                source.placeholders = [{ path = "/x", knid = "fifo" }]"#,
+                "package.a.source.placeholders.0.knid",
+                "did you mean 'kind'?",
+            ),
+            (
+                r#"source.type = "composite"
+                source.packages = [{ package = "b.tar.gz", allow_overide = true }]"#,
+                "package.a.source.packages.0.allow_overide",
+                "did you mean 'allow_override'?",
+            ),
+            (
+                r#"source.type = "local"
+                source.rust.binary_names = [{ name = "a", instal_as = "b" }]
+                source.rust.release = true"#,
+                "package.a.source.rust.binary_names.0.instal_as",
+                "did you mean 'install_as'?",
+            ),
+        ];
+        for (source, key, hint) in cases {
+            let manifest = format!(
+                r#"
+                [package.a]
+                service_name = "a"
+                output.type = "zone"
+                {source}
+                "#
+            );
+
+            let err = parse_manifest_with_mode(&manifest, ParseMode::Strict).unwrap_err();
+            let ParseError::Invalid(diagnostic) = &err else {
+                panic!("unexpected error: {err}");
+            };
+            assert_eq!(diagnostic.key.as_deref(), Some(key), "{err}");
+            assert_eq!(diagnostic.hint.as_deref(), Some(hint), "{err}");
+
+            // Lenient mode ignores the key, with a warning.
+            let (_, warnings) =
+                parse_manifest_with_warnings(&manifest, ParseMode::Lenient).unwrap();
+            let warnings = warnings
+                .iter()
+                .map(|warning| warning.key.as_deref())
+                .collect::<Vec<_>>();
+            assert_eq!(warnings, [Some(key)]);
+        }
+    }
+
+    #[test]
+    fn test_parse_mode_removes_only_unknown_field() {
+        // "paths" is valid within "source", but not within "source.rust"
+        let cfg = parse_manifest(
+            r#"
+            [package.a]
+            service_name = "a"
+            source.type = "local"
+            source.paths = [ { from = "a", to = "/a" } ]
+            source.rust.binary_names = ["a"]
+            source.rust.release = true
+            source.rust.paths = ["b"]
+            output.type = "zone"
+            "#,
+        )
+        .unwrap();
+        let PackageSource::Local { paths, rust, .. } =
+            &cfg.packages[&PackageName::new_const("a")].source
+        else {
+            panic!("unexpected source");
+        };
+        assert_eq!(paths.len(), 1);
+        assert!(rust.is_some());
+    }
+
+    #[test]
+    fn test_build_subset() {
+        let cfg = parse_manifest(
+            r#"
+            [package.a]
+            service_name = "a"
+            source.type = "manual"
+            output.type = "zone"
+            output.intermediate_only = true
+
+            [package.b]
+            service_name = "b"
+            source.type = "composite"
+            source.packages = ["a.tar.gz"]
+            output.type = "zone"
+
+            [package.c]
+            service_name = "c"
+            source.type = "manual"
+            output.type = "tarball"
+
+            [package.d]
+            service_name = "d"
+            source.type = "composite"
+            source.packages = ["b.tar.gz"]
+            output.type = "zone"
+            "#,
+        )
+        .unwrap();
+        let target = TargetMap::default();
+
+        let subset = cfg
+            .packages_to_build_subset(&target, &[PackageName::new_const("d")])
+            .unwrap();
+        let order = subset
+            .build_order()
+            .map(|batch| {
+                batch
+                    .into_iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(order, [["a"], ["b"], ["d"]]);
+
+        let Err(err) = cfg.packages_to_build_subset(&target, &[PackageName::new_const("e")]) else {
+            panic!("expected an unknown package to be rejected");
+        };
+        assert_eq!(err.to_string(), "Package 'e' is not defined");
+    }
+
+    #[test]
+    fn test_vars() {
+        let manifest = r#"
+            [vars]
+            version = "1.0.4"
+            root = "out"
+
+            [template.firmware]
+            source.type = "local"
+            output.type = "tarball"
+            source.paths = [
+              { from = "{{var.root}}/fw-{{var.version}}.bin", to = "opt/{{image}}/fw.bin" },
+            ]
+
+            [package.firmware]
+            template = "firmware"
+            service_name = "firmware"
+            setup_hint = "Build version {{var.version}} first"
+        "#;
+        let cfg = parse_manifest(manifest).unwrap();
+        let package = &cfg.packages[&PackageName::new_const("firmware")];
+        assert_eq!(
+            package.setup_hint.as_deref(),
+            Some("Build version 1.0.4 first")
+        );
+        let PackageSource::Local { paths, .. } = &package.source else {
+            panic!("unexpected source: {:?}", package.source);
+        };
+        // Target keys are left for build time
+        assert_eq!(paths[0].from, InterpolatedString::from("out/fw-1.0.4.bin"));
+        assert_eq!(
+            paths[0].to,
+            InterpolatedString::from("opt/{{image}}/fw.bin")
+        );
+
+        let err =
+            parse_manifest(&manifest.replace("{{var.version}} first", "{{var.vers}}")).unwrap_err();
+        assert_eq!(err.to_string(), "Variable 'vers' is not defined");
+    }
+
+    #[test]
+    fn test_target_filters() {
+        let cfg = parse_manifest(
+            r#"
+            [package.a]
+            service_name = "a"
+            source.type = "manual"
+            output.type = "tarball"
+            only_for_targets.machine = ["gimlet", "cosmo"]
+            not_for_targets.switch = "stub"
+            "#,
+        )
+        .unwrap();
+        let included = |target: &str| !cfg.packages_to_build(&target.parse().unwrap()).0.is_empty();
+
+        assert!(included("machine=gimlet switch=asic"));
+        assert!(included("machine=cosmo"));
+        assert!(!included("machine=cosmo switch=stub"));
+        assert!(!included("machine=sled switch=asic"));
+    }
+
+    #[test]
+    fn test_target_keys() {
+        let keys = r#"
+            [target.keys]
+            machine = ["gimlet", "cosmo"]
+            switch = ["asic", "stub"]
+            rack = []
+        "#;
+        let parse = |rest: &str| parse_manifest(&format!("{keys}{rest}"));
+
+        let cfg = parse(
+            r#"
+            [target.preset.dev]
+            machine = "gimlet"
+            rack = "anything"
+
+            [package.a]
+            service_name = "a"
+            source.type = "manual"
+            output.type = "tarball"
+            only_for_targets.machine = ["gimlet", "cosmo"]
+            not_for_targets.switch = "stub"
+            only_if = "target.rack != 'test'"
+            "#,
+        )
+        .unwrap();
+        let schema = cfg.target.keys.as_ref().unwrap();
+        assert!(schema.parse("machine=cosmo switch=asic").is_ok());
+        assert!(schema.parse("machine=cosmo swtich=asic").is_err());
+
+        for (manifest, message) in [
+            (
+                r#"
+                [target.preset.dev]
+                macine = "gimlet"
+                "#,
+                "Preset 'dev': Unknown target key 'macine'; did you mean 'machine'?",
+            ),
+            (
+                r#"
+                [package.a]
+                service_name = "a"
+                source.type = "manual"
+                output.type = "tarball"
+                only_for_targets.machine = ["gimlet", "sled"]
+                "#,
+                "Package 'a' (only_for_targets): Invalid value 'sled' for target key \
+                 'machine' (expected one of: gimlet, cosmo)",
+            ),
+            (
+                r#"
+                [package.a]
+                service_name = "a"
+                source.type = "manual"
+                output.type = "tarball"
+                only_if = "target.switch == 'softnpu'"
+                "#,
+                "Package 'a' (only_if): Invalid value 'softnpu' for target key \
+                 'switch' (expected one of: asic, stub)",
+            ),
+            (
+                r#"
+                [package.a]
+                service_name = "a"
+                source.type = "local"
+                source.paths = [
+                    { from = "a.txt", to = "opt/a.txt" },
+                    { from = "b.txt", to = "opt/b.txt", only_if = "target.swtich == 'asic'" },
+                ]
+                output.type = "tarball"
+                "#,
+                "Package 'a' (paths[1].only_if): Unknown target key 'swtich'; did you \
+                 mean 'switch'?",
+            ),
+        ] {
+            let err = parse(manifest).unwrap_err();
+            assert_eq!(err.to_string(), message);
+        }
+
+        // Without declared keys, anything goes.
+        parse_manifest(
+            r#"
+            [target.preset.dev]
+            macine = "gimlet"
+            "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let base = parse_manifest(
+            r#"
+            [package.a]
+            service_name = "a"
+            source.type = "prebuilt"
+            source.repo = "a"
+            source.commit = "0000000000000000000000000000000000000000"
+            source.sha256 = "0000000000000000000000000000000000000000000000000000000000000000"
+            output.type = "zone"
+            only_for_targets.image = "standard"
+            tags = ["dev"]
+
+            [package.b]
+            service_name = "b"
+            source.type = "manual"
+            output.type = "zone"
+
+            [target.preset.dev]
+            image = "standard"
+            "#,
+        )
+        .unwrap();
+        let overlay = parse_manifest(
+            r#"
+            [package.a]
+            service_name = "a-local"
+            source.type = "local"
+            output.type = "tarball"
+
+            [package.c]
+            service_name = "c"
+            source.type = "manual"
+            output.type = "zone"
+            "#,
+        )
+        .unwrap();
+        let a = PackageName::new_const("a");
+
+        let mut merged = base.clone();
+        let conflicts = merged
+            .merge(overlay.clone(), MergePolicy::PreferOverlay)
+            .unwrap();
+        // The overlay's service name is dropped, and reported as such.
+        assert_eq!(
+            conflicts,
+            [
+                MergeConflict::Package(a.clone()),
+                MergeConflict::DroppedField {
+                    package: a.clone(),
+                    field: "service_name",
+                },
+            ]
+        );
+        assert_eq!(
+            conflicts[1].to_string(),
+            "field 'service_name' of package 'a'"
+        );
+        assert_eq!(merged.packages.len(), 3);
+        assert!(matches!(
+            merged.packages[&a].source,
+            PackageSource::Local { .. }
+        ));
+        assert_eq!(merged.packages[&a].output, PackageOutput::Tarball);
+
+        // Fields other than the source and output are inherited.
+        assert_eq!(
+            merged.packages[&a].service_name,
+            base.packages[&a].service_name
+        );
+        assert_eq!(
+            merged.packages[&a].only_for_targets,
+            base.packages[&a].only_for_targets
+        );
+        assert_eq!(merged.packages[&a].tags, ["dev"]);
+
+        let mut merged = base.clone();
+        merged
+            .merge(overlay.clone(), MergePolicy::PreferBase)
+            .unwrap();
+        assert_eq!(merged.packages.len(), 3);
+        assert!(matches!(
+            merged.packages[&a].source,
+            PackageSource::Prebuilt { .. }
+        ));
+
+        let mut merged = base.clone();
+        let err = merged.merge(overlay, MergePolicy::Reject).unwrap_err();
+        assert_eq!(err.to_string(), "Overlay redefines package 'a'");
+        assert_eq!(merged.packages.len(), 2);
+
+        // The result must only use the target keys it declares
+        let keys = parse_manifest(
+            r#"
+            [target.keys]
+            image = ["trampoline"]
+            "#,
+        )
+        .unwrap();
+        let err = merged.merge(keys, MergePolicy::Reject).unwrap_err();
+        assert!(matches!(err, MergeError::InvalidTarget(_)), "{err}");
+        assert!(merged.target.keys.is_none());
+    }
+
+    #[test]
+    fn test_external_packages() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let write = |path: &str, contents: &str| {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+        write(
+            "propolis/manifest.toml",
+            r#"
+            [package.propolis-base]
+            service_name = "propolis-base"
+            source.type = "local"
+            source.paths = [
+                { from = "bin/propolis", to = "/opt/propolis" },
+                { from = "/usr/bin/true", to = "/opt/true" },
+            ]
+            output.type = "zone"
+            output.intermediate_only = true
+
+            [package.propolis-server]
+            service_name = "propolis-server"
+            source.type = "composite"
+            source.packages = ["propolis-base.tar.gz"]
+            output.type = "zone"
+            output.intermediate_only = true
+
+            [package.unrelated]
+            service_name = "unrelated"
+            source.type = "manual"
+            output.type = "zone"
+            "#,
+        );
+        let root = write(
+            "omicron/manifest.toml",
+            r#"
+            [package.omicron]
+            service_name = "omicron"
+            source.type = "composite"
+            source.packages = [
+                { package = "propolis-server", manifest = "../propolis/manifest.toml" },
+            ]
+            output.type = "zone"
+            "#,
+        );
+
+        let cfg = parse(&root).unwrap();
+        let order = cfg
+            .packages_to_build(&TargetMap::default())
+            .build_order()
+            .map(|batch| {
+                batch
+                    .into_iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(order, [["propolis-base"], ["propolis-server"], ["omicron"]]);
+
+        // The component is replaced by the file created by the package
+        let PackageSource::Composite { packages } =
+            &cfg.packages[&PackageName::new_const("omicron")].source
+        else {
+            panic!("unexpected source");
+        };
+        assert_eq!(packages[0].package, "propolis-server.tar.gz");
+
+        // Relative paths are found within the other manifest's directory
+        let PackageSource::Local { paths, .. } =
+            &cfg.packages[&PackageName::new_const("propolis-base")].source
+        else {
+            panic!("unexpected source");
+        };
+        let from = paths
+            .iter()
+            .map(|path| path.from.interpolate(&TargetMap::default()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            from,
+            [
+                dir.path().join("omicron/../propolis/bin/propolis").as_str(),
+                "/usr/bin/true"
+            ]
+        );
+
+        let root = write(
+            "omicron/manifest.toml",
+            r#"
+            [package.omicron]
+            service_name = "omicron"
+            source.type = "composite"
+            source.packages = [
+                { package = "propolis-server.tar.gz", manifest = "../propolis/manifest.toml" },
+            ]
+            output.type = "zone"
+            "#,
+        );
+        let err = parse(&root).unwrap_err();
+        assert!(
+            matches!(err, ParseError::ExternalPackageMissing { ref package, .. } if package == "propolis-server.tar.gz"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_tags() {
+        let cfg = parse_manifest(
+            r#"
+            [package.base]
+            service_name = "base"
+            source.type = "manual"
+            output.type = "zone"
+            output.intermediate_only = true
+
+            [package.nexus]
+            service_name = "nexus"
+            tags = ["control-plane"]
+            source.type = "composite"
+            source.packages = ["base.tar.gz"]
+            output.type = "zone"
+
+            [package.sled-agent]
+            service_name = "sled-agent"
+            tags = ["sled", "control-plane"]
+            only_for_targets.image = "standard"
+            source.type = "manual"
+            output.type = "tarball"
+
+            [package.other]
+            service_name = "other"
+            source.type = "manual"
+            output.type = "tarball"
+            "#,
+        )
+        .unwrap();
+        let names = |packages: PackageMap<'_>| {
+            packages
+                .0
+                .into_keys()
+                .map(|name| name.as_str().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(cfg.packages_with_tag("control-plane")),
+            ["nexus", "sled-agent"]
+        );
+        assert_eq!(names(cfg.packages_with_tag("sled")), ["sled-agent"]);
+
+        // Dependencies are included, and the target is respected.
+        let target = "image=trampoline".parse().unwrap();
+        assert_eq!(
+            names(
+                cfg.packages_to_build_with_tags(&target, &["control-plane"])
+                    .unwrap()
+            ),
+            ["base", "nexus"]
+        );
+    }
+
+    #[test]
+    fn test_schema() {
+        let manifest = |schema: &str| {
+            format!(
+                r#"
+                {schema}
+                [package.a]
+                service_name = "a"
+                source.type = "manual"
+                output.type = "tarball"
+                "#
+            )
+        };
+        parse_manifest(&manifest("")).unwrap();
+        parse_manifest(&manifest(&format!("schema = {SCHEMA_VERSION}"))).unwrap();
+
+        let err =
+            parse_manifest(&manifest(&format!("schema = {}", SCHEMA_VERSION + 1))).unwrap_err();
+        assert!(
+            matches!(err, ParseError::UnsupportedSchema { version } if version == SCHEMA_VERSION + 1),
+            "{err}"
+        );
+        assert!(err.to_string().contains("try upgrading"), "{err}");
+
+        for schema in ["schema = 0", "schema = \"1\""] {
+            let err = parse_manifest(&manifest(schema)).unwrap_err();
+            assert!(matches!(err, ParseError::InvalidSchema), "{schema}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_validate_deployment() {
+        let cfg = parse_manifest(
+            r#"
+            [package.nexus]
+            service_name = "nexus"
+            source.type = "manual"
+            output.type = "zone"
+
+            [package.nexus-debug]
+            service_name = "nexus"
+            only_for_targets.image = "debug"
+            source.type = "manual"
+            output.type = "zone"
+
+            [package.nexus-intermediate]
+            service_name = "nexus"
+            source.type = "manual"
+            output.type = "zone"
+            output.intermediate_only = true
+
+            [package.nexus-tools]
+            service_name = "nexus"
+            source.type = "manual"
+            output.type = "tarball"
+            "#,
+        )
+        .unwrap();
+
+        // Intermediate packages, and those installed with other
+        // extensions, do not collide.
+        cfg.validate_deployment(&"image=standard".parse().unwrap())
+            .unwrap();
+
+        let err = cfg
+            .validate_deployment(&"image=debug".parse().unwrap())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Packages share an installed file name: 'nexus', 'nexus-debug' (nexus.tar.gz)"
+        );
+    }
 }
@@ -0,0 +1,251 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Identifying likely mistakes within a manifest.
+
+use crate::input::BuildInput;
+use crate::package::PackageOutput;
+use crate::target::TargetMap;
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::imp::dependencies;
+use super::{Config, PackageName, ServiceName};
+
+/// An issue identified by [Config::lint].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LintFinding {
+    /// An intermediate package which no composite package includes.
+    UnusedIntermediate { package: PackageName },
+    /// An intermediate package built for the target, which only composite
+    /// packages built for other targets include.
+    ///
+    /// It is never built for the target.
+    Unreachable { package: PackageName },
+    /// A package which depends upon a file which no package builds for the
+    /// target.
+    MissingDependency {
+        package: PackageName,
+        dependency: String,
+    },
+    /// Packages deployed to the target which share a service name.
+    DuplicateServiceName {
+        service: ServiceName,
+        packages: Vec<PackageName>,
+    },
+    /// A blob which is overwritten by a later blob at the same destination.
+    UnusedBlob {
+        package: PackageName,
+        destination: Utf8PathBuf,
+    },
+}
+
+impl std::fmt::Display for LintFinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LintFinding::UnusedIntermediate { package } => write!(
+                f,
+                "Package '{package}' is intermediate_only, but no composite package includes it"
+            ),
+            LintFinding::Unreachable { package } => write!(
+                f,
+                "Package '{package}' is intermediate_only, but no composite package \
+                 built for this target includes it"
+            ),
+            LintFinding::MissingDependency {
+                package,
+                dependency,
+            } => write!(
+                f,
+                "Package '{package}' depends on '{dependency}', which no package \
+                 builds for this target"
+            ),
+            LintFinding::DuplicateServiceName { service, packages } => write!(
+                f,
+                "Service '{service}' is deployed by multiple packages: {}",
+                packages
+                    .iter()
+                    .map(|name| format!("'{name}'"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            LintFinding::UnusedBlob {
+                package,
+                destination,
+            } => write!(
+                f,
+                "Package '{package}' places multiple blobs at '{destination}'; \
+                 only the last is used"
+            ),
+        }
+    }
+}
+
+impl Config {
+    /// Identifies likely mistakes within the packages built for `target`.
+    ///
+    /// An empty result indicates that no issues were found.
+    pub fn lint(&self, target: &TargetMap) -> anyhow::Result<Vec<LintFinding>> {
+        let mut findings = vec![];
+        let to_build = self.packages_to_build(target).0;
+
+        // Output files included by any composite package, and by those
+        // built for this target.
+        let mut included_anywhere = BTreeSet::new();
+        let mut included_for_target = BTreeSet::new();
+        for (name, package) in &self.packages {
+            let dependencies = dependencies(package);
+            if to_build.contains_key(name) {
+                included_for_target.extend(dependencies.iter().copied());
+            }
+            included_anywhere.extend(dependencies);
+        }
+
+        let outputs = to_build
+            .iter()
+            .map(|(name, package)| package.get_output_file(name))
+            .collect::<BTreeSet<_>>();
+        for (name, package) in &to_build {
+            if matches!(
+                package.output,
+                PackageOutput::Zone {
+                    intermediate_only: true,
+                    ..
+                }
+            ) {
+                let output = package.get_output_file(name);
+                if !included_anywhere.contains(output.as_str()) {
+                    findings.push(LintFinding::UnusedIntermediate {
+                        package: (*name).clone(),
+                    });
+                } else if !included_for_target.contains(output.as_str()) {
+                    findings.push(LintFinding::Unreachable {
+                        package: (*name).clone(),
+                    });
+                }
+            }
+
+            for dependency in dependencies(package) {
+                if !outputs.contains(dependency) {
+                    findings.push(LintFinding::MissingDependency {
+                        package: (*name).clone(),
+                        dependency: dependency.to_string(),
+                    });
+                }
+            }
+
+            let zoned = matches!(package.output, PackageOutput::Zone { .. });
+            let blobs = package.get_blobs_inputs(target, Utf8Path::new(""), zoned)?;
+            let mut destinations = BTreeMap::<_, usize>::new();
+            for input in &blobs {
+                if let BuildInput::AddBlob { path, .. } = input {
+                    *destinations.entry(&path.to).or_default() += 1;
+                }
+            }
+            findings.extend(
+                destinations
+                    .into_iter()
+                    .filter(|(_, count)| *count > 1)
+                    .map(|(destination, _)| LintFinding::UnusedBlob {
+                        package: (*name).clone(),
+                        destination: destination.clone(),
+                    }),
+            );
+        }
+
+        let mut services = BTreeMap::<_, Vec<_>>::new();
+        for (name, package) in self.packages_to_deploy(target).0 {
+            services
+                .entry(&package.service_name)
+                .or_default()
+                .push(name.clone());
+        }
+        findings.extend(
+            services
+                .into_iter()
+                .filter(|(_, packages)| packages.len() > 1)
+                .map(|(service, packages)| LintFinding::DuplicateServiceName {
+                    service: service.clone(),
+                    packages,
+                }),
+        );
+
+        Ok(findings)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::parse_manifest;
+
+    #[test]
+    fn test_lint() {
+        let cfg = parse_manifest(
+            r#"
+            [package.unused]
+            service_name = "unused"
+            source.type = "manual"
+            output.type = "zone"
+            output.intermediate_only = true
+
+            [package.gimlet-only]
+            service_name = "gimlet-only"
+            source.type = "manual"
+            output.type = "zone"
+            output.intermediate_only = true
+
+            [package.composite]
+            service_name = "composite"
+            only_for_targets.machine = "gimlet"
+            source.type = "composite"
+            source.packages = ["gimlet-only.tar.gz", "missing.tar.gz"]
+            output.type = "zone"
+
+            [package.first]
+            service_name = "svc"
+            source.type = "local"
+            source.blobs = ["a.bin", { path = "b.bin", to = "/opt/oxide/svc/blob/a.bin" }]
+            output.type = "zone"
+
+            [package.second]
+            service_name = "svc"
+            source.type = "manual"
+            output.type = "tarball"
+            "#,
+        )
+        .unwrap();
+
+        let findings = cfg.lint(&"machine=sled".parse().unwrap()).unwrap();
+        let name = PackageName::new_const;
+        assert_eq!(
+            findings,
+            [
+                LintFinding::UnusedBlob {
+                    package: name("first"),
+                    destination: "root/opt/oxide/svc/blob/a.bin".into(),
+                },
+                LintFinding::Unreachable {
+                    package: name("gimlet-only"),
+                },
+                LintFinding::UnusedIntermediate {
+                    package: name("unused"),
+                },
+                LintFinding::DuplicateServiceName {
+                    service: ServiceName::new_const("svc"),
+                    packages: vec![name("first"), name("second")],
+                },
+            ]
+        );
+
+        let findings = cfg.lint(&"machine=gimlet".parse().unwrap()).unwrap();
+        assert!(findings.contains(&LintFinding::MissingDependency {
+            package: name("composite"),
+            dependency: "missing.tar.gz".to_string(),
+        }));
+        assert!(!findings.contains(&LintFinding::Unreachable {
+            package: name("gimlet-only"),
+        }));
+    }
+}
@@ -2,8 +2,14 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+mod builder;
+mod diagnostic;
 mod identifier;
 mod imp;
+mod lint;
 
+pub use builder::*;
+pub use diagnostic::*;
 pub use identifier::*;
 pub use imp::*;
+pub use lint::*;
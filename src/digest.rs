@@ -12,16 +12,41 @@ use hex::ToHex;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest as _, Sha256};
 use tokio::io::{AsyncReadExt, BufReader};
+use xxhash_rust::xxh3::Xxh3Default;
 
 // The buffer size used to hash smaller files.
 const HASH_BUFFER_SIZE: usize = 16 * (1 << 10);
 
-// When files are larger than this size, we try to hash them using techniques
-// like memory mapping and rayon.
-//
-// NOTE: This is currently only blake3-specific.
+// When files are larger than this size, they're hashed off of the async
+// runtime, to avoid tying up an async worker. BLAKE3 additionally uses memory
+// mapping and rayon; other algorithms read them in chunks of this size.
 const LARGE_HASH_SIZE: usize = 1 << 20;
 
+// Feeds the contents of a large file at `path` to `hasher` on a blocking
+// thread, returning the hasher to be finalized.
+async fn hash_large_file<H: Send + 'static>(
+    path: &Utf8Path,
+    mut hasher: H,
+    update: fn(&mut H, &[u8]),
+) -> anyhow::Result<H> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file =
+            std::fs::File::open(&path).with_context(|| format!("could not open {path:?}"))?;
+        let mut buf = vec![0; LARGE_HASH_SIZE];
+        loop {
+            let count = std::io::Read::read(&mut file, &mut buf)
+                .with_context(|| format!("failed to read {path:?}"))?;
+            if count == 0 {
+                break;
+            }
+            update(&mut hasher, &buf[..count]);
+        }
+        Ok(hasher)
+    })
+    .await?
+}
+
 struct ShaDigest([u8; 32]);
 
 /// Implemented by algorithms which can take digests of files.
@@ -33,6 +58,13 @@ pub trait FileDigester {
 #[async_trait]
 impl FileDigester for ShaDigest {
     async fn get_digest(path: &Utf8Path) -> anyhow::Result<Digest> {
+        let size = path.metadata()?.len();
+
+        if size >= LARGE_HASH_SIZE as u64 {
+            let hasher = hash_large_file(path, Sha256::new(), |h, data| h.update(data)).await?;
+            return Ok(ShaDigest(hasher.finalize().into()).into());
+        }
+
         let mut reader = BufReader::new(
             tokio::fs::File::open(&path)
                 .await
@@ -98,12 +130,60 @@ impl FileDigester for BlakeDigest {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// A fast, non-cryptographic digest, suitable for detecting changes to
+/// files during local development.
+///
+/// This is the 128-bit variant of XXH3.
+pub struct Xxh3Digest(u128);
+
+#[async_trait]
+impl FileDigester for Xxh3Digest {
+    async fn get_digest(path: &Utf8Path) -> anyhow::Result<Digest> {
+        let size = path.metadata()?.len();
+
+        if size >= LARGE_HASH_SIZE as u64 {
+            let hasher =
+                hash_large_file(path, Xxh3Default::new(), |h, data| h.update(data)).await?;
+            return Ok(Xxh3Digest(hasher.digest128()).into());
+        }
+
+        let mut reader = BufReader::new(
+            tokio::fs::File::open(&path)
+                .await
+                .with_context(|| format!("could not open {path:?}"))?,
+        );
+        let mut hasher = Xxh3Default::new();
+        let mut buf = [0; HASH_BUFFER_SIZE];
+        loop {
+            let count = reader
+                .read(&mut buf)
+                .await
+                .with_context(|| format!("failed to read {path:?}"))?;
+            if count == 0 {
+                break;
+            }
+            hasher.update(&buf[..count]);
+        }
+        Ok(Xxh3Digest(hasher.digest128()).into())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Digest {
     // Sha256 support, as a hex-encoded string.
     Sha2(String),
     // Blake3 support, as a hex-encoded string.
     Blake3(String),
+    // XXH3 (128-bit) support, as a hex-encoded string.
+    Xxh3(String),
+    // The size and modification time of a file, as "SIZE:SECONDS.NANOS".
+    Metadata(String),
+}
+
+impl From<Xxh3Digest> for Digest {
+    fn from(digest: Xxh3Digest) -> Self {
+        Self::Xxh3(format!("{:032x}", digest.0))
+    }
 }
 
 impl From<ShaDigest> for Digest {
@@ -120,3 +200,130 @@ impl From<BlakeDigest> for Digest {
 
 /// Although we support both interfaces, we use blake3 digests by default.
 pub type DefaultDigest = BlakeDigest;
+
+/// Selects the algorithm used to take digests of a package's inputs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DigestAlgorithm {
+    /// BLAKE3, which is used unless another algorithm is selected.
+    #[default]
+    Blake3,
+    /// XXH3, which is much faster, but not cryptographically secure.
+    ///
+    /// This is intended for local development; packages built for release
+    /// should use the default.
+    Xxh3,
+    /// Rather than reading files, records their size and modification
+    /// time, as make and ninja do.
+    ///
+    /// This makes lookups nearly instant, but misses changes to files which
+    /// preserve both; it's intended for iterative local builds.
+    Metadata,
+}
+
+impl DigestAlgorithm {
+    /// Takes the digest of the file at `path`.
+    pub(crate) async fn get_digest(self, path: &Utf8Path) -> anyhow::Result<Digest> {
+        match self {
+            DigestAlgorithm::Blake3 => DefaultDigest::get_digest(path).await,
+            DigestAlgorithm::Xxh3 => Xxh3Digest::get_digest(path).await,
+            DigestAlgorithm::Metadata => {
+                let metadata = tokio::fs::metadata(path)
+                    .await
+                    .with_context(|| format!("could not stat {path:?}"))?;
+                let modified = metadata
+                    .modified()?
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default();
+                Ok(Digest::Metadata(format!(
+                    "{}:{}.{:09}",
+                    metadata.len(),
+                    modified.as_secs(),
+                    modified.subsec_nanos()
+                )))
+            }
+        }
+    }
+
+    /// Takes the digest of `data`, which doesn't live in a file.
+    ///
+    /// There's no metadata to record, so [DigestAlgorithm::Metadata] uses
+    /// BLAKE3.
+    pub(crate) fn get_data_digest(self, data: &[u8]) -> Digest {
+        match self {
+            DigestAlgorithm::Blake3 | DigestAlgorithm::Metadata => blake3::hash(data).into(),
+            DigestAlgorithm::Xxh3 => Xxh3Digest(xxhash_rust::xxh3::xxh3_128(data)).into(),
+        }
+    }
+
+    /// Returns true if the contents of files are read to take their
+    /// digests.
+    pub(crate) fn reads_contents(self) -> bool {
+        self != DigestAlgorithm::Metadata
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sha_digest_large_file() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let path = dir.path().join("large");
+        let contents = vec![0xa5; LARGE_HASH_SIZE + 1];
+        std::fs::write(&path, &contents).unwrap();
+
+        let expected = Digest::Sha2(Sha256::digest(&contents).encode_hex::<String>());
+        assert_eq!(ShaDigest::get_digest(&path).await.unwrap(), expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_xxh3_digest() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let small = dir.path().join("small");
+        std::fs::write(&small, b"").unwrap();
+        assert_eq!(
+            DigestAlgorithm::Xxh3.get_digest(&small).await.unwrap(),
+            Digest::Xxh3("99aa06d3014798d86001c324468d497f".to_string())
+        );
+
+        // Large files are hashed identically, though on another thread.
+        let large = dir.path().join("large");
+        let contents = vec![0xa5; 3 * LARGE_HASH_SIZE + 1];
+        std::fs::write(&large, &contents).unwrap();
+        std::fs::write(&small, &contents[..LARGE_HASH_SIZE - 1]).unwrap();
+        assert_eq!(
+            Xxh3Digest::get_digest(&large).await.unwrap(),
+            Digest::Xxh3(format!("{:032x}", xxhash_rust::xxh3::xxh3_128(&contents)))
+        );
+        assert_eq!(
+            Xxh3Digest::get_digest(&small).await.unwrap(),
+            Digest::Xxh3(format!(
+                "{:032x}",
+                xxhash_rust::xxh3::xxh3_128(&contents[..LARGE_HASH_SIZE - 1])
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metadata_digest() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"contents").unwrap();
+        let mtime = filetime::FileTime::from_unix_time(1_700_000_000, 5);
+        filetime::set_file_mtime(&path, mtime).unwrap();
+        assert_eq!(
+            DigestAlgorithm::Metadata.get_digest(&path).await.unwrap(),
+            Digest::Metadata("8:1700000000.000000005".to_string())
+        );
+
+        // Changes which preserve the size and modification time are
+        // invisible.
+        std::fs::write(&path, b"CONTENTS").unwrap();
+        filetime::set_file_mtime(&path, mtime).unwrap();
+        assert_eq!(
+            DigestAlgorithm::Metadata.get_digest(&path).await.unwrap(),
+            Digest::Metadata("8:1700000000.000000005".to_string())
+        );
+    }
+}
@@ -0,0 +1,244 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Records how long each phase of a package's build took, so that later
+//! builds can estimate how long they have remaining.
+//!
+//! The durations of the most recent build of each package are written to
+//! [HISTORY_FILE], within the [CACHE_SUBDIRECTORY] of the output directory.
+
+use crate::cache::CACHE_SUBDIRECTORY;
+use crate::config::PackageName;
+use crate::json_file::update_locked_json;
+
+use anyhow::anyhow;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The file within the cache directory recording the durations of builds.
+pub const HISTORY_FILE: &str = "durations.json";
+
+/// The duration of a single phase of a previous build.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PhaseRecord {
+    pub name: String,
+    pub duration: Duration,
+}
+
+/// The durations of the most recent build of each package.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BuildHistory {
+    packages: BTreeMap<PackageName, Vec<PhaseRecord>>,
+}
+
+impl BuildHistory {
+    /// Reads the history recorded within `output_directory`.
+    ///
+    /// If no history has been recorded, or it cannot be parsed, this
+    /// returns an empty history.
+    pub async fn load(output_directory: &Utf8Path) -> anyhow::Result<Self> {
+        let path = history_path(output_directory);
+        match tokio::fs::read(&path).await {
+            Ok(contents) => Ok(serde_json::from_slice(&contents).unwrap_or_default()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(anyhow!(e).context(format!("Reading {path}"))),
+        }
+    }
+
+    /// Returns the phases of the most recent build of `package`.
+    pub fn previous(&self, package: &PackageName) -> Option<&[PhaseRecord]> {
+        self.packages.get(package).map(Vec::as_slice)
+    }
+
+    /// Returns how long the most recent build of `package` took, in total.
+    pub fn total(&self, package: &PackageName) -> Option<Duration> {
+        self.previous(package)
+            .map(|phases| phases.iter().map(|phase| phase.duration).sum())
+    }
+
+    /// Estimates how long remains to build `package`, assuming that each
+    /// phase takes as long as it did previously.
+    ///
+    /// `completed` names the phases which have already finished, and
+    /// `current` names the phase in progress (if any), alongside how long it
+    /// has been running. Phases which didn't occur in the previous build
+    /// are assumed to take no time.
+    ///
+    /// Returns [None] if `package` has not been built before.
+    pub fn remaining(
+        &self,
+        package: &PackageName,
+        completed: &[String],
+        current: Option<(&str, Duration)>,
+    ) -> Option<Eta> {
+        let previous = self.previous(package)?;
+        let remaining = previous
+            .iter()
+            .filter(|phase| !completed.contains(&phase.name))
+            .map(|phase| match current {
+                Some((name, elapsed)) if name == phase.name => {
+                    phase.duration.saturating_sub(elapsed)
+                }
+                _ => phase.duration,
+            })
+            .sum();
+        Some(Eta(remaining))
+    }
+
+    // Replaces the phases recorded for `package` within `output_directory`.
+    //
+    // The history is locked while it's updated, since it's shared by every
+    // build using the output directory.
+    pub(crate) async fn record(
+        output_directory: &Utf8Path,
+        package: &PackageName,
+        phases: Vec<PhaseRecord>,
+    ) -> anyhow::Result<()> {
+        let package = package.clone();
+        // An unreadable history is discarded; at worst, this causes estimates
+        // to be missing until packages are rebuilt.
+        update_locked_json(
+            history_path(output_directory),
+            |history: &mut BuildHistory| {
+                history.packages.insert(package, phases);
+            },
+        )
+        .await
+    }
+}
+
+fn history_path(output_directory: &Utf8Path) -> Utf8PathBuf {
+    output_directory.join(CACHE_SUBDIRECTORY).join(HISTORY_FILE)
+}
+
+/// An estimate of the time remaining to build a package; see
+/// [BuildHistory::remaining].
+///
+/// Displays as a rounded duration, as in "~2m remaining".
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Eta(pub Duration);
+
+impl std::fmt::Display for Eta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.0.as_secs_f64().round() as u64;
+        if secs < 60 {
+            return write!(f, "~{secs}s remaining");
+        }
+        // Round before picking the unit, so that durations just short of an
+        // hour aren't displayed as "~60m".
+        let mins = (secs + 30) / 60;
+        if mins < 60 {
+            write!(f, "~{mins}m remaining")
+        } else {
+            write!(f, "~{}h{:02}m remaining", mins / 60, mins % 60)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn phase(name: &str, secs: u64) -> PhaseRecord {
+        PhaseRecord {
+            name: name.to_string(),
+            duration: Duration::from_secs(secs),
+        }
+    }
+
+    #[tokio::test]
+    async fn history_round_trips() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let nexus = PackageName::new_const("nexus");
+        let oximeter = PackageName::new_const("oximeter");
+
+        let history = BuildHistory::load(dir.path()).await.unwrap();
+        assert_eq!(history, BuildHistory::default());
+
+        BuildHistory::record(dir.path(), &nexus, vec![phase("a", 1)])
+            .await
+            .unwrap();
+        BuildHistory::record(dir.path(), &oximeter, vec![phase("a", 2)])
+            .await
+            .unwrap();
+        // Only the most recent build is kept.
+        BuildHistory::record(dir.path(), &nexus, vec![phase("a", 3), phase("b", 4)])
+            .await
+            .unwrap();
+
+        let history = BuildHistory::load(dir.path()).await.unwrap();
+        assert_eq!(
+            history.previous(&nexus).unwrap(),
+            [phase("a", 3), phase("b", 4)]
+        );
+        assert_eq!(history.total(&nexus), Some(Duration::from_secs(7)));
+        assert_eq!(history.total(&oximeter), Some(Duration::from_secs(2)));
+
+        // A corrupt history is treated as empty.
+        std::fs::write(history_path(dir.path()), "not json").unwrap();
+        let history = BuildHistory::load(dir.path()).await.unwrap();
+        assert_eq!(history.previous(&nexus), None);
+    }
+
+    #[test]
+    fn remaining_follows_phases() {
+        let nexus = PackageName::new_const("nexus");
+        let mut history = BuildHistory::default();
+        history.packages.insert(
+            nexus.clone(),
+            vec![
+                phase("lookup", 10),
+                phase("archive", 100),
+                phase("cache", 5),
+            ],
+        );
+
+        let remaining = |completed: &[&str], current| {
+            let completed: Vec<_> = completed.iter().map(|s| s.to_string()).collect();
+            history.remaining(&nexus, &completed, current).unwrap().0
+        };
+        assert_eq!(remaining(&[], None), Duration::from_secs(115));
+        assert_eq!(
+            remaining(&["lookup"], Some(("archive", Duration::from_secs(40)))),
+            Duration::from_secs(65)
+        );
+        // Phases which run longer than before contribute nothing.
+        assert_eq!(
+            remaining(&["lookup"], Some(("archive", Duration::from_secs(400)))),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            remaining(&["lookup", "archive", "cache"], None),
+            Duration::ZERO
+        );
+        // Unknown phases take no time.
+        assert_eq!(
+            remaining(&["lookup"], Some(("other", Duration::from_secs(1)))),
+            Duration::from_secs(105)
+        );
+
+        assert_eq!(
+            history.remaining(&PackageName::new_const("other"), &[], None),
+            None
+        );
+    }
+
+    #[test]
+    fn eta_display() {
+        let eta = |secs| Eta(Duration::from_secs(secs)).to_string();
+        assert_eq!(eta(0), "~0s remaining");
+        assert_eq!(eta(45), "~45s remaining");
+        assert_eq!(
+            Eta(Duration::from_millis(59_600)).to_string(),
+            "~1m remaining"
+        );
+        assert_eq!(eta(119), "~2m remaining");
+        assert_eq!(eta(3569), "~59m remaining");
+        assert_eq!(eta(3570), "~1h00m remaining");
+        assert_eq!(eta(3599), "~1h00m remaining");
+        assert_eq!(eta(3600 + 25 * 60), "~1h25m remaining");
+    }
+}
@@ -0,0 +1,220 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Commands which run before or after a package is built.
+
+use crate::package::InterpolatedString;
+use crate::progress::Progress;
+use crate::target::TargetMap;
+
+use anyhow::{bail, Context, Result};
+use camino::Utf8PathBuf;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::process::{ExitStatus, Stdio};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+
+fn default_inherit_env() -> bool {
+    true
+}
+
+/// Describes a command executed while building a package.
+///
+/// Example:
+///
+/// ```toml
+/// [[package.my-service.pre_build]]
+/// command = ["./generate.sh", "{{image}}"]
+/// env = { OUT_DIR = "out/{{image}}" }
+/// outputs = ["out/{{image}}/generated.txt"]
+/// ```
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BuildHook {
+    /// The program to run, followed by its arguments.
+    pub command: Vec<InterpolatedString>,
+
+    /// Environment variables set for the command.
+    #[serde(default)]
+    pub env: BTreeMap<String, InterpolatedString>,
+
+    /// If "false", the command only sees `PATH` and the variables in `env`,
+    /// rather than the entire environment of the caller.
+    #[serde(default = "default_inherit_env")]
+    pub inherit_env: bool,
+
+    /// Files produced by the command.
+    ///
+    /// Each must exist once the command completes. For hooks which run
+    /// before the build, these files are tracked as inputs of the package,
+    /// so changes to them invalidate cached packages.
+    #[serde(default)]
+    pub outputs: Vec<InterpolatedString>,
+}
+
+impl BuildHook {
+    /// Returns the paths of the files produced by this hook.
+    pub fn outputs(&self, target: &TargetMap) -> Result<Vec<Utf8PathBuf>> {
+        self.outputs
+            .iter()
+            .map(|output| Ok(Utf8PathBuf::from(output.interpolate(target)?)))
+            .collect()
+    }
+
+    /// Runs the command, relaying its output through `progress`.
+    pub async fn run(&self, target: &TargetMap, progress: &dyn Progress) -> Result<()> {
+        let args = self
+            .command
+            .iter()
+            .map(|arg| arg.interpolate(target))
+            .collect::<Result<Vec<_>>>()?;
+        let display = args.join(" ");
+        let Some((program, args)) = args.split_first() else {
+            bail!("Build hook has an empty command");
+        };
+
+        let mut command = Command::new(program);
+        command.args(args);
+        if !self.inherit_env {
+            command.env_clear();
+            if let Some(path) = std::env::var_os("PATH") {
+                command.env("PATH", path);
+            }
+        }
+        for (key, value) in &self.env {
+            command.env(key, value.interpolate(target)?);
+        }
+
+        progress.set_message(format!("Running: {display}").into());
+        slog::info!(progress.get_log(), "Running: {display}");
+        let status = run_with_progress(command, progress)
+            .await
+            .with_context(|| format!("Failed to run '{display}'"))?;
+        if !status.success() {
+            bail!("'{display}' failed: {status}");
+        }
+
+        for output in self.outputs(target)? {
+            if !output.exists() {
+                bail!("'{display}' did not produce {output}");
+            }
+        }
+        Ok(())
+    }
+}
+
+// Relays each line read from `reader` through `progress`.
+//
+// Commands may print anything, so lines which aren't valid UTF-8 are relayed
+// lossily rather than treated as errors.
+async fn relay_lines<R: AsyncRead + Unpin>(reader: R, progress: &dyn Progress) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(());
+        }
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches(['\n', '\r']);
+        slog::debug!(progress.get_log(), "{line}");
+        progress.set_message(line.trim().to_string().into());
+    }
+}
+
+/// Runs a command to completion, relaying its stdout and stderr through
+/// `progress` as they are written.
+pub(crate) async fn run_with_progress(
+    mut command: Command,
+    progress: &dyn Progress,
+) -> Result<ExitStatus> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    tokio::try_join!(relay_lines(stdout, progress), relay_lines(stderr, progress))?;
+    Ok(child.wait().await?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::progress::NoProgress;
+
+    #[tokio::test]
+    async fn hook_runs_with_env_and_checks_outputs() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.txt");
+        let hook: BuildHook = toml::from_str(&format!(
+            r#"
+            command = ["sh", "-c", "echo $GREETING > {output}"]
+            env = {{ GREETING = "hello-{{{{name}}}}" }}
+            inherit_env = false
+            outputs = ["{output}"]
+            "#
+        ))
+        .unwrap();
+        let mut target = TargetMap(BTreeMap::new());
+        target.0.insert("name".to_string(), "world".to_string());
+
+        hook.run(&target, &NoProgress::new()).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "hello-world\n");
+
+        let missing: BuildHook = toml::from_str(
+            r#"
+            command = ["true"]
+            outputs = ["does-not-exist"]
+            "#,
+        )
+        .unwrap();
+        let err = missing
+            .run(&target, &NoProgress::new())
+            .await
+            .expect_err("Missing output should fail");
+        assert_eq!(err.to_string(), "'true' did not produce does-not-exist");
+    }
+
+    #[tokio::test]
+    async fn hook_output_need_not_be_utf8() {
+        use std::borrow::Cow;
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Messages {
+            log: NoProgress,
+            messages: Mutex<Vec<String>>,
+        }
+
+        impl Progress for Messages {
+            fn get_log(&self) -> &slog::Logger {
+                self.log.get_log()
+            }
+
+            fn set_message(&self, msg: Cow<'static, str>) {
+                self.messages.lock().unwrap().push(msg.into_owned());
+            }
+        }
+
+        let hook: BuildHook = toml::from_str(
+            r#"
+            command = ["printf", "caf\\351\\nok\\n"]
+            "#,
+        )
+        .unwrap();
+        let progress = Messages::default();
+        hook.run(&TargetMap(BTreeMap::new()), &progress)
+            .await
+            .unwrap();
+        assert_eq!(
+            progress.messages.lock().unwrap()[1..],
+            ["caf\u{FFFD}", "ok"]
+        );
+    }
+}
@@ -2,9 +2,12 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::Context;
+use crate::archive::EntryMetadata;
+use anyhow::{bail, Context};
 use camino::{Utf8Path, Utf8PathBuf};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
 
 /// A directory that should be added to the target archive
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -23,6 +26,42 @@ pub struct MappedPath {
     pub to: Utf8PathBuf,
 }
 
+/// Describes which part of a package's manifest produced an input.
+///
+/// See [BuildInputs::iter_with_origins].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum InputOrigin {
+    /// An entry of `source.paths`, as written in the manifest.
+    Path { from: String, to: String },
+    /// A binary built from `source.rust`.
+    RustBinary { name: String },
+    /// A blob, downloaded from `url`.
+    Blob { url: String },
+    /// An entry of `source.package_files`.
+    PackageFiles { package: String },
+    /// A component of a composite package.
+    Component { package: String },
+    /// The output of the command which generates the package.
+    CommandOutput,
+}
+
+impl std::fmt::Display for InputOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputOrigin::Path { from, to } => {
+                write!(f, "path {{ from = \"{from}\", to = \"{to}\" }}")
+            }
+            InputOrigin::RustBinary { name } => write!(f, "rust binary \"{name}\""),
+            InputOrigin::Blob { url } => write!(f, "blob {url}"),
+            InputOrigin::PackageFiles { package } => {
+                write!(f, "files from package \"{package}\"")
+            }
+            InputOrigin::Component { package } => write!(f, "component \"{package}\""),
+            InputOrigin::CommandOutput => write!(f, "output of the source command"),
+        }
+    }
+}
+
 /// All possible inputs which are used to construct Omicron packages
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum BuildInput {
@@ -32,12 +71,38 @@ pub enum BuildInput {
     AddInMemoryFile {
         dst_path: Utf8PathBuf,
         contents: String,
+        /// Overrides the default permissions and ownership of the file.
+        metadata: EntryMetadata,
+    },
+
+    /// Adds an empty file to the target archive.
+    ///
+    /// This is useful for placeholders which a service expects to exist at
+    /// runtime.
+    AddEmptyFile {
+        path: Utf8PathBuf,
+        /// Overrides the default permissions and ownership of the file.
+        metadata: EntryMetadata,
+    },
+
+    /// Adds a named pipe (FIFO) to the target archive.
+    ///
+    /// This is only supported by tar-based outputs; IPS packages cannot
+    /// contain FIFOs.
+    AddFifo {
+        path: Utf8PathBuf,
+        /// Overrides the default permissions and ownership of the FIFO.
+        metadata: EntryMetadata,
     },
 
     /// Add a single directory to the target archive.
     ///
     /// This directory doesn't need to exist on the build host.
-    AddDirectory(TargetDirectory),
+    AddDirectory {
+        dir: TargetDirectory,
+        /// Overrides the default permissions and ownership of the directory.
+        metadata: EntryMetadata,
+    },
 
     /// Add a file directly from source to target.
     AddFile {
@@ -54,6 +119,9 @@ pub enum BuildInput {
         /// changes too. Comparing u64s is significantly faster than hashing,
         /// in this situation.
         len: u64,
+
+        /// Overrides the permissions and ownership of the file on the host.
+        metadata: EntryMetadata,
     },
 
     /// Add a dowloaded file from source to target.
@@ -65,11 +133,45 @@ pub enum BuildInput {
         blob: crate::blob::Source,
     },
 
+    /// Add a symbolic link to the target archive.
+    ///
+    /// The link is recorded as-is; `target` isn't resolved, and needn't
+    /// exist on the build host.
+    AddSymlink {
+        /// The path of the link within the archive.
+        link: Utf8PathBuf,
+        /// The path which the link points to.
+        target: Utf8PathBuf,
+    },
+
     /// Add a package from source to target.
     ///
     /// This is similar to "AddFile", though it requires unpacking the package
     /// and re-packaging it into the target.
     AddPackage(TargetPackage),
+
+    /// Add files from another package to the target.
+    ///
+    /// Unlike "AddPackage", only the files matching `paths` (glob patterns
+    /// of absolute paths, as installed) are copied.
+    AddPackageFiles {
+        package: TargetPackage,
+        paths: Vec<String>,
+    },
+
+    /// Records a value which influences the package, without adding anything
+    /// to the target archive.
+    ///
+    /// This exists so that changes to the value (such as the set of cargo
+    /// features used to build a binary) invalidate cached packages.
+    Fingerprint { name: String, value: String },
+
+    /// Records a file on the host which influences the package, without
+    /// adding it to the target archive.
+    ///
+    /// Like [Self::Fingerprint], this only exists to invalidate cached
+    /// packages when the file changes.
+    Dependency(Utf8PathBuf),
 }
 
 impl BuildInput {
@@ -78,12 +180,40 @@ impl BuildInput {
         match self {
             // This file is stored in-memory, it isn't cached.
             BuildInput::AddInMemoryFile { .. } => None,
+            // These entries are fabricated on the target, without contents.
+            BuildInput::AddEmptyFile { .. } | BuildInput::AddFifo { .. } => None,
             // This path doesn't need to exist on the host, it's just fabricated
             // on the target.
-            BuildInput::AddDirectory(_target) => None,
+            BuildInput::AddDirectory { .. } => None,
             BuildInput::AddFile { mapped_path, .. } => Some(&mapped_path.from),
             BuildInput::AddBlob { path, .. } => Some(&path.from),
+            // The link is fabricated on the target, from its target path.
+            BuildInput::AddSymlink { .. } => None,
             BuildInput::AddPackage(target_package) => Some(&target_package.0),
+            BuildInput::AddPackageFiles { package, .. } => Some(&package.0),
+            BuildInput::Fingerprint { .. } => None,
+            BuildInput::Dependency(path) => Some(path),
+        }
+    }
+
+    /// Returns the number of bytes which the input adds to the archive, if
+    /// that's known before it's added.
+    ///
+    /// This is unknown for blobs (which may not have been downloaded yet)
+    /// and for inputs read from other packages.
+    pub fn byte_len(&self) -> Option<u64> {
+        match self {
+            BuildInput::AddInMemoryFile { contents, .. } => Some(contents.len() as u64),
+            BuildInput::AddFile { len, .. } => Some(*len),
+            BuildInput::AddBlob { .. }
+            | BuildInput::AddPackage(_)
+            | BuildInput::AddPackageFiles { .. } => None,
+            BuildInput::AddEmptyFile { .. }
+            | BuildInput::AddFifo { .. }
+            | BuildInput::AddDirectory { .. }
+            | BuildInput::AddSymlink { .. }
+            | BuildInput::Fingerprint { .. }
+            | BuildInput::Dependency(_) => Some(0),
         }
     }
 
@@ -94,16 +224,297 @@ impl BuildInput {
             .with_context(|| format!("Failed to get length of {src}"))?
             .len();
 
-        Ok(Self::AddFile { mapped_path, len })
+        Ok(Self::AddFile {
+            mapped_path,
+            len,
+            metadata: EntryMetadata::default(),
+        })
+    }
+
+    /// Returns the path at which the input is placed within the archive, if
+    /// it adds a single entry.
+    pub fn destination(&self) -> Option<&Utf8Path> {
+        let path = match self {
+            BuildInput::AddInMemoryFile { dst_path, .. } => dst_path,
+            BuildInput::AddEmptyFile { path, .. } | BuildInput::AddFifo { path, .. } => path,
+            BuildInput::AddDirectory { dir, .. } => &dir.0,
+            BuildInput::AddFile { mapped_path, .. } => &mapped_path.to,
+            BuildInput::AddBlob { path, .. } => &path.to,
+            BuildInput::AddSymlink { link, .. } => link,
+            BuildInput::AddPackage(_)
+            | BuildInput::AddPackageFiles { .. }
+            | BuildInput::Fingerprint { .. }
+            | BuildInput::Dependency(_) => return None,
+        };
+        Some(path)
+    }
+
+    // Describes where the entry added by this input comes from, for errors.
+    fn describe_source(&self) -> String {
+        match self {
+            BuildInput::AddInMemoryFile { .. } => "generated contents".to_string(),
+            BuildInput::AddEmptyFile { .. } => "an empty file".to_string(),
+            BuildInput::AddFifo { .. } => "a FIFO".to_string(),
+            BuildInput::AddDirectory { .. } => "a directory".to_string(),
+            BuildInput::AddFile { mapped_path, .. } => format!("'{}'", mapped_path.from),
+            BuildInput::AddBlob { path, .. } => format!("blob '{}'", path.from),
+            BuildInput::AddSymlink { target, .. } => format!("a link to '{target}'"),
+            input => format!("{input:?}"),
+        }
+    }
+
+    /// Adds the directory `dir`, with default permissions and ownership.
+    pub fn add_directory(dir: TargetDirectory) -> Self {
+        Self::AddDirectory {
+            dir,
+            metadata: EntryMetadata::default(),
+        }
     }
 }
 
 /// A ordered collection of build inputs.
-pub struct BuildInputs(pub Vec<BuildInput>);
+///
+/// Where inputs came from is recorded alongside them, rather than among
+/// them; see [Self::iter_with_origins].
+#[derive(Clone, Debug)]
+pub struct BuildInputs(Vec<BuildInput>, InputOrigins);
+
+// The origins of consecutive ranges of inputs, in order. Inputs outside of
+// any range have no recorded origin.
+#[derive(Clone, Debug, Default)]
+struct InputOrigins(Vec<(Range<usize>, InputOrigin)>);
+
+impl InputOrigins {
+    // Records that the inputs within `range` came from `origin`.
+    fn push(&mut self, range: Range<usize>, origin: InputOrigin) {
+        if range.is_empty() {
+            return;
+        }
+        match self.0.last_mut() {
+            Some((last, last_origin)) if last.end == range.start && *last_origin == origin => {
+                last.end = range.end;
+            }
+            _ => self.0.push((range, origin)),
+        }
+    }
+
+    // Records that the input at `index`, which follows every range, came
+    // from `origin`.
+    fn push_one(&mut self, index: usize, origin: &InputOrigin) {
+        match self.0.last_mut() {
+            Some((last, last_origin)) if last.end == index && last_origin == origin => {
+                last.end = index + 1;
+            }
+            _ => self.0.push((index..index + 1, origin.clone())),
+        }
+    }
+
+    // Returns the index of the range containing the input at `index`.
+    fn find(&self, index: usize) -> Option<usize> {
+        let i = self.0.partition_point(|(range, _)| range.end <= index);
+        self.0
+            .get(i)
+            .filter(|(range, _)| range.contains(&index))
+            .map(|_| i)
+    }
+}
 
 impl BuildInputs {
     pub fn new() -> Self {
-        Self(vec![])
+        Self(vec![], InputOrigins::default())
+    }
+
+    /// Returns the number of inputs.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true if there are no inputs.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterates over the inputs, in order.
+    pub fn iter(&self) -> std::slice::Iter<'_, BuildInput> {
+        self.0.iter()
+    }
+
+    /// Returns the inputs, in order.
+    pub fn as_slice(&self) -> &[BuildInput] {
+        &self.0
+    }
+
+    /// Adds `input`, whose origin isn't recorded.
+    pub fn push(&mut self, input: BuildInput) {
+        self.0.push(input);
+    }
+
+    /// Iterates over the inputs, in order, along with where each came from.
+    pub fn iter_with_origins(
+        &self,
+    ) -> impl Iterator<Item = (Option<&InputOrigin>, &BuildInput)> + '_ {
+        self.0
+            .iter()
+            .enumerate()
+            .map(|(i, input)| (self.origin(i), input))
+    }
+
+    /// Returns where the input at `index` came from, if that's known.
+    pub fn origin(&self, index: usize) -> Option<&InputOrigin> {
+        self.1.find(index).map(|i| &self.1 .0[i].1)
+    }
+
+    /// Returns the number of bytes which the inputs are known to add to the
+    /// archive; see [BuildInput::byte_len].
+    pub fn byte_len(&self) -> u64 {
+        self.0.iter().filter_map(BuildInput::byte_len).sum()
+    }
+
+    /// Adds `input`, recording that it came from `origin`.
+    ///
+    /// Unlike [Self::extend_with_origin], this only copies `origin` if the
+    /// previous input came from elsewhere, so that inputs may be added one
+    /// at a time as they're discovered.
+    pub fn push_with_origin(&mut self, input: BuildInput, origin: &InputOrigin) {
+        self.1.push_one(self.0.len(), origin);
+        self.0.push(input);
+    }
+
+    /// Adds `inputs`, recording that they came from `origin`.
+    pub fn extend_with_origin<I: IntoIterator<Item = BuildInput>>(
+        &mut self,
+        origin: InputOrigin,
+        inputs: I,
+    ) {
+        let start = self.0.len();
+        self.0.extend(inputs);
+        self.1.push(start..self.0.len(), origin);
+    }
+
+    /// Adds `inputs`, along with where they came from.
+    pub fn append(&mut self, inputs: BuildInputs) {
+        let offset = self.0.len();
+        self.0.extend(inputs.0);
+        for (range, origin) in inputs.1 .0 {
+            self.1
+                .push(range.start + offset..range.end + offset, origin);
+        }
+    }
+
+    /// Ensures that no input is placed at an absolute path, which tarballs
+    /// cannot represent.
+    pub fn check_relative(&self) -> anyhow::Result<()> {
+        for (origin, input) in self.iter_with_origins() {
+            let Some(destination) = input.destination() else {
+                continue;
+            };
+            if destination.is_absolute() {
+                bail!(
+                    "{} would be placed at '{destination}', but paths within a \
+                     tarball must be relative",
+                    with_origin(input.describe_source(), origin),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes redundant inputs, and ensures that no two inputs place
+    /// different entries at the same path.
+    ///
+    /// Inputs from different sources (such as globs, rust binaries, and the
+    /// parent directories which zone images require of every path) often
+    /// overlap. Each directory is kept once, in its first position; if any
+    /// copy has explicit metadata, that's used. Files which are added more
+    /// than once are kept once, as are dependencies.
+    pub fn normalize(&mut self) -> anyhow::Result<()> {
+        // Indices of inputs within `normalized` (and of the ranges of their
+        // origins), by their destination.
+        let mut destinations: BTreeMap<Utf8PathBuf, (usize, Option<usize>)> = BTreeMap::new();
+        let mut dependencies = BTreeSet::new();
+        let mut normalized: Vec<BuildInput> = Vec::with_capacity(self.0.len());
+        let mut origins = InputOrigins::default();
+
+        for (i, input) in std::mem::take(&mut self.0).into_iter().enumerate() {
+            let origin = self.1.find(i);
+            if let BuildInput::Dependency(path) = &input {
+                if !dependencies.insert(path.clone()) {
+                    continue;
+                }
+            } else if let Some(destination) = input.destination() {
+                if let Some(&(index, previous_origin)) = destinations.get(destination) {
+                    if merge_duplicate(&mut normalized[index], &input) {
+                        continue;
+                    }
+                    let origin_of = |index: Option<usize>| index.map(|i| &self.1 .0[i].1);
+                    let previous = &normalized[index];
+                    if let (
+                        BuildInput::AddDirectory {
+                            metadata: previous_metadata,
+                            ..
+                        },
+                        BuildInput::AddDirectory { metadata, .. },
+                    ) = (previous, &input)
+                    {
+                        bail!(
+                            "Directory '{destination}' is added with conflicting metadata: \
+                             {} and {}",
+                            with_origin(
+                                format!("{previous_metadata:?}"),
+                                origin_of(previous_origin)
+                            ),
+                            with_origin(format!("{metadata:?}"), origin_of(origin)),
+                        );
+                    }
+                    bail!(
+                        "Both {} and {} would be placed at '{destination}'",
+                        with_origin(previous.describe_source(), origin_of(previous_origin)),
+                        with_origin(input.describe_source(), origin_of(origin)),
+                    );
+                }
+                destinations.insert(destination.to_path_buf(), (normalized.len(), origin));
+            }
+            if let Some(origin) = origin {
+                let index = normalized.len();
+                origins.push(index..index + 1, self.1 .0[origin].1.clone());
+            }
+            normalized.push(input);
+        }
+
+        self.0 = normalized;
+        self.1 = origins;
+        Ok(())
+    }
+}
+
+// Folds `input` into `previous`, an earlier input with the same
+// destination, returning false if they conflict.
+fn merge_duplicate(previous: &mut BuildInput, input: &BuildInput) -> bool {
+    if previous == input {
+        return true;
+    }
+    match (previous, input) {
+        (
+            BuildInput::AddDirectory {
+                metadata: previous_metadata,
+                ..
+            },
+            BuildInput::AddDirectory { metadata, .. },
+        ) => {
+            if previous_metadata.is_empty() {
+                *previous_metadata = *metadata;
+            }
+            metadata.is_empty() || previous_metadata == metadata
+        }
+        _ => false,
+    }
+}
+
+// Appends where an input came from to its description, for errors.
+fn with_origin(description: String, origin: Option<&InputOrigin>) -> String {
+    match origin {
+        Some(origin) => format!("{description} (from {origin})"),
+        None => description,
     }
 }
 
@@ -112,3 +523,210 @@ impl Default for BuildInputs {
         Self::new()
     }
 }
+
+impl From<Vec<BuildInput>> for BuildInputs {
+    fn from(inputs: Vec<BuildInput>) -> Self {
+        Self(inputs, InputOrigins::default())
+    }
+}
+
+impl Extend<BuildInput> for BuildInputs {
+    fn extend<I: IntoIterator<Item = BuildInput>>(&mut self, inputs: I) {
+        self.0.extend(inputs);
+    }
+}
+
+impl IntoIterator for BuildInputs {
+    type Item = BuildInput;
+    type IntoIter = std::vec::IntoIter<BuildInput>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a BuildInputs {
+    type Item = &'a BuildInput;
+    type IntoIter = std::slice::Iter<'a, BuildInput>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn dir(path: &str, mode: Option<u32>) -> BuildInput {
+        BuildInput::AddDirectory {
+            dir: TargetDirectory(path.into()),
+            metadata: EntryMetadata {
+                mode,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn file(from: &str, to: &str) -> BuildInput {
+        BuildInput::AddFile {
+            mapped_path: MappedPath {
+                from: from.into(),
+                to: to.into(),
+            },
+            len: 0,
+            metadata: EntryMetadata::default(),
+        }
+    }
+
+    #[test]
+    fn normalize_removes_duplicates() {
+        let mut inputs = BuildInputs::from(vec![
+            dir("root/", None),
+            dir("root/opt", None),
+            file("a.txt", "root/opt/a.txt"),
+            BuildInput::Dependency("build.rs".into()),
+            dir("root", None),
+            dir("root/opt", Some(0o700)),
+            file("a.txt", "root/opt/a.txt"),
+            BuildInput::Dependency("build.rs".into()),
+        ]);
+        inputs.normalize().unwrap();
+        assert_eq!(
+            inputs.0,
+            [
+                dir("root/", None),
+                dir("root/opt", Some(0o700)),
+                file("a.txt", "root/opt/a.txt"),
+                BuildInput::Dependency("build.rs".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn normalize_detects_conflicts() {
+        let mut inputs = BuildInputs::from(vec![
+            file("a.txt", "root/opt/a.txt"),
+            file("b.txt", "root/opt/a.txt"),
+        ]);
+        let err = inputs.normalize().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Both 'a.txt' and 'b.txt' would be placed at 'root/opt/a.txt'"
+        );
+
+        let mut inputs = BuildInputs::from(vec![dir("root/opt", None), file("a.txt", "root/opt")]);
+        let err = inputs.normalize().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Both a directory and 'a.txt' would be placed at 'root/opt'"
+        );
+
+        let mut inputs = BuildInputs::from(vec![
+            dir("root/opt", Some(0o700)),
+            dir("root/opt", Some(0o755)),
+        ]);
+        let err = inputs.normalize().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Directory 'root/opt' is added with conflicting metadata"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn normalize_reports_origins() {
+        let mut inputs = BuildInputs::new();
+        inputs.extend_with_origin(
+            InputOrigin::Path {
+                from: "a.txt".to_string(),
+                to: "root/opt/a.txt".to_string(),
+            },
+            [file("a.txt", "root/opt/a.txt")],
+        );
+        inputs.extend_with_origin(
+            InputOrigin::RustBinary {
+                name: "b".to_string(),
+            },
+            [file("b.txt", "root/opt/a.txt")],
+        );
+        assert_eq!(inputs.iter_with_origins().count(), 2);
+
+        let err = inputs.normalize().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Both 'a.txt' (from path { from = \"a.txt\", to = \"root/opt/a.txt\" }) \
+             and 'b.txt' (from rust binary \"b\") would be placed at 'root/opt/a.txt'"
+        );
+    }
+
+    #[test]
+    fn origins_follow_their_inputs() {
+        let blob = InputOrigin::Blob {
+            url: "https://example.com/blob".to_string(),
+        };
+        let mut inputs = BuildInputs::from(vec![dir("root/", None)]);
+        let mut appended = BuildInputs::new();
+        appended.extend_with_origin(blob.clone(), [dir("root/", None), file("a", "root/a")]);
+        appended.0.push(file("b", "root/b"));
+        inputs.append(appended);
+        assert_eq!(inputs.0.len(), 4);
+        assert_eq!(inputs.origin(0), None);
+        assert_eq!(inputs.origin(1), Some(&blob));
+        assert_eq!(inputs.origin(2), Some(&blob));
+        assert_eq!(inputs.origin(3), None);
+
+        // Removing the duplicate directory moves the inputs which follow,
+        // along with their origins.
+        inputs.normalize().unwrap();
+        assert_eq!(
+            inputs
+                .iter_with_origins()
+                .map(|(origin, input)| (origin, input.destination()))
+                .collect::<Vec<_>>(),
+            vec![
+                (None, Some(Utf8Path::new("root/"))),
+                (Some(&blob), Some(Utf8Path::new("root/a"))),
+                (None, Some(Utf8Path::new("root/b"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn pushed_origins_share_ranges() {
+        let blob = InputOrigin::Blob {
+            url: "https://example.com/blob".to_string(),
+        };
+        let mut inputs = BuildInputs::new();
+        inputs.push_with_origin(file("a", "root/a"), &blob);
+        inputs.push_with_origin(file("b", "root/b"), &blob);
+        inputs.push(file("c", "root/c"));
+        inputs.push_with_origin(file("d", "root/d"), &blob);
+        assert_eq!(inputs.1 .0, [(0..2, blob.clone()), (3..4, blob.clone())],);
+        assert_eq!(inputs.origin(1), Some(&blob));
+        assert_eq!(inputs.origin(2), None);
+    }
+
+    #[test]
+    fn byte_len_counts_known_sizes() {
+        let inputs = BuildInputs::from(vec![
+            BuildInput::AddInMemoryFile {
+                dst_path: "root/a".into(),
+                contents: "hello".to_string(),
+                metadata: EntryMetadata::default(),
+            },
+            BuildInput::AddFile {
+                mapped_path: MappedPath {
+                    from: "b".into(),
+                    to: "root/b".into(),
+                },
+                len: 1024,
+                metadata: EntryMetadata::default(),
+            },
+            BuildInput::AddPackage(TargetPackage("c.tar.gz".into())),
+            dir("root", None),
+        ]);
+        assert_eq!(inputs.0[2].byte_len(), None);
+        assert_eq!(inputs.byte_len(), 1029);
+    }
+}
@@ -0,0 +1,733 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Construction of illumos IPS package archives.
+//!
+//! A p5p archive is a tar archive containing, in order:
+//!
+//! - `pkg5.index.0.gz`, a gzip-compressed index of the entries which follow
+//!   it.
+//! - `pkg5.repository`, the configuration of the repository.
+//! - `publisher/<publisher>/pub.p5i`, describing the publisher.
+//! - For each package, its manifest at
+//!   `publisher/<publisher>/pkg/<stem>/<version>`, and the gzip-compressed
+//!   payload of each file at `publisher/<publisher>/file/<xx>/<hash>`, where
+//!   `hash` is the SHA-1 digest of the uncompressed file and `xx` is its
+//!   first two characters.
+//!
+//! Each line of the index describes one entry, as
+//! `<path>NUL<offset>NUL<entry size>NUL<size>NUL<type>NUL`. Offsets are
+//! relative to the end of the index's own entry, and entry sizes include the
+//! entry's headers and padding.
+
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use camino_tempfile::Utf8TempDir;
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+
+// The owner and group of all delivered files and directories.
+const OWNER: &str = "root";
+const GROUP: &str = "bin";
+
+// The name of the archive's index, which must be its first entry.
+const INDEX_PATH: &str = "pkg5.index.0.gz";
+
+// The type of regular files, as recorded in the index.
+const REGULAR_FILE: char = '0';
+
+// The format of timestamps within FMRIs.
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+enum Action {
+    Set {
+        name: String,
+        value: String,
+    },
+    Dir {
+        path: Utf8PathBuf,
+        mode: u32,
+    },
+    File {
+        path: Utf8PathBuf,
+        mode: u32,
+        hash: String,
+        size: u64,
+        csize: u64,
+        chash: String,
+    },
+}
+
+/// An IPS package under construction.
+pub struct IpsPackage {
+    publisher: String,
+    stem: String,
+    version: String,
+    timestamp: DateTime<Utc>,
+    actions: Vec<Action>,
+    directories: BTreeSet<Utf8PathBuf>,
+    // Compressed file payloads, named by their hash.
+    payloads: Utf8TempDir,
+}
+
+// Returns a copy of `path` suitable for an IPS action, which must be
+// relative to the image root.
+fn action_path(path: &Utf8Path) -> Utf8PathBuf {
+    path.components()
+        .filter(|c| matches!(c, camino::Utf8Component::Normal(_)))
+        .collect()
+}
+
+// Converts a semantic version to an IPS version.
+fn ips_version(version: &semver::Version) -> Result<String> {
+    if !version.pre.is_empty() || !version.build.is_empty() {
+        bail!("IPS versions cannot contain pre-release or build metadata: {version}");
+    }
+    Ok(format!(
+        "{}.{}.{}",
+        version.major, version.minor, version.patch
+    ))
+}
+
+// Percent-encodes a component of a path within the archive.
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+// Quotes an attribute value within a manifest, if necessary.
+fn quote_value(s: &str) -> String {
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// Returns the version of a package within its FMRI, as in
+// `1.2.3:20260101T000000Z`.
+fn fmri_version(version: &str, timestamp: &DateTime<Utc>) -> String {
+    format!("{version}:{}", timestamp.format(TIMESTAMP_FORMAT))
+}
+
+impl IpsPackage {
+    /// Creates an empty package, identified as
+    /// `pkg://<publisher>/<stem>@<version>:<timestamp>`.
+    ///
+    /// So that builds are reproducible, the timestamp is fixed until the
+    /// package is stamped by [restamp].
+    pub fn new(publisher: &str, stem: &str, version: &semver::Version) -> Result<Self> {
+        Ok(Self {
+            publisher: publisher.to_string(),
+            stem: stem.to_string(),
+            version: ips_version(version)?,
+            timestamp: DateTime::from_timestamp(crate::archive::DETERMINISTIC_MTIME as i64, 0)
+                .expect("The fixed timestamp is valid"),
+            actions: vec![],
+            directories: BTreeSet::new(),
+            payloads: camino_tempfile::tempdir()?,
+        })
+    }
+
+    /// Returns the FMRI identifying the package.
+    pub fn fmri(&self) -> String {
+        format!(
+            "pkg://{}/{}@{}",
+            self.publisher,
+            self.stem,
+            fmri_version(&self.version, &self.timestamp)
+        )
+    }
+
+    /// Adds a package attribute, such as `pkg.summary`.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.actions.push(Action::Set {
+            name: name.to_string(),
+            value: value.to_string(),
+        });
+    }
+
+    /// Adds a directory at `path`, along with any of its missing parents.
+    pub fn add_directory(&mut self, path: &Utf8Path) {
+        let path = action_path(path);
+        if path.as_str().is_empty() || self.directories.contains(&path) {
+            return;
+        }
+        if let Some(parent) = path.parent() {
+            self.add_directory(parent);
+        }
+        self.directories.insert(path.clone());
+        self.actions.push(Action::Dir { path, mode: 0o755 });
+    }
+
+    /// Adds a directory at `path` with the permissions `mode`.
+    ///
+    /// If the directory has already been added, its permissions are updated.
+    pub fn add_directory_with_mode(&mut self, path: &Utf8Path, mode: u32) {
+        self.add_directory(path);
+        let path = action_path(path);
+        for action in &mut self.actions {
+            if let Action::Dir {
+                path: dir,
+                mode: dir_mode,
+            } = action
+            {
+                if *dir == path {
+                    *dir_mode = mode;
+                }
+            }
+        }
+    }
+
+    /// Adds a file at `path` with the contents and permissions of `src`.
+    pub fn add_file(&mut self, path: &Utf8Path, src: &Utf8Path) -> Result<()> {
+        self.add_file_with_mode(path, src, None)
+    }
+
+    /// Identical to [Self::add_file], but uses the permissions `mode`
+    /// rather than those of `src`, if provided.
+    pub fn add_file_with_mode(
+        &mut self,
+        path: &Utf8Path,
+        src: &Utf8Path,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        let mode = match mode {
+            Some(mode) => mode,
+            None => file_mode(&src.metadata()?),
+        };
+        let file = File::open(src).with_context(|| format!("Cannot open {src}"))?;
+        self.add_reader(path, file, mode)
+    }
+
+    /// Adds a file at `path` containing `data`.
+    pub fn add_data(&mut self, path: &Utf8Path, data: &[u8], mode: u32) -> Result<()> {
+        self.add_reader(path, data, mode)
+    }
+
+    fn add_reader<R: Read>(&mut self, path: &Utf8Path, mut reader: R, mode: u32) -> Result<()> {
+        let path = action_path(path);
+        if let Some(parent) = path.parent() {
+            self.add_directory(parent);
+        }
+
+        // Compress the payload while hashing it, since the manifest records
+        // the digests of both the original and compressed contents.
+        let tmp = self.payloads.path().join("payload");
+        let mut encoder = GzEncoder::new(
+            HashingWriter::new(File::create(&tmp)?),
+            flate2::Compression::default(),
+        );
+        let mut hasher = Sha1::new();
+        let mut size = 0;
+        let mut buf = [0; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            encoder.write_all(&buf[..n])?;
+            size += n as u64;
+        }
+        let compressed = encoder.finish()?;
+        let hash = hex::encode(hasher.finalize());
+        let chash = hex::encode(compressed.hasher.finalize());
+        std::fs::rename(&tmp, self.payloads.path().join(&hash))?;
+
+        self.actions.push(Action::File {
+            path,
+            mode,
+            hash,
+            size,
+            csize: compressed.len,
+            chash,
+        });
+        Ok(())
+    }
+
+    /// Returns the package manifest.
+    pub fn manifest(&self) -> String {
+        let mut manifest = format!("set name=pkg.fmri value={}\n", self.fmri());
+        for action in &self.actions {
+            let line = match action {
+                Action::Set { name, value } => {
+                    format!(
+                        "set name={} value={}",
+                        quote_value(name),
+                        quote_value(value)
+                    )
+                }
+                Action::Dir { path, mode } => format!(
+                    "dir group={GROUP} mode={mode:04o} owner={OWNER} path={}",
+                    quote_value(path.as_str())
+                ),
+                Action::File {
+                    path,
+                    mode,
+                    hash,
+                    size,
+                    csize,
+                    chash,
+                } => format!(
+                    "file {hash} chash={chash} group={GROUP} mode={mode:04o} owner={OWNER} \
+                     path={} pkg.csize={csize} pkg.size={size}",
+                    quote_value(path.as_str())
+                ),
+            };
+            manifest.push_str(&line);
+            manifest.push('\n');
+        }
+        manifest
+    }
+
+    /// Writes the package as a p5p archive to `writer`.
+    pub fn write<W: Write>(self, writer: W) -> Result<W> {
+        let mut archive = P5pWriter::new()?;
+        archive.append_publisher(&self.publisher)?;
+        let manifest = self.manifest();
+        archive.append(
+            &manifest_path(
+                &self.publisher,
+                &self.stem,
+                &fmri_version(&self.version, &self.timestamp),
+            ),
+            manifest.len() as u64,
+            &mut manifest.as_bytes(),
+        )?;
+
+        let mut written = std::collections::BTreeSet::new();
+        for action in &self.actions {
+            let Action::File { hash, .. } = action else {
+                continue;
+            };
+            if !written.insert(hash) {
+                continue;
+            }
+            let src = self.payloads.path().join(hash);
+            let mut file = File::open(&src)?;
+            archive.append(
+                &format!(
+                    "publisher/{}/file/{}/{hash}",
+                    quote(&self.publisher),
+                    &hash[..2]
+                ),
+                file.metadata()?.len(),
+                &mut file,
+            )?;
+        }
+        archive.finish(writer)
+    }
+}
+
+// Returns the path of a package's manifest within an archive.
+fn manifest_path(publisher: &str, stem: &str, version: &str) -> String {
+    format!(
+        "publisher/{}/pkg/{}/{}",
+        quote(publisher),
+        quote(stem),
+        quote(version)
+    )
+}
+
+// Returns the header of an entry within an archive, holding `size` bytes.
+fn entry_header(size: u64) -> tar::Header {
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_mtime(crate::archive::DETERMINISTIC_MTIME);
+    header.set_size(size);
+    header
+}
+
+// Writes the entries of a p5p archive, which must be preceded by an index
+// of them. Entries are written to a temporary file until the index is
+// complete.
+struct P5pWriter {
+    entries: tar::Builder<CountingWriter<File>>,
+    index: Vec<u8>,
+}
+
+impl P5pWriter {
+    fn new() -> Result<Self> {
+        let mut writer = Self {
+            entries: tar::Builder::new(CountingWriter::new(camino_tempfile::tempfile()?)),
+            index: vec![],
+        };
+        let config = "[repository]\nversion = 4\n";
+        writer.append(
+            "pkg5.repository",
+            config.len() as u64,
+            &mut config.as_bytes(),
+        )?;
+        Ok(writer)
+    }
+
+    // Appends the description of `publisher`.
+    fn append_publisher(&mut self, publisher: &str) -> Result<()> {
+        let p5i = serde_json::to_vec(&serde_json::json!({
+            "packages": [],
+            "publishers": [{
+                "alias": null,
+                "name": publisher,
+                "packages": [],
+                "repositories": [],
+            }],
+            "version": 1,
+        }))?;
+        self.append(
+            &format!("publisher/{}/pub.p5i", quote(publisher)),
+            p5i.len() as u64,
+            &mut p5i.as_slice(),
+        )
+    }
+
+    fn append(&mut self, path: &str, size: u64, data: &mut dyn Read) -> Result<()> {
+        let offset = self.entries.get_ref().len;
+        self.entries
+            .append_data(&mut entry_header(size), path, data)?;
+        let entry_size = self.entries.get_ref().len - offset;
+        writeln!(
+            self.index,
+            "{path}\0{offset}\0{entry_size}\0{size}\0{REGULAR_FILE}\0"
+        )?;
+        Ok(())
+    }
+
+    // Writes the index, followed by the entries, to `writer`.
+    fn finish<W: Write>(self, writer: W) -> Result<W> {
+        let len = self.entries.get_ref().len;
+        let mut entries = self.entries.into_inner()?.inner;
+        entries.rewind()?;
+
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&self.index)?;
+        let index = encoder.finish()?;
+        let mut builder = tar::Builder::new(writer);
+        builder.append_data(
+            &mut entry_header(index.len() as u64),
+            INDEX_PATH,
+            index.as_slice(),
+        )?;
+        // Entries are copied without the end-of-archive marker.
+        std::io::copy(&mut entries.take(len), builder.get_mut())?;
+        Ok(builder.into_inner()?)
+    }
+}
+
+/// Rewrites a p5p archive read from `reader` to `writer`, changing the
+/// version of its package to `version`, and its timestamp to the present.
+///
+/// Each entry of `metadata` is recorded as a package attribute, replacing
+/// any existing attribute with the same name.
+pub fn restamp<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    version: &semver::Version,
+    metadata: &BTreeMap<String, String>,
+) -> Result<W> {
+    let version = fmri_version(&ips_version(version)?, &Utc::now());
+    let replaced: BTreeSet<String> = metadata
+        .keys()
+        .map(|name| format!("set name={} ", quote_value(name)))
+        .collect();
+    let mut archive = tar::Archive::new(reader);
+    let mut stamped = P5pWriter::new()?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = Utf8PathBuf::try_from(entry.path()?.into_owned())?;
+        let components: Vec<&str> = path.iter().collect();
+        match components.as_slice() {
+            // These are written anew.
+            [INDEX_PATH] | ["pkg5.repository"] => (),
+            ["publisher", publisher, "pkg", stem, _] => {
+                let mut manifest = String::new();
+                entry.read_to_string(&mut manifest)?;
+                let mut manifest = manifest
+                    .lines()
+                    .filter(|line| !replaced.iter().any(|prefix| line.starts_with(prefix)))
+                    .map(|line| match line.strip_prefix("set name=pkg.fmri value=") {
+                        Some(fmri) => {
+                            let stem = fmri.rsplit_once('@').map_or(fmri, |(stem, _)| stem);
+                            format!("set name=pkg.fmri value={stem}@{version}\n")
+                        }
+                        None => format!("{line}\n"),
+                    })
+                    .collect::<String>();
+                for (name, value) in metadata {
+                    manifest.push_str(&format!(
+                        "set name={} value={}\n",
+                        quote_value(name),
+                        quote_value(value)
+                    ));
+                }
+                stamped.append(
+                    &format!("publisher/{publisher}/pkg/{stem}/{}", quote(&version)),
+                    manifest.len() as u64,
+                    &mut manifest.as_bytes(),
+                )?;
+            }
+            _ => {
+                let size = entry.header().size()?;
+                stamped.append(path.as_str(), size, &mut entry)?;
+            }
+        }
+    }
+    stamped.finish(writer)
+}
+
+fn file_mode(meta: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        if meta.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        }
+    }
+}
+
+// Counts the bytes written through it.
+struct CountingWriter<W> {
+    inner: W,
+    len: u64,
+}
+
+impl<W> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, len: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Computes the SHA-1 digest and length of data as it is written.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha1,
+    len: u64,
+}
+
+impl<W> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha1::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ips_package_round_trip() {
+        let mut pkg =
+            IpsPackage::new("oxide", "my-service", &semver::Version::new(0, 0, 0)).unwrap();
+        pkg.set("pkg.summary", "My service");
+        pkg.set("git_commit", "abc");
+        pkg.add_directory(Utf8Path::new("/opt/oxide"));
+        pkg.add_data(Utf8Path::new("/opt/oxide/a.txt"), b"hello", 0o644)
+            .unwrap();
+        pkg.add_data(Utf8Path::new("opt/oxide/b.txt"), b"hello", 0o755)
+            .unwrap();
+        pkg.add_directory_with_mode(Utf8Path::new("/opt"), 0o700);
+
+        let hash = "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d";
+        let manifest = pkg.manifest();
+        let mut lines = manifest.lines();
+        assert_eq!(
+            lines.next(),
+            Some("set name=pkg.fmri value=pkg://oxide/my-service@0.0.0:20060724T012128Z")
+        );
+        assert_eq!(
+            lines.next(),
+            Some(r#"set name=pkg.summary value="My service""#)
+        );
+        assert_eq!(lines.next(), Some("set name=git_commit value=abc"));
+        assert_eq!(
+            lines.next(),
+            Some("dir group=bin mode=0700 owner=root path=opt")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("dir group=bin mode=0755 owner=root path=opt/oxide")
+        );
+        let file = lines.next().unwrap();
+        assert!(file.starts_with(&format!("file {hash} chash=")), "{file}");
+        assert!(
+            file.ends_with("path=opt/oxide/a.txt pkg.csize=25 pkg.size=5"),
+            "{file}"
+        );
+        assert!(lines.next().unwrap().contains("mode=0755"));
+        assert!(lines.next().is_none());
+
+        // Identical payloads are only stored once.
+        let bytes = pkg.write(vec![]).unwrap();
+        let metadata = BTreeMap::from([("git_commit".to_string(), "def".to_string())]);
+        let stamped = restamp(
+            bytes.as_slice(),
+            vec![],
+            &semver::Version::new(1, 2, 3),
+            &metadata,
+        )
+        .unwrap();
+        let mut archive = tar::Archive::new(stamped.as_slice());
+        let mut entries = archive.entries().unwrap().skip(3);
+        let mut manifest = entries.next().unwrap().unwrap();
+        let path = manifest.path().unwrap().to_str().unwrap().to_string();
+        assert!(
+            path.starts_with("publisher/oxide/pkg/my-service/1.2.3%3A"),
+            "{path}"
+        );
+        let mut contents = String::new();
+        manifest.read_to_string(&mut contents).unwrap();
+        assert!(contents.starts_with("set name=pkg.fmri value=pkg://oxide/my-service@1.2.3:"));
+        assert!(!contents.contains("value=abc"));
+        assert!(contents.ends_with("set name=git_commit value=def\n"));
+
+        let payload = entries.next().unwrap().unwrap();
+        assert_eq!(
+            payload.path().unwrap().to_str(),
+            Some(format!("publisher/oxide/file/aa/{hash}").as_str())
+        );
+        let mut data = String::new();
+        flate2::read::GzDecoder::new(payload)
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "hello");
+        assert!(entries.next().is_none());
+    }
+
+    // Checks that `archive` begins with an index describing every other
+    // entry, returning their paths.
+    fn check_index(archive: &[u8]) -> Vec<String> {
+        let header = |offset: usize| tar::Header::from_byte_slice(&archive[offset..offset + 512]);
+        let index = header(0);
+        assert_eq!(index.path().unwrap().to_str(), Some(INDEX_PATH));
+        let size = index.size().unwrap() as usize;
+        let mut contents = String::new();
+        flate2::read::GzDecoder::new(&archive[512..512 + size])
+            .read_to_string(&mut contents)
+            .unwrap();
+
+        // Offsets are relative to the end of the index.
+        let base = 512 + size.next_multiple_of(512);
+        let mut next = 0;
+        let mut paths = vec![];
+        for line in contents.lines() {
+            let fields: Vec<&str> = line.split('\0').collect();
+            let [path, offset, entry_size, size, "0", ""] = fields.as_slice() else {
+                panic!("Invalid index entry: {line:?}");
+            };
+            let offset: usize = offset.parse().unwrap();
+            let size: u64 = size.parse().unwrap();
+            assert_eq!(offset, next, "{path}");
+            let entry = header(base + offset);
+            assert_eq!(entry.path().unwrap().to_str(), Some(*path));
+            assert_eq!(entry.size().unwrap(), size);
+            next = offset + entry_size.parse::<usize>().unwrap();
+            paths.push(path.to_string());
+        }
+        // Only the end of the archive follows the last entry.
+        assert_eq!(archive.len(), base + next + 1024);
+        assert!(archive[base + next..].iter().all(|b| *b == 0));
+        paths
+    }
+
+    #[test]
+    fn p5p_archive_structure() {
+        let mut pkg =
+            IpsPackage::new("oxide", "my-service", &semver::Version::new(0, 0, 0)).unwrap();
+        pkg.add_data(Utf8Path::new("/opt/oxide/a.txt"), b"hello", 0o644)
+            .unwrap();
+        pkg.add_data(Utf8Path::new("/opt/oxide/b.txt"), &[7; 2000], 0o644)
+            .unwrap();
+        let bytes = pkg.write(vec![]).unwrap();
+
+        let paths = check_index(&bytes);
+        assert_eq!(paths.len(), 5, "{paths:?}");
+        assert_eq!(
+            paths[..3],
+            [
+                "pkg5.repository",
+                "publisher/oxide/pub.p5i",
+                "publisher/oxide/pkg/my-service/0.0.0%3A20060724T012128Z",
+            ]
+        );
+        assert!(paths[3..]
+            .iter()
+            .all(|path| path.starts_with("publisher/oxide/file/")));
+
+        let mut archive = tar::Archive::new(bytes.as_slice());
+        let mut entries = archive.entries().unwrap().skip(1);
+        let mut config = String::new();
+        let mut entry = entries.next().unwrap().unwrap();
+        entry.read_to_string(&mut config).unwrap();
+        assert!(config.contains("[repository]\nversion = 4\n"), "{config}");
+        let p5i: serde_json::Value =
+            serde_json::from_reader(entries.next().unwrap().unwrap()).unwrap();
+        assert_eq!(p5i["publishers"][0]["name"], "oxide");
+
+        // Stamping rewrites the index to match the new manifest
+        let stamped = restamp(
+            bytes.as_slice(),
+            vec![],
+            &semver::Version::new(1, 2, 3),
+            &BTreeMap::from([("commit".to_string(), "abc".to_string())]),
+        )
+        .unwrap();
+        let stamped_paths = check_index(&stamped);
+        assert_eq!(stamped_paths.len(), paths.len());
+        assert!(stamped_paths[2].starts_with("publisher/oxide/pkg/my-service/1.2.3%3A"));
+        assert_eq!(stamped_paths[3..], paths[3..]);
+    }
+
+    #[test]
+    fn ips_version_rejects_prerelease() {
+        let version = semver::Version::parse("1.0.0-rc.1").unwrap();
+        assert!(IpsPackage::new("oxide", "pkg", &version).is_err());
+    }
+}
@@ -0,0 +1,50 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Updates JSON files which are shared by every build using an output
+//! directory
+
+use anyhow::anyhow;
+use camino::Utf8PathBuf;
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// Applies `f` to the value stored as JSON at `path`, writing it back
+// afterwards.
+//
+// The file is locked while it's updated, and replaced atomically, so that
+// concurrent builds neither lose each other's updates nor observe a partially
+// written file. A missing or unreadable file is treated as holding the default
+// value.
+pub(crate) async fn update_locked_json<T, R, F>(path: Utf8PathBuf, f: F) -> anyhow::Result<R>
+where
+    T: Default + DeserializeOwned + Serialize,
+    R: Send + 'static,
+    F: FnOnce(&mut T) -> R + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_extension("lock"))?;
+        lock.lock_exclusive()?;
+
+        let mut value = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => T::default(),
+            Err(e) => return Err(anyhow!(e).context(format!("Reading {path}"))),
+        };
+        let result = f(&mut value);
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(&value)?)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(result)
+    })
+    .await?
+}
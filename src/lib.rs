@@ -2,13 +2,22 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-mod archive;
+pub mod archive;
 pub mod blob;
 pub mod cache;
+pub mod cargo;
 pub mod config;
 mod digest;
+pub mod history;
+pub mod hook;
 pub mod input;
+pub mod ips;
+mod json_file;
 pub mod package;
 pub mod progress;
+pub mod provenance;
+pub mod report;
+pub mod sbom;
+pub mod smf;
 pub mod target;
 mod timer;
@@ -5,26 +5,40 @@
 //! Utility for bundling target binaries as tarfiles.
 
 use crate::archive::{
-    add_package_to_zone_archive, create_tarfile, open_tarfile, ArchiveBuilder, AsyncAppendFile,
-    Encoder,
+    add_component_to_zone_archive, copy_tarball_archive, copy_zone_archive, create_tarfile,
+    entry_contents, new_compressed_archive_writer, open_tarfile, visit_package_entries,
+    ArchiveBuilder, ComponentFiles, ComponentOptions, Compression, Compressor, Encoder,
+    EntryMetadata, InMemoryEntry,
 };
-use crate::blob::{self, BLOB};
-use crate::cache::{Cache, CacheError};
+use crate::blob::{self, get_sha256_digest, BlobFreshness, BLOB};
+use crate::cache::{
+    ArtifactLock, Cache, CacheBackend, CacheCounters, CacheError, CacheMissKind, DigestAlgorithm,
+    DigestMemo, ManifestEncoding, MissReason, DEFAULT_HASHING_PARALLELISM,
+};
+use crate::cargo::CargoMetadata;
 use crate::config::{PackageName, ServiceName};
-use crate::input::{BuildInput, BuildInputs, MappedPath, TargetDirectory, TargetPackage};
-use crate::progress::{NoProgress, Progress};
-use crate::target::TargetMap;
+use crate::history::{BuildHistory, PhaseRecord};
+use crate::hook::{run_with_progress, BuildHook};
+use crate::input::{
+    BuildInput, BuildInputs, InputOrigin, MappedPath, TargetDirectory, TargetPackage,
+};
+use crate::ips::IpsPackage;
+use crate::progress::{NoProgress, Progress, ProgressEvent};
+use crate::report::{BuildReport, CacheOutcome};
+use crate::smf::SmfConfig;
+use crate::target::{TargetExpr, TargetFilter, TargetMap};
 use crate::timer::BuildTimer;
 
 use anyhow::{anyhow, bail, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use flate2::write::GzEncoder;
+use camino_tempfile::NamedUtf8TempFile;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
 use std::fs::File;
+use std::path::Path;
 use tar::Builder;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
 
 // Returns the path as it should be placed within an archive, by
 // prepending "root/".
@@ -64,28 +78,449 @@ fn zone_get_all_parent_inputs(to: &Utf8Path) -> Result<Vec<TargetDirectory>> {
     Ok(outputs)
 }
 
+/// The default server from which prebuilt packages and blobs are downloaded.
+pub const DEFAULT_BUILDOMAT_URL: &str = "https://buildomat.eng.oxide.computer";
+
+/// The default GitHub organization owning the repositories of prebuilt
+/// packages.
+pub const DEFAULT_BUILDOMAT_ORG: &str = "oxidecomputer";
+
 /// Describes a path to a Buildomat-generated artifact that should reside at
 /// the following path:
 ///
 /// <https://buildomat.eng.oxide.computer/public/file/oxidecomputer/REPO/SERIES/COMMIT/ARTIFACT>
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct PrebuiltBlob {
     pub repo: String,
     pub series: String,
     pub commit: String,
     pub artifact: String,
     pub sha256: String,
+
+    /// Destination path of the blob within the package.
+    ///
+    /// If omitted, the blob is placed in the package's blob directory.
+    //
+    // This is always serialized, even when unset, since cache manifests may
+    // be encoded with postcard, which isn't self-describing.
+    #[serde(default)]
+    pub to: Option<InterpolatedString>,
+}
+
+// A value which may be written in a manifest either as a string, or as a
+// table with more detail.
+//
+// Unlike an untagged enum, this reports errors within the table (such as
+// unknown fields) as they are, rather than as a failure to match any
+// variant, so that they may be located, and ignored in lenient mode.
+enum StringOrTable<S, T> {
+    String(S),
+    Table(T),
+}
+
+impl<'de, S, T> Deserialize<'de> for StringOrTable<S, T>
+where
+    S: for<'a> From<&'a str>,
+    T: Deserialize<'de>,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct StringOrTableVisitor<S, T>(std::marker::PhantomData<(S, T)>);
+
+        impl<'de, S, T> serde::de::Visitor<'de> for StringOrTableVisitor<S, T>
+        where
+            S: for<'a> From<&'a str>,
+            T: Deserialize<'de>,
+        {
+            type Value = StringOrTable<S, T>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a string or a table")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(StringOrTable::String(S::from(value)))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> Result<Self::Value, A::Error> {
+                T::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+                    .map(StringOrTable::Table)
+            }
+        }
+
+        deserializer.deserialize_any(StringOrTableVisitor(std::marker::PhantomData))
+    }
+}
+
+/// Describes a blob from the Omicron build S3 bucket.
+///
+/// This may be written in a manifest as a plain path, or as a table with
+/// `path` and `to` keys.
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(from = "S3BlobSpec")]
+pub struct S3Blob {
+    /// Path of the blob within the bucket.
+    pub path: Utf8PathBuf,
+
+    /// Destination path of the blob within the package.
+    ///
+    /// If omitted, the blob is placed in the package's blob directory.
+    pub to: Option<InterpolatedString>,
+}
+
+type S3BlobSpec = StringOrTable<Utf8PathBuf, S3BlobTable>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct S3BlobTable {
+    path: Utf8PathBuf,
+    #[serde(default)]
+    to: Option<InterpolatedString>,
+}
+
+impl From<S3BlobSpec> for S3Blob {
+    fn from(spec: S3BlobSpec) -> Self {
+        match spec {
+            S3BlobSpec::String(path) => S3Blob { path, to: None },
+            S3BlobSpec::Table(S3BlobTable { path, to }) => S3Blob { path, to },
+        }
+    }
+}
+
+/// Describes an empty directory created within a package.
+///
+/// This may be written in a manifest as a plain path, or as a table with
+/// `path`, `mode`, `uid`, and `gid` keys.
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(from = "PackageDirectorySpec")]
+pub struct PackageDirectory {
+    /// Path of the directory within the package.
+    pub path: InterpolatedString,
+
+    /// Permission bits of the directory.
+    pub mode: u32,
+
+    /// Numeric ID of the directory's owner.
+    pub uid: u64,
+
+    /// Numeric ID of the directory's group.
+    pub gid: u64,
+}
+
+fn default_directory_mode() -> u32 {
+    0o755
+}
+
+type PackageDirectorySpec = StringOrTable<InterpolatedString, PackageDirectoryTable>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PackageDirectoryTable {
+    path: InterpolatedString,
+    #[serde(default = "default_directory_mode")]
+    mode: u32,
+    #[serde(default)]
+    uid: u64,
+    #[serde(default)]
+    gid: u64,
+}
+
+impl From<PackageDirectorySpec> for PackageDirectory {
+    fn from(spec: PackageDirectorySpec) -> Self {
+        match spec {
+            PackageDirectorySpec::String(path) => PackageDirectory {
+                path,
+                mode: default_directory_mode(),
+                uid: 0,
+                gid: 0,
+            },
+            PackageDirectorySpec::Table(PackageDirectoryTable {
+                path,
+                mode,
+                uid,
+                gid,
+            }) => PackageDirectory {
+                path,
+                mode,
+                uid,
+                gid,
+            },
+        }
+    }
+}
+
+/// The kind of entry created by a [PackagePlaceholder].
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaceholderKind {
+    /// An empty regular file.
+    #[default]
+    File,
+    /// A named pipe. Only supported by tar-based outputs.
+    Fifo,
+}
+
+/// Describes an empty file or FIFO which should be created within a
+/// package, for services which expect it to exist at runtime.
+///
+/// This may be written in a manifest as a plain path, or as a table with
+/// `path`, `kind`, `mode`, `uid`, and `gid` keys.
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(from = "PackagePlaceholderSpec")]
+pub struct PackagePlaceholder {
+    /// Path of the entry within the package.
+    pub path: InterpolatedString,
+
+    /// The kind of entry to create.
+    pub kind: PlaceholderKind,
+
+    /// Permission bits of the entry.
+    pub mode: u32,
+
+    /// Numeric ID of the entry's owner.
+    pub uid: u64,
+
+    /// Numeric ID of the entry's group.
+    pub gid: u64,
+}
+
+fn default_placeholder_mode() -> u32 {
+    0o644
+}
+
+type PackagePlaceholderSpec = StringOrTable<InterpolatedString, PackagePlaceholderTable>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PackagePlaceholderTable {
+    path: InterpolatedString,
+    #[serde(default)]
+    kind: PlaceholderKind,
+    #[serde(default = "default_placeholder_mode")]
+    mode: u32,
+    #[serde(default)]
+    uid: u64,
+    #[serde(default)]
+    gid: u64,
+}
+
+impl From<PackagePlaceholderSpec> for PackagePlaceholder {
+    fn from(spec: PackagePlaceholderSpec) -> Self {
+        match spec {
+            PackagePlaceholderSpec::String(path) => PackagePlaceholder {
+                path,
+                kind: PlaceholderKind::default(),
+                mode: default_placeholder_mode(),
+                uid: 0,
+                gid: 0,
+            },
+            PackagePlaceholderSpec::Table(PackagePlaceholderTable {
+                path,
+                kind,
+                mode,
+                uid,
+                gid,
+            }) => PackagePlaceholder {
+                path,
+                kind,
+                mode,
+                uid,
+                gid,
+            },
+        }
+    }
+}
+
+/// Describes a component of a composite package.
+///
+/// This may be written in a manifest as the file name of the component's
+/// package, or as a table with a `package` key and additional options:
+///
+/// ```toml
+/// source.packages = [
+///     "base.tar.gz",
+///     { package = "my-service.tar.gz", exclude = ["/opt/oxide/my-service/*.toml"] },
+///     { package = "other.tar.gz", rename = { "/etc/default.conf" = "/etc/other.conf" } },
+///     { package = "propolis-server", manifest = "../propolis/package-manifest.toml" },
+///     { package = "overrides.tar.gz", allow_override = true, after = ["other.tar.gz"] },
+/// ]
+/// ```
+///
+/// Components are merged in the order in which they're listed, except as
+/// required by [Self::after].
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(from = "CompositeComponentSpec")]
+pub struct CompositeComponent {
+    /// The file name of the component's package within the output directory.
+    pub package: String,
+
+    /// If "true", files within this component may replace files with the
+    /// same path from earlier components, which are then omitted.
+    ///
+    /// Otherwise, such conflicts are an error.
+    pub allow_override: bool,
+
+    /// The file names of other components which must be merged before this
+    /// one, such as those whose files it overrides.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+
+    /// Glob patterns matching paths within the zone which are omitted from
+    /// this component.
+    pub exclude: Vec<String>,
+
+    /// Paths within the zone which are added under a different path, keyed
+    /// by their original path.
+    pub rename: BTreeMap<Utf8PathBuf, Utf8PathBuf>,
+
+    /// Another manifest, which defines the component's package.
+    ///
+    /// If set, [Self::package] names the package, rather than its file. The
+    /// path is relative to the directory containing this manifest. When
+    /// parsed, the package (and any packages it depends upon) is added to
+    /// this manifest's configuration, so that it is built first, and
+    /// [Self::package] is replaced by the file it creates.
+    ///
+    /// Relative paths from which the imported packages are built are
+    /// relative to the directory containing the other manifest.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<Utf8PathBuf>,
+}
+
+impl CompositeComponent {
+    // Returns true if any options differ from the defaults.
+    fn has_options(&self) -> bool {
+        self.allow_override
+            || !self.after.is_empty()
+            || !self.exclude.is_empty()
+            || !self.rename.is_empty()
+    }
+
+    /// Returns the options used when adding this component to a zone image.
+    pub fn options(&self) -> Result<ComponentOptions> {
+        let exclude = self
+            .exclude
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern)
+                    .with_context(|| format!("Invalid pattern '{pattern}' in {}", self.package))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (from, to) in &self.rename {
+            if !from.is_absolute() || !to.is_absolute() {
+                bail!(
+                    "Cannot rename '{from}' to '{to}' in {}: paths must be absolute",
+                    self.package
+                );
+            }
+        }
+        Ok(ComponentOptions {
+            allow_override: self.allow_override,
+            exclude,
+            rename: self.rename.clone(),
+        })
+    }
+}
+
+type CompositeComponentSpec = StringOrTable<String, CompositeComponentTable>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompositeComponentTable {
+    package: String,
+    #[serde(default)]
+    allow_override: bool,
+    #[serde(default)]
+    after: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    rename: BTreeMap<Utf8PathBuf, Utf8PathBuf>,
+    #[serde(default)]
+    manifest: Option<Utf8PathBuf>,
+}
+
+impl From<CompositeComponentSpec> for CompositeComponent {
+    fn from(spec: CompositeComponentSpec) -> Self {
+        match spec {
+            CompositeComponentSpec::String(package) => CompositeComponent {
+                package,
+                allow_override: false,
+                after: vec![],
+                exclude: vec![],
+                rename: BTreeMap::new(),
+                manifest: None,
+            },
+            CompositeComponentSpec::Table(CompositeComponentTable {
+                package,
+                allow_override,
+                after,
+                exclude,
+                rename,
+                manifest,
+            }) => CompositeComponent {
+                package,
+                allow_override,
+                after,
+                exclude,
+                rename,
+                manifest,
+            },
+        }
+    }
+}
+
+impl From<String> for CompositeComponent {
+    fn from(package: String) -> Self {
+        CompositeComponentSpec::String(package).into()
+    }
+}
+
+impl From<&str> for CompositeComponent {
+    fn from(package: &str) -> Self {
+        package.to_string().into()
+    }
+}
+
+/// Describes files copied from another package in the same manifest.
+///
+/// The other package is built first. For example:
+///
+/// ```toml
+/// source.package_files = [
+///     { package = "generator", paths = ["/opt/oxide/generator/*.json"] },
+/// ]
+/// ```
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PackageFiles {
+    /// The name of the other package.
+    ///
+    /// When parsed, this is replaced by the file the package creates within
+    /// the output directory.
+    pub package: String,
+
+    /// Glob patterns matching the absolute paths of the files to copy, as
+    /// they would be installed.
+    ///
+    /// Files keep their paths within this package. Each pattern must match
+    /// at least one file. Zone images also receive the parent directories
+    /// of each file.
+    pub paths: Vec<String>,
 }
 
 /// Describes the origin of an externally-built package.
 #[derive(Clone, Deserialize, Debug, PartialEq)]
-#[serde(tag = "type", rename_all = "lowercase")]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
 pub enum PackageSource {
     /// Describes a package which should be assembled locally.
     Local {
         /// A list of blobs from the Omicron build S3 bucket which should be placed
         /// within this package.
-        blobs: Option<Vec<Utf8PathBuf>>,
+        blobs: Option<Vec<S3Blob>>,
 
         /// A list of Buildomat blobs that should be placed in this package.
         buildomat_blobs: Option<Vec<PrebuiltBlob>>,
@@ -96,21 +531,69 @@ pub enum PackageSource {
         /// A set of mapped paths which appear within the archive.
         #[serde(default)]
         paths: Vec<InterpolatedMappedPath>,
+
+        /// Empty directories which appear within the archive.
+        #[serde(default)]
+        dirs: Vec<PackageDirectory>,
+
+        /// Empty files and FIFOs which appear within the archive.
+        #[serde(default)]
+        placeholders: Vec<PackagePlaceholder>,
+
+        /// Files copied from other packages.
+        #[serde(default)]
+        package_files: Vec<PackageFiles>,
     },
 
     /// Downloads the package from the following URL:
     ///
+    /// <BASE_URL/public/file/ORG/REPO/image/COMMIT/PACKAGE>
+    ///
+    /// By default, packages are downloaded from
     /// <https://buildomat.eng.oxide.computer/public/file/oxidecomputer/REPO/image/COMMIT/PACKAGE>
+    ///
+    /// The download is verified against `sha256` before being placed in the
+    /// output directory.
+    ///
+    /// See [Package::get_prebuilt_url].
     Prebuilt {
         repo: String,
         commit: String,
         sha256: String,
+
+        /// The server hosting the package.
+        #[serde(default = "default_prebuilt_base_url")]
+        base_url: String,
+
+        /// The organization owning `repo`.
+        #[serde(default = "default_prebuilt_org")]
+        org: String,
     },
 
     /// A composite package, created by merging multiple tarballs into one.
     ///
-    /// Currently, this package can only merge zone images.
-    Composite { packages: Vec<String> },
+    /// Currently, this package can only merge zone images. Components are
+    /// merged in the order in which they are listed.
+    Composite { packages: Vec<CompositeComponent> },
+
+    /// A package whose contents are generated by running a command.
+    ///
+    /// The command populates the directory named by the `OUTPUT_DIR`
+    /// environment variable, which becomes the root of the package. The
+    /// command is skipped if neither it nor its inputs have changed since
+    /// it last ran.
+    Command {
+        /// The program to run, followed by its arguments.
+        command: Vec<InterpolatedString>,
+
+        /// Environment variables set for the command.
+        #[serde(default)]
+        env: BTreeMap<String, InterpolatedString>,
+
+        /// Files and directories read by the command.
+        #[serde(default)]
+        inputs: Vec<InterpolatedString>,
+    },
 
     /// Expects that a package will be manually built and placed into the output
     /// directory.
@@ -118,6 +601,14 @@ pub enum PackageSource {
 }
 
 impl PackageSource {
+    // Returns true if the package's contents are assembled on this machine.
+    fn is_local(&self) -> bool {
+        matches!(
+            self,
+            PackageSource::Local { .. } | PackageSource::Command { .. }
+        )
+    }
+
     fn rust_package(&self) -> Option<&RustPackage> {
         match self {
             PackageSource::Local {
@@ -128,7 +619,7 @@ impl PackageSource {
         }
     }
 
-    fn blobs(&self) -> Option<&[Utf8PathBuf]> {
+    fn blobs(&self) -> Option<&[S3Blob]> {
         match self {
             PackageSource::Local {
                 blobs: Some(blobs), ..
@@ -150,7 +641,7 @@ impl PackageSource {
 
 /// Describes the output format of the package.
 #[derive(Deserialize, Debug, Clone, PartialEq)]
-#[serde(tag = "type", rename_all = "lowercase")]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
 pub enum PackageOutput {
     /// A complete zone image, ready to be deployed to the target.
     Zone {
@@ -160,13 +651,44 @@ pub enum PackageOutput {
         /// installed by itself.
         #[serde(default)]
         intermediate_only: bool,
+
+        /// How the zone image should be compressed.
+        #[serde(default)]
+        compression: Compression,
     },
     /// A tarball, ready to be deployed to the target.
     Tarball,
+    /// An illumos IPS package archive (p5p), which can be installed with
+    /// `pkg(1)`.
+    ///
+    /// Paths within the package are installed relative to the root of the
+    /// image.
+    Ips {
+        /// The publisher of the package.
+        #[serde(default = "default_ips_publisher")]
+        publisher: String,
+
+        /// A one-line description of the package, recorded as `pkg.summary`.
+        #[serde(default)]
+        summary: Option<String>,
+    },
+}
+
+fn default_prebuilt_base_url() -> String {
+    DEFAULT_BUILDOMAT_URL.to_string()
+}
+
+fn default_prebuilt_org() -> String {
+    DEFAULT_BUILDOMAT_ORG.to_string()
+}
+
+fn default_ips_publisher() -> String {
+    "oxide".to_string()
 }
 
 /// A single package.
 #[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct Package {
     /// The name of the service name to be used on the target OS.
     pub service_name: ServiceName,
@@ -182,88 +704,712 @@ pub struct Package {
 
     /// Identifies the targets for which the package should be included.
     ///
+    /// Each key may name a single value, or a list of values of which the
+    /// target may have any one. Values may be wildcards, such as
+    /// `machine = "gimlet*"`; see [TargetFilter].
+    ///
     /// If ommitted, the package is assumed to be included for all targets.
-    pub only_for_targets: Option<TargetMap>,
+    pub only_for_targets: Option<TargetFilter>,
+
+    /// Identifies targets for which the package should be excluded, as in
+    /// `not_for_targets.machine = "stub"`.
+    ///
+    /// The package is excluded if the target has any one of the listed
+    /// values, for any key. This takes precedence over
+    /// [Self::only_for_targets].
+    #[serde(default)]
+    pub not_for_targets: Option<TargetFilter>,
+
+    /// Free-form labels grouping related packages, such as "control-plane".
+    ///
+    /// See [crate::config::Config::packages_with_tag].
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// An expression which the target must satisfy for the package to be
+    /// included, such as "target.machine == 'gimlet'", or
+    /// "target.machine in ['gimlet', 'cosmo'] and target.switch != 'stub'";
+    /// see [TargetExpr].
+    ///
+    /// This may be combined with [Self::only_for_targets], in which case
+    /// the target must satisfy both.
+    #[serde(default)]
+    pub only_if: Option<TargetExpr>,
 
     /// A human-readable string with suggestions for setup if packaging fails.
     #[serde(default)]
     pub setup_hint: Option<String>,
+
+    /// If "true", a [crate::archive::CHECKSUM_MANIFEST] listing the digest of every file
+    /// is added to the archive, so the unpacked contents can be verified on
+    /// the target.
+    #[serde(default)]
+    pub checksum_manifest: bool,
+
+    /// If "true", symbolic links within `paths` are added to the package
+    /// as links, rather than being replaced by the files they point to.
+    #[serde(default)]
+    pub preserve_symlinks: bool,
+
+    /// If "true", stamped packages include their version within their file
+    /// name, as "NAME-VERSION.tar.gz" (for example).
+    ///
+    /// See [Package::get_stamped_output_path].
+    #[serde(default)]
+    pub version_in_filename: bool,
+
+    /// A template for the file name of the package, such as
+    /// "{{name}}-{{machine}}-{{version}}.{{ext}}", so that packages built for
+    /// different targets may share an output directory.
+    ///
+    /// In addition to the keys of the target, `{{name}}` (the package name),
+    /// `{{version}}`, and `{{ext}}` (the usual file extension) may be used.
+    /// Until the package is stamped, `{{version}}` is "0.0.0". This takes
+    /// precedence over [Self::version_in_filename].
+    ///
+    /// Manual packages are always expected at [Package::get_output_path].
+    ///
+    /// See [Package::get_target_output_path] and
+    /// [Package::get_target_versioned_output_path].
+    #[serde(default)]
+    pub output_template: Option<InterpolatedString>,
+
+    /// If provided, an SMF service manifest is generated and installed
+    /// within the zone image.
+    #[serde(default)]
+    pub smf: Option<SmfConfig>,
+
+    /// Additional key-value pairs recorded in the package's metadata.
+    ///
+    /// For zone images, these are added to "oxide.json"; for tarballs, they
+    /// are written to a "METADATA" file; for IPS packages, they are recorded
+    /// as attributes within the package manifest.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, InterpolatedString>,
+
+    /// Commands to run before the package is built.
+    ///
+    /// Their declared outputs are tracked as inputs of the package.
+    #[serde(default)]
+    pub pre_build: Vec<BuildHook>,
+
+    /// Commands to run after the package has been built.
+    #[serde(default)]
+    pub post_build: Vec<BuildHook>,
+
+    /// Estimates of the resources needed to build the package, as in
+    /// `resources.heavy = true`.
+    #[serde(default)]
+    pub resources: ResourceHints,
 }
 
-// What version should we stamp on packages, before they have been stamped?
-const DEFAULT_VERSION: semver::Version = semver::Version::new(0, 0, 0);
+/// Estimates of the resources needed to build a package, which executors
+/// may use to avoid building too many large packages at once.
+///
+/// These are hints; they are not enforced while building.
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceHints {
+    /// If "true", the package should not be built concurrently with other
+    /// heavy packages.
+    ///
+    /// [crate::config::Config::build_all] builds heavy packages one at a
+    /// time.
+    #[serde(default)]
+    pub heavy: bool,
 
-async fn new_zone_archive_builder(
-    package_name: &PackageName,
-    output_directory: &Utf8Path,
-) -> Result<ArchiveBuilder<GzEncoder<File>>> {
-    let tarfile = output_directory.join(format!("{}.tar.gz", package_name));
-    crate::archive::new_compressed_archive_builder(&tarfile).await
+    /// The memory needed to build the package, in MiB.
+    #[serde(default)]
+    pub memory_mib: Option<u64>,
+
+    /// The scratch disk space needed to build the package, in MiB.
+    #[serde(default)]
+    pub disk_mib: Option<u64>,
 }
 
-/// Configuration that can modify how a package is built.
-pub struct BuildConfig<'a> {
-    /// Describes the [Target] to build the package for.
-    pub target: &'a TargetMap,
+/// Errors which identify a problem with a package that the user can correct.
+#[derive(thiserror::Error, Debug)]
+pub enum PackageError {
+    /// A manual package has not been placed in the output directory.
+    #[error(
+        "Package {name} must be built manually and placed at {path}{}",
+        format_hint(.setup_hint)
+    )]
+    ManualPackageMissing {
+        name: PackageName,
+        path: Utf8PathBuf,
 
-    /// Describes how progress will be communicated back to the caller.
-    pub progress: &'a dyn Progress,
+        /// The [Package::setup_hint] of the package.
+        setup_hint: Option<String>,
+    },
+}
 
-    /// If "true", disables all caching.
-    pub cache_disabled: bool,
+fn format_hint(hint: &Option<String>) -> String {
+    match hint {
+        Some(hint) => format!(" (hint: {hint})"),
+        None => String::new(),
+    }
 }
 
-static DEFAULT_TARGET: TargetMap = TargetMap(BTreeMap::new());
-static DEFAULT_PROGRESS: NoProgress = NoProgress::new();
+// Keys within "oxide.json" which are defined by the package format, and which
+// cannot be supplied as additional metadata.
+const RESERVED_METADATA_KEYS: [&str; 4] = ["v", "t", "pkg", "version"];
 
-impl Default for BuildConfig<'_> {
-    fn default() -> Self {
-        Self {
-            target: &DEFAULT_TARGET,
-            progress: &DEFAULT_PROGRESS,
-            cache_disabled: false,
+// Encodes key-value pairs as a JSON object, preserving their order.
+fn metadata_json<'a>(kvs: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let quote = |s: &str| serde_json::Value::from(s).to_string();
+    String::from("{")
+        + &kvs
+            .into_iter()
+            .map(|(k, v)| format!("{}:{}", quote(k), quote(v)))
+            .collect::<Vec<String>>()
+            .join(",")
+        + "}"
+}
+
+// Reads the additional metadata from the "oxide.json" of a zone image.
+fn read_zone_metadata(path: &Utf8Path) -> Result<BTreeMap<String, String>> {
+    let mut archive = tar::Archive::new(crate::archive::open_tarfile_any(path)?);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.path()? != std::path::Path::new("oxide.json") {
+            continue;
         }
+        let json: serde_json::Map<String, serde_json::Value> = serde_json::from_reader(entry)
+            .with_context(|| format!("Failed to parse oxide.json in {path}"))?;
+        return Ok(json
+            .into_iter()
+            .filter(|(k, _)| !RESERVED_METADATA_KEYS.contains(&k.as_str()))
+            .map(|(k, v)| match v {
+                serde_json::Value::String(s) => (k, s),
+                v => (k, v.to_string()),
+            })
+            .collect());
     }
+    bail!("Missing oxide.json in {path}");
 }
 
-impl Package {
-    /// The path of a package once it is built.
-    pub fn get_output_path(&self, id: &PackageName, output_directory: &Utf8Path) -> Utf8PathBuf {
-        output_directory.join(self.get_output_file(id))
+// Reads the additional metadata from the "METADATA" file of a tarball, if it
+// has one.
+fn read_tarball_metadata(path: &Utf8Path) -> Result<BTreeMap<String, String>> {
+    let mut archive = tar::Archive::new(crate::archive::open_tarfile_any(path)?);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.path()? != std::path::Path::new("METADATA") {
+            continue;
+        }
+        return serde_json::from_reader(entry)
+            .with_context(|| format!("Failed to parse METADATA in {path}"));
     }
+    Ok(BTreeMap::new())
+}
 
-    /// The path of the service name with respect to the install directory.
-    pub fn get_output_path_for_service(&self, install_directory: &Utf8Path) -> Utf8PathBuf {
-        install_directory.join(self.get_output_file_for_service())
+// What version should we stamp on packages, before they have been stamped?
+const DEFAULT_VERSION: semver::Version = semver::Version::new(0, 0, 0);
+
+async fn new_zone_archive_builder(
+    tarfile: &Utf8Path,
+    compression: Compression,
+) -> Result<ArchiveBuilder<Compressor<File>>> {
+    crate::archive::new_compressed_archive_builder(tarfile, compression).await
+}
+
+// A package being written to a temporary file alongside its final path.
+//
+// The file is only moved into place by [Self::persist], so builds which fail
+// (or are cancelled) never leave a partially-written package behind.
+struct PartialOutput {
+    tmp: NamedUtf8TempFile,
+    path: Utf8PathBuf,
+}
+
+impl PartialOutput {
+    fn new(path: &Utf8Path) -> Result<Self> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_str().is_empty() => dir,
+            _ => Utf8Path::new("."),
+        };
+        let tmp = NamedUtf8TempFile::new_in(dir)
+            .with_context(|| format!("Cannot create temporary file for {path}"))?;
+        Ok(Self {
+            tmp,
+            path: path.to_path_buf(),
+        })
     }
 
-    /// The path of a package after it has been "stamped" with a version.
-    pub fn get_stamped_output_path(
-        &self,
-        name: &PackageName,
-        output_directory: &Utf8Path,
-    ) -> Utf8PathBuf {
-        output_directory
-            .join("versioned")
-            .join(self.get_output_file(name))
+    // Returns a handle for writing the package.
+    fn file(&self) -> Result<File> {
+        Ok(self.tmp.as_file().try_clone()?)
     }
 
-    /// The filename of a package once it is built.
-    pub fn get_output_file(&self, name: &PackageName) -> String {
-        match self.output {
-            PackageOutput::Zone { .. } => format!("{}.tar.gz", name),
-            PackageOutput::Tarball => format!("{}.tar", name),
-        }
+    // Returns the path of the incomplete package.
+    fn tmp_path(&self) -> &Utf8Path {
+        self.tmp.path()
     }
 
-    pub fn get_output_file_for_service(&self) -> String {
-        match self.output {
-            PackageOutput::Zone { .. } => format!("{}.tar.gz", self.service_name),
-            PackageOutput::Tarball => format!("{}.tar", self.service_name),
+    // Moves the completed package to its final path.
+    fn persist(self) -> Result<File> {
+        // Temporary files are only accessible by their owner, unlike the
+        // packages we've historically created.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            self.tmp
+                .as_file()
+                .set_permissions(std::fs::Permissions::from_mode(0o644))?;
         }
+        self.tmp
+            .persist(&self.path)
+            .with_context(|| format!("Cannot create {}", self.path))
     }
+}
 
-    #[deprecated = "Use 'Package::create', which now takes a 'BuildConfig', and implements 'Default'"]
+// Returns true if a path should be expanded as a glob pattern: it contains
+// glob metacharacters, and doesn't name a path which exists (such as
+// "out/[id].json").
+fn is_glob(path: &Utf8Path) -> bool {
+    path.as_str().contains(['*', '?', '[']) && !path.exists()
+}
+
+// Expands a mapped path whose source is a glob pattern, such as
+// "assets/*.so", into one mapped path per match (in sorted order), each
+// placed within the destination directory.
+fn expand_glob(path: &MappedPath) -> Result<Vec<MappedPath>> {
+    let mut matches = glob::glob(path.from.as_str())
+        .with_context(|| format!("Invalid pattern '{}'", path.from))?
+        .map(|entry| {
+            let from = entry.map_err(std::io::Error::from)?;
+            Utf8PathBuf::try_from(from).map_err(|err| anyhow!(err))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    matches.sort();
+    matches
+        .into_iter()
+        .map(|from| {
+            let name = from
+                .file_name()
+                .ok_or_else(|| anyhow!("'{from}' has no file name"))?;
+            Ok(MappedPath {
+                to: path.to.join(name),
+                from,
+            })
+        })
+        .collect()
+}
+
+// Parses glob patterns, as used by [PackageFiles::paths].
+fn parse_patterns(patterns: &[String]) -> Result<Vec<glob::Pattern>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            glob::Pattern::new(pattern).with_context(|| format!("Invalid pattern '{pattern}'"))
+        })
+        .collect()
+}
+
+// Returns the directory containing every path matched by `pattern`: its
+// leading components which contain no wildcards, excluding the last.
+fn pattern_directory(pattern: &str) -> &Utf8Path {
+    let parent = Utf8Path::new(pattern)
+        .parent()
+        .unwrap_or(Utf8Path::new(pattern));
+    parent
+        .ancestors()
+        .find(|dir| !dir.as_str().contains(['*', '?', '[']))
+        .unwrap_or(Utf8Path::new("/"))
+}
+
+// Returns the components of a composite package in the order in which they
+// are merged: as listed, except that each follows those named by
+// [CompositeComponent::after].
+fn order_components(components: &[CompositeComponent]) -> Result<Vec<&CompositeComponent>> {
+    for component in components {
+        if let Some(missing) = component
+            .after
+            .iter()
+            .find(|after| !components.iter().any(|other| other.package == **after))
+        {
+            bail!(
+                "Component {} must follow {missing}, which is not a component",
+                component.package
+            );
+        }
+    }
+    let mut remaining = components.iter().collect::<Vec<_>>();
+    let mut ordered: Vec<&CompositeComponent> = vec![];
+    while !remaining.is_empty() {
+        let Some(next) = remaining.iter().position(|component| {
+            component
+                .after
+                .iter()
+                .all(|after| ordered.iter().any(|other| other.package == *after))
+        }) else {
+            bail!(
+                "Components {} cannot be ordered: they must each follow another",
+                remaining
+                    .iter()
+                    .map(|component| component.package.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        };
+        ordered.push(remaining.remove(next));
+    }
+    Ok(ordered)
+}
+
+// Returns an error if the build has been cancelled.
+fn check_cancelled(config: &BuildConfig<'_>) -> Result<()> {
+    if config.cancel.is_some_and(|cancel| cancel.is_cancelled()) {
+        bail!("Build cancelled");
+    }
+    Ok(())
+}
+
+// Runs `fut` to completion, unless the build is cancelled first.
+async fn cancellable<T>(
+    config: &BuildConfig<'_>,
+    fut: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    match config.cancel {
+        Some(cancel) => tokio::select! {
+            biased;
+            _ = cancel.cancelled() => bail!("Build cancelled"),
+            result = fut => result,
+        },
+        None => fut.await,
+    }
+}
+
+/// Configuration that can modify how a package is built.
+pub struct BuildConfig<'a> {
+    /// Describes the [Target] to build the package for.
+    pub target: &'a TargetMap,
+
+    /// Describes how progress will be communicated back to the caller.
+    pub progress: &'a dyn Progress,
+
+    /// If "true", disables all caching.
+    pub cache_disabled: bool,
+
+    /// Overrides the cargo target directory used to locate Rust binaries,
+    /// as with `cargo build --target-dir`.
+    ///
+    /// If unset, the target directory from `cargo_metadata` is used, then
+    /// `CARGO_TARGET_DIR`; otherwise `target/` is searched in the current
+    /// directory and its ancestors.
+    pub target_dir: Option<&'a Utf8Path>,
+
+    /// Describes the cargo workspace which builds Rust binaries.
+    ///
+    /// If provided, binaries are located within its target directory, and
+    /// must be binary targets within the workspace.
+    pub cargo_metadata: Option<&'a CargoMetadata>,
+
+    /// Additional key-value pairs recorded in the package's metadata (e.g.
+    /// a git commit or build timestamp).
+    ///
+    /// These take precedence over metadata from the package's manifest.
+    pub metadata: Option<&'a BTreeMap<String, String>>,
+
+    /// If provided, cancelling this token aborts the build.
+    ///
+    /// Cancellation is checked between inputs (including while blobs are
+    /// being downloaded). Partially-written packages are removed, and the
+    /// cache is left untouched.
+    pub cancel: Option<&'a CancellationToken>,
+
+    /// If provided, built packages are shared through this cache, in
+    /// addition to the output directory; see [Cache::set_remote].
+    pub remote_cache: Option<&'a dyn CacheBackend>,
+
+    /// If provided, cache lookups are recorded here, so that the
+    /// effectiveness of the cache can be measured across many builds.
+    pub cache_counters: Option<&'a CacheCounters>,
+
+    /// The number of files hashed concurrently when comparing inputs with
+    /// the cache.
+    pub hashing_parallelism: usize,
+
+    /// If provided, the digests of inputs are remembered here, so that
+    /// inputs shared by many packages are only hashed once.
+    pub digest_memo: Option<&'a DigestMemo>,
+
+    /// The algorithm used to take digests of inputs, when comparing them
+    /// with the cache.
+    ///
+    /// [DigestAlgorithm::Xxh3] is faster, and suits local development, as
+    /// does [DigestAlgorithm::Metadata], which avoids reading inputs.
+    pub digest_algorithm: DigestAlgorithm,
+
+    /// If "true", builds of a package wait for other builds of it which
+    /// share the output directory to finish, rather than failing.
+    pub wait_for_locks: bool,
+
+    /// Additional key-value pairs describing the build environment (e.g. the
+    /// version of the toolchain, or of environment variables read by build
+    /// scripts).
+    ///
+    /// These are recorded alongside cached packages, which are rebuilt when
+    /// any of them change.
+    pub fingerprint: Option<&'a BTreeMap<String, String>>,
+
+    /// If "true", cache hits are only reported for packages whose contents
+    /// match those recorded when they were cached; see
+    /// [Cache::set_verify_outputs].
+    pub verify_cached_outputs: bool,
+
+    /// The encoding of manifests within the cache.
+    pub manifest_encoding: ManifestEncoding,
+
+    /// If provided, packages are only reused from the cache if they were
+    /// cached with the same salt; see [Cache::set_salt].
+    ///
+    /// Changing the salt forces every package to be rebuilt.
+    pub cache_salt: Option<&'a str>,
+
+    /// If provided, blobs recently confirmed to be current are used without
+    /// asking the server again.
+    pub blob_freshness: Option<&'a BlobFreshness>,
+}
+
+static DEFAULT_TARGET: TargetMap = TargetMap(BTreeMap::new());
+static DEFAULT_PROGRESS: NoProgress = NoProgress::new();
+
+impl Default for BuildConfig<'_> {
+    fn default() -> Self {
+        Self {
+            target: &DEFAULT_TARGET,
+            progress: &DEFAULT_PROGRESS,
+            cache_disabled: false,
+            target_dir: None,
+            cargo_metadata: None,
+            metadata: None,
+            cancel: None,
+            remote_cache: None,
+            cache_counters: None,
+            hashing_parallelism: DEFAULT_HASHING_PARALLELISM,
+            digest_memo: None,
+            digest_algorithm: DigestAlgorithm::default(),
+            wait_for_locks: false,
+            fingerprint: None,
+            verify_cached_outputs: false,
+            manifest_encoding: ManifestEncoding::default(),
+            cache_salt: None,
+            blob_freshness: None,
+        }
+    }
+}
+
+impl<'a> BuildConfig<'a> {
+    /// Opens the cache within `output_directory`, configured as it is when
+    /// building packages, such as to [Cache::explain] why a package would be
+    /// rebuilt.
+    pub async fn open_cache(&self, output_directory: &Utf8Path) -> Result<Cache<'a>> {
+        let mut cache = Cache::new(output_directory).await?;
+        cache.set_disable(self.cache_disabled);
+        cache.set_remote(self.remote_cache);
+        cache.set_counters(self.cache_counters);
+        cache.set_hashing_parallelism(self.hashing_parallelism);
+        cache.set_digest_memo(self.digest_memo);
+        cache.set_digest_algorithm(self.digest_algorithm);
+        cache.set_verify_outputs(self.verify_cached_outputs);
+        cache.set_manifest_encoding(self.manifest_encoding);
+        cache.set_salt(self.cache_salt);
+        Ok(cache)
+    }
+}
+
+impl Package {
+    // Rebases the relative paths from which the package is built onto
+    // `dir`, as for packages defined by a manifest within it.
+    pub(crate) fn rebase_paths(&mut self, dir: &Path) {
+        let rebase = |path: &mut InterpolatedString| {
+            if Path::new(&path.0).is_relative() {
+                path.0 = dir.join(&path.0).to_string_lossy().into_owned();
+            }
+        };
+        match &mut self.source {
+            PackageSource::Local { paths, .. } => {
+                paths.iter_mut().for_each(|path| rebase(&mut path.from));
+            }
+            PackageSource::Command { inputs, .. } => inputs.iter_mut().for_each(rebase),
+            _ => (),
+        }
+    }
+
+    /// The path of a package once it is built.
+    ///
+    /// This ignores [Self::output_template]; use
+    /// [Self::get_target_output_path] to account for it.
+    pub fn get_output_path(&self, id: &PackageName, output_directory: &Utf8Path) -> Utf8PathBuf {
+        output_directory.join(self.get_output_file(id))
+    }
+
+    /// The path of the service name with respect to the install directory.
+    pub fn get_output_path_for_service(&self, install_directory: &Utf8Path) -> Utf8PathBuf {
+        install_directory.join(self.get_output_file_for_service())
+    }
+
+    /// The path of a package after it has been "stamped" with `version`.
+    ///
+    /// If [Self::version_in_filename] is set, the version is included in
+    /// the file name.
+    pub fn get_stamped_output_path(
+        &self,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        version: &semver::Version,
+    ) -> Utf8PathBuf {
+        let file = if self.version_in_filename {
+            format!("{name}-{version}.{}", self.output_extension())
+        } else {
+            self.get_output_file(name)
+        };
+        output_directory.join("versioned").join(file)
+    }
+
+    /// The path of a package once it is built for `target`.
+    ///
+    /// If [Self::output_template] is set, it determines the file name.
+    /// Otherwise, this is identical to [Self::get_output_path].
+    pub fn get_target_output_path(
+        &self,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        target: &TargetMap,
+    ) -> Result<Utf8PathBuf> {
+        Ok(output_directory.join(self.get_target_output_file(name, target)?))
+    }
+
+    /// The filename of a package once it is built for `target`.
+    ///
+    /// See [Self::get_target_output_path].
+    pub fn get_target_output_file(&self, name: &PackageName, target: &TargetMap) -> Result<String> {
+        if matches!(self.source, PackageSource::Manual) {
+            return Ok(self.get_output_file(name));
+        }
+        Ok(self
+            .templated_output_file(name, target, &DEFAULT_VERSION)?
+            .unwrap_or_else(|| self.get_output_file(name)))
+    }
+
+    /// The path of a package after it has been "stamped" with `version` for
+    /// `target`.
+    ///
+    /// If [Self::output_template] is set, it determines the file name.
+    /// Otherwise, this is identical to [Self::get_stamped_output_path].
+    pub fn get_target_versioned_output_path(
+        &self,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        target: &TargetMap,
+        version: &semver::Version,
+    ) -> Result<Utf8PathBuf> {
+        match self.templated_output_file(name, target, version)? {
+            Some(file) => Ok(output_directory.join("versioned").join(file)),
+            None => Ok(self.get_stamped_output_path(name, output_directory, version)),
+        }
+    }
+
+    // Returns the file name produced by [Self::output_template], if set.
+    fn templated_output_file(
+        &self,
+        name: &PackageName,
+        target: &TargetMap,
+        version: &semver::Version,
+    ) -> Result<Option<String>> {
+        let Some(template) = &self.output_template else {
+            return Ok(None);
+        };
+        let mut keys = target.clone();
+        keys.0.insert("name".to_string(), name.to_string());
+        keys.0.insert("version".to_string(), version.to_string());
+        keys.0
+            .insert("ext".to_string(), self.output_extension().to_string());
+        let file = template.interpolate(&keys)?;
+        if file.is_empty() || file.contains('/') || file == "." || file == ".." {
+            bail!(
+                "Output template '{}' of {name} must produce a file name, not '{file}'",
+                template.0
+            );
+        }
+        Ok(Some(file))
+    }
+
+    /// The filename of a package once it is built.
+    ///
+    /// Composite packages and [PackageFiles] refer to other packages by
+    /// this name. It ignores [Self::output_template]; use
+    /// [Self::get_target_output_file] to account for it.
+    pub fn get_output_file(&self, name: &PackageName) -> String {
+        format!("{}.{}", name, self.output_extension())
+    }
+
+    // Returns the file extension of the package's output.
+    fn output_extension(&self) -> &'static str {
+        match self.output {
+            PackageOutput::Zone { compression, .. } => compression.extension(),
+            PackageOutput::Tarball => "tar",
+            PackageOutput::Ips { .. } => "p5p",
+        }
+    }
+
+    /// Returns an error if this is a manual package which has not been placed
+    /// in `output_directory`.
+    ///
+    /// The error carries the package's [Self::setup_hint], so it can be
+    /// reported to the user.
+    pub fn ensure_manual_present(
+        &self,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+    ) -> Result<(), PackageError> {
+        let path = self.get_output_path(name, output_directory);
+        if matches!(self.source, PackageSource::Manual) && !path.exists() {
+            return Err(PackageError::ManualPackageMissing {
+                name: name.clone(),
+                path,
+                setup_hint: self.setup_hint.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The URL from which a prebuilt package is downloaded, or "None" if the
+    /// package is not prebuilt.
+    pub fn get_prebuilt_url(&self, name: &PackageName) -> Option<String> {
+        let PackageSource::Prebuilt {
+            repo,
+            commit,
+            base_url,
+            org,
+            ..
+        } = &self.source
+        else {
+            return None;
+        };
+        Some(format!(
+            "{}/public/file/{org}/{repo}/image/{commit}/{}",
+            base_url.trim_end_matches('/'),
+            self.get_output_file(name)
+        ))
+    }
+
+    pub fn get_output_file_for_service(&self) -> String {
+        format!("{}.{}", self.service_name, self.output_extension())
+    }
+
+    // Returns the compression used by zone images, or "None" for other
+    // outputs.
+    fn zone_compression(&self) -> Option<Compression> {
+        match self.output {
+            PackageOutput::Zone { compression, .. } => Some(compression),
+            PackageOutput::Tarball | PackageOutput::Ips { .. } => None,
+        }
+    }
+
+    #[deprecated = "Use 'Package::create', which now takes a 'BuildConfig', and implements 'Default'"]
     pub async fn create_for_target(
         &self,
         target: &TargetMap,
@@ -288,69 +1434,236 @@ impl Package {
             .await
     }
 
+    /// Identical to [`Self::create`], but writes the archive to `writer`
+    /// instead of a file within `output_directory`.
+    ///
+    /// This allows callers to stream a package elsewhere (such as to object
+    /// storage) without staging it on local disk. The output directory is
+    /// still used to store downloaded blobs and to locate component packages.
+    ///
+    /// Since there is no output file to compare against, the cache is not
+    /// consulted.
+    pub async fn create_to_writer<W: Encoder>(
+        &self,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        build_config: &BuildConfig<'_>,
+        writer: W,
+    ) -> Result<W> {
+        Self::run_hooks(&self.pre_build, build_config).await?;
+        self.build_rust_binaries(build_config).await?;
+        self.strip_rust_binaries(output_directory, build_config)
+            .await?;
+
+        let progress = build_config.progress;
+        progress.set_message("Identifying inputs".into());
+        let zoned = matches!(self.output, PackageOutput::Zone { .. });
+        if !zoned && !self.source.is_local() {
+            bail!("Cannot create non-local {}", self.output_description());
+        }
+        let inputs = self
+            .get_all_inputs(
+                name,
+                build_config.target,
+                output_directory,
+                zoned,
+                None,
+                build_config,
+            )
+            .context("Identifying all input paths")?;
+        progress.increment_total(inputs.len() as u64);
+        progress.increment_total_bytes(inputs.byte_len());
+        let inputs = self
+            .run_source_command(name, output_directory, build_config, zoned, inputs)
+            .await?;
+
+        let timer = BuildTimer::new(build_config.progress);
+        let writer = if let PackageOutput::Ips { .. } = self.output {
+            let metadata = self.get_metadata(build_config.target, build_config)?;
+            self.build_ips_package(name, build_config, &timer, &inputs, &metadata, writer)
+                .await?
+        } else if let Some(compression) = self.zone_compression() {
+            let mut archive =
+                self.configure_archive(new_compressed_archive_writer(writer, compression));
+            self.add_inputs_to_package(build_config, &timer, &mut archive, &inputs)
+                .await?;
+            archive.into_inner()?.finish()?
+        } else {
+            let mut archive = self.configure_archive(ArchiveBuilder::new(Builder::new(writer)));
+            self.add_inputs_to_package(build_config, &timer, &mut archive, &inputs)
+                .await?;
+            archive.into_inner()?
+        };
+        Self::run_hooks(&self.post_build, build_config).await?;
+        Ok(writer)
+    }
+
+    /// Identifies the inputs of a package, without building it.
+    ///
+    /// This performs the same input discovery, interpolation, and validation
+    /// as [`Self::create`], but writes nothing: build hooks are not run,
+    /// Rust binaries are neither built nor stripped, and blobs and prebuilt
+    /// packages are not downloaded. For packages generated by a command, the
+    /// inputs reflect the output of the command when it last ran.
+    pub fn plan(
+        &self,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        build_config: &BuildConfig<'_>,
+    ) -> Result<BuildInputs> {
+        if let Some(inputs) = self.get_prebuilt_inputs(name) {
+            return Ok(inputs);
+        }
+        let zoned = matches!(self.output, PackageOutput::Zone { .. });
+        if !zoned && !self.source.is_local() {
+            bail!("Cannot create non-local {}", self.output_description());
+        }
+        self.get_all_inputs(
+            name,
+            build_config.target,
+            output_directory,
+            zoned,
+            None,
+            build_config,
+        )
+        .context("Identifying all input paths")
+    }
+
+    /// Stamps a package with a version.
+    ///
+    /// The package is found and named as for an empty target; use
+    /// [Self::stamp_for_target] for packages with an [Self::output_template].
     pub async fn stamp(
         &self,
         name: &PackageName,
         output_directory: &Utf8Path,
         version: &semver::Version,
     ) -> Result<Utf8PathBuf> {
-        let stamp_path = self.get_stamped_output_path(name, output_directory);
+        self.stamp_with_metadata(name, output_directory, version, &BTreeMap::new())
+            .await
+    }
+
+    /// Stamps a package with a version, additionally recording `metadata`.
+    ///
+    /// This is intended to record build provenance (see
+    /// [crate::provenance::Provenance]). Metadata already present in the
+    /// package is preserved unless overridden by `metadata`.
+    ///
+    /// As with [Self::stamp], the package is found and named as for an
+    /// empty target.
+    pub async fn stamp_with_metadata(
+        &self,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        version: &semver::Version,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<Utf8PathBuf> {
+        self.stamp_for_target(name, output_directory, &DEFAULT_TARGET, version, metadata)
+            .await
+    }
+
+    /// Identical to [Self::stamp_with_metadata], but stamps the package
+    /// built for `target`, and names the stamped package for it.
+    ///
+    /// See [Self::get_target_versioned_output_path].
+    pub async fn stamp_for_target(
+        &self,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        target: &TargetMap,
+        version: &semver::Version,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<Utf8PathBuf> {
+        let stamp_path =
+            self.get_target_versioned_output_path(name, output_directory, target, version)?;
         std::fs::create_dir_all(stamp_path.parent().unwrap())?;
 
         match self.output {
             PackageOutput::Zone { .. } => {
-                let mut inputs = BuildInputs::new();
-                inputs.0.push(self.get_version_input(name, Some(version)));
-                inputs.0.push(BuildInput::AddPackage(TargetPackage(
-                    self.get_output_path(name, output_directory),
-                )));
+                let original = self.get_target_output_path(name, output_directory, target)?;
+                let mut all_metadata = read_zone_metadata(&original)?;
+                all_metadata.extend(metadata.iter().map(|(k, v)| (k.clone(), v.clone())));
+                let version_input = self.get_version_input(name, Some(version), &all_metadata)?;
 
                 // Add the package to "itself", but as a stamped version.
                 //
                 // We jump through some hoops to avoid modifying the archive
                 // in-place, which would complicate the ordering and determinism
-                // in the build system.
-                let mut archive =
-                    new_zone_archive_builder(name, stamp_path.parent().unwrap()).await?;
-                for input in inputs.0.iter() {
-                    self.add_input_to_package(&NoProgress::new(), &mut archive, input)
-                        .await
-                        .with_context(|| format!("Adding input {input:?}"))?;
-                }
+                // in the build system. Entries are copied directly from the
+                // original archive, so only "oxide.json" is rewritten.
+                let compression = self.zone_compression().unwrap_or_default();
+                let mut archive = self
+                    .configure_archive(new_zone_archive_builder(&stamp_path, compression).await?);
+                self.add_input_to_package(
+                    &NoProgress::new(),
+                    &BuildTimer::new(&NoProgress::new()),
+                    None,
+                    &mut archive,
+                    &mut ComponentFiles::default(),
+                    &version_input,
+                )
+                .await
+                .context("Adding version")?;
+                tokio::task::block_in_place(|| copy_zone_archive(&mut archive, &original))
+                    .with_context(|| format!("Copying {original}"))?;
 
                 // Finalize the archive.
-                archive
-                    .builder
-                    .into_inner()
-                    .map_err(|err| anyhow!("Failed to finalize archive: {}", err))?
-                    .finish()?;
+                archive.into_inner()?.finish()?;
             }
             PackageOutput::Tarball => {
-                // Unpack the old tarball
-                let original_file = self.get_output_path(name, output_directory);
-                let mut reader = tar::Archive::new(open_tarfile(&original_file)?);
-                let tmp = camino_tempfile::tempdir()?;
-                reader.unpack(tmp.path())?;
-
-                // Remove the placeholder version
-                if let Err(err) = std::fs::remove_file(tmp.path().join("VERSION")) {
-                    if err.kind() != std::io::ErrorKind::NotFound {
-                        return Err(err.into());
-                    }
+                let original = self.get_target_output_path(name, output_directory, target)?;
+                let mut inputs = vec![self.get_version_input(name, Some(version), metadata)?];
+                let mut omitted = vec!["VERSION"];
+
+                // Merge any additional metadata
+                if !metadata.is_empty() {
+                    let mut all_metadata = read_tarball_metadata(&original)?;
+                    all_metadata.extend(metadata.iter().map(|(k, v)| (k.clone(), v.clone())));
+                    inputs.push(BuildInput::AddInMemoryFile {
+                        dst_path: "METADATA".into(),
+                        contents: metadata_json(
+                            all_metadata.iter().map(|(k, v)| (k.as_str(), v.as_str())),
+                        ),
+                        metadata: EntryMetadata::default(),
+                    });
+                    omitted.push("METADATA");
                 }
 
-                // Create the new tarball
+                // As with zone images, entries are copied directly from the
+                // original archive, so that their headers are preserved and
+                // only "VERSION" and "METADATA" are rewritten.
                 let file = create_tarfile(&stamp_path)?;
                 // TODO: We could add compression here, if we'd like?
-                let mut archive = Builder::new(file);
-                archive.mode(tar::HeaderMode::Deterministic);
-                archive.append_dir_all_async(".", tmp.path()).await?;
-
-                self.add_stamp_to_tarball_package(&mut archive, version)
-                    .await?;
+                let mut archive = self.configure_archive(ArchiveBuilder::new(Builder::new(file)));
+                for input in &inputs {
+                    self.add_input_to_package(
+                        &NoProgress::new(),
+                        &BuildTimer::new(&NoProgress::new()),
+                        None,
+                        &mut archive,
+                        &mut ComponentFiles::default(),
+                        input,
+                    )
+                    .await
+                    .context("Adding version")?;
+                }
+                tokio::task::block_in_place(|| {
+                    copy_tarball_archive(&mut archive, &original, &omitted)
+                })
+                .with_context(|| format!("Copying {original}"))?;
 
                 // Finalize the archive.
-                archive.finish()?;
+                archive.into_inner()?;
+            }
+            PackageOutput::Ips { .. } => {
+                let original = self.get_target_output_path(name, output_directory, target)?;
+                let reader = std::io::BufReader::new(open_tarfile(&original)?);
+                let writer = std::io::BufWriter::new(create_tarfile(&stamp_path)?);
+                tokio::task::block_in_place(|| {
+                    crate::ips::restamp(reader, writer, version, metadata)
+                })
+                .with_context(|| format!("Stamping {original}"))?
+                .into_inner()?;
             }
         }
         Ok(stamp_path)
@@ -374,34 +1687,163 @@ impl Package {
         self.create_internal(name, output_directory, &config).await
     }
 
+    /// Identical to [`Self::create`], but returns a [BuildReport] describing
+    /// the outcome of the build.
+    ///
+    /// Computing the digest of the package requires reading it in full, so
+    /// this is more expensive than [`Self::create`].
+    pub async fn create_with_report(
+        &self,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        build_config: &BuildConfig<'_>,
+    ) -> Result<BuildReport> {
+        let (build, timer) = self
+            .build_internal(name, output_directory, build_config)
+            .await?;
+        let output_path =
+            self.get_target_output_path(name, output_directory, build_config.target)?;
+        let bytes_written = build.file.metadata()?.len();
+        let output_digest = hex::encode(get_sha256_digest(&output_path).await?);
+        Ok(BuildReport {
+            output_path,
+            cache: build.cache,
+            phases: timer.to_report(),
+            bytes_written,
+            downloaded_blobs: build.downloaded_blobs,
+            output_digest,
+        })
+    }
+
     async fn create_internal(
         &self,
         name: &PackageName,
         output_directory: &Utf8Path,
         config: &BuildConfig<'_>,
     ) -> Result<File> {
-        let mut timer = BuildTimer::new();
+        let (build, _) = self.build_internal(name, output_directory, config).await?;
+        Ok(build.file)
+    }
+
+    async fn build_internal<'a>(
+        &self,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        config: &BuildConfig<'a>,
+    ) -> Result<(PackageBuild, BuildTimer<'a>)> {
+        check_cancelled(config)?;
+        config.progress.report(ProgressEvent::PackageStarted {
+            package: name.clone(),
+        });
+        let mut timer = BuildTimer::new(config.progress);
+        if !self.pre_build.is_empty() {
+            timer.start("running pre-build hooks");
+            Self::run_hooks(&self.pre_build, config).await?;
+            timer.finish()?;
+        }
+        if self.source.rust_package().is_some_and(|rust| rust.build) {
+            timer.start("building rust binaries");
+            self.build_rust_binaries(config).await?;
+            timer.finish()?;
+        }
+        if self.source.rust_package().is_some_and(|rust| rust.strip) {
+            timer.start("stripping rust binaries");
+            self.strip_rust_binaries(output_directory, config).await?;
+            timer.finish()?;
+        }
         let output = match self.output {
+            _ if matches!(self.source, PackageSource::Manual) => {
+                self.ensure_manual_present(name, output_directory)?;
+                PackageBuild::cached(&self.get_output_path(name, output_directory))?
+            }
+            _ if matches!(self.source, PackageSource::Prebuilt { .. }) => {
+                self.create_prebuilt_package(&mut timer, name, output_directory, config)
+                    .await?
+            }
             PackageOutput::Zone { .. } => {
                 self.create_zone_package(&mut timer, name, output_directory, config)
                     .await?
             }
             PackageOutput::Tarball => {
-                self.create_tarball_package(name, output_directory, config)
+                self.create_tarball_package(&mut timer, name, output_directory, config)
+                    .await?
+            }
+            PackageOutput::Ips { .. } => {
+                self.create_ips_package(&mut timer, name, output_directory, config)
                     .await?
             }
         };
+        if !self.post_build.is_empty() {
+            timer.start("running post-build hooks");
+            Self::run_hooks(&self.post_build, config).await?;
+            timer.finish()?;
+        }
 
         timer.log_all(config.progress.get_log());
-        Ok(output)
+        let phases = timer
+            .completed()
+            .iter()
+            .map(|phase| PhaseRecord {
+                name: phase.name().to_string(),
+                duration: phase.duration(),
+            })
+            .collect();
+        // Only full builds are recorded, so that estimates aren't skewed by
+        // cache hits.
+        if matches!(output.cache, CacheOutcome::Miss { .. }) {
+            if let Err(err) = BuildHistory::record(output_directory, name, phases).await {
+                config
+                    .progress
+                    .warn(format!("Failed to record build durations: {err:#}").into());
+            }
+        }
+        config.progress.report(ProgressEvent::PackageCompleted {
+            package: name.clone(),
+        });
+        Ok((output, timer))
     }
 
-    // Adds the version file to the archive
-    fn get_version_input(
-        &self,
-        package_name: &PackageName,
+    // Runs each of the provided hooks in order.
+    async fn run_hooks(hooks: &[BuildHook], config: &BuildConfig<'_>) -> Result<()> {
+        for hook in hooks {
+            hook.run(config.target, config.progress).await?;
+        }
+        Ok(())
+    }
+
+    // Invokes `cargo build` for Rust packages which have opted into it.
+    async fn build_rust_binaries(&self, config: &BuildConfig<'_>) -> Result<()> {
+        let Some(rust_pkg) = self.source.rust_package().filter(|rust| rust.build) else {
+            return Ok(());
+        };
+        let progress = config.progress;
+        let args = rust_pkg.cargo_build_args(config.target_dir);
+        progress.set_message("Building rust binaries".into());
+        slog::info!(progress.get_log(), "Running: cargo {}", args.join(" "));
+
+        let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let mut command = tokio::process::Command::new(cargo);
+        command.args(&args);
+        let status = run_with_progress(command, progress)
+            .await
+            .context("Failed to run cargo")?;
+        if !status.success() {
+            let err = anyhow!("cargo {} failed: {status}", args.join(" "));
+            return Err(match &self.setup_hint {
+                Some(hint) => err.context(format!("Hint: {hint}")),
+                None => err,
+            });
+        }
+        Ok(())
+    }
+
+    // Adds the version file to the archive
+    fn get_version_input(
+        &self,
+        package_name: &PackageName,
         version: Option<&semver::Version>,
-    ) -> BuildInput {
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<BuildInput> {
         match &self.output {
             PackageOutput::Zone { .. } => {
                 // The first file in the archive must always be a JSON file
@@ -411,122 +1853,299 @@ impl Package {
                 let version = version.cloned().unwrap_or(DEFAULT_VERSION);
                 let version = &version.to_string();
 
-                let kvs = vec![
+                let mut kvs = vec![
                     ("v", "1"),
                     ("t", "layer"),
                     ("pkg", package_name.as_ref()),
                     ("version", version),
                 ];
+                for (k, v) in metadata {
+                    if RESERVED_METADATA_KEYS.contains(&k.as_str()) {
+                        bail!("Metadata key '{k}' is reserved");
+                    }
+                    kvs.push((k, v));
+                }
 
-                let contents = String::from("{")
-                    + &kvs
-                        .into_iter()
-                        .map(|(k, v)| format!("\"{k}\":\"{v}\""))
-                        .collect::<Vec<String>>()
-                        .join(",")
-                    + "}";
-
-                BuildInput::AddInMemoryFile {
+                Ok(BuildInput::AddInMemoryFile {
                     dst_path: "oxide.json".into(),
-                    contents,
-                }
+                    contents: metadata_json(kvs),
+                    metadata: EntryMetadata::default(),
+                })
             }
             PackageOutput::Tarball => {
                 let version = version.cloned().unwrap_or(DEFAULT_VERSION);
                 let contents = version.to_string();
-                BuildInput::AddInMemoryFile {
+                Ok(BuildInput::AddInMemoryFile {
                     dst_path: "VERSION".into(),
                     contents,
-                }
+                    metadata: EntryMetadata::default(),
+                })
+            }
+            PackageOutput::Ips { .. } => {
+                // The version is part of the package manifest, which is
+                // generated once all other inputs have been added.
+                let version = version.cloned().unwrap_or(DEFAULT_VERSION);
+                Ok(BuildInput::Fingerprint {
+                    name: "version".to_string(),
+                    value: version.to_string(),
+                })
             }
         }
     }
 
+    // Collects additional metadata from the package and build configuration.
+    fn get_metadata(
+        &self,
+        target: &TargetMap,
+        config: &BuildConfig<'_>,
+    ) -> Result<BTreeMap<String, String>> {
+        let mut metadata = BTreeMap::new();
+        for (k, v) in &self.metadata {
+            metadata.insert(k.clone(), v.interpolate(target)?);
+        }
+        if let Some(extra) = config.metadata {
+            metadata.extend(extra.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        Ok(metadata)
+    }
+
     fn get_paths_inputs(
         &self,
         target: &TargetMap,
-        paths: &Vec<InterpolatedMappedPath>,
+        paths: &[InterpolatedMappedPath],
+        progress: &dyn Progress,
     ) -> Result<BuildInputs> {
-        let mut inputs = BuildInputs::new();
-
+        let mut mapped_paths = vec![];
         for path in paths {
-            let path = path.interpolate(target)?;
-            let from = path.from;
-            let to = path.to;
+            if path
+                .only_if
+                .as_ref()
+                .is_some_and(|expr| !expr.evaluate(target))
+            {
+                continue;
+            }
+            let mapped_path = path.interpolate(target)?;
+            let expanded = if is_glob(&mapped_path.from) {
+                expand_glob(&mapped_path)?
+            } else if mapped_path.from.exists() {
+                vec![mapped_path.clone()]
+            } else {
+                vec![]
+            };
+            if path.optional && expanded.is_empty() {
+                let msg = format!(
+                    "Skipping optional path \"{}\" of package \"{}\" because it does not exist",
+                    mapped_path.from, self.service_name,
+                );
+                slog::warn!(progress.get_log(), "{msg}");
+                progress.warn(msg.into());
+                continue;
+            }
+            if expanded.is_empty() && is_glob(&mapped_path.from) {
+                bail!("No files match '{}'", mapped_path.from);
+            }
+            let origin = InputOrigin::Path {
+                from: path.from.0.clone(),
+                to: path.to.0.clone(),
+            };
+            if expanded.is_empty() {
+                // Let the missing path be reported as usual.
+                mapped_paths.push((mapped_path, Some(origin)));
+            } else {
+                mapped_paths.extend(
+                    expanded
+                        .into_iter()
+                        .map(|mapped_path| (mapped_path, Some(origin.clone()))),
+                );
+            }
+        }
+        self.get_mapped_paths_inputs(mapped_paths)
+    }
 
-            match self.output {
+    fn get_dirs_inputs(
+        &self,
+        target: &TargetMap,
+        dirs: &[PackageDirectory],
+    ) -> Result<BuildInputs> {
+        let mut inputs = BuildInputs::new();
+        for dir in dirs {
+            let path = Utf8PathBuf::from(dir.path.interpolate(target)?);
+            let path = match self.output {
                 PackageOutput::Zone { .. } => {
-                    // Zone images require all paths to have their parents before
-                    // they may be unpacked.
-                    inputs.0.extend(
-                        zone_get_all_parent_inputs(to.parent().unwrap())?
+                    inputs.extend(
+                        zone_get_all_parent_inputs(path.parent().unwrap_or(&path))?
                             .into_iter()
-                            .map(BuildInput::AddDirectory),
+                            .map(BuildInput::add_directory),
                     );
+                    zone_archive_path(&path)?
                 }
-                PackageOutput::Tarball => {}
+                PackageOutput::Tarball | PackageOutput::Ips { .. } => path,
+            };
+            inputs.push(BuildInput::AddDirectory {
+                dir: TargetDirectory(path),
+                metadata: EntryMetadata {
+                    mode: Some(dir.mode),
+                    uid: Some(dir.uid),
+                    gid: Some(dir.gid),
+                },
+            });
+        }
+        Ok(inputs)
+    }
+
+    fn get_placeholders_inputs(
+        &self,
+        target: &TargetMap,
+        placeholders: &[PackagePlaceholder],
+    ) -> Result<BuildInputs> {
+        let mut inputs = BuildInputs::new();
+        for placeholder in placeholders {
+            let path = Utf8PathBuf::from(placeholder.path.interpolate(target)?);
+            let path = match self.output {
+                PackageOutput::Zone { .. } => {
+                    inputs.extend(
+                        zone_get_all_parent_inputs(path.parent().unwrap_or(&path))?
+                            .into_iter()
+                            .map(BuildInput::add_directory),
+                    );
+                    zone_archive_path(&path)?
+                }
+                PackageOutput::Tarball | PackageOutput::Ips { .. } => path,
+            };
+            let metadata = EntryMetadata {
+                mode: Some(placeholder.mode),
+                uid: Some(placeholder.uid),
+                gid: Some(placeholder.gid),
+            };
+            inputs.push(match placeholder.kind {
+                PlaceholderKind::File => BuildInput::AddEmptyFile { path, metadata },
+                PlaceholderKind::Fifo => BuildInput::AddFifo { path, metadata },
+            });
+        }
+        Ok(inputs)
+    }
+
+    // Returns the inputs for each of `paths`, attributed to the origin
+    // accompanying it.
+    fn get_mapped_paths_inputs(
+        &self,
+        paths: Vec<(MappedPath, Option<InputOrigin>)>,
+    ) -> Result<BuildInputs> {
+        let mut inputs = BuildInputs::new();
+        let (paths, origins): (Vec<_>, Vec<_>) = paths.into_iter().unzip();
+        for input in self.walk_mapped_paths(paths) {
+            let (index, input) = input?;
+            match &origins[index] {
+                Some(origin) => inputs.push_with_origin(input, origin),
+                None => inputs.push(input),
             }
-            if !from.exists() {
-                // Strictly speaking, this check is redundant, but it provides
-                // a better error message.
-                bail!(
-                    "Cannot add path \"{}\" to package \"{}\" because it does not exist",
-                    from,
-                    self.service_name,
-                );
+        }
+        Ok(inputs)
+    }
+
+    // Returns an iterator over the inputs for each of `paths`, along with the
+    // index of the path which produced each.
+    //
+    // Paths are walked lazily: nothing is read from the filesystem until the
+    // iterator is advanced, and only one directory tree is open at a time.
+    fn walk_mapped_paths(&self, paths: Vec<MappedPath>) -> MappedPathWalk<'_> {
+        MappedPathWalk {
+            package: self,
+            paths: paths.into_iter().enumerate(),
+            current: None,
+        }
+    }
+
+    // Begins walking `path`, returning the inputs which precede its entries.
+    fn start_mapped_path_walk(&self, path: MappedPath) -> Result<(Vec<BuildInput>, CurrentWalk)> {
+        let MappedPath { from, to } = path;
+        let parents = match self.output {
+            PackageOutput::Zone { .. } => {
+                // Zone images require all paths to have their parents before
+                // they may be unpacked.
+                zone_get_all_parent_inputs(to.parent().unwrap())?
+                    .into_iter()
+                    .map(BuildInput::add_directory)
+                    .collect()
             }
+            PackageOutput::Tarball | PackageOutput::Ips { .. } => vec![],
+        };
+        if !from.exists() {
+            // Strictly speaking, this check is redundant, but it provides
+            // a better error message.
+            bail!(
+                "Cannot add path \"{}\" to package \"{}\" because it does not exist",
+                from,
+                self.service_name,
+            );
+        }
 
-            let from_root = std::fs::canonicalize(&from)
-                .map_err(|e| anyhow!("failed to canonicalize \"{}\": {}", from, e))?;
-            let entries = walkdir::WalkDir::new(&from_root)
-                // Pick up symlinked files.
-                .follow_links(true)
-                // Ensure the output tarball is deterministic.
-                .sort_by_file_name();
-            for entry in entries {
-                let entry = entry?;
-                let dst = if from.is_dir() {
-                    // If copying a directory (and intermediates), strip out the
-                    // source prefix when creating the target path.
-                    to.join(<&Utf8Path>::try_from(
-                        entry.path().strip_prefix(&from_root)?,
-                    )?)
-                } else {
-                    // If copying a single file, it should be copied exactly.
-                    assert_eq!(entry.path(), from_root.as_path());
-                    to.clone()
-                };
+        let from_root = std::fs::canonicalize(&from)
+            .map_err(|e| anyhow!("failed to canonicalize \"{}\": {}", from, e))?;
+        let entries = walkdir::WalkDir::new(&from_root)
+            // Pick up symlinked files, unless they're preserved as links.
+            .follow_links(!self.preserve_symlinks)
+            // Ensure the output tarball is deterministic.
+            .sort_by_file_name()
+            .into_iter();
+        let walk = CurrentWalk {
+            entries,
+            from_root,
+            is_dir: from.is_dir(),
+            to,
+        };
+        Ok((parents, walk))
+    }
 
-                let dst = match self.output {
-                    PackageOutput::Zone { .. } => {
-                        // Zone images must explicitly label all destination paths
-                        // as within "root/".
-                        zone_archive_path(&dst)?
-                    }
-                    PackageOutput::Tarball => dst,
-                };
+    // Returns the input for `entry`, found while walking `walk`.
+    fn walked_entry_input(
+        &self,
+        walk: &CurrentWalk,
+        entry: walkdir::DirEntry,
+    ) -> Result<BuildInput> {
+        let dst = if walk.is_dir {
+            // If copying a directory (and intermediates), strip out the
+            // source prefix when creating the target path.
+            walk.to.join(<&Utf8Path>::try_from(
+                entry.path().strip_prefix(&walk.from_root)?,
+            )?)
+        } else {
+            // If copying a single file, it should be copied exactly.
+            assert_eq!(entry.path(), walk.from_root.as_path());
+            walk.to.clone()
+        };
 
-                if entry.file_type().is_dir() {
-                    inputs
-                        .0
-                        .push(BuildInput::AddDirectory(TargetDirectory(dst)));
-                } else if entry.file_type().is_file() {
-                    let src = <&Utf8Path>::try_from(entry.path())?;
-                    inputs.0.push(BuildInput::add_file(MappedPath {
-                        from: src.to_path_buf(),
-                        to: dst,
-                    })?);
-                } else {
-                    panic!(
-                        "Unsupported file type: {:?} for {:?}",
-                        entry.file_type(),
-                        entry
-                    );
-                }
+        let dst = match self.output {
+            PackageOutput::Zone { .. } => {
+                // Zone images must explicitly label all destination paths
+                // as within "root/".
+                zone_archive_path(&dst)?
             }
-        }
+            PackageOutput::Tarball | PackageOutput::Ips { .. } => dst,
+        };
 
-        Ok(inputs)
+        if entry.file_type().is_dir() {
+            Ok(BuildInput::add_directory(TargetDirectory(dst)))
+        } else if entry.file_type().is_file() {
+            let src = <&Utf8Path>::try_from(entry.path())?;
+            BuildInput::add_file(MappedPath {
+                from: src.to_path_buf(),
+                to: dst,
+            })
+        } else if entry.file_type().is_symlink() {
+            let target = std::fs::read_link(entry.path())?;
+            Ok(BuildInput::AddSymlink {
+                link: dst,
+                target: Utf8PathBuf::try_from(target)?,
+            })
+        } else {
+            bail!(
+                "Cannot add {} to package \"{}\": unsupported file type {:?}",
+                entry.path().display(),
+                self.service_name,
+                entry.file_type(),
+            );
+        }
     }
 
     fn get_all_inputs(
@@ -536,27 +2155,108 @@ impl Package {
         output_directory: &Utf8Path,
         zoned: bool,
         version: Option<&semver::Version>,
+        config: &BuildConfig<'_>,
     ) -> Result<BuildInputs> {
         let mut all_paths = BuildInputs::new();
 
         // For all archive formats, the version comes first
-        all_paths
-            .0
-            .push(self.get_version_input(package_name, version));
+        let metadata = self.get_metadata(target, config)?;
+        all_paths.push(self.get_version_input(package_name, version, &metadata)?);
+        if !zoned && !metadata.is_empty() {
+            let contents = metadata_json(metadata.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+            all_paths.push(match self.output {
+                // IPS packages record metadata within their manifest.
+                PackageOutput::Ips { .. } => BuildInput::Fingerprint {
+                    name: "metadata".to_string(),
+                    value: contents,
+                },
+                _ => BuildInput::AddInMemoryFile {
+                    dst_path: "METADATA".into(),
+                    contents,
+                    metadata: EntryMetadata::default(),
+                },
+            });
+        }
+        if self.checksum_manifest {
+            // The manifest is generated from the other inputs, but whether
+            // it's present at all changes the archive.
+            all_paths.push(BuildInput::Fingerprint {
+                name: "checksum_manifest".to_string(),
+                value: "true".to_string(),
+            });
+        }
+        for (name, value) in config.fingerprint.into_iter().flatten() {
+            all_paths.push(BuildInput::Fingerprint {
+                name: format!("config:{name}"),
+                value: value.clone(),
+            });
+        }
 
         match &self.source {
-            PackageSource::Local { paths, .. } => {
-                all_paths.0.extend(self.get_paths_inputs(target, paths)?.0);
-                all_paths.0.extend(self.get_rust_inputs()?.0);
-                all_paths
-                    .0
-                    .extend(self.get_blobs_inputs(output_directory, zoned)?.0);
+            PackageSource::Local {
+                paths,
+                dirs,
+                placeholders,
+                package_files,
+                ..
+            } => {
+                all_paths.append(self.get_paths_inputs(target, paths, config.progress)?);
+                all_paths.append(self.get_dirs_inputs(target, dirs)?);
+                all_paths.append(self.get_placeholders_inputs(target, placeholders)?);
+                for files in package_files {
+                    parse_patterns(&files.paths)?;
+                    // The files themselves aren't known until the other
+                    // package is built, but directories named by every
+                    // pattern are.
+                    let mut inputs = vec![];
+                    if zoned {
+                        for pattern in &files.paths {
+                            inputs.extend(
+                                zone_get_all_parent_inputs(pattern_directory(pattern))?
+                                    .into_iter()
+                                    .map(BuildInput::add_directory),
+                            );
+                        }
+                    }
+                    inputs.push(BuildInput::AddPackageFiles {
+                        package: TargetPackage(output_directory.join(&files.package)),
+                        paths: files.paths.clone(),
+                    });
+                    all_paths.extend_with_origin(
+                        InputOrigin::PackageFiles {
+                            package: files.package.clone(),
+                        },
+                        inputs,
+                    );
+                }
+                all_paths.append(self.get_rust_inputs(output_directory, config)?);
+                all_paths.append(self.get_blobs_inputs(target, output_directory, zoned)?);
+            }
+            PackageSource::Command { inputs, .. } => {
+                all_paths.append(self.get_command_inputs(
+                    package_name,
+                    target,
+                    output_directory,
+                    inputs,
+                )?);
             }
             PackageSource::Composite { packages } => {
-                for component_package in packages {
-                    all_paths.0.push(BuildInput::AddPackage(TargetPackage(
-                        output_directory.join(component_package),
-                    )));
+                for component in order_components(packages)? {
+                    let mut inputs = vec![BuildInput::AddPackage(TargetPackage(
+                        output_directory.join(&component.package),
+                    ))];
+                    if component.has_options() {
+                        inputs.push(BuildInput::Fingerprint {
+                            name: format!("component:{}", component.package),
+                            value: serde_json::to_string(component)?,
+                        });
+                    }
+                    all_paths.extend_with_origin(
+                        InputOrigin::Component {
+                            package: component.package.clone(),
+                        },
+                        inputs,
+                    );
                 }
             }
             _ => {
@@ -567,130 +2267,426 @@ impl Package {
             }
         }
 
+        if let Some(smf) = &self.smf {
+            all_paths.append(self.get_smf_inputs(smf, target, zoned)?);
+        }
+
+        for hook in &self.pre_build {
+            all_paths.extend(
+                hook.outputs(target)?
+                    .into_iter()
+                    .map(BuildInput::Dependency),
+            );
+        }
+
+        if matches!(self.output, PackageOutput::Tarball) {
+            all_paths
+                .check_relative()
+                .with_context(|| format!("Invalid destination in package '{package_name}'"))?;
+        }
+        all_paths.normalize()?;
         Ok(all_paths)
     }
 
-    fn get_rust_inputs(&self) -> Result<BuildInputs> {
+    fn get_smf_inputs(
+        &self,
+        smf: &SmfConfig,
+        target: &TargetMap,
+        zoned: bool,
+    ) -> Result<BuildInputs> {
+        if !zoned {
+            bail!("SMF manifests can only be generated for zone packages");
+        }
+        let mut inputs = BuildInputs::new();
+        let service_name = self.service_name.as_str();
+        let manifest_path = smf.manifest_path(service_name);
+        inputs.extend(
+            zone_get_all_parent_inputs(manifest_path.parent().unwrap())?
+                .into_iter()
+                .map(BuildInput::add_directory),
+        );
+        inputs.push(BuildInput::AddInMemoryFile {
+            dst_path: zone_archive_path(&manifest_path)?,
+            contents: smf
+                .render(service_name, target)
+                .context("Rendering SMF manifest")?,
+            metadata: EntryMetadata::default(),
+        });
+        Ok(inputs)
+    }
+
+    // Returns the directory populated by a command source.
+    fn command_output_directory(name: &PackageName, output_directory: &Utf8Path) -> Utf8PathBuf {
+        output_directory.join("command").join(name.as_str())
+    }
+
+    // Returns the hook which populates a command source, if any.
+    fn command_hook(&self, command_output: &Utf8Path) -> Option<BuildHook> {
+        let PackageSource::Command { command, env, .. } = &self.source else {
+            return None;
+        };
+        let mut env = env.clone();
+        env.insert(
+            "OUTPUT_DIR".to_string(),
+            InterpolatedString::from(command_output.as_str()),
+        );
+        Some(BuildHook {
+            command: command.clone(),
+            env,
+            inherit_env: true,
+            outputs: vec![],
+        })
+    }
+
+    fn get_command_inputs(
+        &self,
+        name: &PackageName,
+        target: &TargetMap,
+        output_directory: &Utf8Path,
+        command_inputs: &[InterpolatedString],
+    ) -> Result<BuildInputs> {
+        let mut inputs = BuildInputs::new();
+        let command_output = Self::command_output_directory(name, output_directory);
+        let hook = self.command_hook(&command_output).unwrap();
+
+        // Changes to the command itself should cause it to re-run.
+        let mut fingerprint = hook
+            .command
+            .iter()
+            .map(|arg| arg.interpolate(target))
+            .collect::<Result<Vec<_>>>()?
+            .join(" ");
+        for (k, v) in &hook.env {
+            fingerprint.push_str(&format!("\n{k}={}", v.interpolate(target)?));
+        }
+        inputs.push(BuildInput::Fingerprint {
+            name: "command".to_string(),
+            value: fingerprint,
+        });
+
+        for input in command_inputs {
+            let input = Utf8PathBuf::from(input.interpolate(target)?);
+            if !input.exists() {
+                bail!(
+                    "Input \"{}\" of package \"{}\" does not exist",
+                    input,
+                    self.service_name,
+                );
+            }
+            for entry in walkdir::WalkDir::new(&input)
+                .follow_links(true)
+                .sort_by_file_name()
+            {
+                let entry = entry?;
+                if entry.file_type().is_file() {
+                    let path = <&Utf8Path>::try_from(entry.path())?;
+                    inputs.push(BuildInput::Dependency(path.to_path_buf()));
+                }
+            }
+        }
+
+        // Include whatever the command produced when it last ran. If this
+        // has been modified (or removed), the command runs again.
+        if command_output.exists() {
+            let zoned = matches!(self.output, PackageOutput::Zone { .. });
+            let mut entries = std::fs::read_dir(&command_output)?
+                .map(|entry| Ok(entry?.file_name()))
+                .collect::<Result<Vec<_>>>()?;
+            entries.sort();
+            let paths = entries
+                .into_iter()
+                .map(|entry| {
+                    let entry = Utf8PathBuf::try_from(std::path::PathBuf::from(entry))?;
+                    let to = if zoned {
+                        Utf8Path::new("/").join(&entry)
+                    } else {
+                        entry.clone()
+                    };
+                    Ok((
+                        MappedPath {
+                            from: command_output.join(entry),
+                            to,
+                        },
+                        Some(InputOrigin::CommandOutput),
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            inputs.append(self.get_mapped_paths_inputs(paths)?);
+        }
+        Ok(inputs)
+    }
+
+    // For packages generated by a command, runs the command from scratch
+    // and returns the updated inputs. Otherwise, returns `inputs` unchanged.
+    async fn run_source_command(
+        &self,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        config: &BuildConfig<'_>,
+        zoned: bool,
+        inputs: BuildInputs,
+    ) -> Result<BuildInputs> {
+        let command_output = Self::command_output_directory(name, output_directory);
+        let Some(hook) = self.command_hook(&command_output) else {
+            return Ok(inputs);
+        };
+        if command_output.exists() {
+            std::fs::remove_dir_all(&command_output)?;
+        }
+        std::fs::create_dir_all(&command_output)?;
+        hook.run(config.target, config.progress)
+            .await
+            .context("Generating package contents")?;
+
+        let new_inputs = self
+            .get_all_inputs(name, config.target, output_directory, zoned, None, config)
+            .context("Identifying all input paths")?;
+        config
+            .progress
+            .increment_total(new_inputs.len().saturating_sub(inputs.len()) as u64);
+        config
+            .progress
+            .increment_total_bytes(new_inputs.byte_len().saturating_sub(inputs.byte_len()));
+        Ok(new_inputs)
+    }
+
+    // Returns the cargo target directory in which to find Rust binaries, if
+    // it has been overridden.
+    fn rust_target_dir<'a>(config: &BuildConfig<'a>) -> Option<&'a Utf8Path> {
+        config.target_dir.or(config
+            .cargo_metadata
+            .map(|metadata| metadata.target_directory.as_path()))
+    }
+
+    // Strips symbols from copies of Rust binaries, for packages which have
+    // opted into it.
+    //
+    // Copies are given the modification time of their original, so they are
+    // only stripped again once the original changes.
+    async fn strip_rust_binaries(
+        &self,
+        output_directory: &Utf8Path,
+        config: &BuildConfig<'_>,
+    ) -> Result<()> {
+        let Some(rust_pkg) = self.source.rust_package().filter(|rust| rust.strip) else {
+            return Ok(());
+        };
+        let progress = config.progress;
+        let target_dir = Self::rust_target_dir(config);
+        for binary in &rust_pkg.binary_names {
+            let src = rust_pkg.local_binary_path(&binary.name, target_dir)?;
+            let dst =
+                rust_pkg.stripped_binary_path(output_directory, &self.service_name, &binary.name);
+            let src_mtime = filetime::FileTime::from_last_modification_time(&src.metadata()?);
+            if let Ok(dst_metadata) = dst.metadata() {
+                if filetime::FileTime::from_last_modification_time(&dst_metadata) == src_mtime {
+                    continue;
+                }
+            }
+
+            progress.set_message(format!("Stripping {}", binary.name).into());
+            tokio::fs::create_dir_all(dst.parent().unwrap()).await?;
+            tokio::fs::copy(&src, &dst)
+                .await
+                .with_context(|| format!("Failed to copy {src} to {dst}"))?;
+
+            let strip = std::env::var_os("STRIP").unwrap_or_else(|| "strip".into());
+            let mut command = tokio::process::Command::new(strip);
+            command.arg(&dst);
+            let status = run_with_progress(command, progress)
+                .await
+                .context("Failed to run strip")?;
+            if !status.success() {
+                bail!("strip {dst} failed: {status}");
+            }
+            filetime::set_file_mtime(&dst, src_mtime)?;
+        }
+        Ok(())
+    }
+
+    fn get_rust_inputs(
+        &self,
+        output_directory: &Utf8Path,
+        config: &BuildConfig<'_>,
+    ) -> Result<BuildInputs> {
         let mut inputs = BuildInputs::new();
         if let Some(rust_pkg) = self.source.rust_package() {
+            if let Some(metadata) = config.cargo_metadata {
+                metadata.validate_binaries(rust_pkg.binary_names.iter().map(|b| &b.name))?;
+            }
+            let target_dir = Self::rust_target_dir(config);
             let dst_directory = match self.output {
                 PackageOutput::Zone { .. } => {
                     let dst = Utf8Path::new("/opt/oxide")
                         .join(self.service_name.as_str())
                         .join("bin");
-                    inputs.0.extend(
+                    inputs.extend(
                         zone_get_all_parent_inputs(&dst)?
                             .into_iter()
-                            .map(BuildInput::AddDirectory),
+                            .map(BuildInput::add_directory),
                     );
 
                     zone_archive_path(&dst)?
                 }
                 PackageOutput::Tarball => Utf8PathBuf::from(""),
+                PackageOutput::Ips { .. } => Utf8Path::new("/opt/oxide")
+                    .join(self.service_name.as_str())
+                    .join("bin"),
             };
 
             for binary in &rust_pkg.binary_names {
-                let from = RustPackage::local_binary_path(binary, rust_pkg.release);
-                let to = dst_directory.join(binary);
-                inputs
-                    .0
-                    .push(BuildInput::add_file(MappedPath { from, to })?);
+                let from = if rust_pkg.strip {
+                    rust_pkg.stripped_binary_path(
+                        output_directory,
+                        &self.service_name,
+                        &binary.name,
+                    )
+                } else {
+                    rust_pkg.local_binary_path(&binary.name, target_dir)?
+                };
+                let to = dst_directory.join(binary.installed_name());
+                inputs.extend_with_origin(
+                    InputOrigin::RustBinary {
+                        name: binary.name.clone(),
+                    },
+                    [BuildInput::add_file(MappedPath { from, to })?],
+                );
+            }
+
+            if !rust_pkg.features.is_empty() {
+                let mut features = rust_pkg.features.clone();
+                features.sort();
+                inputs.push(BuildInput::Fingerprint {
+                    name: "features".to_string(),
+                    value: features.join(","),
+                });
+            }
+            if rust_pkg.fingerprint_toolchain {
+                inputs.push(BuildInput::Fingerprint {
+                    name: "rustc".to_string(),
+                    value: rustc_version()?,
+                });
             }
         }
         Ok(inputs)
     }
 
-    fn get_blobs_inputs(&self, download_directory: &Utf8Path, zoned: bool) -> Result<BuildInputs> {
+    pub(crate) fn get_blobs_inputs(
+        &self,
+        target: &TargetMap,
+        download_directory: &Utf8Path,
+        zoned: bool,
+    ) -> Result<BuildInputs> {
         let mut inputs = BuildInputs::new();
 
-        let destination_path = if zoned {
-            zone_archive_path(
-                &Utf8Path::new("/opt/oxide")
-                    .join(self.service_name.as_str())
-                    .join(BLOB),
-            )?
-        } else {
-            Utf8PathBuf::from(BLOB)
+        let blob_directory = Utf8Path::new("/opt/oxide")
+            .join(self.service_name.as_str())
+            .join(BLOB);
+        let destination_path = match self.output {
+            PackageOutput::Zone { .. } => zone_archive_path(&blob_directory)?,
+            PackageOutput::Tarball => Utf8PathBuf::from(BLOB),
+            PackageOutput::Ips { .. } => blob_directory,
         };
-        if let Some(s3_blobs) = self.source.blobs() {
-            inputs.0.extend(s3_blobs.iter().map(|blob| {
-                let from = download_directory
-                    .join(self.service_name.as_str())
-                    .join(blob);
-                let to = destination_path.join(blob);
-                BuildInput::AddBlob {
-                    path: MappedPath { from, to },
-                    blob: crate::blob::Source::S3(blob.clone()),
+        let mut add_blob = |name: &Utf8Path,
+                            to: Option<&InterpolatedString>,
+                            blob: crate::blob::Source|
+         -> Result<()> {
+            let from = download_directory
+                .join(self.service_name.as_str())
+                .join(name);
+            let to = if let Some(to) = to {
+                let to = Utf8PathBuf::from(to.interpolate(target)?);
+                if zoned {
+                    inputs.extend(
+                        zone_get_all_parent_inputs(to.parent().unwrap())?
+                            .into_iter()
+                            .map(BuildInput::add_directory),
+                    );
+                    zone_archive_path(&to)?
+                } else {
+                    to
                 }
-            }))
+            } else {
+                destination_path.join(name)
+            };
+            inputs.extend_with_origin(
+                InputOrigin::Blob {
+                    url: blob.get_url(),
+                },
+                [BuildInput::AddBlob {
+                    path: MappedPath { from, to },
+                    blob,
+                }],
+            );
+            Ok(())
+        };
+
+        if let Some(s3_blobs) = self.source.blobs() {
+            for blob in s3_blobs {
+                add_blob(
+                    &blob.path,
+                    blob.to.as_ref(),
+                    crate::blob::Source::S3(blob.path.clone()),
+                )?;
+            }
         }
         if let Some(buildomat_blobs) = self.source.buildomat_blobs() {
-            inputs.0.extend(buildomat_blobs.iter().map(|blob| {
-                let from = download_directory
-                    .join(self.service_name.as_str())
-                    .join(&blob.artifact);
-                let to = destination_path.join(&blob.artifact);
-                BuildInput::AddBlob {
-                    path: MappedPath { from, to },
-                    blob: crate::blob::Source::Buildomat(blob.clone()),
-                }
-            }));
+            for blob in buildomat_blobs {
+                add_blob(
+                    Utf8Path::new(&blob.artifact),
+                    blob.to.as_ref(),
+                    crate::blob::Source::Buildomat(blob.clone()),
+                )?;
+            }
         }
         Ok(inputs)
     }
 
     async fn create_zone_package(
         &self,
-        timer: &mut BuildTimer,
+        timer: &mut BuildTimer<'_>,
         name: &PackageName,
         output_directory: &Utf8Path,
         config: &BuildConfig<'_>,
-    ) -> Result<File> {
+    ) -> Result<PackageBuild> {
         let target = &config.target;
         let progress = &config.progress;
-        let mut cache = Cache::new(output_directory).await?;
-        cache.set_disable(config.cache_disabled);
+        let cache = config.open_cache(output_directory).await?;
         timer.start("walking paths (identifying all inputs)");
 
         progress.set_message("Identifying inputs".into());
         let zoned = true;
         let inputs = self
-            .get_all_inputs(name, target, output_directory, zoned, None)
+            .get_all_inputs(name, target, output_directory, zoned, None, config)
             .context("Identifying all input paths")?;
-        progress.increment_total(inputs.0.len() as u64);
+        progress.increment_total(inputs.len() as u64);
+        progress.increment_total_bytes(inputs.byte_len());
 
-        let output_file = self.get_output_file(name);
-        let output_path = output_directory.join(&output_file);
+        let output_path = self.get_target_output_path(name, output_directory, target)?;
 
         // Decide whether or not to use a cached copy of the zone package
         timer.start("cache lookup");
-
-        match cache.lookup(&inputs, &output_path).await {
-            Ok(_) => {
-                timer.finish_with_label("Cache hit")?;
-                progress.set_message("Cache hit".into());
-                return Ok(File::open(output_path)?);
-            }
-            Err(CacheError::CacheMiss { reason }) => {
-                timer.finish_with_label(format!("Cache miss: {reason}"))?;
-                progress.set_message("Cache miss".into());
-            }
-            Err(CacheError::Other(other)) => {
-                return Err(other).context("Reading from package cache");
-            }
-        }
+        let (_lock, reason) =
+            match lookup_cached(&cache, &inputs, &output_path, timer, config).await? {
+                Ok(build) => return Ok(build),
+                Err(miss) => miss,
+            };
 
         // Actually build the package
+        timer.start("generating contents");
+        let inputs = self
+            .run_source_command(name, output_directory, config, zoned, inputs)
+            .await?;
         timer.start("add inputs to package");
-        let mut archive = new_zone_archive_builder(name, output_directory).await?;
-
-        for input in inputs.0.iter() {
-            self.add_input_to_package(&**progress, &mut archive, input)
-                .await
-                .with_context(|| format!("Adding input {input:?}"))?;
-        }
+        let compression = self.zone_compression().unwrap_or_default();
+        let partial = PartialOutput::new(&output_path)?;
+        let mut archive =
+            self.configure_archive(new_compressed_archive_writer(partial.file()?, compression));
+        self.add_inputs_to_package(config, timer, &mut archive, &inputs)
+            .await?;
         timer.start("finalize archive");
-        let file = archive.into_inner()?.finish()?;
+        archive.into_inner()?.finish()?;
+        let file = partial.persist()?;
 
         // Cache information about the built package
         timer.start("update cache manifest");
@@ -700,174 +2696,979 @@ impl Package {
             .update(&inputs, &output_path)
             .await
             .context("Updating package cache")?;
+        report_cache_warnings(*progress, &cache);
 
         timer.finish()?;
-        Ok(file)
+        Ok(PackageBuild::built(file, reason, &inputs))
     }
 
-    async fn add_stamp_to_tarball_package(
+    // Applies package-specific options to a new archive.
+    fn configure_archive<E: Encoder>(&self, archive: ArchiveBuilder<E>) -> ArchiveBuilder<E> {
+        if self.checksum_manifest {
+            archive.with_checksum_manifest()
+        } else {
+            archive
+        }
+    }
+
+    // Blobs are downloaded through the progress of the current phase of
+    // `timer`, which also records the time spent downloading and appending
+    // to the archive.
+    async fn add_inputs_to_package<E: Encoder>(
         &self,
-        archive: &mut Builder<File>,
-        version: &semver::Version,
+        config: &BuildConfig<'_>,
+        timer: &BuildTimer<'_>,
+        archive: &mut ArchiveBuilder<E>,
+        inputs: &BuildInputs,
     ) -> Result<()> {
-        // Add the version file to the archive
-        let mut version_file = tokio::fs::File::from_std(camino_tempfile::tempfile()?);
-        version_file
-            .write_all(version.to_string().as_bytes())
-            .await?;
-        version_file.seek(std::io::SeekFrom::Start(0)).await?;
-        let version_filename = Utf8Path::new("VERSION");
-        archive
-            .append_file_async(version_filename, &mut version_file.into_std().await)
-            .await?;
+        // Files replaced by later components are omitted from earlier ones,
+        // so that each appears in the archive once.
+        let mut components = ComponentFiles::default();
+        for input in inputs.iter() {
+            let BuildInput::AddPackage(package) = input else {
+                continue;
+            };
+            if let Some(component) = self
+                .composite_component(&package.0)
+                .filter(|component| component.allow_override)
+            {
+                let options = component.options()?;
+                tokio::task::block_in_place(|| components.add_overrides(&package.0, &options))?;
+            }
+        }
+        for (origin, input) in inputs.iter_with_origins() {
+            cancellable(
+                config,
+                self.add_input_to_package(
+                    config.progress,
+                    timer,
+                    config.blob_freshness,
+                    archive,
+                    &mut components,
+                    input,
+                ),
+            )
+            .await
+            .with_context(|| match origin {
+                Some(origin) => format!("Adding input {input:?} (from {origin})"),
+                None => format!("Adding input {input:?}"),
+            })?;
+        }
         Ok(())
     }
 
     async fn add_input_to_package<E: Encoder>(
         &self,
         progress: &dyn Progress,
+        timer: &BuildTimer<'_>,
+        blob_freshness: Option<&BlobFreshness>,
         archive: &mut ArchiveBuilder<E>,
+        components: &mut ComponentFiles,
         input: &BuildInput,
     ) -> Result<()> {
         match &input {
-            BuildInput::AddInMemoryFile { dst_path, contents } => {
-                let mut src_file = tokio::fs::File::from_std(camino_tempfile::tempfile()?);
-                src_file.write_all(contents.as_bytes()).await?;
-                src_file.seek(std::io::SeekFrom::Start(0)).await?;
-                archive
-                    .builder
-                    .append_file_async(dst_path, &mut src_file.into_std().await)
-                    .await?;
+            BuildInput::AddInMemoryFile {
+                dst_path,
+                contents,
+                metadata,
+            } => {
+                archive.append_entry(
+                    &InMemoryEntry::file(dst_path, contents.as_bytes()).with_metadata(metadata),
+                )?;
             }
-            BuildInput::AddDirectory(dir) => archive.builder.append_dir(&dir.0, ".")?,
-            BuildInput::AddFile { mapped_path, .. } => {
+            BuildInput::AddEmptyFile { path, metadata } => {
+                archive.append_entry(&InMemoryEntry::file(path, vec![]).with_metadata(metadata))?;
+            }
+            BuildInput::AddFifo { path, metadata } => {
+                archive.append_entry(&InMemoryEntry::fifo(path).with_metadata(metadata))?;
+            }
+            BuildInput::AddDirectory { dir, metadata } => {
+                archive.append_dir_with_metadata(&dir.0, Utf8Path::new("."), metadata)?
+            }
+            BuildInput::AddFile {
+                mapped_path,
+                metadata,
+                ..
+            } => {
                 let src = &mapped_path.from;
                 let dst = &mapped_path.to;
                 progress.set_message(format!("adding file: {}", src).into());
+                let _timer = timer.child("appending files");
                 archive
-                    .builder
-                    .append_path_with_name_async(src, dst)
+                    .append_path_with_metadata_async(src, dst, metadata)
                     .await
                     .context(format!("Failed to add file '{}' to '{}'", src, dst,))?;
             }
             BuildInput::AddBlob { path, blob } => {
-                // TODO: Like the rust packages being built ahead-of-time,
-                // we could ensure all the blobs have been downloaded before
-                // adding them to this package?
-                //
-                // That seems important it we want downloads to be concurrent.
-                // Granted, this optimization matters less for an incremental
-                // workflow.
-                let blobs_path = path.from.parent().unwrap();
-                std::fs::create_dir_all(blobs_path)?;
-
-                let blob_path = match &blob {
-                    blob::Source::S3(s) => blobs_path.join(s),
-                    blob::Source::Buildomat(spec) => blobs_path.join(&spec.artifact),
-                };
-
-                blob::download(progress, blob, &blob_path)
+                let download = timer.child("downloading blobs");
+                Self::download_blob(timer.phase_progress(), blob_freshness, path, blob).await?;
+                drop(download);
+                let _timer = timer.child("appending files");
+                archive
+                    .append_path_with_name_async(&path.from, &path.to)
                     .await
-                    .with_context(|| format!("failed to download blob: {}", blob.get_url()))?;
+                    .with_context(|| format!("Failed to add blob '{}'", path.from))?;
+            }
+            BuildInput::AddSymlink { link, target } => {
+                archive.append_entry(&InMemoryEntry::symlink(link, target))?;
             }
             BuildInput::AddPackage(component_package) => {
                 progress.set_message(format!("adding package: {}", component_package.0).into());
-                add_package_to_zone_archive(archive, &component_package.0).await?;
+                let appending = timer.child("appending packages");
+                // Attribute the time to each component, for composite
+                // packages with several.
+                let _timer = appending.child(component_name(&component_package.0));
+                let options = match self.composite_component(&component_package.0) {
+                    Some(component) => component.options()?,
+                    None => ComponentOptions::default(),
+                };
+                add_component_to_zone_archive(archive, &component_package.0, components, &options)
+                    .await?;
             }
+            BuildInput::AddPackageFiles { package, paths } => {
+                progress.set_message(format!("adding files from: {}", package.0).into());
+                let appending = timer.child("appending packages");
+                let _timer = appending.child(component_name(&package.0));
+                let zoned = matches!(self.output, PackageOutput::Zone { .. });
+                let patterns = parse_patterns(paths)?;
+                // Directories up to those named by each pattern were added
+                // when planning; add any others beneath them once each.
+                let mut dirs = BTreeSet::new();
+                if zoned {
+                    for pattern in paths {
+                        dirs.extend(
+                            zone_get_all_parent_inputs(pattern_directory(pattern))?
+                                .into_iter()
+                                .map(|dir| dir.0),
+                        );
+                    }
+                }
+                tokio::task::block_in_place(|| {
+                    visit_package_entries(&package.0, &patterns, |path, entry| {
+                        if !zoned {
+                            return archive
+                                .append_archive_entry_with_name(entry, path.strip_prefix("/")?);
+                        }
+                        for dir in zone_get_all_parent_inputs(path.parent().unwrap_or(path))? {
+                            if !dirs.contains(&dir.0) {
+                                archive.append_dir(&dir.0, Utf8Path::new("."))?;
+                                dirs.insert(dir.0);
+                            }
+                        }
+                        let name = zone_archive_path(path)?;
+                        if entry.header().entry_type().is_dir() {
+                            dirs.insert(name.clone());
+                        }
+                        archive.append_archive_entry_with_name(entry, &name)
+                    })
+                })?;
+            }
+            BuildInput::Fingerprint { .. } | BuildInput::Dependency(_) => {}
         }
+        progress.report(ProgressEvent::InputAdded {
+            input: input.clone(),
+        });
+        report_input_bytes(progress, input);
         progress.increment_completed(1);
         Ok(())
     }
 
-    async fn create_tarball_package(
+    // Returns the component of a composite package stored at `path`.
+    fn composite_component(&self, path: &Utf8Path) -> Option<&CompositeComponent> {
+        let PackageSource::Composite { packages } = &self.source else {
+            return None;
+        };
+        packages
+            .iter()
+            .find(|component| path.ends_with(&component.package))
+    }
+
+    // Downloads a blob to the source of `path`.
+    async fn download_blob(
+        progress: &dyn Progress,
+        freshness: Option<&BlobFreshness>,
+        path: &MappedPath,
+        blob: &crate::blob::Source,
+    ) -> Result<()> {
+        // TODO: Like the rust packages being built ahead-of-time,
+        // we could ensure all the blobs have been downloaded before
+        // adding them to this package?
+        //
+        // That seems important it we want downloads to be concurrent.
+        // Granted, this optimization matters less for an incremental
+        // workflow.
+        let blobs_path = path.from.parent().unwrap();
+        std::fs::create_dir_all(blobs_path)?;
+
+        blob::download_with_freshness(progress, blob, &path.from, freshness)
+            .await
+            .with_context(|| format!("failed to download blob: {}", blob.get_url()))?;
+        Ok(())
+    }
+
+    // Returns the inputs of a prebuilt package, or "None" for packages which
+    // are not prebuilt.
+    fn get_prebuilt_inputs(&self, name: &PackageName) -> Option<BuildInputs> {
+        let PackageSource::Prebuilt { sha256, .. } = &self.source else {
+            return None;
+        };
+        Some(BuildInputs::from(vec![
+            BuildInput::Fingerprint {
+                name: "url".to_string(),
+                value: self.get_prebuilt_url(name)?,
+            },
+            BuildInput::Fingerprint {
+                name: "sha256".to_string(),
+                value: sha256.to_lowercase(),
+            },
+        ]))
+    }
+
+    // Downloads a prebuilt package into the output directory.
+    async fn create_prebuilt_package(
+        &self,
+        timer: &mut BuildTimer<'_>,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        config: &BuildConfig<'_>,
+    ) -> Result<PackageBuild> {
+        let progress = &config.progress;
+        let (Some(inputs), Some(url), PackageSource::Prebuilt { sha256, .. }) = (
+            self.get_prebuilt_inputs(name),
+            self.get_prebuilt_url(name),
+            &self.source,
+        ) else {
+            bail!("Package {name} is not prebuilt");
+        };
+        let expected_digest =
+            hex::decode(sha256).with_context(|| format!("Invalid sha256 for {name}: {sha256}"))?;
+
+        let output_path = self.get_target_output_path(name, output_directory, config.target)?;
+        let cache = config.open_cache(output_directory).await?;
+        progress.increment_total(1);
+
+        timer.start("cache lookup");
+        let (_lock, reason) =
+            match lookup_cached(&cache, &inputs, &output_path, timer, config).await? {
+                Ok(build) => return Ok(build),
+                Err(miss) => miss,
+            };
+
+        // The package may already be present, even if the cache has no record
+        // of it.
+        let file = if output_path.exists()
+            && get_sha256_digest(&output_path).await?.as_slice() == expected_digest
+        {
+            File::open(&output_path)?
+        } else {
+            timer.start("downloading package");
+            progress.set_message(format!("downloading package: {url}").into());
+            let partial = PartialOutput::new(&output_path)?;
+            let client = reqwest::Client::new();
+            let file = tokio::fs::File::from_std(partial.file()?);
+            cancellable(
+                config,
+                blob::fetch(
+                    timer.phase_progress(),
+                    &client,
+                    &url,
+                    &self.get_output_file(name),
+                    file,
+                ),
+            )
+            .await
+            .with_context(|| format!("failed to download package: {url}"))?;
+
+            timer.start("verifying digest");
+            let digest = get_sha256_digest(partial.tmp_path()).await?;
+            if digest.as_slice() != expected_digest {
+                bail!(
+                    "Digest mismatch for {url}: expected {}, saw {}",
+                    sha256.to_lowercase(),
+                    hex::encode(digest)
+                );
+            }
+            partial.persist()?
+        };
+        progress.increment_completed(1);
+
+        timer.start("update cache manifest");
+        progress.set_message("Updating cached copy".into());
+        cache
+            .update(&inputs, &output_path)
+            .await
+            .context("Updating package cache")?;
+        report_cache_warnings(*progress, &cache);
+
+        timer.finish()?;
+        Ok(PackageBuild::built(file, reason, &inputs))
+    }
+
+    // Returns a short description of the output format, for error messages.
+    fn output_description(&self) -> &'static str {
+        match self.output {
+            PackageOutput::Zone { .. } => "zone",
+            PackageOutput::Tarball => "tarball",
+            PackageOutput::Ips { .. } => "IPS package",
+        }
+    }
+
+    async fn create_tarball_package(
+        &self,
+        timer: &mut BuildTimer<'_>,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        config: &BuildConfig<'_>,
+    ) -> Result<PackageBuild> {
+        let progress = &config.progress;
+
+        if !self.source.is_local() {
+            bail!("Cannot create non-local tarball");
+        }
+
+        let output_path = self.get_target_output_path(name, output_directory, config.target)?;
+        let cache = config.open_cache(output_directory).await?;
+
+        timer.start("walking paths (identifying all inputs)");
+        let zoned = false;
+        let inputs = self
+            .get_all_inputs(name, config.target, output_directory, zoned, None, config)
+            .context("Identifying all input paths")?;
+        progress.increment_total(inputs.len() as u64);
+        progress.increment_total_bytes(inputs.byte_len());
+
+        timer.start("cache lookup");
+        let (_lock, reason) =
+            match lookup_cached(&cache, &inputs, &output_path, timer, config).await? {
+                Ok(build) => return Ok(build),
+                Err(miss) => miss,
+            };
+
+        timer.start("generating contents");
+        let inputs = self
+            .run_source_command(name, output_directory, config, zoned, inputs)
+            .await?;
+        timer.start("add inputs to package");
+        let partial = PartialOutput::new(&output_path)?;
+        // TODO: We could add compression here, if we'd like?
+        let mut archive =
+            self.configure_archive(ArchiveBuilder::new(Builder::new(partial.file()?)));
+        self.add_inputs_to_package(config, timer, &mut archive, &inputs)
+            .await?;
+
+        archive.into_inner()?;
+        let file = partial.persist()?;
+
+        timer.start("update cache manifest");
+        progress.set_message("Updating cached copy".into());
+        cache
+            .update(&inputs, &output_path)
+            .await
+            .context("Updating package cache")?;
+        report_cache_warnings(*progress, &cache);
+
+        timer.finish()?;
+        Ok(PackageBuild::built(file, reason, &inputs))
+    }
+
+    async fn create_ips_package(
+        &self,
+        timer: &mut BuildTimer<'_>,
+        name: &PackageName,
+        output_directory: &Utf8Path,
+        config: &BuildConfig<'_>,
+    ) -> Result<PackageBuild> {
+        let progress = &config.progress;
+
+        if !self.source.is_local() {
+            bail!("Cannot create non-local IPS package");
+        }
+
+        let output_path = self.get_target_output_path(name, output_directory, config.target)?;
+        let cache = config.open_cache(output_directory).await?;
+
+        timer.start("walking paths (identifying all inputs)");
+        let zoned = false;
+        let inputs = self
+            .get_all_inputs(name, config.target, output_directory, zoned, None, config)
+            .context("Identifying all input paths")?;
+        progress.increment_total(inputs.len() as u64);
+        progress.increment_total_bytes(inputs.byte_len());
+
+        timer.start("cache lookup");
+        let (_lock, reason) =
+            match lookup_cached(&cache, &inputs, &output_path, timer, config).await? {
+                Ok(build) => return Ok(build),
+                Err(miss) => miss,
+            };
+
+        timer.start("generating contents");
+        let inputs = self
+            .run_source_command(name, output_directory, config, zoned, inputs)
+            .await?;
+        timer.start("add inputs to package");
+        let metadata = self.get_metadata(config.target, config)?;
+        let partial = PartialOutput::new(&output_path)?;
+        self.build_ips_package(name, config, timer, &inputs, &metadata, partial.file()?)
+            .await?;
+        let file = partial.persist()?;
+
+        timer.start("update cache manifest");
+        progress.set_message("Updating cached copy".into());
+        cache
+            .update(&inputs, &output_path)
+            .await
+            .context("Updating package cache")?;
+        report_cache_warnings(*progress, &cache);
+
+        timer.finish()?;
+        Ok(PackageBuild::built(file, reason, &inputs))
+    }
+
+    // Assembles an IPS package from `inputs`, writing it to `writer`.
+    // As with [Self::add_inputs_to_package], blobs are downloaded through
+    // the progress of the current phase of `timer`.
+    async fn build_ips_package<W: Encoder>(
+        &self,
+        name: &PackageName,
+        config: &BuildConfig<'_>,
+        timer: &BuildTimer<'_>,
+        inputs: &BuildInputs,
+        metadata: &BTreeMap<String, String>,
+        writer: W,
+    ) -> Result<W> {
+        let PackageOutput::Ips { publisher, summary } = &self.output else {
+            bail!("Package {name} is not an IPS package");
+        };
+        let progress = config.progress;
+        let mut pkg = IpsPackage::new(publisher, name.as_str(), &DEFAULT_VERSION)?;
+        if let Some(summary) = summary {
+            pkg.set("pkg.summary", summary);
+        }
+        for (k, v) in metadata {
+            pkg.set(k, v);
+        }
+
+        for input in inputs.iter() {
+            check_cancelled(config)?;
+            match input {
+                BuildInput::AddInMemoryFile {
+                    dst_path,
+                    contents,
+                    metadata,
+                } => {
+                    check_ips_owner(dst_path, metadata)?;
+                    pkg.add_data(
+                        dst_path,
+                        contents.as_bytes(),
+                        metadata.mode.unwrap_or(0o644),
+                    )?;
+                }
+                BuildInput::AddDirectory { dir, metadata } => {
+                    check_ips_owner(&dir.0, metadata)?;
+                    match metadata.mode {
+                        Some(mode) => pkg.add_directory_with_mode(&dir.0, mode),
+                        None => pkg.add_directory(&dir.0),
+                    }
+                }
+                BuildInput::AddFile {
+                    mapped_path,
+                    metadata,
+                    ..
+                } => {
+                    progress.set_message(format!("adding file: {}", mapped_path.from).into());
+                    check_ips_owner(&mapped_path.to, metadata)?;
+                    let _timer = timer.child("appending files");
+                    pkg.add_file_with_mode(&mapped_path.to, &mapped_path.from, metadata.mode)
+                        .with_context(|| {
+                            format!(
+                                "Failed to add file '{}' to '{}'",
+                                mapped_path.from, mapped_path.to
+                            )
+                        })?;
+                }
+                BuildInput::AddBlob { path, blob } => {
+                    let download = timer.child("downloading blobs");
+                    cancellable(
+                        config,
+                        Self::download_blob(
+                            timer.phase_progress(),
+                            config.blob_freshness,
+                            path,
+                            blob,
+                        ),
+                    )
+                    .await?;
+                    drop(download);
+                    let _timer = timer.child("appending files");
+                    pkg.add_file(&path.to, &path.from)
+                        .with_context(|| format!("Failed to add blob '{}'", path.from))?;
+                }
+                BuildInput::AddSymlink { link, .. } => {
+                    bail!("Cannot add symlink {link} to an IPS package");
+                }
+                BuildInput::AddEmptyFile { path, metadata } => {
+                    check_ips_owner(path, metadata)?;
+                    pkg.add_data(path, &[], metadata.mode.unwrap_or(0o644))?;
+                }
+                BuildInput::AddFifo { path, .. } => {
+                    bail!("Cannot add FIFO {path} to an IPS package");
+                }
+                BuildInput::AddPackage(component_package) => {
+                    bail!(
+                        "Cannot add package {} to an IPS package",
+                        component_package.0
+                    );
+                }
+                BuildInput::AddPackageFiles { package, paths } => {
+                    progress.set_message(format!("adding files from: {}", package.0).into());
+                    let patterns = parse_patterns(paths)?;
+                    tokio::task::block_in_place(|| {
+                        visit_package_entries(&package.0, &patterns, |path, entry| {
+                            let mode = entry.header().mode()?;
+                            match entry.header().entry_type() {
+                                tar::EntryType::Directory => {
+                                    pkg.add_directory_with_mode(path, mode);
+                                    Ok(())
+                                }
+                                entry_type if entry_type.is_file() => {
+                                    let mut data = vec![];
+                                    std::io::Read::read_to_end(
+                                        &mut entry_contents(entry)?,
+                                        &mut data,
+                                    )?;
+                                    pkg.add_data(path, &data, mode)
+                                }
+                                entry_type => {
+                                    bail!("Cannot add {entry_type:?} to an IPS package")
+                                }
+                            }
+                        })
+                    })?;
+                }
+                BuildInput::Fingerprint { .. } | BuildInput::Dependency(_) => (),
+            }
+            progress.report(ProgressEvent::InputAdded {
+                input: input.clone(),
+            });
+            report_input_bytes(progress, input);
+            progress.increment_completed(1);
+        }
+
+        progress.set_message("Writing IPS package".into());
+        tokio::task::block_in_place(|| pkg.write(writer))
+    }
+}
+
+// Walks the trees named by mapped paths, one at a time; see
+// [Package::walk_mapped_paths].
+struct MappedPathWalk<'a> {
+    package: &'a Package,
+    paths: std::iter::Enumerate<std::vec::IntoIter<MappedPath>>,
+    // The index of the path being walked, the inputs which precede its
+    // entries, and the walk itself.
+    current: Option<(usize, std::vec::IntoIter<BuildInput>, CurrentWalk)>,
+}
+
+// The walk of a single mapped path.
+struct CurrentWalk {
+    entries: walkdir::IntoIter,
+    from_root: std::path::PathBuf,
+    is_dir: bool,
+    to: Utf8PathBuf,
+}
+
+impl Iterator for MappedPathWalk<'_> {
+    type Item = Result<(usize, BuildInput)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((index, parents, walk)) = &mut self.current {
+                if let Some(parent) = parents.next() {
+                    return Some(Ok((*index, parent)));
+                }
+                if let Some(entry) = walk.entries.next() {
+                    let input = entry
+                        .map_err(anyhow::Error::from)
+                        .and_then(|entry| self.package.walked_entry_input(walk, entry));
+                    return Some(input.map(|input| (*index, input)));
+                }
+                self.current = None;
+            }
+            let (index, path) = self.paths.next()?;
+            match self.package.start_mapped_path_walk(path) {
+                Ok((parents, walk)) => self.current = Some((index, parents.into_iter(), walk)),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+// Names a component package, such as within the timings of the package
+// which contains it.
+fn component_name(path: &Utf8Path) -> String {
+    path.file_name().unwrap_or(path.as_str()).to_string()
+}
+
+// Looks up the package at `output_path` in the cache, reporting whether it was
+// hit.
+//
+// On a miss, returns the reason along with the artifact's lock, which must be
+// held until the package is cached, so that concurrent builds sharing the
+// output directory don't interleave their writes.
+async fn lookup_cached(
+    cache: &Cache<'_>,
+    inputs: &BuildInputs,
+    output_path: &Utf8Path,
+    timer: &mut BuildTimer<'_>,
+    config: &BuildConfig<'_>,
+) -> Result<Result<PackageBuild, (ArtifactLock, MissReason)>> {
+    let progress = config.progress;
+    let lock = cache
+        .lock(inputs, output_path, config.wait_for_locks)
+        .await?;
+    match cache.lookup(inputs, output_path).await {
+        Ok(_) => {
+            timer.finish_with_label("Cache hit")?;
+            progress.report(ProgressEvent::CacheHit);
+            report_cache_warnings(progress, cache);
+            progress.set_message("Cache hit".into());
+            PackageBuild::cached(output_path).map(Ok)
+        }
+        Err(CacheError::CacheMiss { reason }) => {
+            timer.finish_with_label(format!("Cache miss: {reason}"))?;
+            report_cache_miss(progress, &reason);
+            Ok(Err((lock, reason)))
+        }
+        Err(err) => Err(err).context("Reading from package cache"),
+    }
+}
+
+// Reports that the cache was missed, warning if it's because the remote cache
+// couldn't be reached.
+fn report_cache_miss(progress: &dyn Progress, reason: &MissReason) {
+    if reason.kind() == CacheMissKind::RemoteUnavailable {
+        progress.warn(format!("Remote cache unavailable: {reason}").into());
+    }
+    progress.report(ProgressEvent::CacheMiss {
+        reason: reason.clone(),
+    });
+    progress.set_message("Cache miss".into());
+}
+
+// Reports problems which the cache encountered, but which didn't prevent it
+// from being used.
+fn report_cache_warnings(progress: &dyn Progress, cache: &Cache<'_>) {
+    for warning in cache.take_warnings() {
+        progress.warn(warning.into());
+    }
+}
+
+// Reports the bytes which `input` added to a package, once it's been added.
+//
+// Inputs whose size wasn't known when they were identified (such as blobs,
+// which may not have been downloaded yet) are added to the total here, too.
+fn report_input_bytes(progress: &dyn Progress, input: &BuildInput) {
+    let len = input.byte_len().unwrap_or_else(|| {
+        let len = input
+            .input_path()
+            .and_then(|path| path.metadata().ok())
+            .map_or(0, |metadata| metadata.len());
+        progress.increment_total_bytes(len);
+        len
+    });
+    progress.increment_completed_bytes(len);
+}
+
+// IPS packages are always owned by root, so entries can't override their
+// ownership.
+fn check_ips_owner(path: &Utf8Path, metadata: &EntryMetadata) -> Result<()> {
+    if (metadata.uid.unwrap_or(0), metadata.gid.unwrap_or(0)) != (0, 0) {
+        bail!("Cannot set ownership of {path} in an IPS package");
+    }
+    Ok(())
+}
+
+// The result of creating a package within an output directory.
+struct PackageBuild {
+    file: File,
+    cache: CacheOutcome,
+    downloaded_blobs: Vec<Utf8PathBuf>,
+}
+
+impl PackageBuild {
+    // Reuses a package which was found in the cache.
+    fn cached(output_path: &Utf8Path) -> Result<Self> {
+        Ok(Self {
+            file: File::open(output_path)?,
+            cache: CacheOutcome::Hit,
+            downloaded_blobs: vec![],
+        })
+    }
+
+    // Describes a package which was built from `inputs`.
+    fn built(file: File, reason: MissReason, inputs: &BuildInputs) -> Self {
+        let downloaded_blobs = inputs
+            .iter()
+            .filter_map(|input| match input {
+                BuildInput::AddBlob { path, .. } => Some(path.from.clone()),
+                _ => None,
+            })
+            .collect();
+        Self {
+            file,
+            cache: CacheOutcome::Miss { reason },
+            downloaded_blobs,
+        }
+    }
+}
+
+/// Describes configuration for a package which contains a Rust binary.
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RustPackage {
+    /// The name of the compiled binary to be used.
+    // TODO: Could be extrapolated to "produced build artifacts", we don't
+    // really care about the individual binary file.
+    pub binary_names: Vec<RustBinary>,
+
+    /// True if the package has been built in release mode.
+    ///
+    /// Ignored if `profile` is set.
+    #[serde(default)]
+    pub release: bool,
+
+    /// The name of the cargo profile used to build the package
+    /// (e.g. `release-lto`).
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// Cargo features to enable when building the package.
+    #[serde(default)]
+    pub features: Vec<String>,
+
+    /// The target triple to build for (e.g. `x86_64-unknown-illumos`).
+    ///
+    /// If set, binaries are read from `target/<triple>/<profile>/`.
+    #[serde(default)]
+    pub target_triple: Option<String>,
+
+    /// If "true", the version of `rustc` is recorded alongside the package's
+    /// inputs, so that packages are rebuilt when the toolchain changes.
+    #[serde(default)]
+    pub fingerprint_toolchain: bool,
+
+    /// If "true", `cargo build` is invoked for `binary_names` before the
+    /// package is created, rather than expecting the binaries to have been
+    /// built already.
+    #[serde(default)]
+    pub build: bool,
+
+    /// If "true", symbols are stripped from copies of the binaries before
+    /// they are added to the package.
+    ///
+    /// Stripped copies are kept within the output directory, and are only
+    /// re-created when the original binary changes.
+    #[serde(default)]
+    pub strip: bool,
+}
+
+impl RustPackage {
+    /// Returns the directory within `target/` which cargo uses for the
+    /// configured profile.
+    pub fn profile_directory(&self) -> &str {
+        match self.profile.as_deref() {
+            // Cargo's built-in profiles don't share names with their output
+            // directories.
+            Some("dev") | Some("test") => "debug",
+            Some("bench") => "release",
+            Some(profile) => profile,
+            None if self.release => "release",
+            None => "debug",
+        }
+    }
+
+    // Returns the directory within a cargo target directory holding the
+    // compiled binaries.
+    fn output_directory(&self, target_dir: &Utf8Path) -> Utf8PathBuf {
+        match &self.target_triple {
+            Some(triple) => target_dir.join(triple).join(self.profile_directory()),
+            None => target_dir.join(self.profile_directory()),
+        }
+    }
+
+    // Returns the path of the stripped copy of a Rust binary, which is kept
+    // apart from copies built with other profiles or for other targets.
+    fn stripped_binary_path(
         &self,
-        name: &PackageName,
         output_directory: &Utf8Path,
-        config: &BuildConfig<'_>,
-    ) -> Result<File> {
-        let progress = &config.progress;
+        service_name: &ServiceName,
+        name: &str,
+    ) -> Utf8PathBuf {
+        self.output_directory(
+            &output_directory
+                .join("stripped")
+                .join(service_name.as_str()),
+        )
+        .join(name)
+    }
 
-        if !matches!(self.source, PackageSource::Local { .. }) {
-            bail!("Cannot create non-local tarball");
+    // Returns the arguments to `cargo` which build all binaries.
+    fn cargo_build_args(&self, target_dir: Option<&Utf8Path>) -> Vec<String> {
+        let mut args = vec!["build".to_string(), "--locked".to_string()];
+        for binary in &self.binary_names {
+            args.push("--bin".to_string());
+            args.push(binary.name.clone());
+        }
+        if let Some(profile) = &self.profile {
+            args.push("--profile".to_string());
+            args.push(profile.clone());
+        } else if self.release {
+            args.push("--release".to_string());
+        }
+        if !self.features.is_empty() {
+            args.push("--features".to_string());
+            args.push(self.features.join(","));
         }
+        if let Some(triple) = &self.target_triple {
+            args.push("--target".to_string());
+            args.push(triple.clone());
+        }
+        if let Some(dir) = target_dir {
+            args.push("--target-dir".to_string());
+            args.push(dir.to_string());
+        }
+        args
+    }
 
-        let output_path = self.get_output_path(name, output_directory);
-        let mut cache = Cache::new(output_directory).await?;
-        cache.set_disable(config.cache_disabled);
+    // Returns the path to the compiled binary, searching each candidate
+    // target directory in turn.
+    fn local_binary_path(&self, name: &str, target_dir: Option<&Utf8Path>) -> Result<Utf8PathBuf> {
+        let candidates: Vec<Utf8PathBuf> = cargo_target_directories(target_dir)?
+            .into_iter()
+            .map(|dir| self.output_directory(&dir).join(name))
+            .collect();
+        if let Some(path) = candidates.iter().find(|path| path.exists()) {
+            return Ok(path.clone());
+        }
+        bail!(
+            "Cannot find binary '{}'; tried:\n{}",
+            name,
+            candidates
+                .iter()
+                .map(|path| format!("  {path}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+}
 
-        let zoned = false;
-        let inputs = self
-            .get_all_inputs(name, config.target, output_directory, zoned, None)
-            .context("Identifying all input paths")?;
-        progress.increment_total(inputs.0.len() as u64);
+// Returns the verbose version of the `rustc` toolchain in use.
+fn rustc_version() -> Result<String> {
+    let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = std::process::Command::new(rustc)
+        .arg("-vV")
+        .output()
+        .context("Failed to run rustc")?;
+    if !output.status.success() {
+        bail!("rustc -vV failed: {}", output.status);
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
 
-        match cache.lookup(&inputs, &output_path).await {
-            Ok(_) => {
-                progress.set_message("Cache hit".into());
-                return Ok(File::open(output_path)?);
-            }
-            Err(CacheError::CacheMiss { reason: _ }) => {
-                progress.set_message("Cache miss".into());
-            }
-            Err(CacheError::Other(other)) => {
-                return Err(other).context("Reading from package cache");
-            }
-        }
+// Returns the directories which may hold cargo build output, in order of
+// preference.
+fn cargo_target_directories(target_dir: Option<&Utf8Path>) -> Result<Vec<Utf8PathBuf>> {
+    if let Some(dir) = target_dir {
+        return Ok(vec![dir.to_path_buf()]);
+    }
+    if let Some(dir) = std::env::var_os("CARGO_TARGET_DIR") {
+        let dir = Utf8PathBuf::try_from(std::path::PathBuf::from(dir))
+            .context("CARGO_TARGET_DIR is not valid UTF-8")?;
+        return Ok(vec![dir]);
+    }
 
-        let file = create_tarfile(&output_path)?;
-        // TODO: We could add compression here, if we'd like?
-        let mut archive = ArchiveBuilder::new(Builder::new(file));
-        archive.builder.mode(tar::HeaderMode::Deterministic);
+    // When packaging from a member of a workspace, cargo places build output
+    // in the workspace root, which may be any ancestor directory.
+    let mut dirs = vec![Utf8PathBuf::from("target")];
+    let cwd = Utf8PathBuf::try_from(std::env::current_dir()?)?;
+    dirs.extend(cwd.ancestors().skip(1).map(|dir| dir.join("target")));
+    Ok(dirs)
+}
 
-        for input in inputs.0.iter() {
-            self.add_input_to_package(&**progress, &mut archive, input)
-                .await?;
-        }
+/// Describes a binary within a [RustPackage].
+///
+/// This may be written in a manifest as a plain binary name, or as a table
+/// with `name` and `install_as` keys.
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(from = "RustBinarySpec")]
+pub struct RustBinary {
+    /// The name of the binary, as built by cargo.
+    pub name: String,
 
-        let file = archive
-            .builder
-            .into_inner()
-            .map_err(|err| anyhow!("Failed to finalize archive: {}", err))?;
+    /// The name of the binary within the package.
+    ///
+    /// If omitted, this is the same as `name`.
+    pub install_as: Option<String>,
+}
 
-        progress.set_message("Updating cached copy".into());
-        cache
-            .update(&inputs, &output_path)
-            .await
-            .context("Updating package cache")?;
+impl RustBinary {
+    /// Returns the name of the binary within the package.
+    pub fn installed_name(&self) -> &str {
+        self.install_as.as_deref().unwrap_or(&self.name)
+    }
+}
 
-        Ok(file)
+impl From<&str> for RustBinary {
+    fn from(name: &str) -> Self {
+        RustBinary {
+            name: name.to_string(),
+            install_as: None,
+        }
     }
 }
 
-/// Describes configuration for a package which contains a Rust binary.
-#[derive(Clone, Deserialize, Debug, PartialEq)]
-pub struct RustPackage {
-    /// The name of the compiled binary to be used.
-    // TODO: Could be extrapolated to "produced build artifacts", we don't
-    // really care about the individual binary file.
-    pub binary_names: Vec<String>,
+type RustBinarySpec = StringOrTable<String, RustBinaryTable>;
 
-    /// True if the package has been built in release mode.
-    pub release: bool,
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RustBinaryTable {
+    name: String,
+    #[serde(default)]
+    install_as: Option<String>,
 }
 
-impl RustPackage {
-    // Returns the path to the compiled binary.
-    fn local_binary_path(name: &str, release: bool) -> Utf8PathBuf {
-        format!(
-            "target/{}/{}",
-            if release { "release" } else { "debug" },
-            name,
-        )
-        .into()
+impl From<RustBinarySpec> for RustBinary {
+    fn from(spec: RustBinarySpec) -> Self {
+        match spec {
+            RustBinarySpec::String(name) => RustBinary {
+                name,
+                install_as: None,
+            },
+            RustBinarySpec::Table(RustBinaryTable { name, install_as }) => {
+                RustBinary { name, install_as }
+            }
+        }
     }
 }
 
 /// A string which can be modified with key-value pairs.
-#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[serde(transparent)]
 pub struct InterpolatedString(String);
 
+impl From<&str> for InterpolatedString {
+    fn from(s: &str) -> Self {
+        InterpolatedString(s.to_string())
+    }
+}
+
 impl InterpolatedString {
+    /// Returns the string as written, before interpolation.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
     // Interpret the string for the specified target.
     // Substitutes key/value pairs as necessary.
     pub fn interpolate(&self, target: &TargetMap) -> Result<String> {
@@ -904,11 +3705,24 @@ impl InterpolatedString {
 /// These paths may require target-specific interpretation before being
 /// transformed to an actual [MappedPath].
 #[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct InterpolatedMappedPath {
     /// Source path.
+    ///
+    /// This may be a glob pattern, such as "assets/*.so", in which case
+    /// each match is placed within `to`. A path which exists is always
+    /// taken literally, even if it contains glob metacharacters.
     pub from: InterpolatedString,
     /// Destination path.
     pub to: InterpolatedString,
+    /// If "true", the path is skipped (with a warning) when `from` does not
+    /// exist, rather than failing the build.
+    #[serde(default)]
+    pub optional: bool,
+    /// An expression which the target must satisfy for the path to be
+    /// included, such as "target.switch != 'stub'".
+    #[serde(default)]
+    pub only_if: Option<TargetExpr>,
 }
 
 impl InterpolatedMappedPath {
@@ -1018,4 +3832,778 @@ mod test {
         let s = is.interpolate(&target).unwrap();
         assert_eq!(s, "value");
     }
+
+    // Plans the package "svc" described by `cfg`.
+    fn plan_svc(cfg: &str, target: &TargetMap, progress: &dyn Progress) -> Result<BuildInputs> {
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let build_config = BuildConfig {
+            target,
+            progress,
+            ..Default::default()
+        };
+        config.packages[&PackageName::new_const("svc")].plan(
+            &PackageName::new_const("svc"),
+            Utf8Path::new("out"),
+            &build_config,
+        )
+    }
+
+    // Returns where the files and blobs of the package "svc" described by
+    // `cfg` are placed.
+    fn file_destinations(cfg: &str, target: &TargetMap) -> Vec<String> {
+        plan_svc(cfg, target, &NoProgress::new())
+            .unwrap()
+            .iter()
+            .filter_map(|input| match input {
+                BuildInput::AddFile { .. } | BuildInput::AddBlob { .. } => {
+                    input.destination().map(|path| path.to_string())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn blob_destinations() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.blobs = [
+                "plain.bin",
+                { path = "mapped.bin", to = "/opt/{{image}}/mapped.bin" },
+            ]
+            output.type = "zone"
+        "#;
+        let mut target = TargetMap(BTreeMap::new());
+        target.0.insert("image".to_string(), "standard".to_string());
+
+        assert_eq!(
+            file_destinations(cfg, &target),
+            [
+                "root/opt/oxide/svc/blob/plain.bin",
+                "root/opt/standard/mapped.bin"
+            ]
+        );
+        let inputs = plan_svc(cfg, &target, &NoProgress::new()).unwrap();
+        assert!(inputs.iter().any(|input| matches!(
+            input,
+            BuildInput::AddDirectory { dir, .. } if dir.0 == "root/opt/standard"
+        )));
+    }
+
+    #[test]
+    fn mapped_paths_are_walked_lazily() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            output.type = "tarball"
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let package = &config.packages[&PackageName::new_const("svc")];
+        let dir = camino_tempfile::tempdir().unwrap();
+        for name in ["a", "b"] {
+            std::fs::create_dir(dir.path().join(name)).unwrap();
+            std::fs::write(dir.path().join(name).join("file.txt"), name).unwrap();
+        }
+        let paths = ["a", "b"]
+            .map(|name| MappedPath {
+                from: dir.path().join(name),
+                to: Utf8Path::new("opt").join(name),
+            })
+            .to_vec();
+
+        // The first tree is walked before the second is read at all.
+        let mut walk = package.walk_mapped_paths(paths);
+        let (index, input) = walk.next().unwrap().unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(input.destination(), Some(Utf8Path::new("opt/a")));
+        std::fs::remove_dir_all(dir.path().join("b")).unwrap();
+
+        let (index, input) = walk.next().unwrap().unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(input.destination(), Some(Utf8Path::new("opt/a/file.txt")));
+        let err = walk.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{err}");
+        assert!(walk.next().is_none());
+    }
+
+    #[test]
+    fn optional_paths_may_be_missing() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.paths = [
+                { from = "tests/service-a/single-file.txt", to = "opt/present.txt" },
+                { from = "tests/does-not-exist.txt", to = "opt/missing.txt", optional = true },
+            ]
+            output.type = "tarball"
+        "#;
+        let target = TargetMap(BTreeMap::new());
+        assert_eq!(file_destinations(cfg, &target), ["opt/present.txt"]);
+
+        // Skipping the path is reported as a warning.
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let (reporter, mut events) = crate::progress::EventReporter::new(log);
+        plan_svc(cfg, &target, &reporter).unwrap();
+        let event = events.try_recv().unwrap().event;
+        let ProgressEvent::Warning { message } = event else {
+            panic!("Unexpected event: {event:?}");
+        };
+        assert!(message.contains("Skipping optional path \"tests/does-not-exist.txt\""));
+
+        // Without the flag, the missing path is an error.
+        let cfg = cfg.replace(", optional = true", "");
+        let err = plan_svc(&cfg, &target, &NoProgress::new()).unwrap_err();
+        let err = format!("{err:#}");
+        assert!(err.contains("because it does not exist"), "{err}");
+    }
+
+    #[test]
+    fn interpolated_absolute_destinations() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.paths = [
+                { from = "tests/service-a/single-file.txt", to = "{{root}}/file.txt" },
+            ]
+            output.type = "tarball"
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let name = PackageName::new_const("svc");
+        let package = &config.packages[&name];
+        let target = TargetMap(BTreeMap::from([("root".to_string(), "/opt".to_string())]));
+        let build_config = BuildConfig {
+            target: &target,
+            ..Default::default()
+        };
+
+        // Destinations which are absolute once interpolated are rejected
+        // when planning.
+        let err = package
+            .plan(&name, Utf8Path::new("out"), &build_config)
+            .unwrap_err();
+        let err = format!("{err:#}");
+        assert!(err.contains("package 'svc'"), "{err}");
+        assert!(
+            err.contains("would be placed at '/opt/file.txt', but paths within a tarball"),
+            "{err}"
+        );
+
+        let target = TargetMap(BTreeMap::from([("root".to_string(), "opt".to_string())]));
+        let build_config = BuildConfig {
+            target: &target,
+            ..Default::default()
+        };
+        package
+            .plan(&name, Utf8Path::new("out"), &build_config)
+            .unwrap();
+    }
+
+    #[test]
+    fn duplicate_destinations() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.paths = [
+                { from = "tests/service-a/single-file.txt", to = "opt/svc/file.txt" },
+                { from = "tests/service-a/subdirectory", to = "opt/svc/dir" },
+                { from = "tests/service-a/subdirectory/contents.txt", to = "opt/svc/dir/contents.txt" },
+                { from = "tests/service-a/single-file.txt", to = "opt/svc/dir/contents.txt" },
+            ]
+            output.type = "tarball"
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let name = PackageName::new_const("svc");
+        let package = &config.packages[&name];
+        let target = TargetMap(BTreeMap::new());
+        let build_config = BuildConfig {
+            target: &target,
+            ..Default::default()
+        };
+
+        let err = package
+            .plan(&name, Utf8Path::new("out"), &build_config)
+            .unwrap_err();
+        let err = format!("{err:#}");
+        assert!(
+            err.contains("would be placed at 'opt/svc/dir/contents.txt'"),
+            "{err}"
+        );
+        assert!(err.contains("tests/service-a/single-file.txt"), "{err}");
+
+        // Adding the same file to the same place twice is harmless.
+        let mut package = package.clone();
+        let PackageSource::Local { paths, .. } = &mut package.source else {
+            panic!("Unexpected source: {:?}", package.source);
+        };
+        paths.pop();
+        package
+            .plan(&name, Utf8Path::new("out"), &build_config)
+            .unwrap();
+    }
+
+    #[test]
+    fn glob_paths() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.paths = [
+                { from = "tests/service-a/*.t?t", to = "opt/svc" },
+                { from = "tests/service-a/*/*.txt", to = "opt/nested" },
+                { from = "tests/service-a/*.so", to = "opt/lib", optional = true },
+            ]
+            output.type = "tarball"
+        "#;
+        let target = TargetMap(BTreeMap::new());
+        assert_eq!(
+            file_destinations(cfg, &target),
+            ["opt/svc/single-file.txt", "opt/nested/contents.txt"]
+        );
+
+        // Patterns which match nothing are errors, unless optional.
+        let cfg = cfg.replace(", optional = true", "");
+        let err = plan_svc(&cfg, &target, &NoProgress::new()).unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "No files match 'tests/service-a/*.so'"
+        );
+    }
+
+    #[test]
+    fn bracketed_literal_paths() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("[id].json"), "{}").unwrap();
+        std::fs::write(dir.path().join("d.json"), "{}").unwrap();
+
+        let cfg = format!(
+            r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.paths = [ {{ from = "{}/[id].json", to = "opt/svc/id.json" }} ]
+            output.type = "tarball"
+            "#,
+            dir.path()
+        );
+        let target = TargetMap(BTreeMap::new());
+
+        // The file is added at `to`, rather than matched as a pattern (which
+        // would pick "d.json", placing it within "opt/svc/id.json").
+        assert_eq!(file_destinations(&cfg, &target), ["opt/svc/id.json"]);
+    }
+
+    #[test]
+    fn preserved_symlinks() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file.txt"), "contents").unwrap();
+        std::os::unix::fs::symlink("file.txt", dir.path().join("link.txt")).unwrap();
+
+        let cfg = format!(
+            r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.paths = [ {{ from = "{}", to = "opt/svc" }} ]
+            output.type = "tarball"
+            "#,
+            dir.path()
+        );
+        let target = TargetMap(BTreeMap::new());
+
+        // By default, links are replaced by the files they point to.
+        assert_eq!(
+            file_destinations(&cfg, &target),
+            ["opt/svc/file.txt", "opt/svc/link.txt"]
+        );
+
+        let cfg = cfg.replace(
+            "output.type = \"tarball\"",
+            "output.type = \"tarball\"\n            preserve_symlinks = true",
+        );
+        let inputs = plan_svc(&cfg, &target, &NoProgress::new()).unwrap();
+        assert!(inputs.as_slice().contains(&BuildInput::AddSymlink {
+            link: Utf8PathBuf::from("opt/svc/link.txt"),
+            target: Utf8PathBuf::from("file.txt"),
+        }));
+        assert!(!inputs.iter().any(|input| matches!(
+            input,
+            BuildInput::AddFile { mapped_path, .. } if mapped_path.to == "opt/svc/link.txt"
+        )));
+    }
+
+    #[test]
+    fn conditional_packages_and_paths() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            only_if = "target.machine == 'gimlet' || target.machine == 'sled'"
+            source.type = "local"
+            source.paths = [
+                { from = "tests/service-a/single-file.txt", to = "opt/always.txt" },
+                { from = "tests/service-a/single-file.txt", to = "opt/{{switch}}.txt", only_if = "target.switch != 'stub'" },
+            ]
+            output.type = "tarball"
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let package = &config.packages[&PackageName::new_const("svc")];
+        let PackageSource::Local { paths, .. } = &package.source else {
+            panic!("Unexpected source: {:?}", package.source);
+        };
+        let destinations = |target: &str| {
+            let target: TargetMap = target.parse().unwrap();
+            let inputs = package
+                .get_paths_inputs(&target, paths, &NoProgress::new())
+                .unwrap();
+            inputs
+                .into_iter()
+                .filter_map(|input| match input {
+                    BuildInput::AddFile { mapped_path, .. } => Some(mapped_path.to.to_string()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            destinations("switch=asic"),
+            ["opt/always.txt", "opt/asic.txt"]
+        );
+        // The second path is skipped before "{{switch}}" is interpolated.
+        assert_eq!(destinations("switch=stub"), ["opt/always.txt"]);
+        assert!(config
+            .packages_to_build(&"machine=gimlet".parse().unwrap())
+            .0
+            .contains_key(&PackageName::new_const("svc")));
+        assert!(config
+            .packages_to_build(&"machine=scrimlet".parse().unwrap())
+            .0
+            .is_empty());
+
+        let err = crate::config::parse_manifest(&cfg.replace("target.machine ==", "machine ="))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("Invalid target expression"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn versioned_output_paths() {
+        let cfg = r#"
+            [package.plain]
+            service_name = "plain"
+            source.type = "manual"
+            output.type = "zone"
+
+            [package.versioned]
+            service_name = "versioned"
+            source.type = "manual"
+            output.type = "tarball"
+            version_in_filename = true
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let out = Utf8Path::new("out");
+        let version = semver::Version::new(1, 2, 3);
+        let path = |name| {
+            let name = PackageName::new_const(name);
+            config.packages[&name].get_stamped_output_path(&name, out, &version)
+        };
+        assert_eq!(path("plain"), "out/versioned/plain.tar.gz");
+        assert_eq!(path("versioned"), "out/versioned/versioned-1.2.3.tar");
+    }
+
+    #[test]
+    fn templated_output_paths() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "manual"
+            output.type = "zone"
+            output_template = "{{name}}-{{machine}}-{{version}}.{{ext}}"
+
+            [package.bad]
+            service_name = "bad"
+            source.type = "manual"
+            output.type = "zone"
+            output_template = "{{machine}}/{{name}}.{{ext}}"
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let out = Utf8Path::new("out");
+        let version = semver::Version::new(1, 2, 3);
+        let mut target = TargetMap(BTreeMap::new());
+        target.0.insert("machine".to_string(), "gimlet".to_string());
+        let path = |name, target: &TargetMap| {
+            let name = PackageName::new_const(name);
+            config.packages[&name].get_target_versioned_output_path(&name, out, target, &version)
+        };
+
+        assert_eq!(
+            path("svc", &target).unwrap(),
+            "out/versioned/svc-gimlet-1.2.3.tar.gz"
+        );
+        let err = path("svc", &TargetMap(BTreeMap::new())).unwrap_err();
+        assert!(err.to_string().contains("Key 'machine' not found"), "{err}");
+        let err = path("bad", &target).unwrap_err();
+        assert!(
+            err.to_string().contains("must produce a file name"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn templated_unstamped_output_paths() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            output.type = "zone"
+            output_template = "{{name}}-{{machine}}-{{version}}.{{ext}}"
+
+            [package.manual]
+            service_name = "manual"
+            source.type = "manual"
+            output.type = "zone"
+            output_template = "{{name}}-{{machine}}.{{ext}}"
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let out = Utf8Path::new("out");
+        let path = |name, machine: &str| {
+            let name = PackageName::new_const(name);
+            let target = format!("machine={machine}").parse().unwrap();
+            config.packages[&name]
+                .get_target_output_path(&name, out, &target)
+                .unwrap()
+        };
+
+        // Packages built for different targets don't collide
+        assert_eq!(path("svc", "gimlet"), "out/svc-gimlet-0.0.0.tar.gz");
+        assert_eq!(path("svc", "non-gimlet"), "out/svc-non-gimlet-0.0.0.tar.gz");
+        // ... though manual packages are supplied under their usual name
+        assert_eq!(path("manual", "gimlet"), "out/manual.tar.gz");
+
+        // Other packages refer to packages by their usual name, so can't be
+        // built from templated ones.
+        let err = crate::config::parse_manifest(&format!(
+            r#"{cfg}
+            [package.composite]
+            service_name = "composite"
+            source.type = "composite"
+            source.packages = ["svc.tar.gz"]
+            output.type = "zone"
+            "#
+        ))
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Package 'composite' cannot be built from 'svc', whose output is \
+             named by a template"
+        );
+    }
+
+    #[test]
+    fn manual_package_missing() {
+        let cfg = r#"
+            [package.manual]
+            service_name = "manual"
+            source.type = "manual"
+            output.type = "zone"
+            setup_hint = "Run ./build-manual.sh"
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let name = PackageName::new_const("manual");
+        let package = &config.packages[&name];
+        let out = camino_tempfile::tempdir().unwrap();
+
+        let err = package
+            .ensure_manual_present(&name, out.path())
+            .unwrap_err();
+        let PackageError::ManualPackageMissing {
+            path, setup_hint, ..
+        } = &err;
+        assert_eq!(path, &out.path().join("manual.tar.gz"));
+        assert_eq!(setup_hint.as_deref(), Some("Run ./build-manual.sh"));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Package manual must be built manually and placed at {path} \
+                 (hint: Run ./build-manual.sh)"
+            )
+        );
+
+        std::fs::write(path, "").unwrap();
+        package.ensure_manual_present(&name, out.path()).unwrap();
+    }
+
+    #[test]
+    fn prebuilt_urls() {
+        let cfg = r#"
+            [package.default]
+            service_name = "default"
+            source.type = "prebuilt"
+            source.repo = "propolis"
+            source.commit = "abc123"
+            source.sha256 = "00"
+            output.type = "zone"
+
+            [package.fork]
+            service_name = "fork"
+            source.type = "prebuilt"
+            source.repo = "propolis"
+            source.commit = "abc123"
+            source.sha256 = "00"
+            source.base_url = "https://artifacts.example.com/"
+            source.org = "example"
+            output.type = "zone"
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let url = |name| {
+            let name = PackageName::new_const(name);
+            config.packages[&name].get_prebuilt_url(&name)
+        };
+        assert_eq!(
+            url("default").unwrap(),
+            "https://buildomat.eng.oxide.computer/public/file/oxidecomputer/propolis/image/abc123/default.tar.gz"
+        );
+        assert_eq!(
+            url("fork").unwrap(),
+            "https://artifacts.example.com/public/file/example/propolis/image/abc123/fork.tar.gz"
+        );
+    }
+
+    #[test]
+    fn empty_directories() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.dirs = [
+                "/var/oxide/{{image}}",
+                { path = "/var/oxide/data", mode = 0o700, uid = 12, gid = 34 },
+            ]
+            output.type = "zone"
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let package = &config.packages[&PackageName::new_const("svc")];
+        let PackageSource::Local { dirs, .. } = &package.source else {
+            panic!("Unexpected source: {:?}", package.source);
+        };
+        let mut target = TargetMap(BTreeMap::new());
+        target.0.insert("image".to_string(), "standard".to_string());
+
+        let inputs = package.get_dirs_inputs(&target, dirs).unwrap();
+        let dirs: Vec<_> = inputs
+            .iter()
+            .filter_map(|input| match input {
+                BuildInput::AddDirectory {
+                    dir,
+                    metadata:
+                        EntryMetadata {
+                            mode: Some(mode),
+                            uid: Some(uid),
+                            gid: Some(gid),
+                        },
+                } => Some((dir.0.as_str(), *mode, *uid, *gid)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            dirs,
+            [
+                ("root/var/oxide/standard", 0o755, 0, 0),
+                ("root/var/oxide/data", 0o700, 12, 34),
+            ]
+        );
+        assert!(inputs.iter().any(|input| matches!(
+            input,
+            BuildInput::AddDirectory { dir, .. } if dir.0 == "root/var/oxide"
+        )));
+    }
+
+    #[test]
+    fn placeholders() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.placeholders = [
+                "/var/svc/ready",
+                { path = "/var/svc/control", kind = "fifo", mode = 0o600, uid = 12 },
+            ]
+            output.type = "zone"
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let package = &config.packages[&PackageName::new_const("svc")];
+        let PackageSource::Local { placeholders, .. } = &package.source else {
+            panic!("Unexpected source: {:?}", package.source);
+        };
+
+        let inputs = package
+            .get_placeholders_inputs(&TargetMap(BTreeMap::new()), placeholders)
+            .unwrap();
+        let metadata = |mode, uid| EntryMetadata {
+            mode: Some(mode),
+            uid: Some(uid),
+            gid: Some(0),
+        };
+        assert!(inputs.as_slice().contains(&BuildInput::AddEmptyFile {
+            path: Utf8PathBuf::from("root/var/svc/ready"),
+            metadata: metadata(0o644, 0),
+        }));
+        assert!(inputs.as_slice().contains(&BuildInput::AddFifo {
+            path: Utf8PathBuf::from("root/var/svc/control"),
+            metadata: metadata(0o600, 12),
+        }));
+        assert!(inputs.iter().any(|input| matches!(
+            input,
+            BuildInput::AddDirectory { dir, .. } if dir.0 == "root/var/svc"
+        )));
+    }
+
+    #[test]
+    fn rust_profile_directory() {
+        let rust = |release, profile: Option<&str>| RustPackage {
+            binary_names: vec![],
+            release,
+            profile: profile.map(String::from),
+            features: vec![],
+            target_triple: None,
+            fingerprint_toolchain: false,
+            build: false,
+            strip: false,
+        };
+        assert_eq!(rust(false, None).profile_directory(), "debug");
+        assert_eq!(rust(true, None).profile_directory(), "release");
+        assert_eq!(rust(false, Some("dev")).profile_directory(), "debug");
+        assert_eq!(rust(false, Some("bench")).profile_directory(), "release");
+        assert_eq!(
+            rust(true, Some("release-lto")).profile_directory(),
+            "release-lto"
+        );
+    }
+
+    #[test]
+    fn rust_binary_missing_lists_paths() {
+        let rust = RustPackage {
+            binary_names: vec![],
+            release: true,
+            profile: None,
+            features: vec![],
+            target_triple: None,
+            fingerprint_toolchain: false,
+            build: false,
+            strip: false,
+        };
+        let err = rust
+            .local_binary_path("svc", Some(Utf8Path::new("does-not-exist")))
+            .expect_err("Binary should not be found");
+        assert_eq!(
+            err.to_string(),
+            "Cannot find binary 'svc'; tried:\n  does-not-exist/release/svc"
+        );
+    }
+
+    #[test]
+    fn rust_cargo_build_args() {
+        let rust = RustPackage {
+            binary_names: vec![RustBinary::from("a"), RustBinary::from("b")],
+            release: true,
+            profile: Some("release-lto".to_string()),
+            features: vec!["x".to_string(), "y".to_string()],
+            target_triple: Some("x86_64-unknown-illumos".to_string()),
+            fingerprint_toolchain: false,
+            build: true,
+            strip: false,
+        };
+        assert_eq!(
+            rust.cargo_build_args(Some(Utf8Path::new("out"))),
+            [
+                "build",
+                "--locked",
+                "--bin",
+                "a",
+                "--bin",
+                "b",
+                "--profile",
+                "release-lto",
+                "--features",
+                "x,y",
+                "--target",
+                "x86_64-unknown-illumos",
+                "--target-dir",
+                "out",
+            ]
+        );
+        assert_eq!(
+            rust.output_directory(Utf8Path::new("target")),
+            "target/x86_64-unknown-illumos/release-lto"
+        );
+        assert_eq!(
+            rust.stripped_binary_path(Utf8Path::new("out"), &ServiceName::new_const("svc"), "a"),
+            "out/stripped/svc/x86_64-unknown-illumos/release-lto/a"
+        );
+    }
+
+    #[test]
+    fn rust_binary_install_as() {
+        let cfg = r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.rust.binary_names = [
+                "plain",
+                { name = "svc-agent", install_as = "agent" },
+            ]
+            output.type = "zone"
+        "#;
+        let config = crate::config::parse_manifest(cfg).unwrap();
+        let package = &config.packages[&PackageName::new_const("svc")];
+        let rust = package.source.rust_package().unwrap();
+        let names: Vec<_> = rust
+            .binary_names
+            .iter()
+            .map(|binary| (binary.name.as_str(), binary.installed_name()))
+            .collect();
+        assert_eq!(names, [("plain", "plain"), ("svc-agent", "agent")]);
+    }
+
+    #[test]
+    fn composite_component_order() {
+        let component = |package: &str, after: &[&str]| CompositeComponent {
+            after: after.iter().map(|after| after.to_string()).collect(),
+            ..package.into()
+        };
+        let packages = |components: &[CompositeComponent]| -> Result<Vec<String>> {
+            Ok(order_components(components)?
+                .into_iter()
+                .map(|component| component.package.clone())
+                .collect())
+        };
+
+        // Components keep their order, unless they must follow another
+        let components = [
+            component("c.tar.gz", &["b.tar.gz"]),
+            component("a.tar.gz", &[]),
+            component("b.tar.gz", &[]),
+            component("d.tar.gz", &[]),
+        ];
+        assert_eq!(
+            packages(&components).unwrap(),
+            ["a.tar.gz", "b.tar.gz", "c.tar.gz", "d.tar.gz"]
+        );
+
+        let err = packages(&[component("a.tar.gz", &["missing.tar.gz"])]).unwrap_err();
+        assert!(err.to_string().contains("not a component"), "{err}");
+
+        let err = packages(&[
+            component("a.tar.gz", &["b.tar.gz"]),
+            component("b.tar.gz", &["a.tar.gz"]),
+        ])
+        .unwrap_err();
+        assert!(err.to_string().contains("cannot be ordered"), "{err}");
+    }
 }
@@ -4,9 +4,67 @@
 
 //! Describes utilities for relaying progress to end-users.
 
+use crate::cache::MissReason;
+use crate::config::PackageName;
+use crate::history::{BuildHistory, Eta};
+use crate::input::BuildInput;
 use slog::Logger;
 use std::borrow::Cow;
-use std::sync::OnceLock;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// A structured description of progress constructing a package.
+///
+/// See [Progress::report].
+#[derive(Clone, Debug)]
+pub enum ProgressEvent {
+    /// `package` will be constructed, once the packages it depends upon
+    /// have been; see [crate::config::Config::build_all].
+    PackageQueued { package: PackageName },
+
+    /// Construction of `package` has started.
+    PackageStarted { package: PackageName },
+
+    /// Construction of `package` has finished successfully, whether it was
+    /// built or found in the cache.
+    PackageCompleted { package: PackageName },
+
+    /// An input has been added to the package.
+    InputAdded { input: BuildInput },
+
+    /// Part of the blob `name` has been downloaded.
+    ///
+    /// `total` is the length advertised by the server, if any.
+    BlobDownloadProgress {
+        name: String,
+        downloaded: u64,
+        total: Option<u64>,
+    },
+
+    /// A phase of the build, such as "cache lookup", has started.
+    PhaseStarted { name: String },
+
+    /// A phase of the build, such as "cache lookup", has completed.
+    PhaseCompleted {
+        name: String,
+        label: Option<String>,
+        duration: Duration,
+    },
+
+    /// A cached copy of the package was used.
+    CacheHit,
+
+    /// The package must be constructed, as no usable cached copy exists.
+    CacheMiss { reason: MissReason },
+
+    /// A non-fatal problem; see [Progress::warn].
+    Warning { message: String },
+
+    /// A problem which caused the build to fail; see [Progress::error].
+    Error { message: String },
+}
 
 /// Trait for propagating progress information while constructing the package.
 pub trait Progress {
@@ -17,16 +75,56 @@ pub trait Progress {
     /// Returns the debug logger
     fn get_log(&self) -> &Logger;
 
+    /// Reports a problem which doesn't prevent the package from being
+    /// built, such as an optional path which was skipped.
+    ///
+    /// Unlike [Self::set_message], this lets reporters collect diagnostics
+    /// separately from progress.
+    fn warn(&self, _msg: Cow<'static, str>) {}
+
+    /// Reports a problem which prevented the package from being built.
+    fn error(&self, _msg: Cow<'static, str>) {}
+
     /// Increments the number of things which need to be completed
     fn increment_total(&self, _delta: u64) {}
 
     /// Increments the number of things which have completed.
     fn increment_completed(&self, _delta: u64) {}
 
+    /// Increments the number of bytes which need to be processed.
+    ///
+    /// Unlike [Self::increment_total], which counts inputs regardless of
+    /// their size, this accounts for the bytes of inputs added to the
+    /// package, and of downloads.
+    fn increment_total_bytes(&self, _delta: u64) {}
+
+    /// Increments the number of bytes which have been processed.
+    fn increment_completed_bytes(&self, _delta: u64) {}
+
     /// Returns a new [`Progress`] which will report progress for a sub task.
     fn sub_progress(&self, _total: u64) -> Box<dyn Progress> {
         Box::new(NoProgress::new())
     }
+
+    /// Returns a new [`Progress`] which will report progress for a sub task
+    /// called `name`.
+    ///
+    /// Sub tasks may be nested, such that reporters can render them as a
+    /// tree: each package built by [crate::config::Config::build_all] is a
+    /// child of the caller's progress, phases of the build which report
+    /// their own progress are children of the package, and blob downloads
+    /// are children of those phases.
+    ///
+    /// By default, this is identical to [Self::sub_progress].
+    fn named_sub_progress(&self, _name: Cow<'static, str>, total: u64) -> Box<dyn Progress> {
+        self.sub_progress(total)
+    }
+
+    /// Reports a structured event.
+    ///
+    /// Unlike [Self::set_message], these events are intended to be consumed
+    /// by programs; see [EventReporter].
+    fn report(&self, _event: ProgressEvent) {}
 }
 
 /// Implements [`Progress`] as a no-op.
@@ -54,3 +152,1497 @@ impl Progress for NoProgress {
             .get_or_init(|| slog::Logger::root(slog::Discard, slog::o!()))
     }
 }
+
+/// An event sent by an [EventReporter].
+#[derive(Clone, Debug)]
+pub struct ReportedEvent {
+    /// The package being constructed, once it has been reported by a
+    /// [ProgressEvent::PackageStarted] event.
+    pub package: Option<PackageName>,
+    /// The names of the sub tasks within which the event was reported,
+    /// outermost first; see [Progress::named_sub_progress].
+    pub scope: Vec<String>,
+    pub event: ProgressEvent,
+}
+
+/// Implements [`Progress`] by sending each [ProgressEvent] over a channel.
+///
+/// Every [Progress::sub_progress] shares the same channel, but is
+/// associated with its own package, so that the events of packages built
+/// concurrently (as by [crate::config::Config::build_all]) can be told
+/// apart. Events also record the [ReportedEvent::scope] in which they were
+/// reported.
+///
+/// The channel is unbounded, as events are sent synchronously; events are
+/// discarded once the receiver has been dropped.
+pub struct EventReporter {
+    sender: mpsc::UnboundedSender<ReportedEvent>,
+    package: OnceLock<PackageName>,
+    scope: Vec<String>,
+    log: Logger,
+}
+
+impl EventReporter {
+    /// Creates a new reporter, returning it along with the receiver of its
+    /// events.
+    pub fn new(log: Logger) -> (Self, mpsc::UnboundedReceiver<ReportedEvent>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let reporter = Self {
+            sender,
+            package: OnceLock::new(),
+            scope: vec![],
+            log,
+        };
+        (reporter, receiver)
+    }
+}
+
+impl Progress for EventReporter {
+    fn get_log(&self) -> &Logger {
+        &self.log
+    }
+
+    fn warn(&self, msg: Cow<'static, str>) {
+        self.report(ProgressEvent::Warning {
+            message: msg.into_owned(),
+        });
+    }
+
+    fn error(&self, msg: Cow<'static, str>) {
+        self.report(ProgressEvent::Error {
+            message: msg.into_owned(),
+        });
+    }
+
+    fn sub_progress(&self, _total: u64) -> Box<dyn Progress> {
+        Box::new(Self {
+            sender: self.sender.clone(),
+            package: self.package.clone(),
+            scope: self.scope.clone(),
+            log: self.log.clone(),
+        })
+    }
+
+    fn named_sub_progress(&self, name: Cow<'static, str>, _total: u64) -> Box<dyn Progress> {
+        let mut scope = self.scope.clone();
+        scope.push(name.into_owned());
+        Box::new(Self {
+            sender: self.sender.clone(),
+            package: self.package.clone(),
+            scope,
+            log: self.log.clone(),
+        })
+    }
+
+    fn report(&self, event: ProgressEvent) {
+        if let ProgressEvent::PackageStarted { package } = &event {
+            let _ = self.package.set(package.clone());
+        }
+        let _ = self.sender.send(ReportedEvent {
+            package: self.package.get().cloned(),
+            scope: self.scope.clone(),
+            event,
+        });
+    }
+}
+
+/// The state of a package followed by a [ProgressTracker].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PackageState {
+    /// The package is waiting for the packages it depends upon.
+    Queued,
+    /// The package is being prepared, such as by building binaries or
+    /// consulting the cache.
+    Started,
+    /// Blobs, or a prebuilt package, are being downloaded.
+    Fetching,
+    /// Inputs are being added to the package.
+    Archiving,
+    /// A cached copy of the package was used.
+    Cached,
+    /// The package has been built.
+    Done,
+    /// The package could not be built.
+    Failed,
+}
+
+impl PackageState {
+    const ALL: [PackageState; 7] = [
+        PackageState::Queued,
+        PackageState::Started,
+        PackageState::Fetching,
+        PackageState::Archiving,
+        PackageState::Cached,
+        PackageState::Done,
+        PackageState::Failed,
+    ];
+
+    /// Returns true if the package is no longer being worked on.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            PackageState::Cached | PackageState::Done | PackageState::Failed
+        )
+    }
+}
+
+impl std::fmt::Display for PackageState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            PackageState::Queued => "queued",
+            PackageState::Started => "started",
+            PackageState::Fetching => "fetching",
+            PackageState::Archiving => "archiving",
+            PackageState::Cached => "cached",
+            PackageState::Done => "done",
+            PackageState::Failed => "failed",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// The progress of a single package within a [ProgressSnapshot].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PackageProgress {
+    pub state: PackageState,
+    /// The number of inputs which have been added to the package.
+    pub inputs_added: u64,
+    /// How long the package has been in its current state.
+    pub elapsed: Duration,
+    /// An estimate of the time remaining to build the package, for packages
+    /// being worked on which appear in the [BuildHistory] given to
+    /// [ProgressTracker::with_history].
+    pub remaining: Option<Eta>,
+}
+
+/// The progress of every package followed by a [ProgressTracker], at a
+/// moment in time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProgressSnapshot {
+    pub packages: BTreeMap<PackageName, PackageProgress>,
+}
+
+impl ProgressSnapshot {
+    /// Returns the number of packages in `state`.
+    pub fn count(&self, state: PackageState) -> usize {
+        self.packages
+            .values()
+            .filter(|package| package.state == state)
+            .count()
+    }
+}
+
+/// Summarizes the number of packages in each state, followed by each
+/// package which is being worked on, as in:
+///
+/// ```text
+/// 12 packages: 3 queued, 1 fetching, 6 cached, 2 done
+///   my-service: fetching for 12.3s, ~2m remaining
+/// ```
+impl std::fmt::Display for ProgressSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let counts: Vec<_> = PackageState::ALL
+            .iter()
+            .map(|state| (state, self.count(*state)))
+            .filter(|(_, count)| *count > 0)
+            .map(|(state, count)| format!("{count} {state}"))
+            .collect();
+        write!(f, "{} packages", self.packages.len())?;
+        if !counts.is_empty() {
+            write!(f, ": {}", counts.join(", "))?;
+        }
+        for (name, package) in &self.packages {
+            if package.state != PackageState::Queued && !package.state.is_finished() {
+                write!(
+                    f,
+                    "\n  {name}: {} for {:.1}s",
+                    package.state,
+                    package.elapsed.as_secs_f64()
+                )?;
+                if let Some(remaining) = package.remaining {
+                    write!(f, ", {remaining}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct TrackedPackage {
+    state: PackageState,
+    inputs_added: u64,
+    since: Instant,
+    // The phases of the build which have completed, and the one in
+    // progress, with the time it started.
+    completed_phases: Vec<String>,
+    phase: Option<(String, Instant)>,
+}
+
+impl TrackedPackage {
+    fn new(state: PackageState) -> Self {
+        Self {
+            state,
+            inputs_added: 0,
+            since: Instant::now(),
+            completed_phases: vec![],
+            phase: None,
+        }
+    }
+
+    fn transition(&mut self, state: PackageState) {
+        // Packages only leave a finished state if they fail afterwards
+        // (e.g., in a post-build hook). Cached packages stay cached once
+        // they're completed.
+        if self.state == state || (self.state.is_finished() && state != PackageState::Failed) {
+            return;
+        }
+        self.state = state;
+        self.since = Instant::now();
+    }
+}
+
+/// Wraps another [`Progress`], following the state of each package being
+/// constructed, so that it can be summarized by [Self::snapshot].
+///
+/// This is intended for periodic status reports in builds of many packages,
+/// as by [crate::config::Config::build_all]. Every [Progress::sub_progress]
+/// shares the same state, and is associated with the package it reports
+/// [ProgressEvent::PackageStarted] for.
+///
+/// Everything is forwarded to the wrapped [`Progress`].
+pub struct ProgressTracker {
+    inner: Box<dyn Progress>,
+    packages: Arc<Mutex<BTreeMap<PackageName, TrackedPackage>>>,
+    package: OnceLock<PackageName>,
+    history: Arc<BuildHistory>,
+}
+
+impl ProgressTracker {
+    pub fn new<P: Progress + 'static>(inner: P) -> Self {
+        Self {
+            inner: Box::new(inner),
+            packages: Arc::default(),
+            package: OnceLock::new(),
+            history: Arc::default(),
+        }
+    }
+
+    /// Estimates the time remaining to build each package from the
+    /// durations of their previous builds, as recorded in `history`.
+    ///
+    /// See [BuildHistory::load].
+    pub fn with_history(mut self, history: BuildHistory) -> Self {
+        self.history = Arc::new(history);
+        self
+    }
+
+    /// Returns the progress of every package seen so far.
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let now = Instant::now();
+        let packages = self.packages.lock().unwrap();
+        ProgressSnapshot {
+            packages: packages
+                .iter()
+                .map(|(name, package)| {
+                    let remaining =
+                        if package.state == PackageState::Queued || package.state.is_finished() {
+                            None
+                        } else {
+                            let current = package
+                                .phase
+                                .as_ref()
+                                .map(|(phase, since)| (phase.as_str(), now.duration_since(*since)));
+                            self.history
+                                .remaining(name, &package.completed_phases, current)
+                        };
+                    let progress = PackageProgress {
+                        state: package.state,
+                        inputs_added: package.inputs_added,
+                        elapsed: now.duration_since(package.since),
+                        remaining,
+                    };
+                    (name.clone(), progress)
+                })
+                .collect(),
+        }
+    }
+
+    fn child(&self, inner: Box<dyn Progress>) -> Box<dyn Progress> {
+        Box::new(Self {
+            inner,
+            packages: self.packages.clone(),
+            package: self.package.clone(),
+            history: self.history.clone(),
+        })
+    }
+
+    fn track(&self, event: &ProgressEvent) {
+        let (package, state) = match event {
+            ProgressEvent::PackageQueued { package } => (package, PackageState::Queued),
+            ProgressEvent::PackageStarted { package } => {
+                let _ = self.package.set(package.clone());
+                (package, PackageState::Started)
+            }
+            ProgressEvent::PackageCompleted { package } => (package, PackageState::Done),
+            event => {
+                let Some(package) = self.package.get() else {
+                    return;
+                };
+                if let ProgressEvent::PhaseStarted { name } = event {
+                    self.track_phase(package, Some(name));
+                    return;
+                }
+                if let ProgressEvent::PhaseCompleted { .. } = event {
+                    self.track_phase(package, None);
+                    return;
+                }
+                let state = match event {
+                    ProgressEvent::BlobDownloadProgress { .. } => PackageState::Fetching,
+                    ProgressEvent::InputAdded { .. } => PackageState::Archiving,
+                    ProgressEvent::CacheHit => PackageState::Cached,
+                    ProgressEvent::Error { .. } => PackageState::Failed,
+                    _ => return,
+                };
+                (package, state)
+            }
+        };
+        let mut packages = self.packages.lock().unwrap();
+        let tracked = packages
+            .entry(package.clone())
+            .or_insert_with(|| TrackedPackage::new(state));
+        tracked.transition(state);
+        if let ProgressEvent::InputAdded { .. } = event {
+            tracked.inputs_added += 1;
+        }
+    }
+
+    // Records that `package` began the phase `started`, or finished its
+    // current phase.
+    fn track_phase(&self, package: &PackageName, started: Option<&String>) {
+        let mut packages = self.packages.lock().unwrap();
+        let Some(tracked) = packages.get_mut(package) else {
+            return;
+        };
+        if let Some((finished, _)) = tracked.phase.take() {
+            tracked.completed_phases.push(finished);
+        }
+        tracked.phase = started.map(|name| (name.clone(), Instant::now()));
+    }
+}
+
+impl Progress for ProgressTracker {
+    fn set_message(&self, msg: Cow<'static, str>) {
+        self.inner.set_message(msg)
+    }
+
+    fn get_log(&self) -> &Logger {
+        self.inner.get_log()
+    }
+
+    fn warn(&self, msg: Cow<'static, str>) {
+        self.inner.warn(msg)
+    }
+
+    fn error(&self, msg: Cow<'static, str>) {
+        self.track(&ProgressEvent::Error {
+            message: msg.to_string(),
+        });
+        self.inner.error(msg)
+    }
+
+    fn increment_total(&self, delta: u64) {
+        self.inner.increment_total(delta)
+    }
+
+    fn increment_completed(&self, delta: u64) {
+        self.inner.increment_completed(delta)
+    }
+
+    fn increment_total_bytes(&self, delta: u64) {
+        self.inner.increment_total_bytes(delta)
+    }
+
+    fn increment_completed_bytes(&self, delta: u64) {
+        self.inner.increment_completed_bytes(delta)
+    }
+
+    fn sub_progress(&self, total: u64) -> Box<dyn Progress> {
+        self.child(self.inner.sub_progress(total))
+    }
+
+    fn named_sub_progress(&self, name: Cow<'static, str>, total: u64) -> Box<dyn Progress> {
+        self.child(self.inner.named_sub_progress(name, total))
+    }
+
+    fn report(&self, event: ProgressEvent) {
+        self.track(&event);
+        self.inner.report(event)
+    }
+}
+
+// A complete event within a [ChromeTraceProgress].
+struct TraceEvent {
+    name: String,
+    category: &'static str,
+    // The package, identifying the "thread" the event is shown on.
+    thread: usize,
+    start: Duration,
+    duration: Duration,
+    args: serde_json::Map<String, serde_json::Value>,
+}
+
+struct TraceState {
+    epoch: Instant,
+    packages: Vec<PackageName>,
+    events: Vec<TraceEvent>,
+}
+
+impl TraceState {
+    fn thread(&mut self, package: &PackageName) -> usize {
+        match self.packages.iter().position(|p| p == package) {
+            Some(thread) => thread,
+            None => {
+                self.packages.push(package.clone());
+                self.packages.len() - 1
+            }
+        }
+    }
+}
+
+/// Wraps another [`Progress`], recording when each package's build, and
+/// each of its phases, started and finished.
+///
+/// Once the build completes, [Self::write] describes them as a Chrome
+/// trace, which may be loaded into `chrome://tracing` or Perfetto to see
+/// where the time went across a full build, as by
+/// [crate::config::Config::build_all]. Each package is shown as a thread,
+/// within which its phases are nested.
+///
+/// Everything is forwarded to the wrapped [`Progress`].
+pub struct ChromeTraceProgress {
+    inner: Box<dyn Progress>,
+    state: Arc<Mutex<TraceState>>,
+    // The package being built by this reporter, and when it started.
+    package: Mutex<Option<(PackageName, Instant)>>,
+    phase: Mutex<Option<(String, Instant)>>,
+}
+
+impl ChromeTraceProgress {
+    pub fn new<P: Progress + 'static>(inner: P) -> Self {
+        Self::with_state(
+            Box::new(inner),
+            Arc::new(Mutex::new(TraceState {
+                epoch: Instant::now(),
+                packages: vec![],
+                events: vec![],
+            })),
+        )
+    }
+
+    fn with_state(inner: Box<dyn Progress>, state: Arc<Mutex<TraceState>>) -> Self {
+        Self {
+            inner,
+            state,
+            package: Mutex::new(None),
+            phase: Mutex::new(None),
+        }
+    }
+
+    /// Returns the events recorded so far, in the Chrome trace event
+    /// format.
+    pub fn to_json(&self) -> serde_json::Value {
+        let state = self.state.lock().unwrap();
+        let micros = |duration: Duration| duration.as_secs_f64() * 1_000_000.0;
+        let threads = state.packages.iter().enumerate().map(|(thread, package)| {
+            serde_json::json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": 1,
+                "tid": thread,
+                "args": { "name": package.to_string() },
+            })
+        });
+        let events = state.events.iter().map(|event| {
+            serde_json::json!({
+                "name": event.name,
+                "cat": event.category,
+                "ph": "X",
+                "pid": 1,
+                "tid": event.thread,
+                "ts": micros(event.start),
+                "dur": micros(event.duration),
+                "args": event.args,
+            })
+        });
+        serde_json::json!({
+            "traceEvents": threads.chain(events).collect::<Vec<_>>(),
+            "displayTimeUnit": "ms",
+        })
+    }
+
+    /// Writes the events recorded so far to `writer`, as a Chrome trace.
+    pub fn write<W: std::io::Write>(&self, writer: W) -> anyhow::Result<()> {
+        serde_json::to_writer(writer, &self.to_json())?;
+        Ok(())
+    }
+
+    // Records an event for `package`, which started at `start` and has just
+    // finished.
+    fn record(
+        &self,
+        name: String,
+        category: &'static str,
+        package: &PackageName,
+        start: Instant,
+        args: serde_json::Map<String, serde_json::Value>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let thread = state.thread(package);
+        let event = TraceEvent {
+            name,
+            category,
+            thread,
+            start: start.saturating_duration_since(state.epoch),
+            duration: start.elapsed(),
+            args,
+        };
+        state.events.push(event);
+    }
+
+    // Records the build of this reporter's package as finished, with an
+    // error if it failed.
+    fn finish_package(&self, error: Option<&str>) {
+        let Some((package, start)) = self.package.lock().unwrap().take() else {
+            return;
+        };
+        if let Some((phase, start)) = self.phase.lock().unwrap().take() {
+            self.record(phase, "phase", &package, start, serde_json::Map::new());
+        }
+        let mut args = serde_json::Map::new();
+        if let Some(error) = error {
+            args.insert("error".to_string(), error.into());
+        }
+        self.record(package.to_string(), "package", &package, start, args);
+    }
+
+    fn track(&self, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::PackageStarted { package } => {
+                self.state.lock().unwrap().thread(package);
+                *self.package.lock().unwrap() = Some((package.clone(), Instant::now()));
+            }
+            ProgressEvent::PackageCompleted { .. } => self.finish_package(None),
+            ProgressEvent::PhaseStarted { name } => {
+                *self.phase.lock().unwrap() = Some((name.clone(), Instant::now()));
+            }
+            ProgressEvent::PhaseCompleted { label, .. } => {
+                let Some((package, _)) = self.package.lock().unwrap().clone() else {
+                    return;
+                };
+                let Some((phase, start)) = self.phase.lock().unwrap().take() else {
+                    return;
+                };
+                let mut args = serde_json::Map::new();
+                if let Some(label) = label {
+                    args.insert("label".to_string(), label.clone().into());
+                }
+                self.record(phase, "phase", &package, start, args);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Progress for ChromeTraceProgress {
+    fn set_message(&self, msg: Cow<'static, str>) {
+        self.inner.set_message(msg)
+    }
+
+    fn get_log(&self) -> &Logger {
+        self.inner.get_log()
+    }
+
+    fn warn(&self, msg: Cow<'static, str>) {
+        self.inner.warn(msg)
+    }
+
+    fn error(&self, msg: Cow<'static, str>) {
+        self.finish_package(Some(&msg));
+        self.inner.error(msg)
+    }
+
+    fn increment_total(&self, delta: u64) {
+        self.inner.increment_total(delta)
+    }
+
+    fn increment_completed(&self, delta: u64) {
+        self.inner.increment_completed(delta)
+    }
+
+    fn increment_total_bytes(&self, delta: u64) {
+        self.inner.increment_total_bytes(delta)
+    }
+
+    fn increment_completed_bytes(&self, delta: u64) {
+        self.inner.increment_completed_bytes(delta)
+    }
+
+    fn sub_progress(&self, total: u64) -> Box<dyn Progress> {
+        Box::new(Self::with_state(
+            self.inner.sub_progress(total),
+            self.state.clone(),
+        ))
+    }
+
+    fn named_sub_progress(&self, name: Cow<'static, str>, total: u64) -> Box<dyn Progress> {
+        Box::new(Self::with_state(
+            self.inner.named_sub_progress(name, total),
+            self.state.clone(),
+        ))
+    }
+
+    fn report(&self, event: ProgressEvent) {
+        self.track(&event);
+        self.inner.report(event)
+    }
+}
+
+/// Implements [`Progress`] through `tracing`, for consumers which don't use
+/// slog.
+///
+/// Each reporter has a span, which is a child of the span that was current
+/// when [TracingProgress::new] was called. [Progress::named_sub_progress]
+/// creates child spans (such as one per package, within
+/// [crate::config::Config::build_all]), and each phase of a build has a span
+/// of its own. Events such as cache hits, warnings, and downloads are
+/// emitted within those spans.
+///
+/// Messages logged through [Progress::get_log] are forwarded as `tracing`
+/// events, too.
+#[cfg(feature = "tracing")]
+pub struct TracingProgress {
+    span: tracing::Span,
+    // The span of the current phase, between PhaseStarted and
+    // PhaseCompleted events.
+    phase: std::sync::Mutex<Option<tracing::Span>>,
+    log: Logger,
+}
+
+#[cfg(feature = "tracing")]
+impl TracingProgress {
+    pub fn new() -> Self {
+        Self::with_span(tracing::info_span!(
+            "build",
+            package = tracing::field::Empty
+        ))
+    }
+
+    fn with_span(span: tracing::Span) -> Self {
+        Self {
+            span,
+            phase: std::sync::Mutex::new(None),
+            log: Logger::root(TracingDrain, slog::o!()),
+        }
+    }
+
+    // Returns the span within which events and sub tasks belong.
+    fn current_span(&self) -> tracing::Span {
+        self.phase
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| self.span.clone())
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Default for TracingProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Progress for TracingProgress {
+    fn set_message(&self, msg: Cow<'static, str>) {
+        tracing::debug!(parent: &self.current_span(), "{msg}");
+    }
+
+    fn get_log(&self) -> &Logger {
+        &self.log
+    }
+
+    fn warn(&self, msg: Cow<'static, str>) {
+        self.report(ProgressEvent::Warning {
+            message: msg.into_owned(),
+        });
+    }
+
+    fn error(&self, msg: Cow<'static, str>) {
+        self.report(ProgressEvent::Error {
+            message: msg.into_owned(),
+        });
+    }
+
+    fn sub_progress(&self, _total: u64) -> Box<dyn Progress> {
+        Box::new(Self::with_span(self.current_span()))
+    }
+
+    fn named_sub_progress(&self, name: Cow<'static, str>, _total: u64) -> Box<dyn Progress> {
+        Box::new(Self::with_span(tracing::info_span!(
+            parent: &self.current_span(),
+            "task",
+            %name,
+            package = tracing::field::Empty
+        )))
+    }
+
+    fn report(&self, event: ProgressEvent) {
+        let span = self.current_span();
+        match event {
+            ProgressEvent::PackageQueued { package } => {
+                tracing::debug!(parent: &span, %package, "package queued");
+            }
+            ProgressEvent::PackageStarted { package } => {
+                self.span
+                    .record("package", tracing::field::display(&package));
+            }
+            ProgressEvent::PackageCompleted { package } => {
+                tracing::info!(parent: &span, %package, "package completed");
+            }
+            ProgressEvent::InputAdded { input } => {
+                tracing::trace!(parent: &span, ?input, "input added");
+            }
+            ProgressEvent::BlobDownloadProgress {
+                name,
+                downloaded,
+                total,
+            } => {
+                tracing::trace!(parent: &span, %name, downloaded, ?total, "downloading");
+            }
+            ProgressEvent::PhaseStarted { name } => {
+                let phase = tracing::info_span!(parent: &self.span, "phase", %name);
+                *self.phase.lock().unwrap() = Some(phase);
+            }
+            ProgressEvent::PhaseCompleted {
+                name,
+                label,
+                duration,
+            } => {
+                tracing::debug!(parent: &span, %name, ?label, ?duration, "phase completed");
+                self.phase.lock().unwrap().take();
+            }
+            ProgressEvent::CacheHit => tracing::info!(parent: &span, "cache hit"),
+            ProgressEvent::CacheMiss { reason } => {
+                tracing::info!(parent: &span, %reason, "cache miss");
+            }
+            ProgressEvent::Warning { message } => tracing::warn!(parent: &span, "{message}"),
+            ProgressEvent::Error { message } => tracing::error!(parent: &span, "{message}"),
+        }
+    }
+}
+
+// Forwards records logged through slog as `tracing` events.
+#[cfg(feature = "tracing")]
+struct TracingDrain;
+
+#[cfg(feature = "tracing")]
+impl slog::Drain for TracingDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record<'_>,
+        _values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        let message = record.msg();
+        match record.level() {
+            slog::Level::Critical | slog::Level::Error => tracing::error!("{message}"),
+            slog::Level::Warning => tracing::warn!("{message}"),
+            slog::Level::Info => tracing::info!("{message}"),
+            slog::Level::Debug => tracing::debug!("{message}"),
+            slog::Level::Trace => tracing::trace!("{message}"),
+        }
+        Ok(())
+    }
+}
+
+/// Implements [`Progress`] by emitting OpenTelemetry spans.
+///
+/// Each reporter has a span, which is a child of the span it's created
+/// with. [Progress::named_sub_progress] creates child spans (one per
+/// package within [crate::config::Config::build_all], and one per blob
+/// download), and each phase of a build has a span of its own, named after
+/// the phase.
+///
+/// Spans are annotated with the name of the package, whether it was found
+/// in the cache (`cache.hit`, and `cache.miss_reason`), and the number of
+/// bytes processed. Warnings and errors are recorded as span events.
+#[cfg(feature = "opentelemetry")]
+pub struct OtelProgress {
+    tracer: Arc<opentelemetry::global::BoxedTracer>,
+    cx: opentelemetry::Context,
+    // The context of the current phase, between PhaseStarted and
+    // PhaseCompleted events.
+    phase: Mutex<Option<opentelemetry::Context>>,
+    package: OnceLock<PackageName>,
+    bytes: std::sync::atomic::AtomicU64,
+    log: Logger,
+}
+
+#[cfg(feature = "opentelemetry")]
+impl OtelProgress {
+    /// Creates a reporter whose spans are created by `tracer`, within the
+    /// current context.
+    ///
+    /// Most callers will use `opentelemetry::global::tracer(...)`.
+    pub fn new(tracer: opentelemetry::global::BoxedTracer, log: Logger) -> Self {
+        let tracer = Arc::new(tracer);
+        let cx = Self::start(&tracer, &opentelemetry::Context::current(), "build", vec![]);
+        Self::with_context(tracer, cx, OnceLock::new(), log)
+    }
+
+    fn with_context(
+        tracer: Arc<opentelemetry::global::BoxedTracer>,
+        cx: opentelemetry::Context,
+        package: OnceLock<PackageName>,
+        log: Logger,
+    ) -> Self {
+        Self {
+            tracer,
+            cx,
+            phase: Mutex::new(None),
+            package,
+            bytes: std::sync::atomic::AtomicU64::new(0),
+            log,
+        }
+    }
+
+    // Starts a span, returning a context containing it.
+    fn start(
+        tracer: &opentelemetry::global::BoxedTracer,
+        parent: &opentelemetry::Context,
+        name: &'static str,
+        attributes: Vec<opentelemetry::KeyValue>,
+    ) -> opentelemetry::Context {
+        use opentelemetry::trace::{SpanBuilder, TraceContextExt, Tracer};
+
+        let span = tracer.build_with_context(
+            SpanBuilder::from_name(name).with_attributes(attributes),
+            parent,
+        );
+        parent.with_span(span)
+    }
+
+    // Returns the context within which events and sub tasks belong.
+    fn current_context(&self) -> opentelemetry::Context {
+        self.phase
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| self.cx.clone())
+    }
+
+    fn package_attribute(&self) -> Vec<opentelemetry::KeyValue> {
+        self.package
+            .get()
+            .map(|package| opentelemetry::KeyValue::new("package.name", package.to_string()))
+            .into_iter()
+            .collect()
+    }
+}
+
+#[cfg(feature = "opentelemetry")]
+impl Drop for OtelProgress {
+    fn drop(&mut self) {
+        use opentelemetry::trace::TraceContextExt;
+
+        let span = self.cx.span();
+        let bytes = *self.bytes.get_mut();
+        if bytes > 0 {
+            span.set_attribute(opentelemetry::KeyValue::new("bytes", bytes as i64));
+        }
+        span.end();
+    }
+}
+
+#[cfg(feature = "opentelemetry")]
+impl Progress for OtelProgress {
+    fn get_log(&self) -> &Logger {
+        &self.log
+    }
+
+    fn increment_completed_bytes(&self, delta: u64) {
+        self.bytes
+            .fetch_add(delta, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn warn(&self, msg: Cow<'static, str>) {
+        self.report(ProgressEvent::Warning {
+            message: msg.into_owned(),
+        });
+    }
+
+    fn error(&self, msg: Cow<'static, str>) {
+        self.report(ProgressEvent::Error {
+            message: msg.into_owned(),
+        });
+    }
+
+    fn sub_progress(&self, total: u64) -> Box<dyn Progress> {
+        self.named_sub_progress("task".into(), total)
+    }
+
+    fn named_sub_progress(&self, name: Cow<'static, str>, total: u64) -> Box<dyn Progress> {
+        use opentelemetry::KeyValue;
+
+        let mut attributes = vec![KeyValue::new("task.name", name)];
+        if total > 0 {
+            attributes.push(KeyValue::new("task.total", total as i64));
+        }
+        attributes.extend(self.package_attribute());
+        let cx = Self::start(&self.tracer, &self.current_context(), "task", attributes);
+        Box::new(Self::with_context(
+            self.tracer.clone(),
+            cx,
+            self.package.clone(),
+            self.log.clone(),
+        ))
+    }
+
+    fn report(&self, event: ProgressEvent) {
+        use opentelemetry::trace::{Status, TraceContextExt};
+        use opentelemetry::KeyValue;
+
+        match event {
+            ProgressEvent::PackageStarted { package } => {
+                self.cx
+                    .span()
+                    .set_attribute(KeyValue::new("package.name", package.to_string()));
+                let _ = self.package.set(package);
+            }
+            ProgressEvent::PhaseStarted { name } => {
+                let mut attributes = vec![KeyValue::new("phase.name", name)];
+                attributes.extend(self.package_attribute());
+                let cx = Self::start(&self.tracer, &self.cx, "phase", attributes);
+                if let Some(previous) = self.phase.lock().unwrap().replace(cx) {
+                    previous.span().end();
+                }
+            }
+            ProgressEvent::PhaseCompleted { label, .. } => {
+                if let Some(cx) = self.phase.lock().unwrap().take() {
+                    if let Some(label) = label {
+                        cx.span().set_attribute(KeyValue::new("phase.label", label));
+                    }
+                    cx.span().end();
+                }
+            }
+            ProgressEvent::CacheHit => {
+                self.cx
+                    .span()
+                    .set_attribute(KeyValue::new("cache.hit", true));
+            }
+            ProgressEvent::CacheMiss { reason } => {
+                self.cx.span().set_attributes([
+                    KeyValue::new("cache.hit", false),
+                    KeyValue::new("cache.miss_reason", reason.to_string()),
+                ]);
+            }
+            ProgressEvent::Warning { message } => {
+                self.current_context()
+                    .span()
+                    .add_event("warning", vec![KeyValue::new("message", message)]);
+            }
+            ProgressEvent::Error { message } => {
+                let cx = self.current_context();
+                cx.span().set_status(Status::error(message.clone()));
+                cx.span()
+                    .add_event("error", vec![KeyValue::new("message", message)]);
+            }
+            ProgressEvent::PackageQueued { .. }
+            | ProgressEvent::PackageCompleted { .. }
+            | ProgressEvent::InputAdded { .. }
+            | ProgressEvent::BlobDownloadProgress { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sub_progress_events_name_their_package() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let (reporter, mut events) = EventReporter::new(log);
+
+        let a = reporter.sub_progress(0);
+        let b = reporter.sub_progress(0);
+        a.report(ProgressEvent::PackageStarted {
+            package: PackageName::new_const("a"),
+        });
+        b.report(ProgressEvent::PackageStarted {
+            package: PackageName::new_const("b"),
+        });
+        a.sub_progress(0).report(ProgressEvent::CacheHit);
+        reporter.report(ProgressEvent::CacheHit);
+
+        let packages: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|event| event.package.map(|package| package.to_string()))
+            .collect();
+        assert_eq!(
+            packages,
+            [
+                Some("a".to_string()),
+                Some("b".to_string()),
+                Some("a".to_string()),
+                None
+            ]
+        );
+
+        // Events are discarded once nobody is listening.
+        drop(events);
+        a.report(ProgressEvent::CacheHit);
+    }
+
+    #[test]
+    fn named_sub_progress_events_record_their_scope() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let (reporter, mut events) = EventReporter::new(log);
+
+        let package = reporter.named_sub_progress("svc".into(), 0);
+        let phase = package.named_sub_progress("add inputs to package".into(), 0);
+        phase.sub_progress(0).report(ProgressEvent::CacheHit);
+        phase
+            .named_sub_progress("blob.tar.gz".into(), 0)
+            .report(ProgressEvent::CacheHit);
+        package.report(ProgressEvent::CacheHit);
+
+        let scopes: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|event| event.scope)
+            .collect();
+        assert_eq!(
+            scopes,
+            [
+                vec!["svc", "add inputs to package"],
+                vec!["svc", "add inputs to package", "blob.tar.gz"],
+                vec!["svc"],
+            ]
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_progress_emits_spans_and_events() {
+        use std::sync::{Arc, Mutex};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata};
+
+        // Records the names of spans, and the messages of events prefixed
+        // with the names of the spans they were emitted within.
+        #[derive(Clone, Default)]
+        struct Recorder {
+            spans: Arc<Mutex<Vec<String>>>,
+            events: Arc<Mutex<Vec<String>>>,
+        }
+
+        struct Message(String);
+
+        impl tracing::field::Visit for Message {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{value:?}");
+                }
+            }
+        }
+
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(span.metadata().name().to_string());
+                Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                let parent = match event.parent() {
+                    Some(id) => self.spans.lock().unwrap()[id.into_u64() as usize - 1].clone(),
+                    None => "none".to_string(),
+                };
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("{parent}: {}", message.0));
+            }
+
+            fn enter(&self, _span: &Id) {}
+
+            fn exit(&self, _span: &Id) {}
+        }
+
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let progress = TracingProgress::new();
+            progress.report(ProgressEvent::PackageStarted {
+                package: PackageName::new_const("svc"),
+            });
+            progress.report(ProgressEvent::PhaseStarted {
+                name: "cache lookup".to_string(),
+            });
+            progress.report(ProgressEvent::CacheHit);
+            progress.report(ProgressEvent::PhaseCompleted {
+                name: "cache lookup".to_string(),
+                label: None,
+                duration: Duration::from_secs(1),
+            });
+            progress.warn("careful".into());
+            progress
+                .named_sub_progress("blob.tar.gz".into(), 0)
+                .error("failed".into());
+            slog::info!(progress.get_log(), "from slog");
+        });
+
+        assert_eq!(*recorder.spans.lock().unwrap(), ["build", "phase", "task"]);
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            [
+                "phase: cache hit",
+                "phase: phase completed",
+                "build: careful",
+                "task: failed",
+                "none: from slog",
+            ]
+        );
+    }
+
+    #[test]
+    fn tracker_follows_packages() {
+        let tracker = ProgressTracker::new(NoProgress::new());
+        let name = |name: &str| PackageName::new(name).unwrap();
+        for package in ["a", "b", "c"] {
+            tracker.report(ProgressEvent::PackageQueued {
+                package: name(package),
+            });
+        }
+        let state = |package: &str| tracker.snapshot().packages[&name(package)].state;
+        assert_eq!(state("a"), PackageState::Queued);
+
+        let a = tracker.sub_progress(0);
+        a.report(ProgressEvent::PackageStarted { package: name("a") });
+        assert_eq!(state("a"), PackageState::Started);
+        a.named_sub_progress("blob".into(), 0)
+            .report(ProgressEvent::BlobDownloadProgress {
+                name: "blob".to_string(),
+                downloaded: 1,
+                total: None,
+            });
+        assert_eq!(state("a"), PackageState::Fetching);
+        a.report(ProgressEvent::InputAdded {
+            input: BuildInput::Fingerprint {
+                name: "n".to_string(),
+                value: "v".to_string(),
+            },
+        });
+        assert_eq!(state("a"), PackageState::Archiving);
+        a.report(ProgressEvent::PackageCompleted { package: name("a") });
+        assert_eq!(state("a"), PackageState::Done);
+
+        let b = tracker.sub_progress(0);
+        b.report(ProgressEvent::PackageStarted { package: name("b") });
+        b.report(ProgressEvent::CacheHit);
+        b.report(ProgressEvent::PackageCompleted { package: name("b") });
+        assert_eq!(state("b"), PackageState::Cached);
+
+        let c = tracker.sub_progress(0);
+        c.report(ProgressEvent::PackageStarted { package: name("c") });
+        let snapshot = tracker.snapshot();
+        assert_eq!(snapshot.packages[&name("a")].inputs_added, 1);
+        assert!(snapshot
+            .to_string()
+            .starts_with("3 packages: 1 started, 1 cached, 1 done\n  c: started for "));
+
+        c.error("oops".into());
+        assert_eq!(state("c"), PackageState::Failed);
+    }
+
+    #[tokio::test]
+    async fn tracker_estimates_remaining_time() {
+        use crate::history::PhaseRecord;
+
+        let dir = camino_tempfile::tempdir().unwrap();
+        let nexus = PackageName::new_const("nexus");
+        let phase = |name: &str, secs| PhaseRecord {
+            name: name.to_string(),
+            duration: Duration::from_secs(secs),
+        };
+        BuildHistory::record(
+            dir.path(),
+            &nexus,
+            vec![phase("cache lookup", 10), phase("archive", 100)],
+        )
+        .await
+        .unwrap();
+        let history = BuildHistory::load(dir.path()).await.unwrap();
+
+        let tracker = ProgressTracker::new(NoProgress::new()).with_history(history);
+        let remaining = || tracker.snapshot().packages[&nexus].remaining;
+        let other = tracker.sub_progress(0);
+        other.report(ProgressEvent::PackageStarted {
+            package: PackageName::new_const("other"),
+        });
+        assert_eq!(
+            tracker.snapshot().packages[&PackageName::new_const("other")].remaining,
+            None
+        );
+
+        let progress = tracker.sub_progress(0);
+        progress.report(ProgressEvent::PackageStarted {
+            package: nexus.clone(),
+        });
+        assert_eq!(remaining(), Some(Eta(Duration::from_secs(110))));
+
+        progress.report(ProgressEvent::PhaseStarted {
+            name: "cache lookup".to_string(),
+        });
+        progress.report(ProgressEvent::PhaseCompleted {
+            name: "cache lookup".to_string(),
+            label: None,
+            duration: Duration::from_secs(1),
+        });
+        progress.report(ProgressEvent::PhaseStarted {
+            name: "archive".to_string(),
+        });
+        let Eta(eta) = remaining().unwrap();
+        assert!(eta <= Duration::from_secs(100) && eta > Duration::from_secs(90));
+        let snapshot = tracker.snapshot().to_string();
+        assert!(
+            snapshot.contains("s, ~2m remaining\n  other: "),
+            "{snapshot}"
+        );
+
+        progress.report(ProgressEvent::PackageCompleted {
+            package: nexus.clone(),
+        });
+        assert_eq!(remaining(), None);
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn otel_progress_emits_spans_with_attributes() {
+        use opentelemetry::trace::{
+            SpanBuilder, SpanContext, SpanId, Status, TraceContextExt, TraceFlags, TraceId,
+            TraceState, Tracer,
+        };
+        use opentelemetry::{Context, KeyValue};
+        use std::time::SystemTime;
+
+        #[derive(Debug, Default)]
+        struct RecordedSpan {
+            name: String,
+            parent: Option<usize>,
+            attributes: Vec<String>,
+            events: Vec<String>,
+            error: bool,
+            ended: bool,
+        }
+
+        // Records every span, identifying spans (and their parents) by
+        // their index within `spans`.
+        #[derive(Clone, Default)]
+        struct Recorder {
+            spans: Arc<Mutex<Vec<RecordedSpan>>>,
+        }
+
+        struct RecordingSpan {
+            cx: SpanContext,
+            spans: Arc<Mutex<Vec<RecordedSpan>>>,
+        }
+
+        impl RecordingSpan {
+            fn with(&self, f: impl FnOnce(&mut RecordedSpan)) {
+                let index = u64::from_be_bytes(self.cx.span_id().to_bytes()) as usize - 1;
+                f(&mut self.spans.lock().unwrap()[index])
+            }
+        }
+
+        impl opentelemetry::trace::Span for RecordingSpan {
+            fn add_event_with_timestamp<T>(
+                &mut self,
+                name: T,
+                _timestamp: SystemTime,
+                attributes: Vec<KeyValue>,
+            ) where
+                T: Into<Cow<'static, str>>,
+            {
+                let name = name.into();
+                self.with(|span| {
+                    for kv in attributes {
+                        span.events.push(format!("{name}: {}", kv.value));
+                    }
+                });
+            }
+
+            fn span_context(&self) -> &SpanContext {
+                &self.cx
+            }
+
+            fn is_recording(&self) -> bool {
+                true
+            }
+
+            fn set_attribute(&mut self, kv: KeyValue) {
+                self.with(|span| span.attributes.push(format!("{}={}", kv.key, kv.value)));
+            }
+
+            fn set_status(&mut self, status: Status) {
+                self.with(|span| span.error = matches!(status, Status::Error { .. }));
+            }
+
+            fn update_name<T>(&mut self, _new_name: T)
+            where
+                T: Into<Cow<'static, str>>,
+            {
+            }
+
+            fn add_link(&mut self, _span_context: SpanContext, _attributes: Vec<KeyValue>) {}
+
+            fn end_with_timestamp(&mut self, _timestamp: SystemTime) {
+                self.with(|span| span.ended = true);
+            }
+        }
+
+        impl Tracer for Recorder {
+            type Span = RecordingSpan;
+
+            fn build_with_context(
+                &self,
+                builder: SpanBuilder,
+                parent_cx: &Context,
+            ) -> RecordingSpan {
+                let mut spans = self.spans.lock().unwrap();
+                let parent = parent_cx.has_active_span().then(|| {
+                    u64::from_be_bytes(parent_cx.span().span_context().span_id().to_bytes())
+                        as usize
+                        - 1
+                });
+                spans.push(RecordedSpan {
+                    name: builder.name.to_string(),
+                    parent,
+                    attributes: builder
+                        .attributes
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|kv| format!("{}={}", kv.key, kv.value))
+                        .collect(),
+                    ..Default::default()
+                });
+                RecordingSpan {
+                    cx: SpanContext::new(
+                        TraceId::from(1u128),
+                        SpanId::from(spans.len() as u64),
+                        TraceFlags::SAMPLED,
+                        false,
+                        TraceState::default(),
+                    ),
+                    spans: self.spans.clone(),
+                }
+            }
+        }
+
+        let recorder = Recorder::default();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let progress = OtelProgress::new(
+            opentelemetry::global::BoxedTracer::new(Box::new(recorder.clone())),
+            log,
+        );
+        {
+            let package = progress.named_sub_progress("svc".into(), 0);
+            package.report(ProgressEvent::PackageStarted {
+                package: PackageName::new_const("svc"),
+            });
+            package.report(ProgressEvent::PhaseStarted {
+                name: "cache lookup".to_string(),
+            });
+            package.report(ProgressEvent::CacheMiss {
+                reason: MissReason::InputSetChanged,
+            });
+            package.report(ProgressEvent::PhaseCompleted {
+                name: "cache lookup".to_string(),
+                label: None,
+                duration: Duration::from_secs(1),
+            });
+            package.report(ProgressEvent::PhaseStarted {
+                name: "download".to_string(),
+            });
+            {
+                let blob = package.named_sub_progress("blob.tar.gz".into(), 0);
+                blob.increment_completed_bytes(1000);
+                blob.increment_completed_bytes(24);
+                blob.warn("slow".into());
+            }
+            package.report(ProgressEvent::PhaseCompleted {
+                name: "download".to_string(),
+                label: Some("1 blob".to_string()),
+                duration: Duration::from_secs(1),
+            });
+            package.error("oops".into());
+        }
+        drop(progress);
+
+        let spans = recorder.spans.lock().unwrap();
+        let summary: Vec<_> = spans
+            .iter()
+            .map(|span| (span.name.as_str(), span.parent))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("build", None),
+                ("task", Some(0)),
+                ("phase", Some(1)),
+                ("phase", Some(1)),
+                ("task", Some(3)),
+            ]
+        );
+        assert!(spans.iter().all(|span| span.ended));
+
+        assert_eq!(
+            spans[1].attributes,
+            [
+                "task.name=svc",
+                "package.name=svc",
+                "cache.hit=false",
+                "cache.miss_reason=Set of inputs has changed",
+            ]
+        );
+        assert!(spans[1].error);
+        assert_eq!(spans[1].events, ["error: oops"]);
+        assert_eq!(
+            spans[2].attributes,
+            ["phase.name=cache lookup", "package.name=svc"]
+        );
+        assert_eq!(
+            spans[3].attributes,
+            [
+                "phase.name=download",
+                "package.name=svc",
+                "phase.label=1 blob"
+            ]
+        );
+        assert_eq!(
+            spans[4].attributes,
+            ["task.name=blob.tar.gz", "package.name=svc", "bytes=1024"]
+        );
+        assert_eq!(spans[4].events, ["warning: slow"]);
+    }
+}
@@ -0,0 +1,80 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Describes where and how a package was built.
+
+use camino::Utf8Path;
+use std::collections::BTreeMap;
+use std::process::Command;
+
+/// Identifies the source revision and environment from which a package was
+/// built.
+///
+/// Fields which cannot be determined (for example, when building outside of
+/// a git repository) are left empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Provenance {
+    /// The git commit being built.
+    pub git_commit: Option<String>,
+
+    /// The git branch being built.
+    pub git_branch: Option<String>,
+
+    /// The user and host performing the build, as "user@host".
+    pub builder: Option<String>,
+
+    /// The time of the build, in RFC 3339 format.
+    pub build_time: Option<String>,
+}
+
+// Runs a command, returning its trimmed stdout if it succeeds.
+fn command_output(cmd: &mut Command) -> Option<String> {
+    let output = cmd.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    let stdout = stdout.trim();
+    (!stdout.is_empty()).then(|| stdout.to_string())
+}
+
+impl Provenance {
+    /// Gathers provenance for a build of the git repository containing
+    /// `directory`.
+    pub fn detect(directory: &Utf8Path) -> Self {
+        let git =
+            |args: &[&str]| command_output(Command::new("git").args(args).current_dir(directory));
+
+        let user = std::env::var("USER")
+            .or_else(|_| std::env::var("LOGNAME"))
+            .ok();
+        let host = command_output(Command::new("uname").arg("-n"));
+        let builder = match (user, host) {
+            (Some(user), Some(host)) => Some(format!("{user}@{host}")),
+            (user, host) => user.or(host),
+        };
+
+        Self {
+            git_commit: git(&["rev-parse", "HEAD"]),
+            git_branch: git(&["rev-parse", "--abbrev-ref", "HEAD"]),
+            builder,
+            build_time: Some(chrono::Utc::now().to_rfc3339()),
+        }
+    }
+
+    /// Returns the provenance as package metadata.
+    ///
+    /// See [crate::package::Package::stamp_with_metadata].
+    pub fn to_metadata(&self) -> BTreeMap<String, String> {
+        [
+            ("git_commit", &self.git_commit),
+            ("git_branch", &self.git_branch),
+            ("builder", &self.builder),
+            ("build_time", &self.build_time),
+        ]
+        .into_iter()
+        .filter_map(|(k, v)| Some((k.to_string(), v.clone()?)))
+        .collect()
+    }
+}
@@ -0,0 +1,144 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Describes the outcome of building a package.
+
+use crate::cache::MissReason;
+use camino::Utf8PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Describes whether a package was reused from the cache.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheOutcome {
+    /// A previously-built package was reused.
+    Hit,
+
+    /// The package was built, for the provided reason.
+    Miss { reason: MissReason },
+}
+
+/// Describes how long a single phase of the build took.
+///
+/// This may be serialized, such as to aggregate the timings of many builds.
+/// The duration is represented as a number of seconds, named
+/// `duration_secs`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BuildPhase {
+    /// The name of the phase.
+    pub name: String,
+
+    /// A label describing how the phase ended, if any.
+    pub label: Option<String>,
+
+    /// How long the phase took.
+    #[serde(rename = "duration_secs", with = "duration_secs")]
+    pub duration: Duration,
+
+    /// The time spent on each kind of work within the phase, such as
+    /// downloading blobs. Each is the total of possibly interleaved work,
+    /// and may itself have children, for work within it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<BuildPhase>,
+}
+
+/// Returns the total time spent in phases of each name, across every report,
+/// such as those of all of the packages built by a CI job.
+pub fn phase_totals<'a>(
+    reports: impl IntoIterator<Item = &'a BuildReport>,
+) -> BTreeMap<String, Duration> {
+    let mut totals = BTreeMap::new();
+    for phase in reports.into_iter().flat_map(|report| &report.phases) {
+        *totals.entry(phase.name.clone()).or_default() += phase.duration;
+    }
+    totals
+}
+
+// Represents durations as fractional seconds, which are simpler for other
+// tools to consume than serde's default representation.
+mod duration_secs {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        Duration::try_from_secs_f64(f64::deserialize(d)?).map_err(D::Error::custom)
+    }
+}
+
+/// Describes the outcome of [crate::package::Package::create_with_report].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuildReport {
+    /// The path of the package.
+    pub output_path: Utf8PathBuf,
+
+    /// Whether the package was reused from the cache.
+    pub cache: CacheOutcome,
+
+    /// The phases of the build, in order.
+    pub phases: Vec<BuildPhase>,
+
+    /// The size of the package, in bytes.
+    pub bytes_written: u64,
+
+    /// Blobs which were downloaded while building the package.
+    pub downloaded_blobs: Vec<Utf8PathBuf>,
+
+    /// The SHA-256 digest of the package, hex-encoded.
+    pub output_digest: String,
+}
+
+impl BuildReport {
+    /// Returns true if the package was reused from the cache.
+    pub fn cache_hit(&self) -> bool {
+        self.cache == CacheOutcome::Hit
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build_phase_serializes_seconds() {
+        let phase = BuildPhase {
+            name: "cache lookup".to_string(),
+            label: Some("Cache hit".to_string()),
+            duration: Duration::from_millis(1500),
+            children: vec![BuildPhase {
+                name: "hashing".to_string(),
+                label: None,
+                duration: Duration::from_millis(500),
+                children: vec![],
+            }],
+        };
+        let json = serde_json::to_value(&phase).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "name": "cache lookup",
+                "label": "Cache hit",
+                "duration_secs": 1.5,
+                "children": [{
+                    "name": "hashing",
+                    "label": null,
+                    "duration_secs": 0.5,
+                }],
+            })
+        );
+        assert_eq!(serde_json::from_value::<BuildPhase>(json).unwrap(), phase);
+
+        let negative = serde_json::json!({
+            "name": "cache lookup",
+            "label": null,
+            "duration_secs": -1.0,
+        });
+        assert!(serde_json::from_value::<BuildPhase>(negative).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Describes what a package contains, and where each part of it came from.
+
+use crate::input::{BuildInput, BuildInputs, InputOrigin};
+use camino::Utf8PathBuf;
+use serde::{Deserialize, Serialize};
+
+/// A software bill of materials: the files, blobs, and other packages which
+/// a package contains.
+///
+/// Entries fabricated on the target (such as directories and links), and
+/// files which only affect how the package is built, are omitted.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Sbom {
+    pub components: Vec<SbomComponent>,
+}
+
+/// A single part of a package, within an [Sbom].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SbomComponent {
+    /// The path of the component on the host.
+    pub source: Utf8PathBuf,
+
+    /// Where the component is placed within the package, if it's added as
+    /// a single entry.
+    pub destination: Option<Utf8PathBuf>,
+
+    /// The part of the manifest which produced the component, if known.
+    pub origin: Option<InputOrigin>,
+}
+
+impl Sbom {
+    /// Describes the contents of a package built from `inputs`, such as
+    /// those returned by [crate::package::Package::plan].
+    pub fn from_inputs(inputs: &BuildInputs) -> Self {
+        let components = inputs
+            .iter_with_origins()
+            .filter(|(_, input)| !matches!(input, BuildInput::Dependency(_)))
+            .filter_map(|(origin, input)| {
+                Some(SbomComponent {
+                    source: input.input_path()?.to_path_buf(),
+                    destination: input.destination().map(|path| path.to_path_buf()),
+                    origin: origin.cloned(),
+                })
+            })
+            .collect();
+        Self { components }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::archive::EntryMetadata;
+    use crate::input::{MappedPath, TargetDirectory};
+
+    #[test]
+    fn components_carry_origins() {
+        let mut inputs = BuildInputs::new();
+        inputs.push(BuildInput::add_directory(TargetDirectory("opt/svc".into())));
+        inputs.extend_with_origin(
+            InputOrigin::Path {
+                from: "a.txt".to_string(),
+                to: "opt/svc/a.txt".to_string(),
+            },
+            [BuildInput::AddFile {
+                mapped_path: MappedPath {
+                    from: "a.txt".into(),
+                    to: "opt/svc/a.txt".into(),
+                },
+                len: 0,
+                metadata: EntryMetadata::default(),
+            }],
+        );
+        inputs.push(BuildInput::Dependency("build.rs".into()));
+
+        let sbom = Sbom::from_inputs(&inputs);
+        assert_eq!(
+            sbom.components,
+            [SbomComponent {
+                source: "a.txt".into(),
+                destination: Some("opt/svc/a.txt".into()),
+                origin: Some(InputOrigin::Path {
+                    from: "a.txt".to_string(),
+                    to: "opt/svc/a.txt".to_string(),
+                }),
+            }]
+        );
+    }
+}
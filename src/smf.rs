@@ -0,0 +1,229 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Generation of illumos SMF service manifests.
+
+use crate::package::InterpolatedString;
+use crate::target::TargetMap;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+fn default_stop() -> InterpolatedString {
+    InterpolatedString::from(":kill")
+}
+
+fn default_duration() -> String {
+    "contract".to_string()
+}
+
+/// Describes an SMF service manifest to be generated for a package.
+///
+/// Example:
+///
+/// ```toml
+/// [package.my-service.smf]
+/// start = "/opt/oxide/my-service/bin/my-service run"
+/// properties = { "config/address" = "{{address}}" }
+/// ```
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SmfConfig {
+    /// The FMRI of the service, such as `svc:/oxide/my-service`.
+    ///
+    /// Defaults to `svc:/oxide/<service_name>`.
+    #[serde(default)]
+    pub fmri: Option<String>,
+
+    /// The command used to start the service.
+    pub start: InterpolatedString,
+
+    /// The command used to stop the service.
+    #[serde(default = "default_stop")]
+    pub stop: InterpolatedString,
+
+    /// The service model used by svc.startd (e.g. `contract`, `child`, or
+    /// `transient`).
+    #[serde(default = "default_duration")]
+    pub duration: String,
+
+    /// Application properties, keyed by `<property_group>/<property>`.
+    #[serde(default)]
+    pub properties: BTreeMap<String, InterpolatedString>,
+}
+
+impl SmfConfig {
+    /// Returns the name of the service, without the `svc:/` scheme.
+    pub fn service(&self, service_name: &str) -> String {
+        match &self.fmri {
+            Some(fmri) => fmri.trim_start_matches("svc:/").to_string(),
+            None => format!("oxide/{service_name}"),
+        }
+    }
+
+    /// Returns the path at which the manifest is installed within the zone.
+    pub fn manifest_path(&self, service_name: &str) -> Utf8PathBuf {
+        Utf8PathBuf::from("/var/svc/manifest/site")
+            .join(service_name)
+            .join("manifest.xml")
+    }
+
+    /// Renders the service manifest as XML, interpolating commands and
+    /// property values for `target`.
+    pub fn render(&self, service_name: &str, target: &TargetMap) -> Result<String> {
+        let service = self.service(service_name);
+
+        // Group properties by their property group.
+        let mut groups: BTreeMap<&str, Vec<(&str, String)>> = BTreeMap::new();
+        for (key, value) in &self.properties {
+            let (group, name) = key.split_once('/').ok_or_else(|| {
+                anyhow!("SMF property '{key}' must be of the form '<group>/<property>'")
+            })?;
+            groups
+                .entry(group)
+                .or_default()
+                .push((name, value.interpolate(target)?));
+        }
+
+        let mut xml = String::new();
+        writeln!(xml, r#"<?xml version="1.0"?>"#)?;
+        writeln!(
+            xml,
+            r#"<!DOCTYPE service_bundle SYSTEM "/usr/share/lib/xml/dtd/service_bundle.dtd.1">"#
+        )?;
+        writeln!(
+            xml,
+            r#"<service_bundle type="manifest" name="{}">"#,
+            escape(service_name)
+        )?;
+        writeln!(
+            xml,
+            r#"  <service name="{}" type="service" version="1">"#,
+            escape(&service)
+        )?;
+        writeln!(xml, r#"    <create_default_instance enabled="true"/>"#)?;
+        writeln!(
+            xml,
+            r#"    <dependency name="multi_user" grouping="require_all" restart_on="none" type="service">"#
+        )?;
+        writeln!(
+            xml,
+            r#"      <service_fmri value="svc:/milestone/multi-user:default"/>"#
+        )?;
+        writeln!(xml, r#"    </dependency>"#)?;
+        for (method, exec) in [("start", &self.start), ("stop", &self.stop)] {
+            writeln!(
+                xml,
+                r#"    <exec_method type="method" name="{method}" exec="{}" timeout_seconds="0"/>"#,
+                escape(&exec.interpolate(target)?)
+            )?;
+        }
+        writeln!(
+            xml,
+            r#"    <property_group name="startd" type="framework">"#
+        )?;
+        writeln!(
+            xml,
+            r#"      <propval name="duration" type="astring" value="{}"/>"#,
+            escape(&self.duration)
+        )?;
+        writeln!(xml, r#"    </property_group>"#)?;
+        for (group, properties) in &groups {
+            writeln!(
+                xml,
+                r#"    <property_group name="{}" type="application">"#,
+                escape(group)
+            )?;
+            for (name, value) in properties {
+                writeln!(
+                    xml,
+                    r#"      <propval name="{}" type="astring" value="{}"/>"#,
+                    escape(name),
+                    escape(value)
+                )?;
+            }
+            writeln!(xml, r#"    </property_group>"#)?;
+        }
+        writeln!(xml, r#"    <stability value="Unstable"/>"#)?;
+        writeln!(xml, r#"  </service>"#)?;
+        writeln!(xml, r#"</service_bundle>"#)?;
+        Ok(xml)
+    }
+}
+
+// Escapes a string for use within an XML attribute.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_manifest() {
+        let smf: SmfConfig = toml::from_str(
+            r#"
+            start = "/opt/oxide/svc/bin/svc --addr {{addr}}"
+            properties = { "config/address" = "{{addr}}", "config/name" = "a&b" }
+            "#,
+        )
+        .unwrap();
+        let mut target = TargetMap(BTreeMap::new());
+        target.0.insert("addr".to_string(), "[::1]:80".to_string());
+
+        assert_eq!(
+            smf.manifest_path("svc"),
+            "/var/svc/manifest/site/svc/manifest.xml"
+        );
+        let xml = smf.render("svc", &target).unwrap();
+        assert!(xml.contains(r#"<service name="oxide/svc" type="service" version="1">"#));
+        assert!(xml.contains(
+            r#"<exec_method type="method" name="start" exec="/opt/oxide/svc/bin/svc --addr [::1]:80" timeout_seconds="0"/>"#
+        ));
+        assert!(xml.contains(
+            r#"<exec_method type="method" name="stop" exec=":kill" timeout_seconds="0"/>"#
+        ));
+        assert!(xml.contains(
+            r#"    <property_group name="config" type="application">
+      <propval name="address" type="astring" value="[::1]:80"/>
+      <propval name="name" type="astring" value="a&amp;b"/>
+    </property_group>"#
+        ));
+    }
+
+    #[test]
+    fn render_rejects_ungrouped_properties() {
+        let smf: SmfConfig = toml::from_str(
+            r#"
+            fmri = "svc:/site/svc"
+            start = "/bin/true"
+            properties = { "address" = "x" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(smf.service("svc"), "site/svc");
+        let err = smf
+            .render("svc", &TargetMap(BTreeMap::new()))
+            .expect_err("Property without a group should fail");
+        assert_eq!(
+            err.to_string(),
+            "SMF property 'address' must be of the form '<group>/<property>'"
+        );
+    }
+}
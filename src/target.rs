@@ -2,6 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::config::{Config, PresetName};
 use crate::package::Package;
 use serde::Deserialize;
 use std::collections::BTreeMap;
@@ -16,61 +17,1187 @@ use std::collections::BTreeMap;
 pub struct TargetMap(pub BTreeMap<String, String>);
 
 impl TargetMap {
+    /// Creates a target from the preset named `preset` within `config`,
+    /// adjusted by `overrides`.
+    ///
+    /// Each key of `overrides` takes precedence over the preset's value for
+    /// that key, and keys not defined by the preset are added. If `config`
+    /// declares [crate::config::TargetConfig::keys], the resulting target is
+    /// validated against them.
+    pub fn from_preset(
+        config: &Config,
+        preset: &PresetName,
+        overrides: &TargetMap,
+    ) -> Result<TargetMap, TargetParseError> {
+        let presets = &config.target.presets;
+        let Some(target) = presets.get(preset) else {
+            let names: Vec<String> = presets.keys().map(|name| name.to_string()).collect();
+            return Err(TargetParseError::UnknownPreset {
+                name: preset.to_string(),
+                suggestion: closest(preset.as_str(), &names),
+            });
+        };
+
+        let mut target = target.clone();
+        target.0.extend(overrides.0.clone());
+        if let Some(keys) = &config.target.keys {
+            keys.validate(&target)?;
+        }
+        Ok(target)
+    }
+
     // Returns true if this target should include the package.
     pub(crate) fn includes_package(&self, pkg: &Package) -> bool {
-        let valid_targets = if let Some(targets) = &pkg.only_for_targets {
-            // If targets are specified for the packages, filter them.
-            targets
-        } else {
-            // If no targets are specified, assume the package should be
-            // included by default.
-            return true;
-        };
+        matches!(self.explain_package(pkg), Inclusion::Included)
+    }
 
-        // For each of the targets permitted by the package, check if
-        // the current target matches.
-        for (k, v) in &valid_targets.0 {
-            let target_value = if let Some(target_value) = self.0.get(k) {
-                target_value
-            } else {
-                return false;
-            };
+    /// Determines whether this target includes `pkg`, and if not, why not.
+    ///
+    /// [Package::only_if] is considered first, then
+    /// [Package::not_for_targets], and then [Package::only_for_targets]; the
+    /// first reason to exclude the package is returned.
+    pub fn explain_package(&self, pkg: &Package) -> Inclusion {
+        if let Some(expr) = &pkg.only_if {
+            if !expr.evaluate(self) {
+                return Inclusion::Excluded(Exclusion::OnlyIf { expr: expr.clone() });
+            }
+        }
 
-            if target_value != v {
-                return false;
-            };
+        if let Some((key, values, actual)) = pkg
+            .not_for_targets
+            .as_ref()
+            .and_then(|excluded| excluded.first_match(self))
+        {
+            return Inclusion::Excluded(Exclusion::NotForTargets {
+                key: key.to_string(),
+                excluded: values.clone(),
+                actual: actual.to_string(),
+            });
+        }
+
+        // If no targets are specified, assume the package should be
+        // included by default.
+        if let Some((key, values, actual)) = pkg
+            .only_for_targets
+            .as_ref()
+            .and_then(|targets| targets.mismatch(self))
+        {
+            return Inclusion::Excluded(Exclusion::OnlyForTargets {
+                key: key.to_string(),
+                expected: values.clone(),
+                actual: actual.map(str::to_string),
+            });
+        }
+        Inclusion::Included
+    }
+}
+
+/// Whether a target includes a package; see [TargetMap::explain_package].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inclusion {
+    Included,
+    Excluded(Exclusion),
+}
+
+/// The reason a target excludes a package.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Exclusion {
+    /// The target does not satisfy [Package::only_if].
+    OnlyIf { expr: TargetExpr },
+    /// The value of `key` is excluded by [Package::not_for_targets].
+    NotForTargets {
+        key: String,
+        excluded: TargetValues,
+        actual: String,
+    },
+    /// The value of `key` is not permitted by [Package::only_for_targets],
+    /// or the target does not define `key` at all.
+    OnlyForTargets {
+        key: String,
+        expected: TargetValues,
+        actual: Option<String>,
+    },
+}
+
+impl std::fmt::Display for Exclusion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Exclusion::OnlyIf { expr } => {
+                write!(f, "target does not satisfy only_if: {expr}")
+            }
+            Exclusion::NotForTargets {
+                key,
+                excluded,
+                actual,
+            } => write!(
+                f,
+                "not_for_targets excludes {key}={actual} (excluded: {excluded})"
+            ),
+            Exclusion::OnlyForTargets {
+                key,
+                expected,
+                actual: Some(actual),
+            } => write!(
+                f,
+                "only_for_targets requires {key} to be {expected}, but it is '{actual}'"
+            ),
+            Exclusion::OnlyForTargets {
+                key,
+                expected,
+                actual: None,
+            } => write!(
+                f,
+                "only_for_targets requires {key} to be {expected}, but the target \
+                 does not define it"
+            ),
         }
-        true
     }
 }
 
+/// Displays each key-value pair as `key=value`, separated by spaces.
+///
+/// Keys and values containing whitespace, `=`, or `"` are enclosed in double
+/// quotes, within which `"` and `\` are escaped by a backslash, so that the
+/// output may be parsed back into the same map.
 impl std::fmt::Display for TargetMap {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         for (key, value) in &self.0 {
-            write!(f, "{}={} ", key, value)?;
+            write!(f, "{}={} ", quote(key), quote(value))?;
         }
         Ok(())
     }
 }
 
+// Quotes `s`, if necessary, so that it's parsed as a single key or value.
+fn quote(s: &str) -> std::borrow::Cow<'_, str> {
+    if !s.contains(|c: char| c.is_whitespace() || c == '=' || c == '"') {
+        return s.into();
+    }
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted.into()
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum TargetParseError {
     #[error("Cannot parse key-value pair out of '{0}'")]
     MissingEquals(String),
+    #[error("Unterminated quote in '{0}'")]
+    UnterminatedQuote(String),
+    #[error("Unknown target preset '{name}'{}", did_you_mean(.suggestion))]
+    UnknownPreset {
+        name: String,
+        suggestion: Option<String>,
+    },
+    #[error("Unknown target key '{key}'{}", did_you_mean(.suggestion))]
+    UnknownKey {
+        key: String,
+        suggestion: Option<String>,
+    },
+    #[error(
+        "Invalid value '{value}' for target key '{key}' (expected one of: {}){}",
+        .allowed.join(", "),
+        did_you_mean(.suggestion)
+    )]
+    InvalidValue {
+        key: String,
+        value: String,
+        allowed: Vec<String>,
+        suggestion: Option<String>,
+    },
 }
 
+fn did_you_mean(suggestion: &Option<String>) -> String {
+    suggestion
+        .as_ref()
+        .map(|s| format!("; did you mean '{s}'?"))
+        .unwrap_or_default()
+}
+
+/// Parses whitespace-separated `key=value` pairs, as displayed by
+/// [TargetMap].
+///
+/// Double quotes may enclose any part of a key or value, such as
+/// `name="a b"`; within them, whitespace and `=` have no special meaning,
+/// and a backslash escapes the following character.
 impl std::str::FromStr for TargetMap {
     type Err = TargetParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let kvs = s
-            .split_whitespace()
-            .map(|kv| {
-                kv.split_once('=')
-                    .ok_or_else(|| TargetParseError::MissingEquals(kv.to_string()))
-                    .map(|(k, v)| (k.to_string(), v.to_string()))
-            })
-            .collect::<Result<BTreeMap<String, String>, _>>()?;
+        let mut kvs = BTreeMap::new();
+        let mut chars = s.chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.peek().is_none() {
+                break;
+            }
+
+            // The pair as written, for use in errors.
+            let mut raw = String::new();
+            let mut key = String::new();
+            let mut value: Option<String> = None;
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                raw.push(c);
+                match c {
+                    '"' => loop {
+                        let c = match chars.next() {
+                            Some('"') => {
+                                raw.push('"');
+                                break;
+                            }
+                            Some('\\') => {
+                                raw.push('\\');
+                                chars.next()
+                            }
+                            c => c,
+                        };
+                        let Some(c) = c else {
+                            return Err(TargetParseError::UnterminatedQuote(raw));
+                        };
+                        raw.push(c);
+                        value.as_mut().unwrap_or(&mut key).push(c);
+                    },
+                    '=' if value.is_none() => value = Some(String::new()),
+                    c => value.as_mut().unwrap_or(&mut key).push(c),
+                }
+            }
+            let Some(value) = value else {
+                return Err(TargetParseError::MissingEquals(raw));
+            };
+            kvs.insert(key, value);
+        }
         Ok(TargetMap(kvs))
     }
 }
+
+/// The keys which targets may define, and the values permitted for each, as
+/// declared by a manifest:
+///
+/// ```toml
+/// [target.keys]
+/// image = ["standard", "trampoline"]
+/// machine = ["gimlet", "cosmo", "non-gimlet"]
+/// rack = []
+/// ```
+///
+/// A key with no listed values may have any value. This catches typos, such
+/// as `macine=gimlet`, which would otherwise silently exclude packages.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct TargetSchema(pub BTreeMap<String, Vec<String>>);
+
+impl TargetSchema {
+    /// Parses a target, as with [TargetMap]'s implementation of
+    /// [std::str::FromStr], and ensures that it satisfies this schema.
+    pub fn parse(&self, s: &str) -> Result<TargetMap, TargetParseError> {
+        let target = s.parse()?;
+        self.validate(&target)?;
+        Ok(target)
+    }
+
+    /// Ensures that every key of `target` is known, and has a permitted
+    /// value.
+    pub fn validate(&self, target: &TargetMap) -> Result<(), TargetParseError> {
+        for (key, value) in &target.0 {
+            self.check(key, Some(value))?;
+        }
+        Ok(())
+    }
+
+    /// Ensures that `filter` only names known keys, and permitted values.
+    ///
+    /// Wildcard values must match at least one permitted value.
+    pub fn validate_filter(&self, filter: &TargetFilter) -> Result<(), TargetParseError> {
+        for (key, values) in &filter.0 {
+            match values {
+                TargetValues::One(value) => self.check_filter_value(key, value)?,
+                TargetValues::Any(values) => {
+                    self.check(key, None)?;
+                    for value in values {
+                        self.check_filter_value(key, value)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Ensures that `expr` only names known keys, and only compares them
+    /// with permitted values.
+    pub fn validate_expr(&self, expr: &TargetExpr) -> Result<(), TargetParseError> {
+        expr.expr.validate(self)
+    }
+
+    /// Adds the keys and values of `other` to this schema.
+    pub fn extend(&mut self, other: TargetSchema) {
+        for (key, values) in other.0 {
+            match self.0.get_mut(&key) {
+                Some(existing) if existing.is_empty() => (),
+                Some(existing) if !values.is_empty() => {
+                    for value in values {
+                        if !existing.contains(&value) {
+                            existing.push(value);
+                        }
+                    }
+                }
+                _ => {
+                    self.0.insert(key, values);
+                }
+            }
+        }
+    }
+
+    // As with `check`, but `value` may be a wildcard, which is permitted if
+    // it matches any permitted value.
+    fn check_filter_value(&self, key: &str, value: &str) -> Result<(), TargetParseError> {
+        if !is_wildcard(value) {
+            return self.check(key, Some(value));
+        }
+        self.check(key, None)?;
+        let allowed = &self.0[key];
+        if allowed.is_empty() || allowed.iter().any(|a| value_matches(value, a)) {
+            Ok(())
+        } else {
+            Err(TargetParseError::InvalidValue {
+                key: key.to_string(),
+                value: value.to_string(),
+                allowed: allowed.clone(),
+                suggestion: None,
+            })
+        }
+    }
+
+    // Ensures that `key` is known, and if provided, that `value` is
+    // permitted for it.
+    fn check(&self, key: &str, value: Option<&str>) -> Result<(), TargetParseError> {
+        let Some(allowed) = self.0.get(key) else {
+            return Err(TargetParseError::UnknownKey {
+                key: key.to_string(),
+                suggestion: closest(key, self.0.keys()),
+            });
+        };
+        match value {
+            Some(value) if !allowed.is_empty() && !allowed.iter().any(|a| a == value) => {
+                Err(TargetParseError::InvalidValue {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    allowed: allowed.clone(),
+                    suggestion: closest(value, allowed),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Returns the candidate most resembling `unknown`, if any are close enough
+// to be a likely typo.
+fn closest<'a>(unknown: &str, candidates: impl IntoIterator<Item = &'a String>) -> Option<String> {
+    let (distance, candidate) = candidates
+        .into_iter()
+        .map(|candidate| (crate::config::edit_distance(unknown, candidate), candidate))
+        .min()?;
+    (distance <= (unknown.len() / 3).max(1)).then(|| candidate.clone())
+}
+
+/// The targets for which a package is built, as in:
+///
+/// ```toml
+/// only_for_targets.image = "standard"
+/// only_for_targets.machine = ["gimlet", "cosmo"]
+/// ```
+///
+/// A target matches if, for every key, its value is one of those permitted.
+///
+/// Values may be glob-style wildcards, as in `machine = "gimlet*"`, where `*`
+/// matches any sequence of characters, `?` matches any single character,
+/// and `[...]` matches any character within the brackets.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct TargetFilter(pub BTreeMap<String, TargetValues>);
+
+impl TargetFilter {
+    /// Returns true if `target` has a permitted value for every key.
+    pub fn matches(&self, target: &TargetMap) -> bool {
+        self.mismatch(target).is_none()
+    }
+
+    /// Returns true if `target` has a permitted value for any key.
+    pub fn matches_any(&self, target: &TargetMap) -> bool {
+        self.first_match(target).is_some()
+    }
+
+    /// Returns the first key for which `target` does not have a permitted
+    /// value, along with the permitted values and the value of `target`, if
+    /// it defines the key at all.
+    pub fn mismatch<'a>(
+        &'a self,
+        target: &'a TargetMap,
+    ) -> Option<(&'a str, &'a TargetValues, Option<&'a str>)> {
+        self.0.iter().find_map(|(key, values)| {
+            let actual = target.0.get(key);
+            (!actual.is_some_and(|actual| values.contains(actual))).then_some((
+                key.as_str(),
+                values,
+                actual.map(String::as_str),
+            ))
+        })
+    }
+
+    /// Returns the first key for which `target` has a permitted value,
+    /// along with the permitted values and the value of `target`.
+    pub fn first_match<'a>(
+        &'a self,
+        target: &'a TargetMap,
+    ) -> Option<(&'a str, &'a TargetValues, &'a str)> {
+        self.0.iter().find_map(|(key, values)| {
+            let actual = target.0.get(key)?;
+            values
+                .contains(actual)
+                .then_some((key.as_str(), values, actual.as_str()))
+        })
+    }
+}
+
+/// The values permitted for a single key of a [TargetFilter].
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum TargetValues {
+    /// Exactly one value.
+    One(String),
+    /// Any of several values.
+    Any(Vec<String>),
+}
+
+impl TargetValues {
+    /// Returns true if `value` is permitted, either exactly or by a
+    /// wildcard.
+    pub fn contains(&self, value: &str) -> bool {
+        match self {
+            TargetValues::One(permitted) => value_matches(permitted, value),
+            TargetValues::Any(permitted) => permitted.iter().any(|p| value_matches(p, value)),
+        }
+    }
+}
+
+impl std::fmt::Display for TargetValues {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TargetValues::One(value) => write!(f, "'{value}'"),
+            TargetValues::Any(values) => {
+                f.write_str("one of ")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "'{value}'")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Returns true if `permitted` contains any wildcard characters.
+fn is_wildcard(permitted: &str) -> bool {
+    permitted.contains(['*', '?', '['])
+}
+
+// Returns true if `value` is `permitted`, or matches it as a wildcard.
+//
+// Values which aren't valid patterns, such as "a[b", only match exactly.
+fn value_matches(permitted: &str, value: &str) -> bool {
+    permitted == value
+        || (is_wildcard(permitted)
+            && glob::Pattern::new(permitted).is_ok_and(|pattern| pattern.matches(value)))
+}
+
+/// A condition upon a [TargetMap], such as
+/// `target.machine == 'gimlet' && target.switch != 'stub'`.
+///
+/// Expressions compare target keys (written as `target.KEY`) and quoted
+/// strings with `==` and `!=`, and combine comparisons with `&&`, `||`, `!`,
+/// and parentheses. `&&` binds more tightly than `||`. A key which the
+/// target does not define is unequal to every value.
+///
+/// `and`, `or`, and `not` may be written in place of `&&`, `||`, and `!`,
+/// and `in` tests whether a value is one of a list, as in
+/// `target.machine in ['gimlet', 'cosmo'] and not target.switch == 'stub'`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TargetExpr {
+    source: String,
+    expr: Expr,
+}
+
+impl TargetExpr {
+    /// Returns true if `target` satisfies this expression.
+    pub fn evaluate(&self, target: &TargetMap) -> bool {
+        self.expr.evaluate(target)
+    }
+}
+
+impl std::fmt::Display for TargetExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid target expression '{expr}': {reason}")]
+pub struct TargetExprError {
+    expr: String,
+    reason: String,
+}
+
+impl std::str::FromStr for TargetExpr {
+    type Err = TargetExprError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |reason: String| TargetExprError {
+            expr: s.to_string(),
+            reason,
+        };
+        let tokens = tokenize(s).map_err(error)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+        let expr = parser.or().map_err(error)?;
+        if let Some(token) = parser.peek() {
+            return Err(error(format!("unexpected {token}")));
+        }
+        Ok(TargetExpr {
+            source: s.to_string(),
+            expr,
+        })
+    }
+}
+
+impl TryFrom<String> for TargetExpr {
+    type Error = TargetExprError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare {
+        lhs: Operand,
+        rhs: Operand,
+        equal: bool,
+    },
+    In {
+        lhs: Operand,
+        values: Vec<Operand>,
+    },
+}
+
+impl Expr {
+    fn evaluate(&self, target: &TargetMap) -> bool {
+        match self {
+            Expr::Not(expr) => !expr.evaluate(target),
+            Expr::And(lhs, rhs) => lhs.evaluate(target) && rhs.evaluate(target),
+            Expr::Or(lhs, rhs) => lhs.evaluate(target) || rhs.evaluate(target),
+            Expr::Compare { lhs, rhs, equal } => match (lhs.value(target), rhs.value(target)) {
+                (Some(lhs), Some(rhs)) => (lhs == rhs) == *equal,
+                _ => !equal,
+            },
+            Expr::In { lhs, values } => lhs
+                .value(target)
+                .is_some_and(|lhs| values.iter().any(|value| value.value(target) == Some(lhs))),
+        }
+    }
+
+    fn validate(&self, schema: &TargetSchema) -> Result<(), TargetParseError> {
+        // Ensures that keys are known, and that literals compared with them
+        // are permitted.
+        let compare = |lhs: &Operand, rhs: &Operand| match (lhs, rhs) {
+            (Operand::Key(key), Operand::Literal(value))
+            | (Operand::Literal(value), Operand::Key(key)) => schema.check(key, Some(value)),
+            (Operand::Key(lhs), Operand::Key(rhs)) => {
+                schema.check(lhs, None)?;
+                schema.check(rhs, None)
+            }
+            (Operand::Literal(_), Operand::Literal(_)) => Ok(()),
+        };
+        match self {
+            Expr::Not(expr) => expr.validate(schema),
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => {
+                lhs.validate(schema)?;
+                rhs.validate(schema)
+            }
+            Expr::Compare { lhs, rhs, .. } => compare(lhs, rhs),
+            Expr::In { lhs, values } => values.iter().try_for_each(|value| compare(lhs, value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    Key(String),
+    Literal(String),
+}
+
+impl Operand {
+    fn value<'a>(&'a self, target: &'a TargetMap) -> Option<&'a str> {
+        match self {
+            Operand::Key(key) => target.0.get(key).map(|value| value.as_str()),
+            Operand::Literal(value) => Some(value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Operand(Operand),
+    Eq,
+    Ne,
+    And,
+    Or,
+    Not,
+    In,
+    Open,
+    Close,
+    OpenList,
+    CloseList,
+    Comma,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Operand(Operand::Key(key)) => write!(f, "'target.{key}'"),
+            Token::Operand(Operand::Literal(value)) => write!(f, "string '{value}'"),
+            Token::Eq => write!(f, "'=='"),
+            Token::Ne => write!(f, "'!='"),
+            Token::And => write!(f, "'&&'"),
+            Token::Or => write!(f, "'||'"),
+            Token::Not => write!(f, "'!'"),
+            Token::In => write!(f, "'in'"),
+            Token::Open => write!(f, "'('"),
+            Token::Close => write!(f, "')'"),
+            Token::OpenList => write!(f, "'['"),
+            Token::CloseList => write!(f, "']'"),
+            Token::Comma => write!(f, "','"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '[' => Token::OpenList,
+            ']' => Token::CloseList,
+            ',' => Token::Comma,
+            '=' | '&' | '|' => {
+                if chars.next_if(|(_, next)| *next == c).is_none() {
+                    return Err(format!("expected '{c}{c}'"));
+                }
+                match c {
+                    '=' => Token::Eq,
+                    '&' => Token::And,
+                    _ => Token::Or,
+                }
+            }
+            '!' => {
+                if chars.next_if(|(_, next)| *next == '=').is_some() {
+                    Token::Ne
+                } else {
+                    Token::Not
+                }
+            }
+            '\'' | '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, next)) if next == c => break,
+                        Some((_, next)) => value.push(next),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                Token::Operand(Operand::Literal(value))
+            }
+            c if is_word_char(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, next)) = chars.next_if(|(_, next)| is_word_char(*next)) {
+                    end = i + next.len_utf8();
+                }
+                let word = &s[start..end];
+                match (word, word.strip_prefix("target.")) {
+                    ("and", _) => Token::And,
+                    ("or", _) => Token::Or,
+                    ("not", _) => Token::Not,
+                    ("in", _) => Token::In,
+                    (_, Some(key)) if !key.is_empty() => {
+                        Token::Operand(Operand::Key(key.to_string()))
+                    }
+                    _ => {
+                        return Err(format!(
+                            "expected 'target.KEY' or a quoted string, found '{word}'"
+                        ))
+                    }
+                }
+            }
+            c => return Err(format!("unexpected character '{c}'")),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+// A recursive descent parser, with one function per level of precedence.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| "unexpected end of expression".to_string())?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.peek() == Some(token);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat(&Token::Open) {
+            let expr = self.or()?;
+            return match self.next()? {
+                Token::Close => Ok(expr),
+                token => Err(format!("expected ')', found {token}")),
+            };
+        }
+        let lhs = self.operand()?;
+        let equal = match self.next()? {
+            Token::Eq => true,
+            Token::Ne => false,
+            Token::In => {
+                return Ok(Expr::In {
+                    lhs,
+                    values: self.list()?,
+                })
+            }
+            token => return Err(format!("expected '==', '!=', or 'in', found {token}")),
+        };
+        let rhs = self.operand()?;
+        Ok(Expr::Compare { lhs, rhs, equal })
+    }
+
+    // Parses a list of operands, as in "['a', 'b']", permitting a trailing
+    // comma.
+    fn list(&mut self) -> Result<Vec<Operand>, String> {
+        match self.next()? {
+            Token::OpenList => (),
+            token => return Err(format!("expected '[', found {token}")),
+        }
+        let mut values = vec![];
+        while !self.eat(&Token::CloseList) {
+            values.push(self.operand()?);
+            match self.next()? {
+                Token::Comma => (),
+                Token::CloseList => break,
+                token => return Err(format!("expected ',' or ']', found {token}")),
+            }
+        }
+        Ok(values)
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.next()? {
+            Token::Operand(operand) => Ok(operand),
+            token => Err(format!(
+                "expected 'target.KEY' or a quoted string, found {token}"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_strategy::proptest;
+
+    #[test]
+    fn test_target_map_quoting() {
+        let target: TargetMap = r#"a=b name="x y" "k=1"=v c="say \"hi\" \\" d="#.parse().unwrap();
+        assert_eq!(target.0["a"], "b");
+        assert_eq!(target.0["name"], "x y");
+        assert_eq!(target.0["k=1"], "v");
+        assert_eq!(target.0["c"], r#"say "hi" \"#);
+        assert_eq!(target.0["d"], "");
+        // Quotes may enclose part of a value, and unquoted backslashes are
+        // kept as-is.
+        let target: TargetMap = r#"path=C:\dir" with spaces" x=a=b"#.parse().unwrap();
+        assert_eq!(target.0["path"], r"C:\dir with spaces");
+        assert_eq!(target.0["x"], "a=b");
+
+        assert_eq!(target.to_string(), r#"path="C:\\dir with spaces" x="a=b" "#);
+
+        assert!(matches!(
+            "a=\"b c".parse::<TargetMap>(),
+            Err(TargetParseError::UnterminatedQuote(raw)) if raw == "a=\"b c"
+        ));
+        assert!(matches!(
+            "a=\"b\\".parse::<TargetMap>(),
+            Err(TargetParseError::UnterminatedQuote(_))
+        ));
+        assert!(matches!(
+            "a=b \"c d\"".parse::<TargetMap>(),
+            Err(TargetParseError::MissingEquals(raw)) if raw == "\"c d\""
+        ));
+    }
+
+    #[proptest]
+    fn target_map_round_trips(
+        #[strategy(proptest::collection::btree_map(".*", ".*", 0..5))] map: BTreeMap<
+            String,
+            String,
+        >,
+    ) {
+        let target = TargetMap(map);
+        let parsed: TargetMap = target.to_string().parse().unwrap();
+        assert_eq!(parsed, target);
+    }
+
+    #[test]
+    fn test_target_filter() {
+        let filter: TargetFilter =
+            toml::from_str("image = 'standard'\nmachine = ['gimlet', 'cosmo']").unwrap();
+        let matches = |target: &str| filter.matches(&target.parse().unwrap());
+
+        assert!(matches("image=standard machine=gimlet"));
+        assert!(matches("image=standard machine=cosmo switch=asic"));
+        assert!(!matches("image=standard machine=sled"));
+        assert!(!matches("image=trampoline machine=gimlet"));
+        assert!(!matches("image=standard"));
+
+        let matches_any = |target: &str| filter.matches_any(&target.parse().unwrap());
+        assert!(matches_any("image=standard machine=sled"));
+        assert!(matches_any("image=trampoline machine=cosmo"));
+        assert!(!matches_any("image=trampoline machine=sled"));
+        assert!(!matches_any(""));
+    }
+
+    #[test]
+    fn test_explain_package() {
+        use crate::config::PackageBuilder;
+        use crate::package::{PackageOutput, PackageSource};
+
+        let package = |f: fn(PackageBuilder) -> PackageBuilder| {
+            f(PackageBuilder::new(
+                "a",
+                PackageSource::Manual,
+                PackageOutput::Zone {
+                    intermediate_only: false,
+                    compression: Default::default(),
+                },
+            ))
+            .build()
+            .unwrap()
+        };
+        let explain = |pkg: &Package, target: &str| {
+            let target: TargetMap = target.parse().unwrap();
+            let inclusion = target.explain_package(pkg);
+            assert_eq!(
+                target.includes_package(pkg),
+                inclusion == Inclusion::Included
+            );
+            match inclusion {
+                Inclusion::Included => None,
+                Inclusion::Excluded(exclusion) => Some(exclusion.to_string()),
+            }
+        };
+
+        let pkg = package(|p| p);
+        assert_eq!(explain(&pkg, "machine=gimlet"), None);
+
+        let pkg = package(|p| {
+            p.only_for_targets(
+                toml::from_str("image = 'standard'\nmachine = ['gimlet', 'cosmo']").unwrap(),
+            )
+            .not_for_targets(toml::from_str("switch = 'stub'").unwrap())
+        });
+        assert_eq!(explain(&pkg, "image=standard machine=cosmo"), None);
+        assert_eq!(
+            explain(&pkg, "image=standard machine=sled").as_deref(),
+            Some("only_for_targets requires machine to be one of 'gimlet', 'cosmo', but it is 'sled'")
+        );
+        assert_eq!(
+            explain(&pkg, "machine=gimlet").as_deref(),
+            Some(
+                "only_for_targets requires image to be 'standard', but the target does not \
+                 define it"
+            )
+        );
+        assert_eq!(
+            explain(&pkg, "image=standard machine=gimlet switch=stub").as_deref(),
+            Some("not_for_targets excludes switch=stub (excluded: 'stub')")
+        );
+
+        let pkg = package(|p| p.only_if("target.machine == 'gimlet'".parse().unwrap()));
+        assert!(matches!(
+            TargetMap::default().explain_package(&pkg),
+            Inclusion::Excluded(Exclusion::OnlyIf { .. })
+        ));
+        assert_eq!(
+            explain(&pkg, "machine=cosmo").as_deref(),
+            Some("target does not satisfy only_if: target.machine == 'gimlet'")
+        );
+    }
+
+    #[test]
+    fn test_target_filter_wildcards() {
+        let filter: TargetFilter =
+            toml::from_str("machine = 'gimlet*'\nswitch = ['asic', 'stub-?', 'a[b']").unwrap();
+        let matches = |target: &str| filter.matches(&target.parse().unwrap());
+
+        assert!(matches("machine=gimlet switch=asic"));
+        assert!(matches("machine=gimlet-rev-b switch=stub-1"));
+        assert!(!matches("machine=cosmo switch=asic"));
+        assert!(!matches("machine=gimlet switch=stub-10"));
+        // Invalid patterns are compared exactly.
+        assert!(matches("machine=gimlet switch=a[b"));
+
+        let target = "machine=cosmo switch=stub-1".parse().unwrap();
+        assert_eq!(
+            filter.mismatch(&target),
+            Some(("machine", &filter.0["machine"], Some("cosmo")))
+        );
+        assert_eq!(
+            filter.first_match(&target),
+            Some(("switch", &filter.0["switch"], "stub-1"))
+        );
+        assert!(filter.matches_any(&target));
+        let target = "machine=gimlet".parse().unwrap();
+        assert_eq!(
+            filter.mismatch(&target),
+            Some(("switch", &filter.0["switch"], None))
+        );
+
+        let schema: TargetSchema =
+            toml::from_str("machine = ['gimlet', 'gimlet-b', 'cosmo']\nswitch = []").unwrap();
+        let filter: TargetFilter = toml::from_str("machine = ['gimlet*']").unwrap();
+        schema.validate_filter(&filter).unwrap();
+        let filter: TargetFilter = toml::from_str("switch = 'stub*'").unwrap();
+        schema.validate_filter(&filter).unwrap();
+        let filter: TargetFilter = toml::from_str("machine = 'sled*'").unwrap();
+        assert!(matches!(
+            schema.validate_filter(&filter),
+            Err(TargetParseError::InvalidValue { value, .. }) if value == "sled*"
+        ));
+    }
+
+    #[test]
+    fn test_target_from_preset() {
+        let config = crate::config::ConfigBuilder::new()
+            .preset("dev", "image=standard switch=stub".parse().unwrap())
+            .target_keys(
+                toml::from_str("image = []\nswitch = ['asic', 'stub']\nrack = []").unwrap(),
+            )
+            .build()
+            .unwrap();
+        let dev = PresetName::new_const("dev");
+        let from_preset =
+            |overrides: &str| TargetMap::from_preset(&config, &dev, &overrides.parse().unwrap());
+
+        assert_eq!(from_preset("").unwrap(), config.target.presets[&dev]);
+        // Overrides replace the preset's values, and may add new keys.
+        assert_eq!(
+            from_preset("switch=asic rack=r1").unwrap(),
+            "image=standard switch=asic rack=r1".parse().unwrap()
+        );
+        assert!(matches!(
+            from_preset("switch=softnpu"),
+            Err(TargetParseError::InvalidValue { .. })
+        ));
+
+        let err = TargetMap::from_preset(
+            &config,
+            &PresetName::new_const("deb"),
+            &TargetMap::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown target preset 'deb'; did you mean 'dev'?"
+        );
+    }
+
+    #[test]
+    fn test_target_schema() {
+        let schema: TargetSchema =
+            toml::from_str("machine = ['gimlet', 'cosmo']\nswitch = ['asic', 'stub']\nrack = []")
+                .unwrap();
+
+        let target = schema
+            .parse("machine=gimlet switch=stub rack=anything")
+            .unwrap();
+        assert_eq!(target.0["rack"], "anything");
+        let err = schema.parse("macine=gimlet").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown target key 'macine'; did you mean 'machine'?"
+        );
+        let err = schema.parse("machine=gimlte").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid value 'gimlte' for target key 'machine' (expected one of: \
+             gimlet, cosmo); did you mean 'gimlet'?"
+        );
+        let err = schema.parse("image=standard").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown target key 'image'");
+
+        let filter: TargetFilter = toml::from_str("machine = ['gimlet', 'sled']").unwrap();
+        assert!(matches!(
+            schema.validate_filter(&filter),
+            Err(TargetParseError::InvalidValue { value, .. }) if value == "sled"
+        ));
+        let filter: TargetFilter = toml::from_str("switch = 'asic'\nrack = 'a'").unwrap();
+        schema.validate_filter(&filter).unwrap();
+
+        let expr = |s: &str| schema.validate_expr(&s.parse().unwrap());
+        expr("target.machine in ['gimlet'] && 'stub' != target.switch").unwrap();
+        expr("target.rack == target.machine").unwrap();
+        assert!(matches!(
+            expr("!(target.switch == 'softnpu')"),
+            Err(TargetParseError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            expr("target.machine == 'gimlet' || target.swich == 'asic'"),
+            Err(TargetParseError::UnknownKey { .. })
+        ));
+
+        // Keys without listed values permit any value.
+        let mut extended = schema.clone();
+        extended.extend(
+            toml::from_str("machine = ['sled', 'gimlet']\nswitch = []\nrack = ['a']").unwrap(),
+        );
+        assert_eq!(extended.0["machine"], ["gimlet", "cosmo", "sled"]);
+        assert!(extended.0["switch"].is_empty());
+        assert!(extended.0["rack"].is_empty());
+    }
+
+    #[test]
+    fn test_target_expr() {
+        let target: TargetMap = "machine=gimlet switch=asic".parse().unwrap();
+        let evaluate = |expr: &str| expr.parse::<TargetExpr>().unwrap().evaluate(&target);
+
+        assert!(evaluate("target.machine == 'gimlet'"));
+        assert!(evaluate(
+            "target.machine == \"gimlet\" && target.switch != 'stub'"
+        ));
+        assert!(!evaluate("target.machine != 'gimlet'"));
+        assert!(evaluate(
+            "!(target.machine == 'sled' || target.switch == 'stub')"
+        ));
+        assert!(evaluate(
+            "target.machine == 'sled' || target.machine == 'gimlet' && target.switch == 'asic'"
+        ));
+        assert!(!evaluate(
+            "(target.machine == 'sled' || target.machine == 'gimlet') && target.switch == 'stub'"
+        ));
+        // Undefined keys are unequal to everything.
+        assert!(!evaluate("target.rack == 'standard'"));
+        assert!(evaluate("target.rack != 'standard'"));
+        assert!(!evaluate("target.rack in ['standard']"));
+
+        // Keywords may be used in place of symbols, and "in" tests lists.
+        assert!(evaluate(
+            "target.machine in ['gimlet', 'cosmo'] and not target.switch == 'stub'"
+        ));
+        assert!(!evaluate("target.machine in ['sled', 'cosmo',]"));
+        assert!(!evaluate("target.machine in []"));
+        assert!(evaluate(
+            "target.machine == 'sled' or target.switch in [target.switch]"
+        ));
+        assert!(!evaluate("not (target.machine in ['gimlet'])"));
+
+        for (expr, reason) in [
+            ("machine == 'gimlet'", "found 'machine'"),
+            ("target.machine = 'gimlet'", "expected '=='"),
+            ("target.machine == 'gimlet", "unterminated string"),
+            (
+                "target.machine == 'gimlet' &&",
+                "unexpected end of expression",
+            ),
+            (
+                "(target.machine == 'gimlet'",
+                "unexpected end of expression",
+            ),
+            ("target.machine == 'gimlet')", "unexpected ')'"),
+            ("target.machine", "unexpected end of expression"),
+            ("target.machine in 'gimlet'", "expected '['"),
+            (
+                "target.machine in ['gimlet' 'cosmo']",
+                "expected ',' or ']'",
+            ),
+            (
+                "target.machine in ['gimlet',",
+                "unexpected end of expression",
+            ),
+            (
+                "target.machine and 'gimlet'",
+                "expected '==', '!=', or 'in'",
+            ),
+        ] {
+            let err = expr.parse::<TargetExpr>().unwrap_err();
+            assert!(err.to_string().contains(reason), "{expr}: {err}");
+        }
+    }
+}
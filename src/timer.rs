@@ -4,9 +4,12 @@
 
 //! A timer to help track how long build phases take
 
+use crate::progress::{Progress, ProgressEvent};
+use crate::report::BuildPhase;
 use anyhow::{bail, Result};
 use slog::Logger;
 use std::borrow::Cow;
+use std::cell::{OnceCell, RefCell};
 use tokio::time::{Duration, Instant};
 
 type CowStr = Cow<'static, str>;
@@ -29,13 +32,14 @@ impl PhaseStart {
         }
     }
 
-    fn finish(self, name: Option<CowStr>) -> Phase {
+    fn finish(self, name: Option<CowStr>, children: Vec<ChildPhase>) -> Phase {
         Phase {
             start: self,
             end: PhaseEnd {
                 name,
                 time: Instant::now(),
             },
+            children,
         }
     }
 }
@@ -44,6 +48,7 @@ impl PhaseStart {
 pub struct Phase {
     start: PhaseStart,
     end: PhaseEnd,
+    children: Vec<ChildPhase>,
 }
 
 impl Phase {
@@ -58,29 +63,175 @@ impl Phase {
     pub fn duration(&self) -> Duration {
         self.end.time.duration_since(self.start.time)
     }
+
+    /// Returns the time spent on each kind of work within this phase, in
+    /// the order in which they were first started.
+    pub fn children(&self) -> &[ChildPhase] {
+        &self.children
+    }
+}
+
+/// Describes the time spent on one kind of work within a [Phase], such as
+/// downloading blobs.
+///
+/// Work of the same kind may be interleaved with other work, so this is the
+/// total of every [ChildTimer] with the same name.
+pub struct ChildPhase {
+    name: CowStr,
+    duration: Duration,
+    children: Vec<ChildPhase>,
+}
+
+impl ChildPhase {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Returns the time spent on each kind of work within this one; see
+    /// [ChildTimer::child].
+    pub fn children(&self) -> &[ChildPhase] {
+        &self.children
+    }
+
+    fn to_report(&self) -> BuildPhase {
+        BuildPhase {
+            name: self.name().to_string(),
+            label: None,
+            duration: self.duration(),
+            children: self.children().iter().map(Self::to_report).collect(),
+        }
+    }
+
+    fn log(&self, log: &Logger, phase: &str, parents: &str) {
+        let name = format!("{parents}{}", self.name());
+        let s = self.duration().as_secs_f64();
+        slog::info!(log, "Phase {phase} spent {s:.6}s {name}");
+        for child in self.children() {
+            child.log(log, phase, &format!("{name} / "));
+        }
+    }
+}
+
+// Adds `phase` to `phases`, combining it with any work of the same name.
+fn record_child(phases: &mut Vec<ChildPhase>, phase: ChildPhase) {
+    match phases.iter_mut().find(|child| child.name == phase.name) {
+        Some(child) => {
+            child.duration += phase.duration;
+            for grandchild in phase.children {
+                record_child(&mut child.children, grandchild);
+            }
+        }
+        None => phases.push(phase),
+    }
+}
+
+/// Attributes the time until it's dropped to a kind of work within the
+/// current phase of a [BuildTimer]; see [BuildTimer::child].
+pub struct ChildTimer<'t> {
+    parent: &'t RefCell<Vec<ChildPhase>>,
+    name: CowStr,
+    start: Instant,
+    // Work within this one, recorded by [Self::child].
+    children: RefCell<Vec<ChildPhase>>,
+}
+
+impl ChildTimer<'_> {
+    /// Starts timing a kind of work within this one, such as copying a
+    /// binary while building a Rust package, until the returned timer is
+    /// dropped.
+    ///
+    /// As with [BuildTimer::child], time spent on work with the same name is
+    /// combined.
+    pub fn child<S: Into<CowStr>>(&self, name: S) -> ChildTimer<'_> {
+        ChildTimer {
+            parent: &self.children,
+            name: name.into(),
+            start: Instant::now(),
+            children: RefCell::new(vec![]),
+        }
+    }
+}
+
+impl Drop for ChildTimer<'_> {
+    fn drop(&mut self) {
+        let phase = ChildPhase {
+            name: std::mem::take(&mut self.name),
+            duration: self.start.elapsed(),
+            children: self.children.take(),
+        };
+        record_child(&mut self.parent.borrow_mut(), phase);
+    }
 }
 
 /// A utility for tracking a series of related timers.
-pub struct BuildTimer {
+///
+/// Each phase is reported to `progress` as it completes.
+pub struct BuildTimer<'a> {
     current: Option<PhaseStart>,
     past: Vec<Phase>,
+    progress: &'a dyn Progress,
+    // Created on demand by [Self::phase_progress].
+    phase_progress: OnceCell<Box<dyn Progress>>,
+    // Work within the current phase, recorded by [Self::child].
+    children: RefCell<Vec<ChildPhase>>,
 }
 
-impl BuildTimer {
-    pub fn new() -> Self {
+impl<'a> BuildTimer<'a> {
+    pub fn new(progress: &'a dyn Progress) -> Self {
         Self {
             current: None,
             past: vec![],
+            progress,
+            phase_progress: OnceCell::new(),
+            children: RefCell::new(vec![]),
+        }
+    }
+
+    /// Starts timing a kind of work within the current phase, such as
+    /// downloading blobs, until the returned timer is dropped.
+    ///
+    /// Time spent on work with the same name is combined, and recorded
+    /// among the [Phase::children] of the current phase. Outside of a phase,
+    /// this has no effect.
+    pub fn child<S: Into<CowStr>>(&self, name: S) -> ChildTimer<'_> {
+        ChildTimer {
+            parent: &self.children,
+            name: name.into(),
+            start: Instant::now(),
+            children: RefCell::new(vec![]),
         }
     }
 
+    /// Returns a [Progress] for work within the current phase, named after
+    /// the phase.
+    ///
+    /// This is created the first time it's requested, so that phases which
+    /// don't report progress of their own don't appear as sub tasks.
+    pub fn phase_progress(&self) -> &dyn Progress {
+        let Some(current) = &self.current else {
+            return self.progress;
+        };
+        self.phase_progress
+            .get_or_init(|| self.progress.named_sub_progress(current.name.clone(), 0))
+            .as_ref()
+    }
+
     /// Starts a new timer, ending a prior phase if one was in progress.
     pub fn start<S: Into<CowStr>>(&mut self, s: S) {
         // If a prior phase was ongoing, mark it completed
         if self.current.is_some() {
             let _ = self.finish();
         }
-        self.current = Some(PhaseStart::new(s.into()));
+        self.children.get_mut().clear();
+        let name = s.into();
+        self.progress.report(ProgressEvent::PhaseStarted {
+            name: name.to_string(),
+        });
+        self.current = Some(PhaseStart::new(name));
     }
 
     /// Terminates the current phase with a label.
@@ -97,7 +248,14 @@ impl BuildTimer {
         let Some(current) = self.current.take() else {
             bail!("No build phase in progress");
         };
-        self.past.push(current.finish(label));
+        self.phase_progress.take();
+        let phase = current.finish(label, self.children.take());
+        self.progress.report(ProgressEvent::PhaseCompleted {
+            name: phase.name().to_string(),
+            label: phase.end_label().map(str::to_string),
+            duration: phase.duration(),
+        });
+        self.past.push(phase);
         Ok(())
     }
 
@@ -106,6 +264,20 @@ impl BuildTimer {
         &self.past
     }
 
+    /// Describes all [Self::completed] phases, in a form which may be
+    /// serialized.
+    pub fn to_report(&self) -> Vec<BuildPhase> {
+        self.completed()
+            .iter()
+            .map(|phase| BuildPhase {
+                name: phase.name().to_string(),
+                label: phase.end_label().map(str::to_string),
+                duration: phase.duration(),
+                children: phase.children().iter().map(ChildPhase::to_report).collect(),
+            })
+            .collect()
+    }
+
     /// A helper for logging all [Self::completed] phases.
     pub fn log_all(&self, log: &Logger) {
         for phase in self.completed() {
@@ -117,6 +289,47 @@ impl BuildTimer {
                 "".to_string()
             };
             slog::info!(log, "Phase {name} took {s:.6}s{label}");
+            for child in phase.children() {
+                child.log(log, name, "");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::progress::NoProgress;
+
+    #[test]
+    fn report_nests_children() {
+        let progress = NoProgress::new();
+        let mut timer = BuildTimer::new(&progress);
+        timer.start("build");
+        for _ in 0..2 {
+            let rust = timer.child("rust");
+            let _copy = rust.child("copy binary");
         }
+        drop(timer.child("downloading blobs"));
+        timer.finish().unwrap();
+
+        let report = timer.to_report();
+        assert_eq!(report.len(), 1);
+        let build = &report[0];
+        assert_eq!(build.name, "build");
+        let names = |phases: &[BuildPhase]| {
+            phases
+                .iter()
+                .map(|phase| phase.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&build.children), ["rust", "downloading blobs"]);
+
+        let rust = &build.children[0];
+        assert_eq!(names(&rust.children), ["copy binary"]);
+        let copy = &rust.children[0];
+        assert!(copy.children.is_empty());
+        assert!(copy.duration <= rust.duration);
+        assert!(rust.duration <= build.duration);
     }
 }
@@ -0,0 +1,89 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+#[cfg(test)]
+mod test {
+    use camino::Utf8Path;
+    use std::process::{Command, Output};
+
+    // Runs the command-line tool on the manifest of "service-a", building
+    // within `out`.
+    fn omicron_package(out: &Utf8Path, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_omicron-package"))
+            .args(["--manifest", "tests/service-a/cfg.toml", "--out"])
+            .arg(out)
+            .args(args)
+            .output()
+            .expect("Failed to run omicron-package")
+    }
+
+    fn stdout(output: &Output) -> String {
+        assert!(
+            output.status.success(),
+            "omicron-package failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout.clone()).unwrap()
+    }
+
+    // Tests that packages can be planned without building them
+    #[test]
+    fn test_plan() {
+        let out = camino_tempfile::tempdir().unwrap();
+        let output = stdout(&omicron_package(out.path(), &["plan"]));
+        assert!(output.starts_with("my-service: "), "{output}");
+        assert!(output.contains(" inputs, "), "{output}");
+
+        let output = stdout(&omicron_package(out.path(), &["plan", "--json"]));
+        let plans: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert!(plans["my-service"].is_array(), "{output}");
+    }
+
+    // Tests that the contents of packages can be listed, along with where
+    // they came from
+    #[test]
+    fn test_sbom() {
+        let out = camino_tempfile::tempdir().unwrap();
+        let output = stdout(&omicron_package(out.path(), &["sbom"]));
+        let sboms: serde_json::Value = serde_json::from_str(&output).unwrap();
+        let components = sboms["my-service"]["components"].as_array().unwrap();
+        let file = components
+            .iter()
+            .find(|c| {
+                c["source"]
+                    .as_str()
+                    .unwrap()
+                    .ends_with("tests/service-a/single-file.txt")
+            })
+            .unwrap_or_else(|| panic!("Missing file: {output}"));
+        assert_eq!(
+            file["destination"],
+            "root/opt/oxide/my-service/single-file.txt"
+        );
+        assert_eq!(
+            file["origin"]["Path"]["from"],
+            "tests/service-a/single-file.txt"
+        );
+    }
+
+    // Tests that built packages are cached, and verified as up-to-date
+    #[test]
+    fn test_build_and_verify() {
+        let out = camino_tempfile::tempdir().unwrap();
+
+        // Nothing has been built yet.
+        let output = omicron_package(out.path(), &["verify"]);
+        assert!(!output.status.success());
+
+        let output = stdout(&omicron_package(out.path(), &["build"]));
+        assert!(output.starts_with("my-service: built"), "{output}");
+        assert!(out.path().join("my-service.tar.gz").exists());
+
+        let output = stdout(&omicron_package(out.path(), &["build"]));
+        assert!(output.starts_with("my-service: cached"), "{output}");
+
+        let output = stdout(&omicron_package(out.path(), &["verify"]));
+        assert_eq!(output, "my-service: up-to-date\n");
+    }
+}
@@ -5,16 +5,28 @@
 #[cfg(test)]
 mod test {
     use anyhow::Result;
-    use camino::Utf8PathBuf;
+    use camino::{Utf8Path, Utf8PathBuf};
+    use std::collections::BTreeMap;
     use std::convert::TryInto;
     use std::fs::File;
     use std::io::Read;
     use tar::Archive;
+    use tokio_util::sync::CancellationToken;
 
+    use omicron_zone_package::archive;
     use omicron_zone_package::blob::download;
+    use omicron_zone_package::cache::MissReason;
+    use omicron_zone_package::cargo::CargoMetadata;
     use omicron_zone_package::config::{self, PackageName, ServiceName};
+    use omicron_zone_package::history::{BuildHistory, Eta};
+    use omicron_zone_package::input::BuildInput;
     use omicron_zone_package::package::BuildConfig;
-    use omicron_zone_package::progress::NoProgress;
+    use omicron_zone_package::progress::{
+        ChromeTraceProgress, EventReporter, NoProgress, PackageState, Progress, ProgressEvent,
+        ProgressTracker,
+    };
+    use omicron_zone_package::provenance::Provenance;
+    use omicron_zone_package::report::{phase_totals, CacheOutcome};
     use omicron_zone_package::target::TargetMap;
 
     const MY_PACKAGE: PackageName = PackageName::new_const("my-package");
@@ -59,6 +71,34 @@ mod test {
         }
     }
 
+    // Reads the header and contents of each entry of the uncompressed
+    // archive at `path`.
+    fn read_entries(path: &Utf8Path) -> BTreeMap<Utf8PathBuf, (tar::Header, Vec<u8>)> {
+        let mut archive = Archive::new(File::open(path).unwrap());
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut contents = vec![];
+                entry.read_to_end(&mut contents).unwrap();
+                (entry_path(&entry), (entry.header().clone(), contents))
+            })
+            .collect()
+    }
+
+    // Returns the type, mode, owner, modification time, and link name
+    // recorded by `header`.
+    fn header_fields(header: &tar::Header) -> impl PartialEq + std::fmt::Debug {
+        (
+            header.entry_type(),
+            header.mode().unwrap(),
+            (header.uid().unwrap(), header.gid().unwrap()),
+            header.mtime().unwrap(),
+            header.link_name().unwrap().map(|link| link.into_owned()),
+        )
+    }
+
     // Tests a package of arbitrary files is being placed into a Zone image
     #[tokio::test(flavor = "multi_thread")]
     async fn test_package_as_zone() {
@@ -70,38 +110,1036 @@ mod test {
         let out = camino_tempfile::tempdir().unwrap();
         let build_config = BuildConfig::default();
         package
-            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+
+        // Verify the contents
+        let path = package.get_output_path_for_service(out.path());
+        assert!(path.exists());
+        let gzr = flate2::read::GzDecoder::new(File::open(path).unwrap());
+        let mut archive = Archive::new(gzr);
+        let mut ents = archive.entries().unwrap();
+        assert_eq!("oxide.json", ents.next_path());
+        assert_eq!("root/", ents.next_path());
+        assert_eq!("root/opt", ents.next_path());
+        assert_eq!("root/opt/oxide", ents.next_path());
+        assert_eq!("root/opt/oxide/my-service", ents.next_path());
+        assert_eq!("root/opt/oxide/my-service/contents.txt", ents.next_path());
+        assert_eq!(
+            "root/opt/oxide/my-service/single-file.txt",
+            ents.next_path()
+        );
+        assert!(ents.next().is_none());
+    }
+
+    // Tests that builds can be followed through structured events
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_progress_events() {
+        let cfg = config::parse("tests/service-a/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let (reporter, mut events) = EventReporter::new(log);
+        let build_config = BuildConfig {
+            progress: &reporter,
+            ..Default::default()
+        };
+
+        let mut received = || {
+            let mut received = vec![];
+            while let Ok(event) = events.try_recv() {
+                assert_eq!(event.package, Some(MY_SERVICE_PACKAGE));
+                received.push(event.event);
+            }
+            received
+        };
+
+        package
+            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+        let events = received();
+        assert!(matches!(
+            events.first(),
+            Some(ProgressEvent::PackageStarted { package }) if *package == MY_SERVICE_PACKAGE
+        ));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, ProgressEvent::CacheMiss { .. })));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ProgressEvent::InputAdded {
+                input: BuildInput::AddFile { mapped_path, .. }
+            } if mapped_path.to == "root/opt/oxide/my-service/single-file.txt"
+        )));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ProgressEvent::PhaseCompleted { name, .. } if name == "add inputs to package"
+        )));
+
+        // Rebuilding uses the cache, so no inputs are added.
+        package
+            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+        let events = received();
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, ProgressEvent::CacheHit)));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, ProgressEvent::InputAdded { .. })));
+    }
+
+    // Tests that the bytes of every input are accounted for
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_progress_bytes() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        #[derive(Default)]
+        struct ByteCounter {
+            log: NoProgress,
+            total: AtomicU64,
+            completed: AtomicU64,
+        }
+
+        impl Progress for ByteCounter {
+            fn get_log(&self) -> &slog::Logger {
+                self.log.get_log()
+            }
+
+            fn increment_total_bytes(&self, delta: u64) {
+                self.total.fetch_add(delta, Ordering::SeqCst);
+            }
+
+            fn increment_completed_bytes(&self, delta: u64) {
+                self.completed.fetch_add(delta, Ordering::SeqCst);
+            }
+        }
+
+        let cfg = config::parse("tests/service-a/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let progress = ByteCounter::default();
+        let build_config = BuildConfig {
+            progress: &progress,
+            ..Default::default()
+        };
+        package
+            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+
+        let contents = ["subdirectory/contents.txt", "single-file.txt"]
+            .iter()
+            .map(|file| {
+                std::fs::metadata(format!("tests/service-a/{file}"))
+                    .unwrap()
+                    .len()
+            })
+            .sum::<u64>();
+        let total = progress.total.load(Ordering::SeqCst);
+        assert!(total >= contents, "{total} < {contents}");
+        assert_eq!(progress.completed.load(Ordering::SeqCst), total);
+    }
+
+    // Tests that a cancelled build leaves nothing behind
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cancelled_build() {
+        // Parse the configuration
+        let cfg = config::parse("tests/service-a/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+
+        // Try to create the packaged file, after it has been cancelled
+        let out = camino_tempfile::tempdir().unwrap();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let build_config = BuildConfig {
+            cancel: Some(&cancel),
+            ..Default::default()
+        };
+        let err = package
+            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .expect_err("Cancelled build should fail");
+        assert_eq!(err.to_string(), "Build cancelled");
+        assert_eq!(out.path().read_dir().unwrap().count(), 0);
+
+        // Without cancellation, the package is created
+        package
+            .create(&MY_SERVICE_PACKAGE, out.path(), &BuildConfig::default())
+            .await
+            .unwrap();
+        let mut entries: Vec<_> = out
+            .path()
+            .read_dir_utf8()
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, ["manifest-cache", "my-service.tar.gz"]);
+    }
+
+    // Tests that building a package describes its outcome
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_package_build_report() {
+        // Parse the configuration
+        let cfg = config::parse("tests/service-a/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+
+        // Create the packaged file
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig::default();
+        let report = package
+            .create_with_report(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+        assert_eq!(
+            report.output_path,
+            package.get_output_path(&MY_SERVICE_PACKAGE, out.path())
+        );
+        assert!(!report.cache_hit());
+        let CacheOutcome::Miss { reason } = &report.cache else {
+            panic!("Unexpected cache outcome: {:?}", report.cache);
+        };
+        assert!(
+            matches!(reason, MissReason::ManifestMissing { .. }),
+            "{reason}"
+        );
+        assert_eq!(
+            report.bytes_written,
+            report.output_path.metadata().unwrap().len()
+        );
+        assert_eq!(report.output_digest.len(), 64);
+        assert!(report.downloaded_blobs.is_empty());
+        assert!(report
+            .phases
+            .iter()
+            .any(|phase| phase.name == "cache lookup"));
+        // Time spent adding files is attributed within the phase.
+        let add_inputs = report
+            .phases
+            .iter()
+            .find(|phase| phase.name == "add inputs to package")
+            .unwrap();
+        assert!(add_inputs
+            .children
+            .iter()
+            .any(|child| child.name == "appending files"));
+        assert!(
+            add_inputs
+                .children
+                .iter()
+                .map(|child| child.duration)
+                .sum::<std::time::Duration>()
+                <= add_inputs.duration
+        );
+
+        // Building again should hit the cache, producing the same package
+        let cached = package
+            .create_with_report(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+        assert_eq!(cached.cache, CacheOutcome::Hit);
+        assert_eq!(cached.output_digest, report.output_digest);
+    }
+
+    // Tests that only full builds are recorded in the build history
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_build_history() {
+        let cfg = config::parse("tests/service-a/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig::default();
+
+        let report = package
+            .create_with_report(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+        assert!(!report.cache_hit());
+        let history = BuildHistory::load(out.path()).await.unwrap();
+        let phases = history.previous(&MY_SERVICE_PACKAGE).unwrap().to_vec();
+        assert!(phases
+            .iter()
+            .any(|phase| phase.name == "add inputs to package"));
+
+        // A cache hit leaves the estimate for a full build in place
+        let cached = package
+            .create_with_report(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+        assert!(cached.cache_hit());
+        let history = BuildHistory::load(out.path()).await.unwrap();
+        assert_eq!(history.previous(&MY_SERVICE_PACKAGE).unwrap(), phases);
+        assert_eq!(
+            history.remaining(&MY_SERVICE_PACKAGE, &[], None),
+            Some(Eta(phases.iter().map(|phase| phase.duration).sum()))
+        );
+    }
+
+    // Tests that packages built for different targets may share an output
+    // directory, when their outputs are named by a template
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_package_output_template() {
+        let mut cfg = config::parse("tests/service-a/cfg.toml").unwrap();
+        let package = cfg.packages.get_mut(&MY_SERVICE_PACKAGE).unwrap();
+        package.output_template = Some("{{name}}-{{machine}}.{{ext}}".into());
+        let package = &cfg.packages[&MY_SERVICE_PACKAGE];
+        let out = camino_tempfile::tempdir().unwrap();
+
+        let mut reports = vec![];
+        for machine in ["gimlet", "non-gimlet", "gimlet"] {
+            let target: TargetMap = format!("machine={machine}").parse().unwrap();
+            let build_config = BuildConfig {
+                target: &target,
+                ..Default::default()
+            };
+            reports.push(
+                package
+                    .create_with_report(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(
+            reports[0].output_path,
+            out.path().join("my-service-gimlet.tar.gz")
+        );
+        assert_eq!(
+            reports[1].output_path,
+            out.path().join("my-service-non-gimlet.tar.gz")
+        );
+        assert!(!reports[0].cache_hit());
+        assert!(!reports[1].cache_hit());
+        // The first package is cached separately from the second
+        assert!(reports[2].cache_hit());
+        assert!(!package
+            .get_output_path(&MY_SERVICE_PACKAGE, out.path())
+            .exists());
+
+        // Stamping uses the package built for the target
+        let target = "machine=non-gimlet".parse().unwrap();
+        let version = semver::Version::new(1, 2, 3);
+        let stamped = package
+            .stamp_for_target(
+                &MY_SERVICE_PACKAGE,
+                out.path(),
+                &target,
+                &version,
+                &BTreeMap::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            stamped,
+            out.path().join("versioned/my-service-non-gimlet.tar.gz")
+        );
+    }
+
+    // Tests that changes to the build environment invalidate cached packages
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_package_build_fingerprint() {
+        let cfg = config::parse("tests/service-a/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+
+        let old = BTreeMap::from([("rustc".to_string(), "1.80.0".to_string())]);
+        let new = BTreeMap::from([("rustc".to_string(), "1.81.0".to_string())]);
+        for (fingerprint, hit) in [(&old, false), (&old, true), (&new, false)] {
+            let build_config = BuildConfig {
+                fingerprint: Some(fingerprint),
+                ..Default::default()
+            };
+            let report = package
+                .create_with_report(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+                .await
+                .unwrap();
+            assert_eq!(report.cache_hit(), hit, "{:?}", report.cache);
+        }
+
+        // Adding a checksum manifest changes the archive, so it's rebuilt.
+        let mut package = package.clone();
+        package.checksum_manifest = true;
+        let build_config = BuildConfig {
+            fingerprint: Some(&new),
+            ..Default::default()
+        };
+        for hit in [false, true] {
+            let report = package
+                .create_with_report(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+                .await
+                .unwrap();
+            assert_eq!(report.cache_hit(), hit, "{:?}", report.cache);
+        }
+    }
+
+    // Tests that prebuilt packages are verified and cached
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_prebuilt_package() {
+        // Parse the configuration
+        let cfg = config::parse("tests/service-k/cfg.toml").unwrap();
+        let name = PackageName::new_const("prebuilt");
+        let package = cfg.packages.get(&name).unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let output_path = package.get_output_path(&name, out.path());
+        assert_eq!(
+            package.get_prebuilt_url(&name).unwrap(),
+            "http://127.0.0.1:1/public/file/oxidecomputer/my-repo/image/0123456789abcdef/prebuilt.tar.gz"
+        );
+
+        // A package with the expected digest is used without downloading it
+        std::fs::write(&output_path, "prebuilt contents\n").unwrap();
+        let build_config = BuildConfig::default();
+        let report = package
+            .create_with_report(&name, out.path(), &build_config)
+            .await
+            .unwrap();
+        assert!(!report.cache_hit());
+        assert_eq!(report.output_path, output_path);
+        let cached = package
+            .create_with_report(&name, out.path(), &build_config)
+            .await
+            .unwrap();
+        assert_eq!(cached.cache, CacheOutcome::Hit);
+
+        // Otherwise, the package is downloaded, which fails here
+        std::fs::write(&output_path, "something else\n").unwrap();
+        let build_config = BuildConfig {
+            cache_disabled: true,
+            ..Default::default()
+        };
+        let err = package
+            .create(&name, out.path(), &build_config)
+            .await
+            .expect_err("Should fail to download package");
+        assert!(
+            format!("{err:#}").contains("failed to download package"),
+            "{err:#}"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&output_path).unwrap(),
+            "something else\n"
+        );
+    }
+
+    // Tests that the inputs of packages can be identified without building
+    // anything.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_plan_all() {
+        // Parse the configuration
+        let cfg = config::parse("tests/service-a/cfg.toml").unwrap();
+
+        // Plan all packages
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig::default();
+        let plans = cfg.plan_all(out.path(), &build_config).unwrap();
+        assert_eq!(plans.keys().collect::<Vec<_>>(), [&MY_SERVICE_PACKAGE]);
+
+        let files: Vec<_> = plans[&MY_SERVICE_PACKAGE]
+            .iter()
+            .filter_map(|input| match input {
+                BuildInput::AddFile { mapped_path, .. } => Some(mapped_path.to.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            files,
+            [
+                "root/opt/oxide/my-service/contents.txt",
+                "root/opt/oxide/my-service/single-file.txt",
+            ]
+        );
+
+        // Nothing should have been written
+        assert_eq!(out.path().read_dir().unwrap().count(), 0);
+    }
+
+    // Tests a zone image generated by a command, which only re-runs when
+    // its inputs change.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_command_package_as_zone() {
+        let cfg = config::parse("tests/service-g/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let runs = out.path().join("command/my-service.runs");
+
+        let out_path = out.path();
+        let build = |greeting: &str| {
+            let target = TargetMap(BTreeMap::from([(
+                "greeting".to_string(),
+                greeting.to_string(),
+            )]));
+            async move {
+                let build_config = BuildConfig {
+                    target: &target,
+                    ..Default::default()
+                };
+                package
+                    .create(&MY_SERVICE_PACKAGE, out_path, &build_config)
+                    .await
+                    .unwrap();
+            }
+        };
+
+        build("hello").await;
+        let path = package.get_output_path_for_service(out.path());
+        let gzr = flate2::read::GzDecoder::new(File::open(&path).unwrap());
+        let mut archive = Archive::new(gzr);
+        let mut ents = archive.entries().unwrap();
+        assert_eq!("oxide.json", ents.next_path());
+        assert_eq!("root/", ents.next_path());
+        assert_eq!("root/opt", ents.next_path());
+        assert_eq!("root/opt/oxide", ents.next_path());
+        assert_eq!("root/opt/oxide/my-service", ents.next_path());
+        assert_eq!("root/opt/oxide/my-service/greeting.txt", ents.next_path());
+        assert!(ents.next().is_none());
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "hello\n");
+
+        // Nothing changed, so the command is skipped.
+        build("hello").await;
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "hello\n");
+
+        // The command's environment changed, so it runs again.
+        build("goodbye").await;
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "hello\ngoodbye\n");
+    }
+
+    // Tests a zone image being written to an in-memory sink
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_package_as_zone_to_writer() {
+        // Parse the configuration
+        let cfg = config::parse("tests/service-a/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+
+        // Create the packaged file
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig::default();
+        let bytes = package
+            .create_to_writer(&MY_SERVICE_PACKAGE, out.path(), &build_config, vec![])
+            .await
+            .unwrap();
+
+        // Nothing should have been written to the output directory
+        assert!(!package.get_output_path_for_service(out.path()).exists());
+
+        // Verify the contents
+        let gzr = flate2::read::GzDecoder::new(bytes.as_slice());
+        let mut archive = Archive::new(gzr);
+        let mut ents = archive.entries().unwrap();
+        assert_eq!("oxide.json", ents.next_path());
+        assert_eq!("root/", ents.next_path());
+        assert_eq!("root/opt", ents.next_path());
+        assert_eq!("root/opt/oxide", ents.next_path());
+        assert_eq!("root/opt/oxide/my-service", ents.next_path());
+        assert_eq!("root/opt/oxide/my-service/contents.txt", ents.next_path());
+        assert_eq!(
+            "root/opt/oxide/my-service/single-file.txt",
+            ents.next_path()
+        );
+        assert!(ents.next().is_none());
+    }
+
+    // Tests that additional metadata is recorded in oxide.json, and survives
+    // stamping.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_package_as_zone_with_metadata() {
+        let cfg = config::parse("tests/service-a/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+
+        let out = camino_tempfile::tempdir().unwrap();
+        let metadata = BTreeMap::from([("git".to_string(), "abc\"123".to_string())]);
+        let build_config = BuildConfig {
+            metadata: Some(&metadata),
+            ..Default::default()
+        };
+        package
+            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+
+        let read_oxide_json = |path| {
+            let gzr = flate2::read::GzDecoder::new(File::open(path).unwrap());
+            let mut archive = Archive::new(gzr);
+            let mut contents = String::new();
+            archive
+                .entries()
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+
+        let path = package.get_output_path_for_service(out.path());
+        assert_eq!(
+            read_oxide_json(path),
+            r#"{"v":"1","t":"layer","pkg":"my-service","version":"0.0.0","git":"abc\"123"}"#
+        );
+
+        let path = package
+            .stamp(
+                &MY_SERVICE_PACKAGE,
+                out.path(),
+                &semver::Version::new(1, 2, 3),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            read_oxide_json(path),
+            r#"{"v":"1","t":"layer","pkg":"my-service","version":"1.2.3","git":"abc\"123"}"#
+        );
+    }
+
+    // Tests that provenance recorded while stamping is merged with the
+    // metadata already present, replacing any values for the same keys.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_package_stamp_with_provenance() {
+        let cfg = config::parse("tests/service-a/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+
+        let out = camino_tempfile::tempdir().unwrap();
+        let metadata = BTreeMap::from([
+            ("git".to_string(), "abc".to_string()),
+            ("git_commit".to_string(), "abc123".to_string()),
+        ]);
+        let build_config = BuildConfig {
+            metadata: Some(&metadata),
+            ..Default::default()
+        };
+        package
+            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+
+        let provenance = Provenance {
+            git_commit: Some("def456".to_string()),
+            ..Default::default()
+        };
+        let path = package
+            .stamp_with_metadata(
+                &MY_SERVICE_PACKAGE,
+                out.path(),
+                &semver::Version::new(1, 2, 3),
+                &provenance.to_metadata(),
+            )
+            .await
+            .unwrap();
+        let gzr = flate2::read::GzDecoder::new(File::open(path).unwrap());
+        let mut archive = Archive::new(gzr);
+        let mut contents = String::new();
+        archive
+            .entries()
+            .unwrap()
+            .next_entry()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(
+            contents,
+            r#"{"v":"1","t":"layer","pkg":"my-service","version":"1.2.3","git":"abc","git_commit":"def456"}"#
+        );
+    }
+
+    // Tests that stamping a zone image copies sparse files without filling in
+    // their holes
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stamp_zone_preserves_sparse_files() {
+        use std::io::{Seek, Write};
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = camino_tempfile::tempdir().unwrap();
+        let disk = dir.path().join("disk.img");
+        let len = 4 << 20;
+        {
+            let mut file = File::create(&disk).unwrap();
+            file.set_len(len).unwrap();
+            file.seek(std::io::SeekFrom::End(-4)).unwrap();
+            file.write_all(b"data").unwrap();
+        }
+        let meta = disk.metadata().unwrap();
+        if meta.blocks() * 512 >= meta.len() {
+            // The filesystem doesn't support holes; nothing to check.
+            return;
+        }
+
+        let cfg = config::parse_manifest(&format!(
+            r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.paths = [ {{ from = "{disk}", to = "/opt/disk.img" }} ]
+            output.type = "zone"
+            "#
+        ))
+        .unwrap();
+        let name = PackageName::new_const("svc");
+        let package = &cfg.packages[&name];
+
+        let out = camino_tempfile::tempdir().unwrap();
+        package
+            .create(&name, out.path(), &BuildConfig::default())
+            .await
+            .unwrap();
+        let path = package
+            .stamp(&name, out.path(), &semver::Version::new(1, 2, 3))
+            .await
+            .unwrap();
+
+        // Compare the uncompressed sizes of the archives, which would grow
+        // by the size of the holes if they were filled in.
+        let uncompressed_len = |path: &Utf8Path| {
+            let mut gzr = flate2::read::GzDecoder::new(File::open(path).unwrap());
+            std::io::copy(&mut gzr, &mut std::io::sink()).unwrap()
+        };
+        let built_len = uncompressed_len(&package.get_output_path(&name, out.path()));
+        let stamped_len = uncompressed_len(&path);
+        assert!(built_len < len, "holes were filled in when building");
+        assert!(
+            stamped_len.abs_diff(built_len) <= 1024,
+            "built {built_len} bytes, but stamped {stamped_len} bytes"
+        );
+
+        let gzr = flate2::read::GzDecoder::new(File::open(&path).unwrap());
+        let mut archive = Archive::new(gzr);
+        let mut entry = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.path().unwrap().ends_with("disk.img"))
+            .unwrap();
+        assert_eq!(
+            archive::entry_path(&mut entry).unwrap(),
+            "root/opt/disk.img"
+        );
+        let mut contents = vec![];
+        archive::entry_contents(entry)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents.len() as u64, len);
+        assert_eq!(&contents[contents.len() - 4..], b"data");
+    }
+
+    // Tests a rust package being placed into a Zone image
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rust_package_as_zone() {
+        // Parse the configuration
+        let cfg = config::parse("tests/service-b/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+
+        // Create the packaged file
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig::default();
+        package
+            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+
+        // Verify the contents
+        let path = package.get_output_path_for_service(out.path());
+        assert!(path.exists());
+        let gzr = flate2::read::GzDecoder::new(File::open(path).unwrap());
+        let mut archive = Archive::new(gzr);
+        let mut ents = archive.entries().unwrap();
+        assert_eq!("oxide.json", ents.next_path());
+        assert_eq!("root/", ents.next_path());
+        assert_eq!("root/opt", ents.next_path());
+        assert_eq!("root/opt/oxide", ents.next_path());
+        assert_eq!("root/opt/oxide/my-service", ents.next_path());
+        assert_eq!("root/opt/oxide/my-service/contents.txt", ents.next_path());
+        assert_eq!("root/opt/oxide/my-service/bin", ents.next_path());
+        assert_eq!(
+            "root/opt/oxide/my-service/bin/test-service",
+            ents.next_path()
+        );
+        assert!(ents.next().is_none());
+    }
+
+    // Tests locating a rust package's binaries via `cargo metadata`
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rust_package_with_cargo_metadata() {
+        let metadata = CargoMetadata::load(Utf8Path::new(".")).await.unwrap();
+        assert!(metadata.binaries.contains("test-service"));
+
+        let cfg = config::parse("tests/service-b/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig {
+            cargo_metadata: Some(&metadata),
+            ..Default::default()
+        };
+        package
+            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+        assert!(package.get_output_path_for_service(out.path()).exists());
+    }
+
+    // Tests a rust package being placed into a non-Zone image.
+    //
+    // This is used for building packages that exist in the Global Zone,
+    // and don't need (nor want) to be packaged into a full Zone image.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rust_package_as_tarball() {
+        // Parse the configuration
+        let cfg = config::parse("tests/service-c/cfg.toml").unwrap();
+        let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
+
+        // Create the packaged file
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig::default();
+        package
+            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
+            .await
+            .unwrap();
+
+        // Verify the contents
+        let path = package.get_output_path_for_service(out.path());
+        assert!(path.exists());
+        let mut archive = Archive::new(File::open(path).unwrap());
+        let mut ents = archive.entries().unwrap();
+        let mut entry = ents.next_entry();
+        assert_eq!("VERSION", entry_path(&entry));
+        let mut s = String::new();
+        entry.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "0.0.0");
+
+        assert_eq!("test-service", ents.next_path());
+        assert!(ents.next().is_none());
+
+        // Try stamping it, verify the contents again
+        let expected_semver = semver::Version::new(3, 3, 3);
+        let path = package
+            .stamp(&MY_SERVICE_PACKAGE, out.path(), &expected_semver)
+            .await
+            .unwrap();
+        assert!(path.exists());
+        let mut archive = Archive::new(File::open(path).unwrap());
+        let mut ents = archive.entries().unwrap();
+        let mut entry = ents.next_entry();
+        assert_eq!("VERSION", entry_path(&entry));
+        s.clear();
+        entry.read_to_string(&mut s).unwrap();
+        assert_eq!(s, expected_semver.to_string());
+
+        assert_eq!("test-service", ents.next_path());
+        assert!(ents.next().is_none());
+    }
+
+    // Tests that stamping a tarball preserves the headers of its entries
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stamp_tarball_preserves_headers() {
+        let cfg = config::parse_manifest(
+            r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.paths = [
+                { from = "tests/service-a/single-file.txt", to = "opt/file.txt" },
+            ]
+            source.dirs = [ { path = "var/data", mode = 0o700, uid = 12, gid = 34 } ]
+            output.type = "tarball"
+            "#,
+        )
+        .unwrap();
+        let name = PackageName::new_const("svc");
+        let package = &cfg.packages[&name];
+
+        let out = camino_tempfile::tempdir().unwrap();
+        let metadata = BTreeMap::from([("a".to_string(), "1".to_string())]);
+        let build_config = BuildConfig {
+            metadata: Some(&metadata),
+            ..Default::default()
+        };
+        package
+            .create(&name, out.path(), &build_config)
+            .await
+            .unwrap();
+        let built = read_entries(&package.get_output_path(&name, out.path()));
+
+        let path = package
+            .stamp_with_metadata(
+                &name,
+                out.path(),
+                &semver::Version::new(1, 2, 3),
+                &BTreeMap::from([("b".to_string(), "2".to_string())]),
+            )
+            .await
+            .unwrap();
+        let stamped = read_entries(&path);
+        assert_eq!(
+            built.keys().collect::<Vec<_>>(),
+            stamped.keys().collect::<Vec<_>>()
+        );
+
+        // Only the version and metadata are rewritten.
+        assert_eq!(stamped[Utf8Path::new("VERSION")].1, b"1.2.3");
+        assert_eq!(
+            stamped[Utf8Path::new("METADATA")].1,
+            br#"{"a":"1","b":"2"}"#
+        );
+        for (path, (header, contents)) in &built {
+            if path == "VERSION" || path == "METADATA" {
+                continue;
+            }
+            assert_eq!(
+                header_fields(&stamped[path].0),
+                header_fields(header),
+                "{path}"
+            );
+            assert_eq!(&stamped[path].1, contents, "{path}");
+        }
+
+        let (data, _) = &stamped[Utf8Path::new("var/data")];
+        assert_eq!(data.entry_type(), tar::EntryType::Directory);
+        assert_eq!(data.mode().unwrap(), 0o700);
+        assert_eq!((data.uid().unwrap(), data.gid().unwrap()), (12, 34));
+    }
+
+    // Tests that stamping a tarball keeps preserved symlinks as links,
+    // including those which point to nothing
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stamp_tarball_preserves_symlinks() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file.txt"), "contents").unwrap();
+        std::os::unix::fs::symlink("file.txt", dir.path().join("link.txt")).unwrap();
+        std::os::unix::fs::symlink("missing.txt", dir.path().join("dangling.txt")).unwrap();
+
+        let cfg = config::parse_manifest(&format!(
+            r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.paths = [ {{ from = "{}", to = "opt/svc" }} ]
+            output.type = "tarball"
+            preserve_symlinks = true
+            "#,
+            dir.path()
+        ))
+        .unwrap();
+        let name = PackageName::new_const("svc");
+        let package = &cfg.packages[&name];
+
+        let out = camino_tempfile::tempdir().unwrap();
+        package
+            .create(&name, out.path(), &BuildConfig::default())
+            .await
+            .unwrap();
+        let path = package
+            .stamp(&name, out.path(), &semver::Version::new(1, 2, 3))
+            .await
+            .unwrap();
+        let stamped = read_entries(&path);
+        for (link, target) in [
+            ("opt/svc/link.txt", "file.txt"),
+            ("opt/svc/dangling.txt", "missing.txt"),
+        ] {
+            let (header, _) = &stamped[Utf8Path::new(link)];
+            assert_eq!(header.entry_type(), tar::EntryType::Symlink, "{link}");
+            assert_eq!(
+                header.link_name().unwrap().unwrap(),
+                Utf8Path::new(target).as_std_path(),
+                "{link}"
+            );
+        }
+        let (file, contents) = &stamped[Utf8Path::new("opt/svc/file.txt")];
+        assert_eq!(file.entry_type(), tar::EntryType::Regular);
+        assert_eq!(contents, b"contents");
+    }
+
+    // Tests that stamping a tarball keeps the type and metadata of its
+    // placeholders
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stamp_tarball_preserves_placeholders() {
+        let cfg = config::parse_manifest(
+            r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.placeholders = [
+                { path = "var/svc/ready", mode = 0o640, uid = 12 },
+                { path = "var/svc/control", kind = "fifo", mode = 0o600, uid = 12, gid = 34 },
+            ]
+            output.type = "tarball"
+            "#,
+        )
+        .unwrap();
+        let name = PackageName::new_const("svc");
+        let package = &cfg.packages[&name];
+
+        let out = camino_tempfile::tempdir().unwrap();
+        package
+            .create(&name, out.path(), &BuildConfig::default())
+            .await
+            .unwrap();
+        let path = package
+            .stamp(&name, out.path(), &semver::Version::new(1, 2, 3))
+            .await
+            .unwrap();
+        let stamped = read_entries(&path);
+
+        let (ready, contents) = &stamped[Utf8Path::new("var/svc/ready")];
+        assert_eq!(ready.entry_type(), tar::EntryType::Regular);
+        assert_eq!(ready.mode().unwrap(), 0o640);
+        assert_eq!((ready.uid().unwrap(), ready.gid().unwrap()), (12, 0));
+        assert!(contents.is_empty());
+
+        let (control, _) = &stamped[Utf8Path::new("var/svc/control")];
+        assert_eq!(control.entry_type(), tar::EntryType::Fifo);
+        assert_eq!(control.mode().unwrap(), 0o600);
+        assert_eq!((control.uid().unwrap(), control.gid().unwrap()), (12, 34));
+    }
+
+    // Tests that blobs are added to the package, at their destinations
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_package_with_blobs() {
+        use sha2::Digest;
+
+        // The blob has already been downloaded, and matches its digest, so
+        // it's used without contacting the server.
+        let out = camino_tempfile::tempdir().unwrap();
+        let contents = b"firmware";
+        std::fs::create_dir_all(out.path().join("svc")).unwrap();
+        std::fs::write(out.path().join("svc/firmware.bin"), contents).unwrap();
+        let sha256 = hex::encode(sha2::Sha256::digest(contents));
+
+        let cfg = config::parse_manifest(&format!(
+            r#"
+            [package.svc]
+            service_name = "svc"
+            source.type = "local"
+            source.buildomat_blobs = [
+                {{ repo = "svc", series = "image", commit = "{commit}", artifact = "firmware.bin", sha256 = "{sha256}", to = "opt/svc/firmware.bin" }},
+            ]
+            output.type = "tarball"
+            "#,
+            commit = "0".repeat(40),
+        ))
+        .unwrap();
+        let name = PackageName::new_const("svc");
+        let package = &cfg.packages[&name];
+
+        let build_config = BuildConfig::default();
+        package
+            .create(&name, out.path(), &build_config)
             .await
             .unwrap();
 
-        // Verify the contents
-        let path = package.get_output_path_for_service(out.path());
-        assert!(path.exists());
-        let gzr = flate2::read::GzDecoder::new(File::open(path).unwrap());
-        let mut archive = Archive::new(gzr);
-        let mut ents = archive.entries().unwrap();
-        assert_eq!("oxide.json", ents.next_path());
-        assert_eq!("root/", ents.next_path());
-        assert_eq!("root/opt", ents.next_path());
-        assert_eq!("root/opt/oxide", ents.next_path());
-        assert_eq!("root/opt/oxide/my-service", ents.next_path());
-        assert_eq!("root/opt/oxide/my-service/contents.txt", ents.next_path());
-        assert_eq!("root/", ents.next_path());
-        assert_eq!("root/opt", ents.next_path());
-        assert_eq!("root/opt/oxide", ents.next_path());
-        assert_eq!("root/opt/oxide/my-service", ents.next_path());
-        assert_eq!(
-            "root/opt/oxide/my-service/single-file.txt",
-            ents.next_path()
-        );
-        assert!(ents.next().is_none());
+        let entries = read_entries(&package.get_output_path(&name, out.path()));
+        let (header, blob) = &entries[Utf8Path::new("opt/svc/firmware.bin")];
+        assert_eq!(header.entry_type(), tar::EntryType::Regular);
+        assert_eq!(blob, contents);
     }
 
-    // Tests a rust package being placed into a Zone image
+    // Tests that a package of files can be built as an IPS package
     #[tokio::test(flavor = "multi_thread")]
-    async fn test_rust_package_as_zone() {
+    async fn test_package_as_ips() {
         // Parse the configuration
-        let cfg = config::parse("tests/service-b/cfg.toml").unwrap();
+        let cfg = config::parse("tests/service-h/cfg.toml").unwrap();
         let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
 
         // Create the packaged file
@@ -114,36 +1152,93 @@ mod test {
 
         // Verify the contents
         let path = package.get_output_path_for_service(out.path());
-        assert!(path.exists());
-        let gzr = flate2::read::GzDecoder::new(File::open(path).unwrap());
-        let mut archive = Archive::new(gzr);
+        assert_eq!(path.extension(), Some("p5p"));
+        let mut archive = Archive::new(File::open(path).unwrap());
         let mut ents = archive.entries().unwrap();
-        assert_eq!("oxide.json", ents.next_path());
-        assert_eq!("root/", ents.next_path());
-        assert_eq!("root/opt", ents.next_path());
-        assert_eq!("root/opt/oxide", ents.next_path());
-        assert_eq!("root/opt/oxide/my-service", ents.next_path());
-        assert_eq!("root/opt/oxide/my-service/contents.txt", ents.next_path());
-        assert_eq!("root/", ents.next_path());
-        assert_eq!("root/opt", ents.next_path());
-        assert_eq!("root/opt/oxide", ents.next_path());
-        assert_eq!("root/opt/oxide/my-service", ents.next_path());
-        assert_eq!("root/opt/oxide/my-service/bin", ents.next_path());
+        assert_eq!("pkg5.index.0.gz", ents.next_path());
+        assert_eq!("pkg5.repository", ents.next_path());
+        assert_eq!("publisher/oxide/pub.p5i", ents.next_path());
+        let mut entry = ents.next_entry();
         assert_eq!(
-            "root/opt/oxide/my-service/bin/test-service",
-            ents.next_path()
+            "publisher/oxide/pkg/my-service/0.0.0%3A20060724T012128Z",
+            entry_path(&entry)
         );
+        let mut manifest = String::new();
+        entry.read_to_string(&mut manifest).unwrap();
+        let actions: Vec<&str> = manifest.lines().collect();
+        assert_eq!(
+            actions[..5],
+            [
+                "set name=pkg.fmri value=pkg://oxide/my-service@0.0.0:20060724T012128Z",
+                r#"set name=pkg.summary value="An example IPS package""#,
+                "dir group=bin mode=0755 owner=root path=opt",
+                "dir group=bin mode=0755 owner=root path=opt/oxide",
+                "dir group=bin mode=0755 owner=root path=opt/oxide/my-service",
+            ]
+        );
+        assert!(actions[5].contains("path=opt/oxide/my-service/hello.txt"));
+        assert_eq!(actions.len(), 6);
+        assert!(ents.next_path().starts_with("publisher/oxide/file/"));
+        assert!(ents.next().is_none());
+
+        // Try stamping it, verify the manifest again
+        let path = package
+            .stamp(
+                &MY_SERVICE_PACKAGE,
+                out.path(),
+                &semver::Version::new(3, 3, 3),
+            )
+            .await
+            .unwrap();
+        let mut archive = Archive::new(File::open(path).unwrap());
+        let mut ents = archive.entries().unwrap();
+        assert_eq!("pkg5.index.0.gz", ents.next_path());
+        assert_eq!("pkg5.repository", ents.next_path());
+        assert_eq!("publisher/oxide/pub.p5i", ents.next_path());
+        let mut entry = ents.next_entry();
+        assert!(entry_path(&entry)
+            .as_str()
+            .starts_with("publisher/oxide/pkg/my-service/3.3.3%3A"));
+        manifest.clear();
+        entry.read_to_string(&mut manifest).unwrap();
+        assert!(manifest.starts_with("set name=pkg.fmri value=pkg://oxide/my-service@3.3.3:"));
+        assert!(ents.next_path().starts_with("publisher/oxide/file/"));
         assert!(ents.next().is_none());
     }
 
-    // Tests a rust package being placed into a non-Zone image.
-    //
-    // This is used for building packages that exist in the Global Zone,
-    // and don't need (nor want) to be packaged into a full Zone image.
+    // Tests that missing Rust binaries are reported while identifying
+    // inputs, before anything is written
     #[tokio::test(flavor = "multi_thread")]
-    async fn test_rust_package_as_tarball() {
+    async fn test_rust_binary_missing() {
+        let cfg = config::parse("tests/service-e/cfg.toml").unwrap();
+        let name = PackageName::new_const("pkg-2");
+        let package = cfg.packages.get(&name).unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig {
+            target_dir: Some(Utf8Path::new("does-not-exist")),
+            ..Default::default()
+        };
+        let expected = "Identifying all input paths: Cannot find binary 'test-service'; \
+                        tried:\n  does-not-exist/debug/test-service";
+
+        let err = package
+            .plan(&name, out.path(), &build_config)
+            .expect_err("Planning should fail");
+        assert_eq!(format!("{err:#}"), expected);
+
+        let err = package
+            .create(&name, out.path(), &build_config)
+            .await
+            .expect_err("Building should fail");
+        assert_eq!(format!("{err:#}"), expected);
+        assert!(!package.get_output_path(&name, out.path()).exists());
+    }
+
+    // Tests that Rust binaries can be stripped before being packaged
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rust_package_stripped() {
         // Parse the configuration
-        let cfg = config::parse("tests/service-c/cfg.toml").unwrap();
+        let cfg = config::parse("tests/service-i/cfg.toml").unwrap();
         let package = cfg.packages.get(&MY_SERVICE_PACKAGE).unwrap();
 
         // Create the packaged file
@@ -154,38 +1249,37 @@ mod test {
             .await
             .unwrap();
 
+        // The stripped copy should be smaller than the original binary
+        let stripped = out.path().join("stripped/my-service/debug/test-service");
+        let stripped_len = stripped.metadata().unwrap().len();
+        let original_len = Utf8Path::new("target/debug/test-service")
+            .metadata()
+            .unwrap()
+            .len();
+        assert!(stripped_len < original_len);
+        let changed = || {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = stripped.metadata().unwrap();
+            (metadata.ctime(), metadata.ctime_nsec())
+        };
+        let stripped_changed = changed();
+
         // Verify the contents
         let path = package.get_output_path_for_service(out.path());
-        assert!(path.exists());
-        let mut archive = Archive::new(File::open(path).unwrap());
+        let mut archive = Archive::new(File::open(&path).unwrap());
         let mut ents = archive.entries().unwrap();
-        let mut entry = ents.next_entry();
-        assert_eq!("VERSION", entry_path(&entry));
-        let mut s = String::new();
-        entry.read_to_string(&mut s).unwrap();
-        assert_eq!(s, "0.0.0");
-
-        assert_eq!("test-service", ents.next_path());
+        assert_eq!("VERSION", ents.next_path());
+        let entry = ents.next_entry();
+        assert_eq!("test-service", entry_path(&entry));
+        assert_eq!(entry.size(), stripped_len);
         assert!(ents.next().is_none());
 
-        // Try stamping it, verify the contents again
-        let expected_semver = semver::Version::new(3, 3, 3);
-        let path = package
-            .stamp(&MY_SERVICE_PACKAGE, out.path(), &expected_semver)
+        // Building again should not strip the binary again
+        package
+            .create(&MY_SERVICE_PACKAGE, out.path(), &build_config)
             .await
             .unwrap();
-        assert!(path.exists());
-        let mut archive = Archive::new(File::open(path).unwrap());
-        let mut ents = archive.entries().unwrap();
-        assert_eq!("./", ents.next_path());
-        assert_eq!("test-service", ents.next_path());
-        let mut entry = ents.next_entry();
-        assert_eq!("VERSION", entry_path(&entry));
-        s.clear();
-        entry.read_to_string(&mut s).unwrap();
-        assert_eq!(s, expected_semver.to_string());
-
-        assert!(ents.next().is_none());
+        assert_eq!(changed(), stripped_changed);
     }
 
     // Although package and service names are often the same, they do
@@ -274,15 +1368,496 @@ mod test {
         assert_eq!("root/opt", ents.next_path());
         assert_eq!("root/opt/oxide", ents.next_path());
         assert_eq!("root/opt/oxide/pkg-2-file.txt", ents.next_path());
-        assert_eq!("root/", ents.next_path());
-        assert_eq!("root/opt", ents.next_path());
-        assert_eq!("root/opt/oxide", ents.next_path());
         assert_eq!("root/opt/oxide/svc-2", ents.next_path());
         assert_eq!("root/opt/oxide/svc-2/bin", ents.next_path());
         assert_eq!("root/opt/oxide/svc-2/bin/test-service", ents.next_path());
         assert!(ents.next().is_none());
     }
 
+    // Tests a composite package compressed with xz, built from components
+    // with differing compression.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_composite_package_xz() {
+        // Parse the configuration
+        let cfg = config::parse("tests/service-f/cfg.toml").unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+
+        // Build everything in dependency order
+        let build_config = BuildConfig::default();
+        for batch in cfg.packages_to_build(&TargetMap::default()).build_order() {
+            for (package_name, package) in batch {
+                package
+                    .create(package_name, out.path(), &build_config)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // Verify the contents
+        let package_name = PackageName::new_const("pkg-3");
+        let package = cfg.packages.get(&package_name).unwrap();
+        let path = package.get_output_path(&package_name, out.path());
+        assert_eq!(path.file_name(), Some("pkg-3.tar.xz"));
+        let xzr = xz2::read::XzDecoder::new(File::open(path).unwrap());
+        let mut archive = Archive::new(xzr);
+        let mut ents = archive.entries().unwrap();
+        assert_eq!("oxide.json", ents.next_path());
+        assert_eq!("root/", ents.next_path());
+        assert_eq!("root/opt", ents.next_path());
+        assert_eq!("root/opt/oxide", ents.next_path());
+        assert_eq!("root/opt/oxide/pkg-1-file.txt", ents.next_path());
+        assert_eq!("root/", ents.next_path());
+        assert_eq!("root/opt", ents.next_path());
+        assert_eq!("root/opt/oxide", ents.next_path());
+        assert_eq!("root/opt/oxide/pkg-2-file.txt", ents.next_path());
+        assert!(ents.next().is_none());
+    }
+
+    // Tests that components of a composite package may only replace each
+    // other's files when explicitly allowed
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_composite_package_overrides() {
+        // Parse the configuration
+        let cfg = config::parse("tests/service-j/cfg.toml").unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig::default();
+        for name in ["pkg-1", "pkg-2"] {
+            let name = PackageName::new_const(name);
+            cfg.packages[&name]
+                .create(&name, out.path(), &build_config)
+                .await
+                .unwrap();
+        }
+
+        // Without "allow_override", the conflict is an error
+        let name = PackageName::new_const("accidental");
+        let err = cfg.packages[&name]
+            .create(&name, out.path(), &build_config)
+            .await
+            .expect_err("Conflicting components should fail");
+        assert!(
+            format!("{err:#}").contains("would override the copy from"),
+            "{err:#}"
+        );
+
+        // With it, the later component's copy replaces the earlier one, even
+        // when the component is listed first but ordered after the other
+        for name in ["intentional", "ordered"] {
+            let name = PackageName::new_const(name);
+            let package = &cfg.packages[&name];
+            package
+                .create(&name, out.path(), &build_config)
+                .await
+                .unwrap();
+            let path = package.get_output_path(&name, out.path());
+            let gzr = flate2::read::GzDecoder::new(File::open(path).unwrap());
+            let mut archive = Archive::new(gzr);
+            let mut contents = vec![];
+            for entry in archive.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                if entry_path(&entry) == "root/opt/oxide/config.txt" {
+                    let mut s = String::new();
+                    entry.read_to_string(&mut s).unwrap();
+                    contents.push(s);
+                }
+            }
+            assert_eq!(contents, ["from pkg-2\n"], "{name}");
+        }
+    }
+
+    // Tests that files may be excluded or renamed when composing packages
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_composite_package_filters() {
+        // Parse the configuration
+        let cfg = config::parse("tests/service-j/cfg.toml").unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig::default();
+        for name in ["pkg-1", "pkg-2", "excluded", "renamed"] {
+            let name = PackageName::new_const(name);
+            cfg.packages[&name]
+                .create(&name, out.path(), &build_config)
+                .await
+                .unwrap();
+        }
+
+        let read_files = |name: &'static str| {
+            let name = PackageName::new_const(name);
+            let path = cfg.packages[&name].get_output_path(&name, out.path());
+            let gzr = flate2::read::GzDecoder::new(File::open(path).unwrap());
+            let mut archive = Archive::new(gzr);
+            let mut files = BTreeMap::new();
+            for entry in archive.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                if entry.header().entry_type() == tar::EntryType::Regular {
+                    let path = entry_path(&entry).to_string();
+                    let mut s = String::new();
+                    entry.read_to_string(&mut s).unwrap();
+                    files.insert(path, s);
+                }
+            }
+            files
+        };
+
+        let files = read_files("excluded");
+        assert_eq!(files["root/opt/oxide/config.txt"], "from pkg-2\n");
+
+        let files = read_files("renamed");
+        assert_eq!(files["root/opt/oxide/config.txt"], "from pkg-1\n");
+        assert_eq!(files["root/opt/oxide/svc-2/config.txt"], "from pkg-2\n");
+    }
+
+    // Tests that files can be copied from another package
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_package_files() {
+        // Parse the configuration
+        let cfg = config::parse("tests/service-l/cfg.toml").unwrap();
+        let generator = PackageName::new_const("generator");
+        let consumer = PackageName::new_const("consumer");
+
+        // The package providing files is built first
+        let order: Vec<Vec<_>> = cfg
+            .packages_to_build(&TargetMap(BTreeMap::new()))
+            .build_order()
+            .map(|batch| batch.into_iter().map(|(name, _)| name.clone()).collect())
+            .collect();
+        assert_eq!(order, [[generator.clone()], [consumer.clone()]]);
+
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig::default();
+        for name in [&generator, &consumer] {
+            cfg.packages[name]
+                .create(name, out.path(), &build_config)
+                .await
+                .unwrap();
+        }
+
+        // Only the matching file is copied, along with its parent directories
+        let path = cfg.packages[&consumer].get_output_path(&consumer, out.path());
+        let gzr = flate2::read::GzDecoder::new(File::open(path).unwrap());
+        let mut archive = Archive::new(gzr);
+        let mut ents = archive.entries().unwrap();
+        for expected in [
+            "oxide.json",
+            "root/",
+            "root/opt",
+            "root/opt/oxide",
+            "root/opt/oxide/gen",
+        ] {
+            assert_eq!(
+                Utf8Path::new(expected),
+                entry_path(&ents.next().unwrap().unwrap())
+            );
+        }
+        let mut entry = ents.next().unwrap().unwrap();
+        assert_eq!(
+            Utf8Path::new("root/opt/oxide/gen/a.json"),
+            entry_path(&entry)
+        );
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "{\"generated\": true}\n");
+        assert!(ents.next().is_none());
+
+        // Files may only be copied from packages named by the manifest
+        let manifest = std::fs::read_to_string("tests/service-l/cfg.toml")
+            .unwrap()
+            .replace("package = \"generator\"", "package = \"generator.tar.gz\"");
+        let err = config::parse_manifest(&manifest).unwrap_err();
+        assert!(matches!(err, config::ParseError::UnknownDependency { .. }));
+    }
+
+    // Tests that all packages can be built in dependency order
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_build_all() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Instant;
+
+        // When a package started and finished building, if it has.
+        type Interval = (Option<Instant>, Option<Instant>);
+
+        // Records when each package starts and finishes building.
+        #[derive(Clone, Default)]
+        struct BuildTimes {
+            log: Arc<NoProgress>,
+            times: Arc<Mutex<BTreeMap<PackageName, Interval>>>,
+        }
+
+        impl Progress for BuildTimes {
+            fn get_log(&self) -> &slog::Logger {
+                self.log.get_log()
+            }
+
+            fn sub_progress(&self, _total: u64) -> Box<dyn Progress> {
+                Box::new(self.clone())
+            }
+
+            fn report(&self, event: ProgressEvent) {
+                let mut times = self.times.lock().unwrap();
+                match event {
+                    ProgressEvent::PackageStarted { package } => {
+                        times.entry(package).or_default().0 = Some(Instant::now());
+                    }
+                    ProgressEvent::PackageCompleted { package } => {
+                        times.entry(package).or_default().1 = Some(Instant::now());
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        // Parse the configuration
+        let cfg = config::parse("tests/service-e/cfg.toml").unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+
+        let pkg_2 = &cfg.packages[&PackageName::new_const("pkg-2")];
+        assert!(pkg_2.resources.heavy);
+        assert_eq!(pkg_2.resources.memory_mib, Some(2048));
+
+        // Build everything. The dependencies are both heavy, so they are
+        // built one at a time, despite the parallelism allowed.
+        let times = BuildTimes::default();
+        let build_config = BuildConfig {
+            progress: &times,
+            ..Default::default()
+        };
+        let results = cfg.build_all(&build_config, out.path(), 2).await;
+        assert_eq!(
+            results.keys().map(|name| name.as_str()).collect::<Vec<_>>(),
+            ["pkg-1", "pkg-2", "pkg-3"]
+        );
+        for (name, result) in &results {
+            let report = result.as_ref().unwrap();
+            assert!(!report.cache_hit(), "{name} should have been built");
+            assert!(report.output_path.exists());
+        }
+        let interval = |name: &'static str| {
+            let (start, finish) = times.times.lock().unwrap()[&PackageName::new_const(name)];
+            (start.unwrap(), finish.unwrap())
+        };
+        let (start_1, finish_1) = interval("pkg-1");
+        let (start_2, finish_2) = interval("pkg-2");
+        assert!(
+            finish_1 <= start_2 || finish_2 <= start_1,
+            "heavy packages were built concurrently"
+        );
+
+        // Building again should only hit the cache
+        let results = cfg.build_all(&build_config, out.path(), 2).await;
+        assert!(results
+            .values()
+            .all(|result| result.as_ref().unwrap().cache_hit()));
+    }
+
+    // Tests that the state of every package can be followed while building
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_build_all_tracked() {
+        let cfg = config::parse("tests/service-e/cfg.toml").unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let tracker = ProgressTracker::new(NoProgress::new());
+        let build_config = BuildConfig {
+            progress: &tracker,
+            ..Default::default()
+        };
+
+        let results = cfg.build_all(&build_config, out.path(), 2).await;
+        assert!(results.values().all(|result| result.is_ok()));
+        let snapshot = tracker.snapshot();
+        assert_eq!(snapshot.count(PackageState::Done), 3);
+        assert!(snapshot
+            .packages
+            .values()
+            .all(|package| package.inputs_added > 0));
+        assert_eq!(snapshot.to_string(), "3 packages: 3 done");
+
+        // Building again only hits the cache.
+        let tracker = ProgressTracker::new(NoProgress::new());
+        let build_config = BuildConfig {
+            progress: &tracker,
+            ..Default::default()
+        };
+        cfg.build_all(&build_config, out.path(), 2).await;
+        assert_eq!(tracker.snapshot().to_string(), "3 packages: 3 cached");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_build_verify_graph() {
+        let cfg = config::parse("tests/service-e/cfg.toml").unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let build_config = BuildConfig::default();
+
+        let results = cfg.build_all(&build_config, out.path(), 2).await;
+        assert!(results.values().all(|result| result.is_ok()));
+
+        // Once built, every package is up-to-date.
+        let cache = build_config.open_cache(out.path()).await.unwrap();
+        for (name, inputs) in cfg.plan_all(out.path(), &build_config).unwrap() {
+            let output_path = cfg.packages[&name]
+                .get_target_output_path(&name, out.path(), build_config.target)
+                .unwrap();
+            let differences = cache.explain(&inputs, &output_path).await.unwrap();
+            assert!(differences.is_empty(), "{name}: {differences:?}");
+        }
+
+        // The composite package depends upon both of its components.
+        let dependencies = cfg.packages_to_build(build_config.target).dependencies();
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.parse::<PackageName>().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            dependencies
+                .iter()
+                .map(|(name, deps)| (name.to_string(), deps.iter().copied().cloned().collect()))
+                .collect::<BTreeMap<_, Vec<_>>>(),
+            BTreeMap::from([
+                ("pkg-1".to_string(), vec![]),
+                ("pkg-2".to_string(), vec![]),
+                ("pkg-3".to_string(), names(&["pkg-1", "pkg-2"])),
+            ])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_build_all_phase_totals() {
+        let cfg = config::parse("tests/service-e/cfg.toml").unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+
+        let results = cfg.build_all(&BuildConfig::default(), out.path(), 2).await;
+        let reports = results
+            .values()
+            .map(|result| result.as_ref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(reports.len(), 3);
+
+        let totals = phase_totals(reports.iter().copied());
+        let lookups: std::time::Duration = reports
+            .iter()
+            .flat_map(|report| &report.phases)
+            .filter(|phase| phase.name == "cache lookup")
+            .map(|phase| phase.duration)
+            .sum();
+        assert_eq!(totals["cache lookup"], lookups);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_build_all_chrome_trace() {
+        let cfg = config::parse("tests/service-e/cfg.toml").unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let trace = ChromeTraceProgress::new(NoProgress::new());
+        let build_config = BuildConfig {
+            progress: &trace,
+            ..Default::default()
+        };
+
+        let results = cfg.build_all(&build_config, out.path(), 2).await;
+        assert!(results.values().all(|result| result.is_ok()));
+
+        let mut json = vec![];
+        trace.write(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        let events = json["traceEvents"].as_array().unwrap();
+
+        // Each package is shown as a thread, with an event spanning its build.
+        let mut threads: Vec<_> = events
+            .iter()
+            .filter(|event| event["ph"] == "M")
+            .map(|event| event["args"]["name"].as_str().unwrap())
+            .collect();
+        threads.sort();
+        assert_eq!(threads, ["pkg-1", "pkg-2", "pkg-3"]);
+        let package = |name: &str| {
+            events
+                .iter()
+                .find(|event| event["cat"] == "package" && event["name"] == name)
+                .unwrap()
+        };
+
+        // Phases are nested within the package which they belong to.
+        let pkg_3 = package("pkg-3");
+        let lookup = events
+            .iter()
+            .find(|event| {
+                event["cat"] == "phase"
+                    && event["name"] == "cache lookup"
+                    && event["tid"] == pkg_3["tid"]
+            })
+            .unwrap();
+        assert!(lookup["args"]["label"]
+            .as_str()
+            .unwrap()
+            .starts_with("Cache miss"));
+        let start = |event: &serde_json::Value| event["ts"].as_f64().unwrap();
+        let end = |event: &serde_json::Value| start(event) + event["dur"].as_f64().unwrap();
+        assert!(start(lookup) >= start(pkg_3) && end(lookup) <= end(pkg_3));
+
+        // pkg-3 depends on the others, so it can't start before they finish.
+        assert!(start(pkg_3) >= end(package("pkg-1")));
+        assert!(start(pkg_3) >= end(package("pkg-2")));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stamp_all() {
+        let cfg = config::parse("tests/service-f/cfg.toml").unwrap();
+        let out = camino_tempfile::tempdir().unwrap();
+        let target = TargetMap::default();
+
+        let build_config = BuildConfig::default();
+        for batch in cfg.packages_to_build(&target).build_order() {
+            for (package_name, package) in batch {
+                package
+                    .create(package_name, out.path(), &build_config)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // Only the composite package is deployed; its intermediate
+        // components are not stamped.
+        let version = semver::Version::new(2, 0, 0);
+        let stamped = cfg.stamp_all(&target, out.path(), &version).await.unwrap();
+        let package_name = PackageName::new_const("pkg-3");
+        let package = cfg.packages.get(&package_name).unwrap();
+        assert_eq!(
+            stamped,
+            [package.get_stamped_output_path(&package_name, out.path(), &version)]
+        );
+        assert!(stamped[0].exists());
+
+        // Stamped composites may also be named for their version, but are
+        // still stamped from the composite built from their components.
+        let mut cfg = cfg.clone();
+        let package = cfg.packages.get_mut(&package_name).unwrap();
+        package.version_in_filename = true;
+        let stamped = cfg.stamp_all(&target, out.path(), &version).await.unwrap();
+        assert_eq!(stamped, [out.path().join("versioned/pkg-3-2.0.0.tar.xz")]);
+        let xzr = xz2::read::XzDecoder::new(File::open(&stamped[0]).unwrap());
+        let mut archive = Archive::new(xzr);
+        let mut version_found = false;
+        let mut files = vec![];
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let entry_path = entry_path(&entry);
+            if entry_path == "oxide.json" {
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                assert!(contents.contains("\"version\":\"2.0.0\""), "{contents}");
+                version_found = true;
+            } else if entry.header().entry_type().is_file() {
+                files.push(entry_path);
+            }
+        }
+        assert!(version_found);
+        assert_eq!(
+            files,
+            [
+                "root/opt/oxide/pkg-1-file.txt",
+                "root/opt/oxide/pkg-2-file.txt"
+            ]
+        );
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_download() -> Result<()> {
         let out = camino_tempfile::tempdir()?;
@@ -296,4 +1871,30 @@ mod test {
 
         Ok(())
     }
+
+    // Tests that a failed download leaves a previously downloaded blob intact
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_failure_keeps_blob() {
+        let out = camino_tempfile::tempdir().unwrap();
+        let dst = out.path().join("firmware.bin");
+        std::fs::write(&dst, "old firmware").unwrap();
+
+        // The blob's digest doesn't match, so it's downloaded again, but no
+        // such artifact exists.
+        let src = omicron_zone_package::blob::Source::Buildomat(
+            omicron_zone_package::package::PrebuiltBlob {
+                repo: "does-not-exist".to_string(),
+                series: "image".to_string(),
+                commit: "0000000000000000000000000000000000000000".to_string(),
+                artifact: "firmware.bin".to_string(),
+                sha256: "0".repeat(64),
+                to: None,
+            },
+        );
+        download(&NoProgress::new(), &src, &dst)
+            .await
+            .expect_err("Download should fail");
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "old firmware");
+        assert_eq!(std::fs::read_dir(out.path()).unwrap().count(), 1);
+    }
 }
@@ -0,0 +1,5 @@
+#!/bin/sh
+set -eu
+mkdir -p "$OUTPUT_DIR/opt/oxide/my-service"
+echo "$GREETING" > "$OUTPUT_DIR/opt/oxide/my-service/greeting.txt"
+echo "$GREETING" >> "$OUTPUT_DIR/../my-service.runs"